@@ -0,0 +1,651 @@
+// ****************************************************************************
+//  args.rs                                                     ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Structured representation of OCI command-line arguments
+//
+//     Each liboci_cli subcommand is converted into a `CmdLine`, which lists
+//     the subcommand name, its options by name, and its operands. Options
+//     use runc's long option names, and are omitted when they have their
+//     default value. Keeping options by name makes it possible to override,
+//     remove or translate them before they are rendered as argv for a given
+//     runtime. Argument values shared by several backends, like signals and
+//     container ID patterns, are also parsed here.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::ffi::OsString;
+
+use anyhow::{anyhow, Result};
+
+use liboci_cli::{CommonCmd, GlobalOpts, StandardCmd};
+
+// Names of all the subcommands known to liboci_cli
+pub const SUBCOMMANDS: &[&str] = &[
+    "create",
+    "start",
+    "state",
+    "kill",
+    "delete",
+    "checkpoint",
+    "events",
+    "exec",
+    "features",
+    "list",
+    "pause",
+    "ps",
+    "resume",
+    "run",
+    "update",
+    "spec",
+];
+
+// Signal names accepted by `kill`, with or without the SIG prefix
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ABRT", libc::SIGABRT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("WINCH", libc::SIGWINCH),
+    ("PWR", libc::SIGPWR),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Opt
+// ----------------------------------------------------------------------------
+//   A single option, e.g. `--bundle path` or `--detach`
+// ----------------------------------------------------------------------------
+{
+    pub name: String,
+    pub value: Option<OsString>,
+}
+
+impl Opt {
+    pub fn render(&self, argv: &mut Vec<OsString>)
+    // ------------------------------------------------------------------------
+    //   Append the option to an argument vector, as `--name [value]`
+    // ------------------------------------------------------------------------
+    //   Names that already start with a dash, like `-q`, are used as is
+    {
+        if self.name.starts_with('-') {
+            argv.push(self.name.clone().into());
+        } else {
+            argv.push(format!("--{}", self.name).into());
+        }
+        if let Some(value) = &self.value {
+            argv.push(value.clone());
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmdLine
+// ----------------------------------------------------------------------------
+//   The structured command line for a subcommand
+// ----------------------------------------------------------------------------
+//   Operands are the positional arguments, e.g. container ID and signal.
+//   Trailing arguments come last, e.g. the command for `exec`.
+{
+    pub subcommand: &'static str,
+    pub options: Vec<Opt>,
+    pub operands: Vec<OsString>,
+    pub trailing: Vec<OsString>,
+}
+
+impl CmdLine {
+    pub fn new(subcommand: &'static str) -> Self {
+        CmdLine {
+            subcommand,
+            options: Vec::new(),
+            operands: Vec::new(),
+            trailing: Vec::new(),
+        }
+    }
+
+    pub fn flag(&mut self, name: &str, enabled: bool)
+    // ------------------------------------------------------------------------
+    //   Add a boolean option if it is enabled
+    // ------------------------------------------------------------------------
+    {
+        if enabled {
+            self.options.push(Opt {
+                name: name.into(),
+                value: None,
+            });
+        }
+    }
+
+    pub fn option(&mut self, name: &str, value: Option<impl Into<OsString>>)
+    // ------------------------------------------------------------------------
+    //   Add an option with a value, if there is one
+    // ------------------------------------------------------------------------
+    {
+        if let Some(value) = value {
+            self.options.push(Opt {
+                name: name.into(),
+                value: Some(value.into()),
+            });
+        }
+    }
+
+    pub fn operand(&mut self, value: impl Into<OsString>) {
+        self.operands.push(value.into());
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.options.iter().any(|opt| opt.name == name)
+    }
+
+    pub fn foreground(&self) -> bool
+    // ------------------------------------------------------------------------
+    //   Check if the command runs a container process attached to the caller
+    // ------------------------------------------------------------------------
+    {
+        matches!(self.subcommand, "run" | "exec") && !self.has("detach")
+    }
+}
+
+pub fn option_name(arg: &str) -> Option<&str>
+// ----------------------------------------------------------------------------
+//   Return the name of an option in raw form, e.g. `--rootless=auto`
+// ----------------------------------------------------------------------------
+{
+    let name = arg.strip_prefix("--").or_else(|| arg.strip_prefix('-'))?;
+    let name = name.split('=').next().unwrap_or(name);
+    (!name.is_empty()).then_some(name)
+}
+
+pub fn matches(pattern: &str, value: &str) -> bool
+// ----------------------------------------------------------------------------
+//   Check if a value matches a pattern with an optional leading/trailing `*`
+// ----------------------------------------------------------------------------
+{
+    if let Some(rest) = pattern.strip_prefix('*') {
+        return match rest.strip_suffix('*') {
+            Some(inner) => value.contains(inner),
+            None => value.ends_with(rest),
+        };
+    }
+    match pattern.strip_suffix('*') {
+        Some(prefix) => value.starts_with(prefix),
+        None => value == pattern,
+    }
+}
+
+pub fn parse_signal(signal: &str) -> Result<libc::c_int>
+// ----------------------------------------------------------------------------
+//   Parse the signal of `kill`, as a number or a name like `TERM` or `SIGTERM`
+// ----------------------------------------------------------------------------
+{
+    if let Ok(number) = signal.parse() {
+        return Ok(number);
+    }
+    let name = signal.strip_prefix("SIG").unwrap_or(signal);
+    SIGNALS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+        .map(|(_, number)| *number)
+        .ok_or_else(|| anyhow!("Unknown signal {:?}", signal))
+}
+
+pub fn global_options(global: &GlobalOpts) -> Vec<Opt>
+// ----------------------------------------------------------------------------
+//   Options shared by all subcommands
+// ----------------------------------------------------------------------------
+{
+    let mut cmd = CmdLine::new("");
+    cmd.flag("debug", global.debug);
+    cmd.option("log", global.log.clone());
+    cmd.option("log-format", global.log_format.clone());
+    cmd.option("root", global.root.clone());
+    cmd.flag("systemd-cgroup", global.systemd_cgroup);
+    cmd.options
+}
+
+pub trait ToCmdLine
+// ----------------------------------------------------------------------------
+//   Conversion of liboci_cli arguments to a structured command line
+// ----------------------------------------------------------------------------
+{
+    fn to_cmdline(&self) -> CmdLine;
+}
+
+impl ToCmdLine for liboci_cli::Create {
+    fn to_cmdline(&self) -> CmdLine {
+        // See https://github.com/opencontainers/runc/blob/main/man/runc-create.8.md
+        let mut cmd = CmdLine::new("create");
+        cmd.option("bundle", (self.bundle.as_os_str() != ".").then(|| self.bundle.clone()));
+        cmd.option("console-socket", self.console_socket.clone());
+        cmd.option("pid-file", self.pid_file.clone());
+        cmd.flag("no-pivot", self.no_pivot);
+        cmd.flag("no-new-keyring", self.no_new_keyring);
+        cmd.option(
+            "preserve-fds",
+            (self.preserve_fds > 0).then(|| self.preserve_fds.to_string()),
+        );
+        cmd.operand(&self.container_id);
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::Start {
+    fn to_cmdline(&self) -> CmdLine {
+        // See https://github.com/opencontainers/runc/blob/main/man/runc-start.8.md
+        let mut cmd = CmdLine::new("start");
+        cmd.operand(&self.container_id);
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::Kill {
+    fn to_cmdline(&self) -> CmdLine {
+        // See https://github.com/opencontainers/runc/blob/main/man/runc-kill.8.md
+        let mut cmd = CmdLine::new("kill");
+        cmd.flag("all", self.all);
+        cmd.operand(&self.container_id);
+        cmd.operand(&self.signal);
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::Delete {
+    fn to_cmdline(&self) -> CmdLine {
+        // See https://github.com/opencontainers/runc/blob/main/man/runc-delete.8.md
+        let mut cmd = CmdLine::new("delete");
+        cmd.flag("force", self.force);
+        cmd.operand(&self.container_id);
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::State {
+    fn to_cmdline(&self) -> CmdLine {
+        // See https://github.com/opencontainers/runc/blob/main/man/runc-state.8.md
+        let mut cmd = CmdLine::new("state");
+        cmd.operand(&self.container_id);
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::Checkpoint {
+    fn to_cmdline(&self) -> CmdLine {
+        // See https://github.com/opencontainers/runc/blob/main/man/runc-checkpoint.8.md
+        let mut cmd = CmdLine::new("checkpoint");
+        cmd.option(
+            "image-path",
+            (self.image_path.as_os_str() != "checkpoint").then(|| self.image_path.clone()),
+        );
+        cmd.option("work-path", self.work_path.clone());
+        cmd.option("parent-path", self.parent_path.clone());
+        cmd.flag("leave-running", self.leave_running);
+        cmd.flag("tcp-established", self.tcp_established);
+        cmd.flag("ext-unix-sk", self.ext_unix_sk);
+        cmd.flag("shell-job", self.shell_job);
+        cmd.flag("lazy-pages", self.lazy_pages);
+        cmd.option("status-fd", self.status_fd.map(|fd| fd.to_string()));
+        cmd.option("page-server", self.page_server.clone());
+        cmd.flag("file-locks", self.file_locks);
+        cmd.flag("pre-dump", self.pre_dump);
+        cmd.option("manage-cgroups-mode", self.manage_cgroups_mode.clone());
+        cmd.flag("empty-ns", self.empty_ns);
+        cmd.flag("auto-dedup", self.auto_dedup);
+        cmd.operand(&self.container_id);
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::Events {
+    fn to_cmdline(&self) -> CmdLine {
+        // See https://github.com/opencontainers/runc/blob/main/man/runc-events.8.md
+        let mut cmd = CmdLine::new("events");
+        cmd.option(
+            "interval",
+            (self.interval != 5).then(|| self.interval.to_string()),
+        );
+        cmd.flag("stats", self.stats);
+        cmd.operand(&self.container_id);
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::Exec {
+    fn to_cmdline(&self) -> CmdLine {
+        // See https://github.com/opencontainers/runc/blob/main/man/runc-exec.8.md
+        let mut cmd = CmdLine::new("exec");
+        cmd.option("console-socket", self.console_socket.clone());
+        cmd.option("cwd", self.cwd.clone());
+        for (key, val) in &self.env {
+            cmd.option("env", Some(format!("{}={}", key, val)));
+        }
+        cmd.flag("tty", self.tty);
+        cmd.option(
+            "user",
+            self.user.map(|(uid, gid)| match gid {
+                Some(gid) => format!("{}:{}", uid, gid),
+                None => uid.to_string(),
+            }),
+        );
+        for gid in &self.additional_gids {
+            cmd.option("additional-gids", Some(gid.to_string()));
+        }
+        cmd.option("process", self.process.clone());
+        cmd.flag("detach", self.detach);
+        cmd.option("pid-file", self.pid_file.clone());
+        cmd.option("process-label", self.process_label.clone());
+        cmd.option("apparmor", self.apparmor.clone());
+        cmd.flag("no-new-privs", self.no_new_privs);
+        for cap in &self.cap {
+            cmd.option("cap", Some(cap));
+        }
+        cmd.option(
+            "preserve-fds",
+            (self.preserve_fds > 0).then(|| self.preserve_fds.to_string()),
+        );
+        cmd.flag("ignore-paused", self.ignore_paused);
+        cmd.option("cgroup", self.cgroup.clone());
+        cmd.operand(&self.container_id);
+        cmd.trailing = self.command.iter().map(OsString::from).collect();
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::Features {
+    fn to_cmdline(&self) -> CmdLine {
+        CmdLine::new("features")
+    }
+}
+
+impl ToCmdLine for liboci_cli::List {
+    fn to_cmdline(&self) -> CmdLine {
+        let mut cmd = CmdLine::new("list");
+        cmd.option("format", (self.format != "table").then(|| self.format.clone()));
+        cmd.flag("quiet", self.quiet);
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::Pause {
+    fn to_cmdline(&self) -> CmdLine {
+        let mut cmd = CmdLine::new("pause");
+        cmd.operand(&self.container_id);
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::Ps {
+    fn to_cmdline(&self) -> CmdLine {
+        let mut cmd = CmdLine::new("ps");
+        cmd.option("format", (self.format != "table").then(|| self.format.clone()));
+        cmd.operand(&self.container_id);
+        cmd.trailing = self.ps_options.iter().map(OsString::from).collect();
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::Resume {
+    fn to_cmdline(&self) -> CmdLine {
+        let mut cmd = CmdLine::new("resume");
+        cmd.operand(&self.container_id);
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::Run {
+    fn to_cmdline(&self) -> CmdLine {
+        let mut cmd = CmdLine::new("run");
+        cmd.option("bundle", (self.bundle.as_os_str() != ".").then(|| self.bundle.clone()));
+        cmd.option("console-socket", self.console_socket.clone());
+        cmd.flag("detach", self.detach);
+        cmd.option("pid-file", self.pid_file.clone());
+        cmd.flag("no-subreaper", self.no_subreaper);
+        cmd.flag("no-pivot", self.no_pivot);
+        cmd.flag("no-new-keyring", self.no_new_keyring);
+        cmd.option(
+            "preserve-fds",
+            (self.preserve_fds > 0).then(|| self.preserve_fds.to_string()),
+        );
+        cmd.flag("keep", self.keep);
+        cmd.operand(&self.container_id);
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::Update {
+    fn to_cmdline(&self) -> CmdLine {
+        let mut cmd = CmdLine::new("update");
+        cmd.option("resources", self.resources.clone());
+        cmd.option("blkio-weight", self.blkio_weight.map(|v| v.to_string()));
+        cmd.option("cpu-period", self.cpu_period.map(|v| v.to_string()));
+        cmd.option("cpu-quota", self.cpu_quota.map(|v| v.to_string()));
+        cmd.option("cpu-rt-period", self.cpu_rt_period.map(|v| v.to_string()));
+        cmd.option("cpu-rt-runtime", self.cpu_rt_runtime.map(|v| v.to_string()));
+        cmd.option("cpu-share", self.cpu_share.map(|v| v.to_string()));
+        cmd.option("cpuset-cpus", self.cpuset_cpus.clone());
+        cmd.option("cpuset-mems", self.cpuset_mems.clone());
+        cmd.option("memory", self.memory.map(|v| v.to_string()));
+        cmd.option(
+            "memory-reservation",
+            self.memory_reservation.map(|v| v.to_string()),
+        );
+        cmd.option("memory-swap", self.memory_swap.map(|v| v.to_string()));
+        cmd.option("pids-limit", self.pids_limit.map(|v| v.to_string()));
+        cmd.option("l3-cache-schema", self.l3_cache_schema.clone());
+        cmd.option("mem-bw-schema", self.mem_bw_schema.clone());
+        cmd.operand(&self.container_id);
+        cmd
+    }
+}
+
+impl ToCmdLine for liboci_cli::Spec {
+    fn to_cmdline(&self) -> CmdLine {
+        let mut cmd = CmdLine::new("spec");
+        cmd.option("bundle", self.bundle.clone());
+        cmd.flag("rootless", self.rootless);
+        cmd
+    }
+}
+
+pub trait Invocation
+// ----------------------------------------------------------------------------
+//   What identifies a subcommand, for logs, journals and layers
+// ----------------------------------------------------------------------------
+{
+    // The name of the subcommand, as used on the command line
+    fn name(&self) -> &'static str;
+
+    // The container ID the subcommand applies to, if any
+    fn container_id(&self) -> Option<&str>;
+}
+
+impl Invocation for StandardCmd {
+    fn name(&self) -> &'static str {
+        match self {
+            StandardCmd::Create(_) => "create",
+            StandardCmd::Start(_) => "start",
+            StandardCmd::State(_) => "state",
+            StandardCmd::Kill(_) => "kill",
+            StandardCmd::Delete(_) => "delete",
+        }
+    }
+
+    fn container_id(&self) -> Option<&str> {
+        let id = match self {
+            StandardCmd::Create(args) => &args.container_id,
+            StandardCmd::Start(args) => &args.container_id,
+            StandardCmd::State(args) => &args.container_id,
+            StandardCmd::Kill(args) => &args.container_id,
+            StandardCmd::Delete(args) => &args.container_id,
+        };
+        Some(id)
+    }
+}
+
+impl Invocation for CommonCmd {
+    fn name(&self) -> &'static str {
+        match self {
+            CommonCmd::Checkpointt(_) => "checkpoint",
+            CommonCmd::Events(_) => "events",
+            CommonCmd::Exec(_) => "exec",
+            CommonCmd::Features(_) => "features",
+            CommonCmd::List(_) => "list",
+            CommonCmd::Pause(_) => "pause",
+            CommonCmd::Ps(_) => "ps",
+            CommonCmd::Resume(_) => "resume",
+            CommonCmd::Run(_) => "run",
+            CommonCmd::Update(_) => "update",
+            CommonCmd::Spec(_) => "spec",
+        }
+    }
+
+    fn container_id(&self) -> Option<&str> {
+        let id = match self {
+            CommonCmd::Checkpointt(args) => &args.container_id,
+            CommonCmd::Events(args) => &args.container_id,
+            CommonCmd::Exec(args) => &args.container_id,
+            CommonCmd::Pause(args) => &args.container_id,
+            CommonCmd::Ps(args) => &args.container_id,
+            CommonCmd::Resume(args) => &args.container_id,
+            CommonCmd::Run(args) => &args.container_id,
+            CommonCmd::Update(args) => &args.container_id,
+            CommonCmd::Features(_) | CommonCmd::List(_) | CommonCmd::Spec(_) => return None,
+        };
+        Some(id)
+    }
+}
+
+pub trait Duplicate
+// ----------------------------------------------------------------------------
+//   Copy liboci_cli arguments, for backends that may run a command twice
+// ----------------------------------------------------------------------------
+{
+    fn duplicate(&self) -> Self;
+}
+
+// Listing all fields ensures that new fields in liboci_cli are not missed
+macro_rules! duplicate {
+    ($type:ident { $($field:ident),* }) => {
+        impl Duplicate for liboci_cli::$type {
+            fn duplicate(&self) -> Self {
+                liboci_cli::$type {
+                    $($field: self.$field.clone()),*
+                }
+            }
+        }
+    };
+}
+
+duplicate!(Create {
+    bundle,
+    console_socket,
+    pid_file,
+    no_pivot,
+    no_new_keyring,
+    preserve_fds,
+    container_id
+});
+duplicate!(Start { container_id });
+duplicate!(State { container_id });
+duplicate!(Kill { container_id, signal, all });
+duplicate!(Delete { container_id, force });
+duplicate!(Checkpoint {
+    image_path,
+    work_path,
+    parent_path,
+    leave_running,
+    tcp_established,
+    ext_unix_sk,
+    shell_job,
+    lazy_pages,
+    status_fd,
+    page_server,
+    file_locks,
+    pre_dump,
+    manage_cgroups_mode,
+    empty_ns,
+    auto_dedup,
+    container_id
+});
+duplicate!(Events { interval, stats, container_id });
+duplicate!(Exec {
+    console_socket,
+    cwd,
+    env,
+    tty,
+    user,
+    additional_gids,
+    process,
+    detach,
+    pid_file,
+    process_label,
+    apparmor,
+    no_new_privs,
+    cap,
+    preserve_fds,
+    ignore_paused,
+    cgroup,
+    container_id,
+    command
+});
+duplicate!(Features {});
+duplicate!(List { format, quiet });
+duplicate!(Pause { container_id });
+duplicate!(Ps { format, container_id, ps_options });
+duplicate!(Resume { container_id });
+duplicate!(Run {
+    bundle,
+    console_socket,
+    pid_file,
+    no_subreaper,
+    no_pivot,
+    no_new_keyring,
+    preserve_fds,
+    keep,
+    container_id,
+    detach
+});
+duplicate!(Update {
+    resources,
+    blkio_weight,
+    cpu_period,
+    cpu_quota,
+    cpu_rt_period,
+    cpu_rt_runtime,
+    cpu_share,
+    cpuset_cpus,
+    cpuset_mems,
+    memory,
+    memory_reservation,
+    memory_swap,
+    pids_limit,
+    l3_cache_schema,
+    mem_bw_schema,
+    container_id
+});
+duplicate!(Spec { bundle, rootless });
@@ -1,555 +1,524 @@
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsString;
-use std::os::unix::process::ExitStatusExt;
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
-use std::process::Command;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use tracing::{debug, warn};
 
 use liboci_cli::GlobalOpts;
 
-use super::Backend;
+use super::args::{self, CmdLine, Opt, ToCmdLine};
+use super::output::{self, Kind};
+use super::probe::{self, Prober};
+use super::reason::{self, Tee};
+use super::translate::{Action, Translator};
+use super::{Backend, Error, StateRoot};
+use crate::{fds, reaper};
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct CommandConfig {
+    // Global options, placed before the subcommand
+    #[serde(default)]
+    global_args: Vec<String>,
+
+    // Subcommand options, placed after the subcommand
+    #[serde(default)]
+    extra_args: Vec<String>,
+
+    // Names of generated options to remove
+    #[serde(default)]
+    remove_args: Vec<String>,
+
+    // Environment changes for the runtime
+    #[serde(default)]
+    set_env: BTreeMap<String, String>,
+    #[serde(default)]
+    unset_env: Vec<String>,
+
+    // Seconds to wait for the runtime, and before killing it after SIGTERM
+    timeout: Option<u64>,
+    kill_grace: Option<u64>,
+}
 
 #[derive(Debug, serde::Deserialize)]
 pub struct Config {
     path: PathBuf,
+
+    // Replace ociplex with the runtime instead of running it as a child
+    #[serde(default)]
+    exec: bool,
+
+    // Print the command and its environment instead of running it
+    #[serde(default)]
+    dry_run: bool,
+
+    // Capture and normalize the JSON output of state, list and ps
+    #[serde(default)]
+    normalize: bool,
+
+    // Translation profile, and additional translations for options
+    profile: Option<String>,
+    #[serde(default)]
+    flags: HashMap<String, Action>,
+
+    // Probe the runtime to find unsupported options
+    probe: Option<probe::Config>,
+
+    // Settings for all subcommands
+    #[serde(flatten)]
+    defaults: CommandConfig,
+
+    // Settings for specific subcommands, overriding the defaults
+    #[serde(default)]
+    commands: HashMap<String, CommandConfig>,
 }
 
 impl Config {
-    pub fn instantiate(self, global: GlobalOpts) -> Box<dyn Backend> {
-        Box::new(CliBackend::new(self.path, global))
+    pub fn state_root(&self) -> StateRoot
+    // ------------------------------------------------------------------------
+    //   The state root of the runtime, from configured global arguments
+    // ------------------------------------------------------------------------
+    {
+        let root = option_groups(&self.defaults.global_args)
+            .into_iter()
+            .rfind(|(name, _)| *name == "root")
+            .and_then(|(_, group)| match group[0].split_once('=') {
+                Some((_, value)) => Some(value.to_string()),
+                None => group.get(1).cloned(),
+            });
+        match root {
+            Some(path) => StateRoot::Path(path),
+            None => StateRoot::Global,
+        }
+    }
+
+    pub fn replaces_process(&self) -> bool {
+        self.exec && !super::dry_run(self.dry_run)
+    }
+
+    pub fn instantiate(self, global: GlobalOpts) -> Result<Box<dyn Backend>> {
+        for name in self.commands.keys() {
+            if !args::SUBCOMMANDS.contains(&name.as_str()) {
+                return Err(anyhow!("Unknown subcommand {:?} in CLI backend config", name));
+            }
+        }
+        Ok(Box::new(CliBackend::new(self, global)?))
     }
 }
 
 #[derive(Debug)]
 struct CliBackend {
     path: PathBuf,
-    global_opts: Vec<OsString>,
-    debug: bool,
+    exec: bool,
+    dry_run: bool,
+    normalize: bool,
+    translator: Translator,
+    prober: Option<Prober>,
+    global_opts: Vec<Opt>,
+    log: Option<PathBuf>,
+    defaults: CommandConfig,
+    commands: HashMap<String, CommandConfig>,
 }
 
-impl CliBackend {
-    fn new(path: PathBuf, global: GlobalOpts) -> Self {
-        let mut opts = Vec::<OsString>::new();
-
-        if global.debug {
-            opts.push("--debug".into());
+// Subcommands where the container may inherit the stdio of the runtime
+const CONTAINER_STDIO: &[&str] = &["create", "run", "exec"];
+
+// Subcommands that may legitimately run forever, only timed out explicitly,
+// like `run` and `exec` in the foreground
+const UNBOUNDED: &[&str] = &["events"];
+
+// Default delay between SIGTERM and SIGKILL when a runtime times out
+const DEFAULT_KILL_GRACE: u64 = 5;
+
+// How often to check if a runtime with a timeout is done
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn option_groups(raw: &[String]) -> Vec<(&str, &[String])>
+// ----------------------------------------------------------------------------
+//   Split raw arguments into options followed by their values, if any
+// ----------------------------------------------------------------------------
+//   For example, `["--criu", "/bin/criu", "--debug"]` is split into two
+//   groups, `--criu` with its value, and `--debug`.
+{
+    let mut groups = Vec::new();
+    let mut start = 0;
+    for index in 1..=raw.len() {
+        if index == raw.len() || args::option_name(&raw[index]).is_some() {
+            let name = args::option_name(&raw[start]).unwrap_or("");
+            groups.push((name, &raw[start..index]));
+            start = index;
         }
+    }
+    groups
+}
 
-        if let Some(logfile) = global.log {
-            opts.push("--log".into());
-            opts.push(logfile.into_os_string());
+fn overrides(
+    generated: &mut Vec<Opt>,
+    configs: &[&CommandConfig],
+    raw: fn(&CommandConfig) -> &[String],
+) -> Vec<OsString>
+// ----------------------------------------------------------------------------
+//   Remove generated options that are overriden, return extra arguments
+// ----------------------------------------------------------------------------
+//   Configurations are given from most generic to most specific. An option
+//   in a configuration overrides the same option in a more generic one, or
+//   an option generated from the ociplex command line.
+{
+    let mut extra: Vec<(&str, &[String])> = Vec::new();
+    for config in configs {
+        for name in &config.remove_args {
+            generated.retain(|opt| &opt.name != name);
+        }
+        for (name, group) in option_groups(raw(config)) {
+            generated.retain(|opt| opt.name != name);
+            extra.retain(|(other, _)| *other != name);
+            extra.push((name, group));
         }
+    }
+    extra
+        .into_iter()
+        .flat_map(|(_, group)| group.iter().map(OsString::from))
+        .collect()
+}
 
-        if let Some(format) = global.log_format {
-            opts.push("--log-format".into());
-            opts.push(format.into());
+fn wait_timeout(
+    child: &mut Child,
+    group: bool,
+    timeout: Duration,
+    grace: Duration,
+) -> Result<ExitStatus>
+// ----------------------------------------------------------------------------
+//   Wait for a child, and terminate it if it takes too long
+// ----------------------------------------------------------------------------
+//   On timeout, the child is sent SIGTERM, then SIGKILL after the grace
+//   period. If the child leads its own process group, the whole group is
+//   signaled, so that processes it started go too. The result is then a
+//   timeout error, whatever the exit status.
+{
+    let poll = |deadline: Instant, child: &mut Child| -> Result<Option<ExitStatus>> {
+        while Instant::now() < deadline {
+            if let Some(status) = reaper::try_wait(child)? {
+                return Ok(Some(status));
+            }
+            thread::sleep(POLL_INTERVAL);
         }
+        Ok(None)
+    };
 
-        if let Some(root) = global.root {
-            opts.push("--root".into());
-            opts.push(root.into_os_string());
-        }
+    let start = Instant::now();
+    if let Some(status) = poll(start + timeout, child)? {
+        return Ok(status);
+    }
 
-        if global.systemd_cgroup {
-            opts.push("--systemd-cgroup".into());
-        }
+    // The child is not waited for, so its pid and group remain valid
+    let pid = child.id() as libc::pid_t;
+    let target = if group { -pid } else { pid };
+    warn!("Backend CLI timed out after {:?}, terminating it", timeout);
+    // SAFETY: kill has no memory effects
+    unsafe { libc::kill(target, libc::SIGTERM) };
+    if poll(Instant::now() + grace, child)?.is_none() {
+        warn!("Backend CLI did not terminate after {:?}, killing it", grace);
+        // SAFETY: kill has no memory effects
+        unsafe { libc::kill(target, libc::SIGKILL) };
+        reaper::wait(child)?;
+    }
+    Err(Error::Timeout {
+        seconds: timeout.as_secs(),
+    }
+    .into())
+}
 
-        CliBackend {
-            path,
-            global_opts: opts,
-            debug: global.debug,
-        }
+fn show(cmd: &Command) -> Result<()>
+// ----------------------------------------------------------------------------
+//   Print the arguments and environment of a command, for a dry run
+// ----------------------------------------------------------------------------
+{
+    let mut env: BTreeMap<String, String> = std::env::vars_os()
+        .map(|(key, value)| (key.to_string_lossy().into(), value.to_string_lossy().into()))
+        .collect();
+    for (key, value) in cmd.get_envs() {
+        let key = key.to_string_lossy().into_owned();
+        match value {
+            Some(value) => env.insert(key, value.to_string_lossy().into()),
+            None => env.remove(&key),
+        };
     }
+    let command: Vec<_> = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect();
+    let line = serde_json::json!({ "command": command, "env": env });
+    writeln!(io::stdout(), "{}", line)?;
+    Ok(())
+}
 
-    fn invoke(&self, args: impl IntoIterator<Item = OsString>) -> Result<()> {
-        let mut cmd = Command::new(&self.path);
-        cmd.args(&self.global_opts).args(args);
+impl CliBackend {
+    fn new(config: Config, global: GlobalOpts) -> Result<Self> {
+        let translator = Translator::new(config.profile.as_deref(), &config.path, config.flags)?;
+        let prober = config.probe.map(|probe| Prober::new(&config.path, probe));
+        Ok(CliBackend {
+            path: config.path,
+            exec: config.exec,
+            dry_run: super::dry_run(config.dry_run),
+            normalize: config.normalize,
+            translator,
+            prober,
+            global_opts: args::global_options(&global),
+            log: global.log,
+            defaults: config.defaults,
+            commands: config.commands,
+        })
+    }
 
-        if self.debug {
-            println!("Running command {:?}", cmd)
-        }
+    fn configs(&self, subcommand: &str) -> Vec<&CommandConfig>
+    // ------------------------------------------------------------------------
+    //   Configurations that apply to a subcommand, most generic first
+    // ------------------------------------------------------------------------
+    {
+        let mut configs = vec![&self.defaults];
+        configs.extend(self.commands.get(subcommand));
+        configs
+    }
 
-        let status = cmd.status()?;
+    fn timeout(&self, cmd: &CmdLine) -> Option<(Duration, Duration)>
+    // ------------------------------------------------------------------------
+    //   The timeout and kill grace period for a command, if any
+    // ------------------------------------------------------------------------
+    //   A timeout at the top level does not apply to unbounded subcommands,
+    //   nor to container processes running in the foreground.
+    {
+        let specific = self.commands.get(cmd.subcommand);
+        let timeout = specific.and_then(|c| c.timeout).or_else(|| {
+            if UNBOUNDED.contains(&cmd.subcommand) || cmd.foreground() {
+                None
+            } else {
+                self.defaults.timeout
+            }
+        })?;
+        let grace = specific
+            .and_then(|c| c.kill_grace)
+            .or(self.defaults.kill_grace)
+            .unwrap_or(DEFAULT_KILL_GRACE);
+        Some((Duration::from_secs(timeout), Duration::from_secs(grace)))
+    }
+
+    fn argv(&self, mut cmd: CmdLine) -> Result<Option<Vec<OsString>>>
+    // ------------------------------------------------------------------------
+    //   Build the runtime arguments, or None if the command is to be dropped
+    // ------------------------------------------------------------------------
+    //   Options are first translated for the target runtime, then those the
+    //   runtime does not support are filtered out, then configured overrides
+    //   are applied, using the spelling of the target runtime.
+    {
+        if let Some(prober) = &self.prober {
+            prober.check_bundle(&cmd)?;
+        }
+        let mut global = self.global_opts.clone();
+        if !self.translator.translate(&mut cmd, &mut global)? {
+            return Ok(None);
+        }
+        if let Some(prober) = &self.prober {
+            prober.filter(&mut cmd, &mut global)?;
+        }
+
+        let configs = self.configs(cmd.subcommand);
+        let global_extra = overrides(&mut global, &configs, |c| &c.global_args);
+        let extra = overrides(&mut cmd.options, &configs, |c| &c.extra_args);
+
+        let mut argv = Vec::new();
+        for opt in &global {
+            opt.render(&mut argv);
+        }
+        argv.extend(global_extra);
+        argv.push(cmd.subcommand.into());
+        for opt in &cmd.options {
+            opt.render(&mut argv);
+        }
+        argv.extend(extra);
+        argv.extend(cmd.operands);
+        argv.extend(cmd.trailing);
+        Ok(Some(argv))
+    }
 
-        if self.debug {
-            println!("Command status {:?}", status)
+    fn not_found(&self, err: io::Error) -> anyhow::Error
+    // ------------------------------------------------------------------------
+    //   Identify the error when the runtime itself does not exist
+    // ------------------------------------------------------------------------
+    {
+        match err.kind() {
+            io::ErrorKind::NotFound => Error::NotFound {
+                path: self.path.display().to_string(),
+            }
+            .into(),
+            _ => err.into(),
         }
+    }
+
+    fn invoke(&self, args: CmdLine) -> Result<()> {
+        let capture = if self.normalize { Kind::of(&args) } else { None };
+        let tee = !CONTAINER_STDIO.contains(&args.subcommand);
+        let timeout = self.timeout(&args);
+        let interactive = args.foreground();
+        let mut cmd = Command::new(&self.path);
+        for config in self.configs(args.subcommand) {
+            for name in &config.unset_env {
+                cmd.env_remove(name);
+            }
+            cmd.envs(&config.set_env);
+        }
+        match self.argv(args)? {
+            Some(argv) => cmd.args(argv),
+            None => return Ok(()),
+        };
+        if self.dry_run {
+            return show(&cmd);
+        }
+        fds::pass_through(&mut cmd)?;
+
+        // Fast path: nothing to do after the runtime is done, so let it take
+        // our place. It keeps our pid and receives the signals sent to us.
+        if self.exec && capture.is_none() && timeout.is_none() {
+            debug!("Executing command {:?}", cmd);
+            let err = self.not_found(cmd.exec());
+            return Err(err).with_context(|| format!("Executing {}", self.path.display()));
+        }
+
+        debug!("Running command {:?}", cmd);
+
+        // A container given our stderr would keep the pipe open, so only
+        // tee stderr for other subcommands, and rely on the log for these
+        if tee {
+            cmd.stderr(Stdio::piped());
+        }
+        if capture.is_some() {
+            cmd.stdout(Stdio::piped());
+        }
+        // A runtime that may be timed out gets its own process group, so
+        // that it can be terminated with all the processes it started. One
+        // attached to the caller stays in the foreground group of its
+        // terminal, to read from it and receive Ctrl-C.
+        let group = timeout.is_some() && !interactive;
+        if group {
+            cmd.process_group(0);
+        }
+        let offset = self.log.as_deref().map(reason::log_offset);
+        let mut child = cmd
+            .spawn()
+            .map_err(|err| self.not_found(err))
+            .with_context(|| format!("Running {}", self.path.display()))?;
+        let stderr = child.stderr.take().map(Tee::start);
+        let stdout = child.stdout.take().map(|mut pipe| {
+            thread::spawn(move || {
+                let mut stdout = Vec::new();
+                let _ = pipe.read_to_end(&mut stdout);
+                stdout
+            })
+        });
+        let status = match timeout {
+            Some((timeout, grace)) => wait_timeout(&mut child, group, timeout, grace)?,
+            None => reaper::wait(&mut child)?,
+        };
+        let stderr = stderr.map(Tee::finish).unwrap_or_default();
+        let stdout = stdout
+            .map(|reader| reader.join().unwrap_or_default())
+            .unwrap_or_default();
+
+        debug!("Command status {:?}", status);
 
         if status.success() {
+            if let Some(kind) = capture {
+                let text = output::normalize(kind, &stdout).with_context(|| {
+                    format!("Invalid {} output from backend {}", kind.subcommand(), self.path.display())
+                })?;
+                io::stdout().write_all(&text)?;
+            }
             return Ok(());
         }
 
-        Err(if let Some(sig) = status.signal() {
-            anyhow!("Backend CLI terminated with signal {:?}", sig)
-        } else if let Some(code) = status.code() {
-            anyhow!("Backend CLI failed with status code {}", code)
-        } else {
-            anyhow!("Unidentified failure in backend CLI")
-        })
+        // The output of a failed command is passed through unchanged
+        io::stdout().write_all(&stdout)?;
+
+        // Keep the exact status so that the caller can reflect it
+        let reason = match (&self.log, offset) {
+            (Some(log), Some(offset)) => reason::from_log(log, offset),
+            _ => None,
+        };
+        match Error::from_status(status, reason.or_else(|| reason::from_stderr(&stderr))) {
+            Some(err) => Err(err.into()),
+            None => Err(anyhow!("Unidentified failure in backend CLI")),
+        }
     }
 }
 
 impl Backend for CliBackend {
     // Standard commands (from liboci_cli::StandardCmd)
     fn create(&self, args: liboci_cli::Create) -> Result<()> {
-        // See https://github.com/opencontainers/runc/blob/main/man/runc-create.8.md
-        let mut backargs = Vec::<OsString>::new();
-
-        backargs.push("create".into());
-
-        if args.bundle.as_os_str() != "." {
-            backargs.push("--bundle".into());
-            backargs.push(args.bundle.into_os_string());
-        }
-
-        if let Some(consock) = args.console_socket {
-            backargs.push("--console-socket".into());
-            backargs.push(consock.into_os_string());
-        }
-
-        if let Some(pidfile) = args.pid_file {
-            backargs.push("--pid-file".into());
-            backargs.push(pidfile.into_os_string());
-        }
-
-        if args.no_pivot {
-            backargs.push("--no-pivot".into())
-        }
-
-        if args.no_new_keyring {
-            backargs.push("--no-new-keyring".into());
-        }
-
-        if args.preserve_fds > 0 {
-            backargs.push("--preserve-fds".into());
-            backargs.push(args.preserve_fds.to_string().into())
-        }
-
-        backargs.push(args.container_id.into());
-
-        self.invoke(backargs)
+        self.invoke(args.to_cmdline())
     }
 
     fn start(&self, args: liboci_cli::Start) -> Result<()> {
-        // See https://github.com/opencontainers/runc/blob/main/man/runc-start.8.md
-        let mut backargs = Vec::<OsString>::new();
-
-        backargs.push("start".into());
-        backargs.push(args.container_id.into());
-
-        self.invoke(backargs)
+        self.invoke(args.to_cmdline())
     }
 
     fn kill(&self, args: liboci_cli::Kill) -> Result<()> {
-        // See https://github.com/opencontainers/runc/blob/main/man/runc-kill.8.md
-        let mut backargs = Vec::<OsString>::new();
-
-        backargs.push("kill".into());
-        if args.all {
-            backargs.push("--all".into())
-        }
-        backargs.push(args.container_id.into());
-        backargs.push(args.signal.into());
-
-        self.invoke(backargs)
+        self.invoke(args.to_cmdline())
     }
 
     fn delete(&self, args: liboci_cli::Delete) -> Result<()> {
-        // See https://github.com/opencontainers/runc/blob/main/man/runc-delete.8.md
-        let mut backargs = Vec::<OsString>::new();
-
-        backargs.push("delete".into());
-        if args.force {
-            backargs.push("--force".into())
-        }
-        backargs.push(args.container_id.into());
-
-        self.invoke(backargs)
+        self.invoke(args.to_cmdline())
     }
 
     fn state(&self, args: liboci_cli::State) -> Result<()> {
-        // See https://github.com/opencontainers/runc/blob/main/man/runc-state.8.md
-        let mut backargs = Vec::<OsString>::new();
-
-        backargs.push("state".into());
-        backargs.push(args.container_id.into());
-
-        self.invoke(backargs)
+        self.invoke(args.to_cmdline())
     }
 
     // Common non-standard commands (from liboci_cli::CommonCmd)
     fn checkpoint(&self, args: liboci_cli::Checkpoint) -> Result<()> {
-        // See https://github.com/opencontainers/runc/blob/main/man/runc-checkpoint.8.md
-        let mut backargs = Vec::<OsString>::new();
-
-        backargs.push("checkpoint".into());
-
-        if args.image_path.as_os_str() != "checkpoint" {
-            backargs.push("--image-path".into());
-            backargs.push(args.image_path.into_os_string())
-        }
-        if let Some(work_path) = args.work_path {
-            backargs.push("--work-path".into());
-            backargs.push(work_path.into_os_string())
-        }
-        if let Some(parent_path) = args.parent_path {
-            backargs.push("--parent-path".into());
-            backargs.push(parent_path.into_os_string())
-        }
-        if args.leave_running {
-            backargs.push("--leave-running".into())
-        }
-        if args.tcp_established {
-            backargs.push("--tcp-established".into())
-        }
-        if args.ext_unix_sk {
-            backargs.push("--ext-unix-sk".into())
-        }
-        if args.shell_job {
-            backargs.push("--shell-job".into())
-        }
-        if args.lazy_pages {
-            backargs.push("--lazy-pages".into())
-        }
-        if let Some(status_fd) = args.status_fd {
-            backargs.push("--status-fd".into());
-            backargs.push(status_fd.to_string().into())
-        }
-        if let Some(page_server) = args.page_server {
-            backargs.push("--page-server".into());
-            backargs.push(page_server.into())
-        }
-        if args.file_locks {
-            backargs.push("--file-locks".into())
-        }
-        if args.pre_dump {
-            backargs.push("--pre-dump".into())
-        }
-        if let Some(cgroups_mode) = args.manage_cgroups_mode {
-            backargs.push("--manage-cgroups-mode".into());
-            backargs.push(cgroups_mode.into())
-        }
-        if args.empty_ns {
-            backargs.push("--empty-ns".into())
-        }
-        if args.auto_dedup {
-            backargs.push("--auto-dedup".into())
-        }
-
-        backargs.push(args.container_id.into());
-
-        self.invoke(backargs)
+        self.invoke(args.to_cmdline())
     }
 
     fn events(&self, args: liboci_cli::Events) -> Result<()> {
-        // See https://github.com/opencontainers/runc/blob/main/man/runc-events.8.md
-        let mut backargs = Vec::<OsString>::new();
-
-        backargs.push("events".into());
-
-        if args.interval != 5 {
-            backargs.push("--interval".into());
-            backargs.push(args.interval.to_string().into());
-        }
-        if args.stats {
-            backargs.push("--stats".into())
-        }
-        backargs.push(args.container_id.into());
-
-        self.invoke(backargs)
+        self.invoke(args.to_cmdline())
     }
 
     fn exec(&self, args: liboci_cli::Exec) -> Result<()> {
-        // See https://github.com/opencontainers/runc/blob/main/man/runc-exec.8.md
-        let mut backargs = Vec::<OsString>::new();
-
-        backargs.push("exec".into());
-
-        if let Some(consock) = args.console_socket {
-            backargs.push("--console-socket".into());
-            backargs.push(consock.into());
-        }
-
-        if let Some(cwd) = args.cwd {
-            backargs.push("--cwd".into());
-            backargs.push(cwd.into());
-        }
-
-        for (key, val) in args.env {
-            backargs.push("--env".into());
-            backargs.push(format!("{}={}", key, val).into());
-        }
-
-        if args.tty {
-            backargs.push("--tty".into());
-        }
-
-        if let Some((uid, ogid)) = args.user {
-            backargs.push("--user".into());
-            if let Some(gid) = ogid {
-                backargs.push(format!("{}:{}", uid, gid).into())
-            } else {
-                backargs.push(uid.to_string().into())
-            }
-        }
-
-        for gid in args.additional_gids {
-            backargs.push("--additional-gids".into());
-            backargs.push(gid.to_string().into())
-        }
-
-        if let Some(process) = args.process {
-            backargs.push("--process".into());
-            backargs.push(process.into());
-        }
-
-        if args.detach {
-            backargs.push("--detach".into());
-        }
-
-        if let Some(pidfile) = args.pid_file {
-            backargs.push("--pid-file".into());
-            backargs.push(pidfile.into_os_string());
-        }
-
-        if let Some(label) = args.process_label {
-            backargs.push("--process-label".into());
-            backargs.push(label.into())
-        }
-
-        if let Some(profile) = args.apparmor {
-            backargs.push("--apparmor".into());
-            backargs.push(profile.into())
-        }
-
-        if args.no_new_privs {
-            backargs.push("--no-new-privs".into());
-        }
-
-        for cap in args.cap {
-            backargs.push("--cap".into());
-            backargs.push(cap.into())
-        }
-
-        if args.preserve_fds > 0 {
-            backargs.push("--preserve-fds".into());
-            backargs.push(args.preserve_fds.to_string().into())
-        }
-
-        if args.ignore_paused {
-            backargs.push("--ignore-paused".into())
-        }
-
-        if let Some(cgroup) = args.cgroup {
-            backargs.push("--cgroup".into());
-            backargs.push(cgroup.into())
-        }
-
-        backargs.push(args.container_id.into());
-        for a in args.command {
-            backargs.push(a.into());
-        }
-
-        self.invoke(backargs)
+        self.invoke(args.to_cmdline())
     }
 
-    fn features(&self, _args: liboci_cli::Features) -> Result<()> {
-        let backargs: Vec<OsString> = vec!["features".into()];
-        self.invoke(backargs)
+    fn features(&self, args: liboci_cli::Features) -> Result<()> {
+        self.invoke(args.to_cmdline())
     }
 
     fn list(&self, args: liboci_cli::List) -> Result<()> {
-        let mut backargs = Vec::<OsString>::new();
-
-        backargs.push("list".into());
-
-        if args.format != "table" {
-            backargs.push("--format".into());
-            backargs.push(args.format.into())
-        }
-
-        if args.quiet {
-            backargs.push("-q".into())
-        }
-
-        self.invoke(backargs)
+        self.invoke(args.to_cmdline())
     }
 
     fn pause(&self, args: liboci_cli::Pause) -> Result<()> {
-        let backargs: Vec<OsString> = vec!["pause".into(), args.container_id.into()];
-
-        self.invoke(backargs)
+        self.invoke(args.to_cmdline())
     }
 
     fn ps(&self, args: liboci_cli::Ps) -> Result<()> {
-        let mut backargs = Vec::<OsString>::new();
-
-        backargs.push("ps".into());
-
-        if args.format != "table" {
-            backargs.push("--format".into());
-            backargs.push(args.format.into())
-        }
-
-        backargs.push(args.container_id.into());
-
-        for opt in args.ps_options {
-            backargs.push(opt.into())
-        }
-
-        self.invoke(backargs)
+        self.invoke(args.to_cmdline())
     }
+
     fn resume(&self, args: liboci_cli::Resume) -> Result<()> {
-        let backargs: Vec<OsString> = vec!["resume".into(), args.container_id.into()];
-        self.invoke(backargs)
+        self.invoke(args.to_cmdline())
     }
-    fn run(&self, args: liboci_cli::Run) -> Result<()> {
-        let mut backargs = Vec::<OsString>::new();
-
-        backargs.push("run".into());
-
-        if args.bundle.as_os_str() != "." {
-            backargs.push("--bundle".into());
-            backargs.push(args.bundle.into_os_string())
-        }
-
-        if let Some(path) = args.console_socket {
-            backargs.push("--console-socket".into());
-            backargs.push(path.into_os_string())
-        }
-
-        if args.detach {
-            backargs.push("--detach".into())
-        }
-
-        if let Some(pidfile) = args.pid_file {
-            backargs.push("--pid-file".into());
-            backargs.push(pidfile.into_os_string())
-        }
 
-        if args.no_subreaper {
-            backargs.push("--no-subreaper".into())
-        }
-
-        if args.no_pivot {
-            backargs.push("--no-pivot".into())
-        }
-
-        if args.no_new_keyring {
-            backargs.push("--no-new-keyring".into())
-        }
-
-        if args.preserve_fds > 0 {
-            backargs.push("--preserve-fds".into());
-            backargs.push(args.preserve_fds.to_string().into())
-        }
-
-        if args.keep {
-            backargs.push("--keep".into())
-        }
-
-        backargs.push(args.container_id.into());
-
-        self.invoke(backargs)
+    fn run(&self, args: liboci_cli::Run) -> Result<()> {
+        self.invoke(args.to_cmdline())
     }
-    fn update(&self, args: liboci_cli::Update) -> Result<()> {
-        let mut backargs = Vec::<OsString>::new();
-
-        backargs.push("update".into());
 
-        if let Some(resources) = args.resources {
-            backargs.push("--resources".into());
-            backargs.push(resources.into_os_string())
-        }
-
-        if let Some(weight) = args.blkio_weight {
-            backargs.push("--blkio-weight".into());
-            backargs.push(weight.to_string().into())
-        }
-
-        if let Some(period) = args.cpu_period {
-            backargs.push("--cpu-period".into());
-            backargs.push(period.to_string().into())
-        }
-
-        if let Some(quota) = args.cpu_quota {
-            backargs.push("--cpu-quota".into());
-            backargs.push(quota.to_string().into())
-        }
-
-        if let Some(period) = args.cpu_rt_period {
-            backargs.push("--cpu-rt-period".into());
-            backargs.push(period.to_string().into())
-        }
-
-        if let Some(runtime) = args.cpu_rt_runtime {
-            backargs.push("--cpu-rt-runtime".into());
-            backargs.push(runtime.to_string().into())
-        }
-
-        if let Some(share) = args.cpu_share {
-            backargs.push("--cpu-share".into());
-            backargs.push(share.to_string().into())
-        }
-
-        if let Some(cpus) = args.cpuset_cpus {
-            backargs.push("--cpuset-cpus".into());
-            backargs.push(cpus.into())
-        }
-
-        if let Some(mems) = args.cpuset_mems {
-            backargs.push("--cpuset-mems".into());
-            backargs.push(mems.into())
-        }
-
-        if let Some(mem) = args.memory {
-            backargs.push("--memory".into());
-            backargs.push(mem.to_string().into())
-        }
-
-        if let Some(mem) = args.memory_reservation {
-            backargs.push("--memory-reservation".into());
-            backargs.push(mem.to_string().into())
-        }
-
-        if let Some(mem) = args.memory_swap {
-            backargs.push("--memory-swap".into());
-            backargs.push(mem.to_string().into())
-        }
-
-        if let Some(limit) = args.pids_limit {
-            backargs.push("--pids-limit".into());
-            backargs.push(limit.to_string().into())
-        }
-
-        if let Some(schema) = args.l3_cache_schema {
-            backargs.push("--l3-cache-schema".into());
-            backargs.push(schema.into())
-        }
-
-        if let Some(schema) = args.mem_bw_schema {
-            backargs.push("--mem-bw-schema".into());
-            backargs.push(schema.into())
-        }
-
-        backargs.push(args.container_id.into());
-
-        self.invoke(backargs)
+    fn update(&self, args: liboci_cli::Update) -> Result<()> {
+        self.invoke(args.to_cmdline())
     }
-    fn spec(&self, args: liboci_cli::Spec) -> Result<()> {
-        let mut backargs = Vec::<OsString>::new();
 
-        backargs.push("spec".into());
-
-        if let Some(path) = args.bundle {
-            backargs.push("--bundle".into());
-            backargs.push(path.into_os_string())
-        }
-
-        if args.rootless {
-            backargs.push("--rootless".into())
-        }
-
-        self.invoke(backargs)
+    fn spec(&self, args: liboci_cli::Spec) -> Result<()> {
+        self.invoke(args.to_cmdline())
     }
 }
@@ -0,0 +1,154 @@
+// ****************************************************************************
+//  error.rs                                                    ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Errors reported by backends that the main program needs to identify
+//
+//     A backend runtime that fails with a given exit status or signal must
+//     be reflected as is to the caller, so that ociplex is transparent.
+//     Other failures are classified, so that a backend can decide to try
+//     another one depending on why the first one failed.
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fmt;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+// Names of the classes of errors, see `Error::class`
+pub const CLASSES: &[&str] = &[
+    "missing",
+    "unsupported",
+    "features",
+    "exit",
+    "signal",
+    "timeout",
+    "rpc",
+    "policy",
+];
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub enum Error
+// ----------------------------------------------------------------------------
+//   Backend errors with a specific meaning for the caller
+// ----------------------------------------------------------------------------
+{
+    // The backend runtime or shim does not exist
+    NotFound { path: String },
+
+    // The backend does not support a subcommand or option
+    Unsupported { message: String },
+
+    // The features of the backend do not match what the bundle requires
+    Features { message: String },
+
+    // The backend runtime exited with a non-zero status code
+    Exited { code: i32, reason: Option<String> },
+
+    // The backend runtime was killed by a signal
+    Signaled { signal: i32, reason: Option<String> },
+
+    // The backend runtime did not complete in time and was terminated
+    Timeout { seconds: u64 },
+
+    // An RPC to a shim failed, or the shim could not be started
+    Rpc { message: String },
+
+    // The bundle violates the configured policy
+    Policy { message: String },
+}
+
+impl Error {
+    pub fn from_status(status: ExitStatus, reason: Option<String>) -> Option<Error>
+    // ------------------------------------------------------------------------
+    //   Build an error from a child exit status, if it indicates a failure
+    // ------------------------------------------------------------------------
+    //   The reason is the error message given by the runtime, if known.
+    {
+        if status.success() {
+            None
+        } else if let Some(signal) = status.signal() {
+            Some(Error::Signaled { signal, reason })
+        } else {
+            status.code().map(|code| Error::Exited { code, reason })
+        }
+    }
+
+    pub fn class(&self) -> &'static str
+    // ------------------------------------------------------------------------
+    //   The name of the class of the error, as used in configuration files
+    // ------------------------------------------------------------------------
+    {
+        match self {
+            Error::NotFound { .. } => "missing",
+            Error::Unsupported { .. } => "unsupported",
+            Error::Features { .. } => "features",
+            Error::Exited { .. } => "exit",
+            Error::Signaled { .. } => "signal",
+            Error::Timeout { .. } => "timeout",
+            Error::Rpc { .. } => "rpc",
+            Error::Policy { .. } => "policy",
+        }
+    }
+}
+
+pub fn outcome(result: &anyhow::Result<()>) -> (&'static str, i32)
+// ----------------------------------------------------------------------------
+//   The class of result of a command, and the exit status it leads to
+// ----------------------------------------------------------------------------
+{
+    match result {
+        Ok(()) => ("ok", 0),
+        Err(err) => match err.downcast_ref::<Error>() {
+            Some(Error::Exited { code, .. }) => ("exit", *code),
+            Some(Error::Signaled { signal, .. }) => ("signal", 128 + signal),
+            Some(err) => (err.class(), 1),
+            None => ("error", 1),
+        },
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Error::NotFound { path } => return write!(f, "Backend {} not found", path),
+            Error::Unsupported { message }
+            | Error::Features { message }
+            | Error::Policy { message } => return write!(f, "{}", message),
+            Error::Rpc { message } => return write!(f, "Backend RPC failed: {}", message),
+            Error::Exited { code, reason } => {
+                write!(f, "Backend CLI failed with status code {}", code)?;
+                reason
+            }
+            Error::Signaled { signal, reason } => {
+                write!(f, "Backend CLI terminated with signal {}", signal)?;
+                reason
+            }
+            Error::Timeout { seconds } => {
+                return write!(f, "Backend CLI timed out after {} seconds", seconds)
+            }
+        };
+        match reason {
+            Some(reason) => write!(f, ": {}", reason),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,352 @@
+// ****************************************************************************
+//  fallback.rs                                                 ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Try backends in order until one does not fail for a known reason
+//
+//     The fallback backend runs a command with the first backend in its
+//     chain. If that fails with one of the configured classes of errors,
+//     e.g. because the runtime is not installed or does not support an
+//     option, the command is retried with the next backend. The backend
+//     that created a container is recorded in the state directory, so that
+//     later commands for the same container go to the same backend.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use tracing::{debug, warn};
+
+use liboci_cli::GlobalOpts;
+
+use super::args::{Duplicate, ToCmdLine};
+use super::error::CLASSES;
+use super::{Backend, Error};
+
+fn default_on() -> Vec<String> {
+    vec!["missing".into(), "unsupported".into(), "features".into()]
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Config
+// ----------------------------------------------------------------------------
+//   Configuration of the fallback backend
+// ----------------------------------------------------------------------------
+{
+    // The backends to try, by name
+    backends: HashMap<String, super::Config>,
+
+    // The names of the backends, in the order they are tried
+    chain: Vec<String>,
+
+    // Classes of errors for which the next backend is tried
+    #[serde(default = "default_on")]
+    on: Vec<String>,
+
+    // Exit codes of a runtime for which the next backend is tried
+    #[serde(default)]
+    exit_codes: Vec<i32>,
+
+    // Where choices are recorded, in a `fallback` subdirectory
+    state_dir: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn replaces_process(&self) -> bool {
+        self.backends.values().any(super::Config::replaces_process)
+    }
+
+    pub fn instantiate(self, global: GlobalOpts) -> Result<Box<dyn Backend>> {
+        if self.chain.is_empty() {
+            return Err(anyhow!("Empty chain in fallback config"));
+        }
+        // A runtime replacing ociplex could not fail over to the next one
+        if self.replaces_process() {
+            return Err(anyhow!("Backends in a fallback chain cannot use exec"));
+        }
+        for name in &self.chain {
+            if !self.backends.contains_key(name) {
+                return Err(anyhow!("Unknown backend {:?} in fallback config", name));
+            }
+        }
+        for class in &self.on {
+            if !CLASSES.contains(&class.as_str()) {
+                return Err(anyhow!("Unknown error class {:?} in fallback config", class));
+            }
+        }
+        let state_dir = self.state_dir.unwrap_or_else(super::default_state_dir);
+        Ok(Box::new(FallbackBackend {
+            configs: RefCell::new(self.backends),
+            backends: RefCell::new(HashMap::new()),
+            chain: self.chain,
+            on: self.on,
+            exit_codes: self.exit_codes,
+            choices: state_dir.join("fallback"),
+            global,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct FallbackBackend
+// ----------------------------------------------------------------------------
+//   A backend trying several backends in order
+// ----------------------------------------------------------------------------
+//   Backends are only instantiated when used.
+{
+    configs: RefCell<HashMap<String, super::Config>>,
+    backends: RefCell<HashMap<String, Box<dyn Backend>>>,
+    chain: Vec<String>,
+    on: Vec<String>,
+    exit_codes: Vec<i32>,
+    choices: PathBuf,
+    global: GlobalOpts,
+}
+
+impl FallbackBackend {
+    fn with<T>(&self, name: &str, action: impl FnOnce(&dyn Backend) -> Result<T>) -> Result<T>
+    // ------------------------------------------------------------------------
+    //   Run an action with a backend, instantiating it if necessary
+    // ------------------------------------------------------------------------
+    {
+        if !self.backends.borrow().contains_key(name) {
+            let config = self
+                .configs
+                .borrow_mut()
+                .remove(name)
+                .ok_or_else(|| anyhow!("Unknown backend {:?}", name))?;
+            debug!("Instantiating backend {} ({})", name, config.backend_type());
+            let backend = config.instantiate(super::clone_global(&self.global))?;
+            self.backends.borrow_mut().insert(name.to_string(), backend);
+        }
+        let backends = self.backends.borrow();
+        action(backends[name].as_ref())
+    }
+
+    fn falls_back(&self, err: &anyhow::Error, foreground: bool) -> bool
+    // ------------------------------------------------------------------------
+    //   Check if an error is one for which the next backend is tried
+    // ------------------------------------------------------------------------
+    //   The exit code of `run` or `exec` without `--detach` is that of the
+    //   container process, which already ran, so it does not select another
+    //   backend.
+    {
+        match err.downcast_ref::<Error>() {
+            Some(Error::Exited { code, .. }) if foreground => {
+                debug!("Not falling back on exit code {} of the container", code);
+                false
+            }
+            Some(Error::Exited { code, .. }) if self.exit_codes.contains(code) => true,
+            Some(err) => self.on.iter().any(|class| class == err.class()),
+            None => false,
+        }
+    }
+
+    fn choice_file(&self, id: &str) -> Result<PathBuf> {
+        if id.is_empty() || id.contains('/') || id == "." || id == ".." {
+            return Err(anyhow!("Invalid container ID {:?}", id));
+        }
+        Ok(self.choices.join(id))
+    }
+
+    fn chosen(&self, id: &str) -> Option<String>
+    // ------------------------------------------------------------------------
+    //   The backend recorded for a container, if any
+    // ------------------------------------------------------------------------
+    {
+        let choice = self.choice_file(id).ok()?;
+        let name = fs::read_to_string(choice).ok()?.trim().to_string();
+        self.chain.contains(&name).then_some(name)
+    }
+
+    fn record(&self, id: &str, name: &str) -> Result<()> {
+        debug!("Recording backend {} for container {}", name, id);
+        let choice = self.choice_file(id)?;
+        fs::create_dir_all(&self.choices)
+            .with_context(|| format!("Creating {}", self.choices.display()))?;
+        fs::write(&choice, format!("{}\n", name))
+            .with_context(|| format!("Recording backend in {}", choice.display()))
+    }
+
+    fn forget(&self, id: &str) {
+        if let Ok(choice) = self.choice_file(id) {
+            let _ = fs::remove_file(choice);
+        }
+    }
+
+    fn attempt<A: Duplicate + ToCmdLine>(
+        &self,
+        created: Option<&str>,
+        args: A,
+        call: impl Fn(&dyn Backend, A) -> Result<()>,
+    ) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Try backends in order, recording each one tried for a new container
+    // ------------------------------------------------------------------------
+    //   The backend is recorded before it runs, since other commands may be
+    //   sent for the container while `run` is still running. The record is
+    //   removed if the container could not be created.
+    {
+        let result = self.try_chain(created, args, call);
+        if let (Some(id), Err(_)) = (created, &result) {
+            self.forget(id);
+        }
+        result
+    }
+
+    fn try_chain<A: Duplicate + ToCmdLine>(
+        &self,
+        created: Option<&str>,
+        args: A,
+        call: impl Fn(&dyn Backend, A) -> Result<()>,
+    ) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Try backends until one succeeds or fails without falling back
+    // ------------------------------------------------------------------------
+    {
+        let (last, first) = self.chain.split_last().expect("chain checked at instantiation");
+        let foreground = args.to_cmdline().foreground();
+        for name in first {
+            if let Some(id) = created {
+                self.record(id, name)?;
+            }
+            let result = self.with(name, |backend| call(backend, args.duplicate()));
+            match result {
+                Err(err) if self.falls_back(&err, foreground) => {
+                    warn!("Backend {} failed, trying the next one: {:#}", name, err);
+                }
+                result => return result,
+            }
+        }
+        if let Some(id) = created {
+            self.record(id, last)?;
+        }
+        self.with(last, |backend| call(backend, args))
+    }
+
+    fn dispatch<A: Duplicate + ToCmdLine>(
+        &self,
+        id: &str,
+        args: A,
+        call: impl Fn(&dyn Backend, A) -> Result<()>,
+    ) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Run a command for a container with the backend that created it
+    // ------------------------------------------------------------------------
+    //   Containers created without ociplex, or whose record was lost, go
+    //   through the chain like commands for no specific container.
+    {
+        match self.chosen(id) {
+            Some(name) => self.with(&name, |backend| call(backend, args)),
+            None => self.attempt(None, args, call),
+        }
+    }
+}
+
+impl Backend for FallbackBackend {
+    // Standard commands (from liboci_cli::StandardCmd)
+    fn create(&self, args: liboci_cli::Create) -> Result<()> {
+        let id = args.container_id.clone();
+        self.attempt(Some(&id), args, |backend, args| backend.create(args))
+    }
+
+    fn start(&self, args: liboci_cli::Start) -> Result<()> {
+        let id = args.container_id.clone();
+        self.dispatch(&id, args, |backend, args| backend.start(args))
+    }
+
+    fn kill(&self, args: liboci_cli::Kill) -> Result<()> {
+        let id = args.container_id.clone();
+        self.dispatch(&id, args, |backend, args| backend.kill(args))
+    }
+
+    fn delete(&self, args: liboci_cli::Delete) -> Result<()> {
+        let id = args.container_id.clone();
+        self.dispatch(&id, args, |backend, args| backend.delete(args))?;
+        self.forget(&id);
+        Ok(())
+    }
+
+    fn state(&self, args: liboci_cli::State) -> Result<()> {
+        let id = args.container_id.clone();
+        self.dispatch(&id, args, |backend, args| backend.state(args))
+    }
+
+    // Common non-standard commands (from liboci_cli::CommonCmd)
+    fn checkpoint(&self, args: liboci_cli::Checkpoint) -> Result<()> {
+        let id = args.container_id.clone();
+        self.dispatch(&id, args, |backend, args| backend.checkpoint(args))
+    }
+
+    fn events(&self, args: liboci_cli::Events) -> Result<()> {
+        let id = args.container_id.clone();
+        self.dispatch(&id, args, |backend, args| backend.events(args))
+    }
+
+    fn exec(&self, args: liboci_cli::Exec) -> Result<()> {
+        let id = args.container_id.clone();
+        self.dispatch(&id, args, |backend, args| backend.exec(args))
+    }
+
+    fn features(&self, args: liboci_cli::Features) -> Result<()> {
+        self.attempt(None, args, |backend, args| backend.features(args))
+    }
+
+    fn list(&self, args: liboci_cli::List) -> Result<()> {
+        self.attempt(None, args, |backend, args| backend.list(args))
+    }
+
+    fn pause(&self, args: liboci_cli::Pause) -> Result<()> {
+        let id = args.container_id.clone();
+        self.dispatch(&id, args, |backend, args| backend.pause(args))
+    }
+
+    fn ps(&self, args: liboci_cli::Ps) -> Result<()> {
+        let id = args.container_id.clone();
+        self.dispatch(&id, args, |backend, args| backend.ps(args))
+    }
+
+    fn resume(&self, args: liboci_cli::Resume) -> Result<()> {
+        let id = args.container_id.clone();
+        self.dispatch(&id, args, |backend, args| backend.resume(args))
+    }
+
+    fn run(&self, args: liboci_cli::Run) -> Result<()> {
+        let id = args.container_id.clone();
+        let detach = args.detach;
+
+        // Without --detach, the container is deleted when run returns
+        let result = self.attempt(Some(&id), args, |backend, args| backend.run(args));
+        if !detach {
+            self.forget(&id);
+        }
+        result
+    }
+
+    fn update(&self, args: liboci_cli::Update) -> Result<()> {
+        let id = args.container_id.clone();
+        self.dispatch(&id, args, |backend, args| backend.update(args))
+    }
+
+    fn spec(&self, args: liboci_cli::Spec) -> Result<()> {
+        self.attempt(None, args, |backend, args| backend.spec(args))
+    }
+}
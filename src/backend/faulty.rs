@@ -0,0 +1,362 @@
+// ****************************************************************************
+//  faulty.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Inject faults in the commands run by another backend
+//
+//     The faulty backend checks each command against a list of faults,
+//     selected by subcommand and container ID, and drawn with a given
+//     probability. A fault can delay the command, run it without pid file,
+//     and make it fail with an exit code and message, or crash with a
+//     signal, either instead of running it or after running it.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tracing::{debug, warn};
+
+use liboci_cli::GlobalOpts;
+
+use super::{args, Backend, Error, StateRoot};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum When
+// ----------------------------------------------------------------------------
+//   When an injected failure happens
+// ----------------------------------------------------------------------------
+{
+    // Instead of running the command
+    Before,
+
+    // After running the command, so that it had its effects
+    After,
+}
+
+fn default_when() -> When {
+    When::Before
+}
+
+fn default_probability() -> f64 {
+    1.0
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Fault
+// ----------------------------------------------------------------------------
+//   A fault, injected in the commands it applies to
+// ----------------------------------------------------------------------------
+//   Container patterns can start or end with `*` like in the router.
+{
+    // Subcommands and container IDs the fault applies to, default is all
+    #[serde(default)]
+    subcommands: Vec<String>,
+    #[serde(default)]
+    containers: Vec<String>,
+
+    // Chance that the fault is injected in a command it applies to
+    #[serde(default = "default_probability")]
+    probability: f64,
+
+    // Seconds to wait before running the command
+    delay: Option<f64>,
+
+    // Run create, run or exec without writing the pid file
+    #[serde(default)]
+    skip_pid_file: bool,
+
+    // Fail with an exit code and message, or die from a signal
+    exit_code: Option<i32>,
+    message: Option<String>,
+    crash: Option<String>,
+    #[serde(default = "default_when")]
+    when: When,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Config
+// ----------------------------------------------------------------------------
+//   Configuration of the faulty backend
+// ----------------------------------------------------------------------------
+{
+    // The backend running the commands
+    backend: Box<super::Config>,
+
+    // Faults, checked in order, the first one drawn is injected
+    #[serde(default)]
+    faults: Vec<Fault>,
+}
+
+impl Config {
+    pub fn state_root(&self) -> Option<StateRoot> {
+        self.backend.state_root()
+    }
+
+    pub fn replaces_process(&self) -> bool {
+        self.backend.replaces_process()
+    }
+
+    pub fn instantiate(self, global: GlobalOpts) -> Result<Box<dyn Backend>> {
+        for fault in &self.faults {
+            for subcommand in &fault.subcommands {
+                if !args::SUBCOMMANDS.contains(&subcommand.as_str()) {
+                    return Err(anyhow!("Unknown subcommand {:?} in faulty config", subcommand));
+                }
+            }
+            if !(0.0..=1.0).contains(&fault.probability) {
+                return Err(anyhow!("Invalid probability {} in faulty config", fault.probability));
+            }
+            if fault.delay.is_some_and(|delay| !(delay >= 0.0 && delay.is_finite())) {
+                return Err(anyhow!("Invalid delay in faulty config"));
+            }
+            if let Some(signal) = &fault.crash {
+                args::parse_signal(signal)?;
+            }
+            if fault.when == When::After && self.backend.replaces_process() {
+                return Err(anyhow!("Faults after commands need a backend not using exec"));
+            }
+        }
+        Ok(Box::new(FaultyBackend {
+            backend: self.backend.instantiate(global)?,
+            faults: self.faults,
+        }))
+    }
+}
+
+fn draw(probability: f64) -> bool
+// ----------------------------------------------------------------------------
+//   Draw a random event with the given probability
+// ----------------------------------------------------------------------------
+//   Each ociplex invocation runs a single command, so the random source has
+//   to differ between processes, and the kernel is the simplest one.
+{
+    if probability >= 1.0 {
+        return true;
+    }
+    let mut bits = 0u64;
+    // SAFETY: the buffer is a local u64, and its size is given
+    let read = unsafe { libc::getrandom((&mut bits as *mut u64).cast(), 8, 0) };
+    if read != 8 {
+        warn!("Cannot draw a random number, injecting no fault");
+        return false;
+    }
+    ((bits >> 11) as f64 / (1u64 << 53) as f64) < probability
+}
+
+impl Fault {
+    fn applies(&self, subcommand: &str, id: Option<&str>) -> bool {
+        (self.subcommands.is_empty() || self.subcommands.iter().any(|s| s == subcommand))
+            && (self.containers.is_empty()
+                || id.is_some_and(|id| self.containers.iter().any(|p| args::matches(p, id))))
+    }
+
+    fn failure(&self) -> Option<Error> {
+        let reason = self.message.clone();
+        if let Some(signal) = &self.crash {
+            let signal = args::parse_signal(signal).expect("signal checked at instantiation");
+            return Some(Error::Signaled { signal, reason });
+        }
+        match (self.exit_code, reason) {
+            (None, None) => None,
+            (code, reason) => Some(Error::Exited {
+                code: code.unwrap_or(1),
+                reason,
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FaultyBackend
+// ----------------------------------------------------------------------------
+//   A backend injecting faults in the commands of another one
+// ----------------------------------------------------------------------------
+{
+    backend: Box<dyn Backend>,
+    faults: Vec<Fault>,
+}
+
+impl FaultyBackend {
+    fn inject<A>(
+        &self,
+        subcommand: &str,
+        id: Option<&str>,
+        mut args: A,
+        skip_pid_file: impl FnOnce(&mut A),
+        call: impl FnOnce(&dyn Backend, A) -> Result<()>,
+    ) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Run a command, with the first fault that applies and is drawn
+    // ------------------------------------------------------------------------
+    {
+        let fault = self
+            .faults
+            .iter()
+            .enumerate()
+            .filter(|(_, fault)| fault.applies(subcommand, id))
+            .find(|(_, fault)| draw(fault.probability));
+        let Some((index, fault)) = fault else {
+            return call(self.backend.as_ref(), args);
+        };
+
+        debug!("Injecting fault {} in {}", index, subcommand);
+        if let Some(delay) = fault.delay {
+            thread::sleep(Duration::from_secs_f64(delay));
+        }
+        if fault.skip_pid_file {
+            skip_pid_file(&mut args);
+        }
+        let failure = fault.failure();
+        if fault.when == When::Before {
+            if let Some(err) = failure {
+                return Err(err.into());
+            }
+        }
+        call(self.backend.as_ref(), args)?;
+        match failure {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Backend for FaultyBackend
+// ----------------------------------------------------------------------------
+//   Implement the backend interface (i.e. OCI runtime commands)
+// ----------------------------------------------------------------------------
+{
+    // ========================================================================
+    //
+    //   All standard commands (liboci_cli::StandardCmd)
+    //
+    // ========================================================================
+
+    fn create(&self, args: liboci_cli::Create) -> Result<()> {
+        let id = args.container_id.clone();
+        let skip = |args: &mut liboci_cli::Create| args.pid_file = None;
+        self.inject("create", Some(&id), args, skip, |backend, args| {
+            backend.create(args)
+        })
+    }
+
+    fn start(&self, args: liboci_cli::Start) -> Result<()> {
+        let id = args.container_id.clone();
+        self.inject("start", Some(&id), args, |_| {}, |backend, args| {
+            backend.start(args)
+        })
+    }
+
+    fn kill(&self, args: liboci_cli::Kill) -> Result<()> {
+        let id = args.container_id.clone();
+        self.inject("kill", Some(&id), args, |_| {}, |backend, args| {
+            backend.kill(args)
+        })
+    }
+
+    fn delete(&self, args: liboci_cli::Delete) -> Result<()> {
+        let id = args.container_id.clone();
+        self.inject("delete", Some(&id), args, |_| {}, |backend, args| {
+            backend.delete(args)
+        })
+    }
+
+    fn state(&self, args: liboci_cli::State) -> Result<()> {
+        let id = args.container_id.clone();
+        self.inject("state", Some(&id), args, |_| {}, |backend, args| {
+            backend.state(args)
+        })
+    }
+
+    // ========================================================================
+    //
+    //   All common but non-standard commands (liboci_cli::CommonCmd)
+    //
+    // ========================================================================
+
+    fn checkpoint(&self, args: liboci_cli::Checkpoint) -> Result<()> {
+        let id = args.container_id.clone();
+        self.inject("checkpoint", Some(&id), args, |_| {}, |backend, args| {
+            backend.checkpoint(args)
+        })
+    }
+
+    fn events(&self, args: liboci_cli::Events) -> Result<()> {
+        let id = args.container_id.clone();
+        self.inject("events", Some(&id), args, |_| {}, |backend, args| {
+            backend.events(args)
+        })
+    }
+
+    fn exec(&self, args: liboci_cli::Exec) -> Result<()> {
+        let id = args.container_id.clone();
+        let skip = |args: &mut liboci_cli::Exec| args.pid_file = None;
+        self.inject("exec", Some(&id), args, skip, |backend, args| {
+            backend.exec(args)
+        })
+    }
+
+    fn features(&self, args: liboci_cli::Features) -> Result<()> {
+        self.inject("features", None, args, |_| {}, |backend, args| {
+            backend.features(args)
+        })
+    }
+
+    fn list(&self, args: liboci_cli::List) -> Result<()> {
+        self.inject("list", None, args, |_| {}, |backend, args| backend.list(args))
+    }
+
+    fn pause(&self, args: liboci_cli::Pause) -> Result<()> {
+        let id = args.container_id.clone();
+        self.inject("pause", Some(&id), args, |_| {}, |backend, args| {
+            backend.pause(args)
+        })
+    }
+
+    fn ps(&self, args: liboci_cli::Ps) -> Result<()> {
+        let id = args.container_id.clone();
+        self.inject("ps", Some(&id), args, |_| {}, |backend, args| backend.ps(args))
+    }
+
+    fn resume(&self, args: liboci_cli::Resume) -> Result<()> {
+        let id = args.container_id.clone();
+        self.inject("resume", Some(&id), args, |_| {}, |backend, args| {
+            backend.resume(args)
+        })
+    }
+
+    fn run(&self, args: liboci_cli::Run) -> Result<()> {
+        let id = args.container_id.clone();
+        let skip = |args: &mut liboci_cli::Run| args.pid_file = None;
+        self.inject("run", Some(&id), args, skip, |backend, args| {
+            backend.run(args)
+        })
+    }
+
+    fn update(&self, args: liboci_cli::Update) -> Result<()> {
+        let id = args.container_id.clone();
+        self.inject("update", Some(&id), args, |_| {}, |backend, args| {
+            backend.update(args)
+        })
+    }
+
+    fn spec(&self, args: liboci_cli::Spec) -> Result<()> {
+        self.inject("spec", None, args, |_| {}, |backend, args| backend.spec(args))
+    }
+}
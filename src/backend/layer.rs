@@ -0,0 +1,292 @@
+// ****************************************************************************
+//  layer.rs                                                    ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Middleware layers, intercepting commands around a backend
+//
+//     A layer sees each command before it is sent to the backend it wraps,
+//     and may change its arguments or fail it. It then sees the result, and
+//     may change it, along with the output of commands that only print.
+//     Layers are stacked by the layered backend. This file defines the
+//     interface of layers and the layers built into ociplex.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use liboci_cli::{CommonCmd, StandardCmd};
+
+use super::args::{Invocation, ToCmdLine};
+use super::error::outcome;
+use super::output::{self, Kind};
+use super::policy::Rules;
+use super::Backend;
+
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Request
+// ----------------------------------------------------------------------------
+//   A command with its arguments, as sent to a backend
+// ----------------------------------------------------------------------------
+{
+    Standard(StandardCmd),
+    Common(CommonCmd),
+}
+
+impl Request {
+    pub fn subcommand(&self) -> &'static str {
+        match self {
+            Request::Standard(cmd) => cmd.name(),
+            Request::Common(cmd) => cmd.name(),
+        }
+    }
+
+    pub fn container_id(&self) -> Option<&str> {
+        match self {
+            Request::Standard(cmd) => cmd.container_id(),
+            Request::Common(cmd) => cmd.container_id(),
+        }
+    }
+
+    pub fn kind(&self) -> Option<Kind>
+    // ------------------------------------------------------------------------
+    //   The kind of JSON output of the command, if any
+    // ------------------------------------------------------------------------
+    {
+        match self {
+            Request::Standard(StandardCmd::State(args)) => Kind::of(&args.to_cmdline()),
+            Request::Common(CommonCmd::List(args)) => Kind::of(&args.to_cmdline()),
+            Request::Common(CommonCmd::Ps(args)) => Kind::of(&args.to_cmdline()),
+            _ => None,
+        }
+    }
+
+    pub fn send(self, backend: &dyn Backend) -> Result<()> {
+        match self {
+            Request::Standard(cmd) => backend.standard_command(cmd),
+            Request::Common(cmd) => backend.common_command(cmd),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Call
+// ----------------------------------------------------------------------------
+//   What layers know about a command being run, before and after
+// ----------------------------------------------------------------------------
+{
+    pub subcommand: &'static str,
+    pub container_id: Option<String>,
+    pub kind: Option<Kind>,
+    pub start: Instant,
+}
+
+pub trait Layer: Debug
+// ----------------------------------------------------------------------------
+//   A middleware layer, intercepting commands before and after the backend
+// ----------------------------------------------------------------------------
+//   An error from `before` skips the backend and the inner layers, and is
+//   the result given to `after` in the outer layers. The output of commands
+//   that only print, like `state`, is captured and given to `after`, which
+//   may rewrite it. It is None for other commands, whose output is not
+//   delayed.
+{
+    fn before(&self, _call: &Call, _request: &mut Request) -> Result<()> {
+        Ok(())
+    }
+
+    fn after(&self, _call: &Call, result: Result<()>, _output: Option<&mut Vec<u8>>) -> Result<()> {
+        result
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "layer-type")]
+pub enum Config
+// ----------------------------------------------------------------------------
+//   Configuration of the layers built into ociplex
+// ----------------------------------------------------------------------------
+{
+    Log(LogLayer),
+    Metrics(MetricsLayer),
+    Normalize,
+    Policy(Rules),
+}
+
+impl Config {
+    pub fn instantiate(self) -> Result<Box<dyn Layer>> {
+        Ok(match self {
+            Config::Log(layer) => Box::new(layer),
+            Config::Metrics(layer) => Box::new(layer),
+            Config::Normalize => Box::new(NormalizeLayer {}),
+            Config::Policy(rules) => Box::new(PolicyLayer { rules }),
+        })
+    }
+}
+
+fn describe(call: &Call) -> String {
+    match &call.container_id {
+        Some(id) => format!("{} {}", call.subcommand, id),
+        None => call.subcommand.to_string(),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct LogLayer
+// ----------------------------------------------------------------------------
+//   Log each command, its outcome and its duration
+// ----------------------------------------------------------------------------
+{
+    // Log at debug level rather than info level
+    #[serde(default)]
+    debug: bool,
+}
+
+impl Layer for LogLayer {
+    fn before(&self, call: &Call, _request: &mut Request) -> Result<()> {
+        match self.debug {
+            true => debug!("Starting {}", describe(call)),
+            false => info!("Starting {}", describe(call)),
+        }
+        Ok(())
+    }
+
+    fn after(&self, call: &Call, result: Result<()>, _output: Option<&mut Vec<u8>>) -> Result<()> {
+        let (class, code) = outcome(&result);
+        let (what, elapsed) = (describe(call), call.start.elapsed());
+        match self.debug {
+            true => debug!("Finished {}: {} ({}) in {:?}", what, class, code, elapsed),
+            false => info!("Finished {}: {} ({}) in {:?}", what, class, code, elapsed),
+        }
+        result
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct MetricsLayer
+// ----------------------------------------------------------------------------
+//   Count commands by subcommand and outcome, with their total duration
+// ----------------------------------------------------------------------------
+//   Each ociplex invocation updates a shared JSON file, under a lock.
+{
+    path: PathBuf,
+}
+
+impl MetricsLayer {
+    fn update(&self, call: &Call, class: &str) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .with_context(|| format!("Opening {}", self.path.display()))?;
+        lock(&file)?;
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        let mut metrics: Value = serde_json::from_str(&text).unwrap_or_else(|_| json!({}));
+        let entry = &mut metrics[call.subcommand];
+        let count = entry["count"].as_u64().unwrap_or(0);
+        let seconds = entry["seconds"].as_f64().unwrap_or(0.0);
+        let outcomes = entry["outcomes"][class].as_u64().unwrap_or(0);
+        entry["count"] = json!(count + 1);
+        entry["seconds"] = json!(seconds + call.start.elapsed().as_secs_f64());
+        entry["outcomes"][class] = json!(outcomes + 1);
+        file.rewind()?;
+        file.set_len(0)?;
+        writeln!(file, "{}", metrics)?;
+        Ok(())
+    }
+}
+
+impl Layer for MetricsLayer {
+    fn after(&self, call: &Call, result: Result<()>, _output: Option<&mut Vec<u8>>) -> Result<()> {
+        let (class, _) = outcome(&result);
+        if let Err(err) = self.update(call, class) {
+            warn!("Cannot update metrics in {}: {:#}", self.path.display(), err);
+        }
+        result
+    }
+}
+
+fn lock(file: &File) -> Result<()>
+// ----------------------------------------------------------------------------
+//   Lock a file for exclusive access, until it is closed
+// ----------------------------------------------------------------------------
+{
+    // SAFETY: the fd is valid for the lifetime of the file
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } < 0 {
+        return Err(anyhow!(std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct NormalizeLayer
+// ----------------------------------------------------------------------------
+//   Rewrite the JSON output of `state`, `list` and `ps` in the format of runc
+// ----------------------------------------------------------------------------
+{}
+
+impl Layer for NormalizeLayer {
+    fn after(&self, call: &Call, result: Result<()>, output: Option<&mut Vec<u8>>) -> Result<()> {
+        // The output of a failed command is passed through unchanged
+        result?;
+        if let (Some(kind), Some(output)) = (call.kind, output) {
+            let normalized = output::normalize(kind, output);
+            output.clear();
+            output.extend(normalized.with_context(|| {
+                format!("Invalid {} output", kind.subcommand())
+            })?);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct PolicyLayer
+// ----------------------------------------------------------------------------
+//   Check `create`, `run`, `exec` and `update` against the rules of a policy
+// ----------------------------------------------------------------------------
+{
+    rules: Rules,
+}
+
+impl Layer for PolicyLayer {
+    fn before(&self, _call: &Call, request: &mut Request) -> Result<()> {
+        match request {
+            Request::Standard(StandardCmd::Create(args)) => {
+                self.rules.check(&args.container_id, &args.bundle)
+            }
+            Request::Common(CommonCmd::Run(args)) => {
+                self.rules.check(&args.container_id, &args.bundle)
+            }
+            Request::Common(CommonCmd::Exec(args)) => self.rules.check_exec(args),
+            Request::Common(CommonCmd::Update(args)) => self.rules.check_update(args),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+// ****************************************************************************
+//  layered.rs                                                  ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Stack middleware layers around another backend
+//
+//     The layered backend sends each command through a list of layers, the
+//     first one being the outermost, then to the inner backend. Results
+//     then go back through the layers in reverse order.
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::io::{self, Write};
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use tracing::debug;
+
+use liboci_cli::{CommonCmd, GlobalOpts, StandardCmd};
+
+use super::layer::{self, Call, Layer, Request};
+use super::output::capture;
+use super::{Backend, StateRoot};
+
+// Subcommands that only print, whose output layers can rewrite
+const CAPTURED: &[&str] = &["state", "list", "ps", "features"];
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Config
+// ----------------------------------------------------------------------------
+//   Configuration of the layered backend
+// ----------------------------------------------------------------------------
+{
+    // Layers, from the outermost to the innermost
+    #[serde(default)]
+    layers: Vec<layer::Config>,
+
+    // The backend running the commands
+    inner: Box<super::Config>,
+}
+
+impl Config {
+    pub fn state_root(&self) -> Option<StateRoot> {
+        self.inner.state_root()
+    }
+
+    pub fn replaces_process(&self) -> bool {
+        self.inner.replaces_process()
+    }
+
+    pub fn instantiate(self, global: GlobalOpts) -> Result<Box<dyn Backend>> {
+        if !self.layers.is_empty() && self.inner.replaces_process() {
+            return Err(anyhow!("Layers need an inner backend not using exec"));
+        }
+        let layers = self
+            .layers
+            .into_iter()
+            .map(layer::Config::instantiate)
+            .collect::<Result<_>>()?;
+        Ok(Box::new(LayeredBackend {
+            layers,
+            inner: self.inner.instantiate(global)?,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct LayeredBackend
+// ----------------------------------------------------------------------------
+//   A backend sending commands through layers
+// ----------------------------------------------------------------------------
+{
+    layers: Vec<Box<dyn Layer>>,
+    inner: Box<dyn Backend>,
+}
+
+impl LayeredBackend {
+    fn dispatch(&self, mut request: Request) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Run a command through the layers and the inner backend
+    // ------------------------------------------------------------------------
+    //   Only the layers that were entered see the result. The output they
+    //   give back is printed once they all saw it.
+    {
+        let call = Call {
+            subcommand: request.subcommand(),
+            container_id: request.container_id().map(String::from),
+            kind: request.kind(),
+            start: Instant::now(),
+        };
+        let mut output = CAPTURED.contains(&call.subcommand).then(Vec::new);
+        let mut entered = 0;
+        let mut result = Ok(());
+        for layer in &self.layers {
+            result = layer.before(&call, &mut request);
+            if result.is_err() {
+                debug!("Layer {:?} stopped {}", layer, call.subcommand);
+                break;
+            }
+            entered += 1;
+        }
+        if result.is_ok() {
+            result = match &mut output {
+                Some(output) => {
+                    let (sent, printed) = capture(|| request.send(self.inner.as_ref()))?;
+                    *output = printed;
+                    sent
+                }
+                None => request.send(self.inner.as_ref()),
+            };
+        }
+        for layer in self.layers[..entered].iter().rev() {
+            result = layer.after(&call, result, output.as_mut());
+        }
+        if let Some(output) = output {
+            io::stdout().write_all(&output)?;
+        }
+        result
+    }
+}
+
+impl Backend for LayeredBackend
+// ----------------------------------------------------------------------------
+//   Implement the backend interface (i.e. OCI runtime commands)
+// ----------------------------------------------------------------------------
+{
+    fn standard_command(&self, cmd: StandardCmd) -> Result<()> {
+        self.dispatch(Request::Standard(cmd))
+    }
+
+    fn common_command(&self, cmd: CommonCmd) -> Result<()> {
+        self.dispatch(Request::Common(cmd))
+    }
+
+    // ========================================================================
+    //
+    //   All standard commands (liboci_cli::StandardCmd)
+    //
+    // ========================================================================
+
+    fn create(&self, args: liboci_cli::Create) -> Result<()> {
+        self.standard_command(StandardCmd::Create(args))
+    }
+
+    fn start(&self, args: liboci_cli::Start) -> Result<()> {
+        self.standard_command(StandardCmd::Start(args))
+    }
+
+    fn kill(&self, args: liboci_cli::Kill) -> Result<()> {
+        self.standard_command(StandardCmd::Kill(args))
+    }
+
+    fn delete(&self, args: liboci_cli::Delete) -> Result<()> {
+        self.standard_command(StandardCmd::Delete(args))
+    }
+
+    fn state(&self, args: liboci_cli::State) -> Result<()> {
+        self.standard_command(StandardCmd::State(args))
+    }
+
+    // ========================================================================
+    //
+    //   All common but non-standard commands (liboci_cli::CommonCmd)
+    //
+    // ========================================================================
+
+    fn checkpoint(&self, args: liboci_cli::Checkpoint) -> Result<()> {
+        self.common_command(CommonCmd::Checkpointt(args))
+    }
+
+    fn events(&self, args: liboci_cli::Events) -> Result<()> {
+        self.common_command(CommonCmd::Events(args))
+    }
+
+    fn exec(&self, args: liboci_cli::Exec) -> Result<()> {
+        self.common_command(CommonCmd::Exec(args))
+    }
+
+    fn features(&self, args: liboci_cli::Features) -> Result<()> {
+        self.common_command(CommonCmd::Features(args))
+    }
+
+    fn list(&self, args: liboci_cli::List) -> Result<()> {
+        self.common_command(CommonCmd::List(args))
+    }
+
+    fn pause(&self, args: liboci_cli::Pause) -> Result<()> {
+        self.common_command(CommonCmd::Pause(args))
+    }
+
+    fn ps(&self, args: liboci_cli::Ps) -> Result<()> {
+        self.common_command(CommonCmd::Ps(args))
+    }
+
+    fn resume(&self, args: liboci_cli::Resume) -> Result<()> {
+        self.common_command(CommonCmd::Resume(args))
+    }
+
+    fn run(&self, args: liboci_cli::Run) -> Result<()> {
+        self.common_command(CommonCmd::Run(args))
+    }
+
+    fn update(&self, args: liboci_cli::Update) -> Result<()> {
+        self.common_command(CommonCmd::Update(args))
+    }
+
+    fn spec(&self, args: liboci_cli::Spec) -> Result<()> {
+        self.common_command(CommonCmd::Spec(args))
+    }
+}
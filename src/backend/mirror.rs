@@ -0,0 +1,557 @@
+// ****************************************************************************
+//  mirror.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Compare a secondary backend with a primary one on real traffic
+//
+//     The mirror backend runs each command on the primary backend, which
+//     alone decides of the result and of the output seen by the caller.
+//     The same command is then replayed on the secondary backend, isolated
+//     from the caller, and differences in outcome, exit status and state
+//     output are appended to a report file, as JSON lines. A `run` or `exec`
+//     that does not detach is not replayed, since it would only start once
+//     the primary process ended. The secondary runs in a child process, so
+//     that it can be killed if it takes too long, and isolated containers
+//     get their own copy of the bundle and root filesystem.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+use liboci_cli::GlobalOpts;
+
+use super::args::Duplicate;
+use super::error::outcome;
+use super::output::{capture, isolate, state_differences};
+use super::{Backend, Error};
+use crate::reaper;
+
+// Subcommands that do not change containers, mirrored in read-only mode
+const READ_ONLY: &[&str] = &["state", "list", "ps", "features"];
+
+// How often to check if the secondary backend is done
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Mode
+// ----------------------------------------------------------------------------
+//   How commands are replayed on the secondary backend
+// ----------------------------------------------------------------------------
+{
+    // All commands, on containers of its own, named with a suffix
+    Isolated,
+
+    // Only commands that do not change containers, on the same containers
+    ReadOnly,
+}
+
+fn default_mode() -> Mode {
+    Mode::Isolated
+}
+
+fn default_suffix() -> String {
+    "-mirror".into()
+}
+
+fn default_timeout() -> u64 {
+    60
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Config
+// ----------------------------------------------------------------------------
+//   Configuration of the mirror backend
+// ----------------------------------------------------------------------------
+{
+    // The backend whose results are used, and the one being compared
+    primary: Box<super::Config>,
+    secondary: Box<super::Config>,
+
+    // Where differences are reported, as JSON lines
+    report: PathBuf,
+
+    // How commands are replayed, and the suffix of isolated container IDs
+    #[serde(default = "default_mode")]
+    mode: Mode,
+    #[serde(default = "default_suffix")]
+    suffix: String,
+
+    // How long the secondary backend may take for a command, in seconds
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+
+    // Where copies of bundles for isolated containers go, in `mirror`
+    state_dir: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn state_root(&self) -> Option<super::StateRoot> {
+        self.primary.state_root()
+    }
+
+    pub fn replaces_process(&self) -> bool {
+        self.primary.replaces_process() || self.secondary.replaces_process()
+    }
+
+    pub fn instantiate(self, global: GlobalOpts) -> Result<Box<dyn Backend>> {
+        if self.replaces_process() {
+            return Err(anyhow!("Mirrored CLI backends cannot use exec"));
+        }
+        let root = self.primary.state_root();
+        if self.mode == Mode::Isolated
+            && self.suffix.is_empty()
+            && root.is_some()
+            && root == self.secondary.state_root()
+        {
+            return Err(anyhow!(
+                "Isolated mirror backends need a suffix or different state roots"
+            ));
+        }
+        let secondary = self.secondary.instantiate(super::clone_global(&global))?;
+        let state_dir = self.state_dir.unwrap_or_else(super::default_state_dir);
+        Ok(Box::new(MirrorBackend {
+            primary: self.primary.instantiate(global)?,
+            secondary,
+            report: self.report,
+            mode: self.mode,
+            suffix: self.suffix,
+            timeout: Duration::from_secs(self.timeout),
+            bundles: state_dir.join("mirror"),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct MirrorBackend
+// ----------------------------------------------------------------------------
+//   A backend replaying commands on a secondary backend for comparison
+// ----------------------------------------------------------------------------
+{
+    primary: Box<dyn Backend>,
+    secondary: Box<dyn Backend>,
+    report: PathBuf,
+    mode: Mode,
+    suffix: String,
+    timeout: Duration,
+    bundles: PathBuf,
+}
+
+fn copy_tree(from: &Path, to: &Path) -> io::Result<()>
+// ----------------------------------------------------------------------------
+//   Copy a directory tree, keeping symbolic links and permissions
+// ----------------------------------------------------------------------------
+//   Device nodes, sockets and FIFOs are not copied, runtimes create those
+//   they need in the container.
+{
+    let metadata = fs::symlink_metadata(from)?;
+    let kind = metadata.file_type();
+    if kind.is_symlink() {
+        symlink(fs::read_link(from)?, to)
+    } else if kind.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        // Permissions last, in case the directory is read-only
+        fs::set_permissions(to, metadata.permissions())
+    } else if kind.is_file() {
+        fs::copy(from, to).map(drop)
+    } else {
+        debug!("Not copying special file {}", from.display());
+        Ok(())
+    }
+}
+
+impl MirrorBackend {
+    fn mirrors(&self, subcommand: &str) -> bool {
+        self.mode == Mode::Isolated || READ_ONLY.contains(&subcommand)
+    }
+
+    fn isolated_id(&self, id: &mut String) {
+        if self.mode == Mode::Isolated {
+            id.push_str(&self.suffix);
+        }
+    }
+
+    fn copy_bundle(&self, bundle: &Path, id: &str) -> Result<PathBuf>
+    // ------------------------------------------------------------------------
+    //   Copy the bundle and root filesystem of a container for the secondary
+    // ------------------------------------------------------------------------
+    //   The root filesystem is copied with the bundle if it is inside it, and
+    //   next to it otherwise, so that the secondary never writes in the one
+    //   of the primary.
+    {
+        if id.is_empty() || id.contains('/') || id == "." || id == ".." {
+            return Err(anyhow!("Invalid container ID {:?}", id));
+        }
+        let bundle = bundle
+            .canonicalize()
+            .with_context(|| format!("Finding bundle {}", bundle.display()))?;
+        let config = bundle.join("config.json");
+        let text = fs::read_to_string(&config)
+            .with_context(|| format!("Reading {}", config.display()))?;
+        let mut doc: Value = serde_json::from_str(&text)
+            .with_context(|| format!("Parsing {}", config.display()))?;
+
+        let copy = self.bundles.join(id);
+        let _ = fs::remove_dir_all(&copy);
+        copy_tree(&bundle, &copy.join("bundle"))
+            .with_context(|| format!("Copying bundle {}", bundle.display()))?;
+        if let Some(Value::String(root)) = doc.pointer_mut("/root/path") {
+            if let Ok(rootfs) = bundle.join(&*root).canonicalize() {
+                *root = match rootfs.strip_prefix(&bundle) {
+                    Ok(inside) => inside.to_string_lossy().into_owned(),
+                    Err(_) => {
+                        copy_tree(&rootfs, &copy.join("rootfs"))
+                            .with_context(|| format!("Copying {}", rootfs.display()))?;
+                        copy.join("rootfs").to_string_lossy().into_owned()
+                    }
+                };
+            }
+        }
+        fs::write(copy.join("bundle/config.json"), serde_json::to_string_pretty(&doc)?)?;
+        debug!("Copied bundle {} to {}", bundle.display(), copy.display());
+        Ok(copy.join("bundle"))
+    }
+
+    fn isolated_bundle(&self, bundle: &Path, id: &str) -> Option<PathBuf>
+    // ------------------------------------------------------------------------
+    //   The bundle for the secondary, None if the command is not replayed
+    // ------------------------------------------------------------------------
+    {
+        if self.mode != Mode::Isolated {
+            return Some(bundle.to_path_buf());
+        }
+        let mut isolated = id.to_string();
+        self.isolated_id(&mut isolated);
+        match self.copy_bundle(bundle, &isolated) {
+            Ok(copy) => Some(copy),
+            Err(err) => {
+                warn!("Cannot copy bundle for secondary backend: {:#}", err);
+                None
+            }
+        }
+    }
+
+    fn remove_bundle(&self, id: &str) {
+        let mut isolated = id.to_string();
+        self.isolated_id(&mut isolated);
+        let copy = self.bundles.join(isolated);
+        if self.mode == Mode::Isolated && copy.exists() {
+            debug!("Removing bundle copy {}", copy.display());
+            let _ = fs::remove_dir_all(&copy);
+        }
+    }
+
+    fn replay(&self, action: impl FnOnce() -> Result<()>) -> Result<((String, i32), Vec<u8>)>
+    // ------------------------------------------------------------------------
+    //   Run the secondary in a child process, killed if it takes too long
+    // ------------------------------------------------------------------------
+    //   The child leads its own process group, so that runtimes it started
+    //   are killed with it. It writes its outcome class and exit code on a
+    //   line of a temporary file, followed by its output.
+    {
+        let mut results = tempfile::tempfile()?;
+        io::stdout().flush()?;
+
+        // SAFETY: ociplex runs commands on a single thread, and the child
+        // exits once the secondary is done, without returning here
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(io::Error::last_os_error()).context("Forking secondary backend");
+        }
+        if pid == 0 {
+            // SAFETY: setpgid and _exit have no memory effects
+            unsafe { libc::setpgid(0, 0) };
+            let written = isolate(action).and_then(|(result, output)| {
+                let (class, code) = outcome(&result);
+                writeln!(results, "{} {}", class, code)?;
+                results.write_all(&output)
+            });
+            // SAFETY: the child must not run the destructors of the parent
+            unsafe { libc::_exit(written.is_err().into()) };
+        }
+
+        // SAFETY: setpgid has no memory effects, and may race with the child
+        unsafe { libc::setpgid(pid, pid) };
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            let waited = reaper::wait_pid(pid, false).context("Waiting for secondary backend")?;
+            if let Some(status) = waited {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                warn!("Secondary backend timed out after {:?}, killing it", self.timeout);
+                // SAFETY: kill has no memory effects
+                unsafe { libc::kill(-pid, libc::SIGKILL) };
+                reaper::wait_pid(pid, true)?;
+                let timeout = Err(Error::Timeout {
+                    seconds: self.timeout.as_secs(),
+                }
+                .into());
+                let (class, code) = outcome(&timeout);
+                return Ok(((class.to_string(), code), Vec::new()));
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        if !status.success() {
+            return Err(anyhow!("Secondary backend process failed with {}", status));
+        }
+
+        let mut contents = Vec::new();
+        results.rewind()?;
+        results.read_to_end(&mut contents)?;
+        let newline = contents.iter().position(|&byte| byte == b'\n').unwrap_or(contents.len());
+        let line = String::from_utf8_lossy(&contents[..newline]).into_owned();
+        let output = contents.get(newline + 1..).unwrap_or_default().to_vec();
+        let (class, code) = line
+            .split_once(' ')
+            .and_then(|(class, code)| Some((class.to_string(), code.parse().ok()?)))
+            .ok_or_else(|| anyhow!("Invalid outcome {:?} of secondary backend", line))?;
+        Ok(((class, code), output))
+    }
+
+    fn mirror<A: Duplicate>(
+        &self,
+        subcommand: &'static str,
+        id: Option<&str>,
+        args: A,
+        isolate_args: impl FnOnce(&mut A),
+        call: impl Fn(&dyn Backend, A) -> Result<()>,
+    ) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Run a command on the primary, replay it on the secondary and compare
+    // ------------------------------------------------------------------------
+    //   Failing to replay or report never changes the result of the primary.
+    {
+        if !self.mirrors(subcommand) {
+            return call(self.primary.as_ref(), args);
+        }
+        let mut replay = args.duplicate();
+        isolate_args(&mut replay);
+
+        // Only the state output is compared, other output is left alone
+        let (primary, primary_output) = if subcommand == "state" {
+            let (result, output) = capture(|| call(self.primary.as_ref(), args))?;
+            io::stdout().write_all(&output)?;
+            (result, output)
+        } else {
+            (call(self.primary.as_ref(), args), Vec::new())
+        };
+
+        debug!("Replaying {} on secondary backend", subcommand);
+        match self.replay(|| call(self.secondary.as_ref(), replay)) {
+            Ok(((secondary_class, secondary_code), secondary_output)) => {
+                let mut differences = Vec::new();
+                let (primary_class, primary_code) = outcome(&primary);
+                if primary_class != secondary_class {
+                    differences.push(format!("outcome: {} vs {}", primary_class, secondary_class));
+                }
+                if primary_code != secondary_code {
+                    differences.push(format!("exit code: {} vs {}", primary_code, secondary_code));
+                }
+                if subcommand == "state" && primary.is_ok() && secondary_class == "ok" {
+                    state_differences(&primary_output, &secondary_output, &mut differences);
+                }
+                if !differences.is_empty() {
+                    let entry = json!({
+                        "time": Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true),
+                        "subcommand": subcommand,
+                        "container": id,
+                        "primary": { "outcome": primary_class, "code": primary_code },
+                        "secondary": { "outcome": secondary_class, "code": secondary_code },
+                        "differences": differences,
+                    });
+                    self.write_report(&entry);
+                }
+            }
+            Err(err) => warn!("Cannot replay on secondary backend: {:#}", err),
+        }
+        primary
+    }
+
+    fn write_report(&self, entry: &Value) {
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.report)
+            .and_then(|mut report| writeln!(report, "{}", entry));
+        if let Err(err) = written {
+            warn!("Cannot write mirror report {}: {}", self.report.display(), err);
+        }
+    }
+}
+
+impl Backend for MirrorBackend {
+    // Standard commands (from liboci_cli::StandardCmd)
+    fn create(&self, args: liboci_cli::Create) -> Result<()> {
+        let id = args.container_id.clone();
+        let Some(bundle) = self.isolated_bundle(&args.bundle, &id) else {
+            return self.primary.create(args);
+        };
+        let isolate_args = |args: &mut liboci_cli::Create| {
+            self.isolated_id(&mut args.container_id);
+            args.bundle = bundle;
+            args.console_socket = None;
+            args.pid_file = None;
+        };
+        self.mirror("create", Some(&id), args, isolate_args, |backend, args| {
+            backend.create(args)
+        })
+    }
+
+    fn start(&self, args: liboci_cli::Start) -> Result<()> {
+        let id = args.container_id.clone();
+        let isolate_args = |args: &mut liboci_cli::Start| self.isolated_id(&mut args.container_id);
+        self.mirror("start", Some(&id), args, isolate_args, |backend, args| {
+            backend.start(args)
+        })
+    }
+
+    fn kill(&self, args: liboci_cli::Kill) -> Result<()> {
+        let id = args.container_id.clone();
+        let isolate_args = |args: &mut liboci_cli::Kill| self.isolated_id(&mut args.container_id);
+        self.mirror("kill", Some(&id), args, isolate_args, |backend, args| {
+            backend.kill(args)
+        })
+    }
+
+    fn delete(&self, args: liboci_cli::Delete) -> Result<()> {
+        let id = args.container_id.clone();
+        let isolate_args = |args: &mut liboci_cli::Delete| self.isolated_id(&mut args.container_id);
+        let result = self.mirror("delete", Some(&id), args, isolate_args, |backend, args| {
+            backend.delete(args)
+        });
+        self.remove_bundle(&id);
+        result
+    }
+
+    fn state(&self, args: liboci_cli::State) -> Result<()> {
+        let id = args.container_id.clone();
+        let isolate_args = |args: &mut liboci_cli::State| self.isolated_id(&mut args.container_id);
+        self.mirror("state", Some(&id), args, isolate_args, |backend, args| {
+            backend.state(args)
+        })
+    }
+
+    // Common non-standard commands (from liboci_cli::CommonCmd)
+    fn checkpoint(&self, args: liboci_cli::Checkpoint) -> Result<()> {
+        // A checkpoint image cannot be shared, so only the primary does it
+        self.primary.checkpoint(args)
+    }
+
+    fn events(&self, args: liboci_cli::Events) -> Result<()> {
+        // Events run until the container stops, and cannot be compared
+        self.primary.events(args)
+    }
+
+    fn exec(&self, args: liboci_cli::Exec) -> Result<()> {
+        // A foreground process would run again on the secondary, after the first
+        if !args.detach {
+            return self.primary.exec(args);
+        }
+        let id = args.container_id.clone();
+        let isolate_args = |args: &mut liboci_cli::Exec| {
+            self.isolated_id(&mut args.container_id);
+            args.console_socket = None;
+            args.tty = false;
+            args.pid_file = None;
+        };
+        self.mirror("exec", Some(&id), args, isolate_args, |backend, args| {
+            backend.exec(args)
+        })
+    }
+
+    fn features(&self, args: liboci_cli::Features) -> Result<()> {
+        self.mirror("features", None, args, |_| {}, |backend, args| {
+            backend.features(args)
+        })
+    }
+
+    fn list(&self, args: liboci_cli::List) -> Result<()> {
+        self.mirror("list", None, args, |_| {}, |backend, args| backend.list(args))
+    }
+
+    fn pause(&self, args: liboci_cli::Pause) -> Result<()> {
+        let id = args.container_id.clone();
+        let isolate_args = |args: &mut liboci_cli::Pause| self.isolated_id(&mut args.container_id);
+        self.mirror("pause", Some(&id), args, isolate_args, |backend, args| {
+            backend.pause(args)
+        })
+    }
+
+    fn ps(&self, args: liboci_cli::Ps) -> Result<()> {
+        let id = args.container_id.clone();
+        let isolate_args = |args: &mut liboci_cli::Ps| self.isolated_id(&mut args.container_id);
+        self.mirror("ps", Some(&id), args, isolate_args, |backend, args| {
+            backend.ps(args)
+        })
+    }
+
+    fn resume(&self, args: liboci_cli::Resume) -> Result<()> {
+        let id = args.container_id.clone();
+        let isolate_args = |args: &mut liboci_cli::Resume| self.isolated_id(&mut args.container_id);
+        self.mirror("resume", Some(&id), args, isolate_args, |backend, args| {
+            backend.resume(args)
+        })
+    }
+
+    fn run(&self, args: liboci_cli::Run) -> Result<()> {
+        // A foreground container would run again on the secondary, after the first
+        if !args.detach {
+            return self.primary.run(args);
+        }
+        let id = args.container_id.clone();
+        let Some(bundle) = self.isolated_bundle(&args.bundle, &id) else {
+            return self.primary.run(args);
+        };
+        let isolate_args = |args: &mut liboci_cli::Run| {
+            self.isolated_id(&mut args.container_id);
+            args.bundle = bundle;
+            args.console_socket = None;
+            args.pid_file = None;
+        };
+        self.mirror("run", Some(&id), args, isolate_args, |backend, args| {
+            backend.run(args)
+        })
+    }
+
+    fn update(&self, args: liboci_cli::Update) -> Result<()> {
+        let id = args.container_id.clone();
+        let isolate_args = |args: &mut liboci_cli::Update| self.isolated_id(&mut args.container_id);
+        self.mirror("update", Some(&id), args, isolate_args, |backend, args| {
+            backend.update(args)
+        })
+    }
+
+    fn spec(&self, args: liboci_cli::Spec) -> Result<()> {
+        // The spec is written in the bundle, a second one would replace it
+        self.primary.spec(args)
+    }
+}
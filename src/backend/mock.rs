@@ -0,0 +1,760 @@
+// ****************************************************************************
+//  mock.rs                                                     ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     A mock runtime, running containers as plain processes
+//
+//     The mock backend implements the OCI lifecycle without namespaces,
+//     cgroups or root filesystem, so that container engines can be tested
+//     without privileges. `create` forks a process that waits on a FIFO,
+//     like `runc init`, and `start` releases it to execute `process.args`
+//     from config.json. Paths such as the working directory are host paths.
+//     State is kept in a directory per container under the state root.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::RawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+use liboci_cli::GlobalOpts;
+
+use super::args::parse_signal;
+use super::{Backend, Error, StateRoot};
+use crate::{fds, reaper};
+
+// How often to check if a process is waiting for `start`, or is gone
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// How long `delete --force` waits for processes to be gone
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Room for the fds opened by `spawn` above those we have open
+const SPAWN_FDS: RawFd = 8;
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct Config
+// ----------------------------------------------------------------------------
+//   Configuration of the mock backend
+// ----------------------------------------------------------------------------
+{
+    // The state root, default is --root, or mock in $XDG_RUNTIME_DIR/ociplex
+    root: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn state_root(&self) -> StateRoot {
+        match &self.root {
+            Some(root) => StateRoot::Path(root.display().to_string()),
+            None => StateRoot::Global,
+        }
+    }
+
+    pub fn instantiate(self, global: GlobalOpts) -> Result<Box<dyn Backend>> {
+        let root = self
+            .root
+            .or(global.root)
+            .unwrap_or_else(|| super::default_state_dir().join("mock"));
+        debug!("Mock backend state in {}", root.display());
+        Ok(Box::new(MockBackend { root }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Status
+// ----------------------------------------------------------------------------
+//   The status of a container, as defined by the OCI specification
+// ----------------------------------------------------------------------------
+{
+    Creating,
+    Created,
+    Running,
+    Paused,
+    Stopped,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Creating => "creating",
+            Status::Created => "created",
+            Status::Running => "running",
+            Status::Paused => "paused",
+            Status::Stopped => "stopped",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+struct Process
+// ----------------------------------------------------------------------------
+//   A process of a container, identified by pid and start time
+// ----------------------------------------------------------------------------
+//   The start time tells a live process from another that reused its pid.
+//   Each process leads its own session and process group.
+{
+    pid: i32,
+    start_time: u64,
+}
+
+fn proc_stat(pid: i32) -> Option<(char, i32, u64)>
+// ----------------------------------------------------------------------------
+//   The state, session and start time of a process, from /proc
+// ----------------------------------------------------------------------------
+{
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 2..)?.split(' ').collect();
+    let state = fields.first()?.chars().next()?;
+    Some((state, fields.get(3)?.parse().ok()?, fields.get(19)?.parse().ok()?))
+}
+
+impl Process {
+    fn of(pid: u32) -> Result<Process> {
+        let pid = pid as i32;
+        let (_, _, start_time) =
+            proc_stat(pid).ok_or_else(|| anyhow!("Process {} exited immediately", pid))?;
+        Ok(Process { pid, start_time })
+    }
+
+    fn alive(&self) -> bool {
+        matches!(proc_stat(self.pid),
+                 Some((state, _, start_time))
+                 if start_time == self.start_time && state != 'Z' && state != 'X')
+    }
+
+    fn signal(&self, signal: libc::c_int, group: bool) -> Result<()> {
+        let target = if group { -self.pid } else { self.pid };
+        // SAFETY: kill has no memory safety requirements
+        if unsafe { libc::kill(target, signal) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ESRCH) {
+                return Err(err).with_context(|| format!("Signaling process {}", self.pid));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Container
+// ----------------------------------------------------------------------------
+//   What the mock backend records about a container
+// ----------------------------------------------------------------------------
+{
+    id: String,
+    status: Status,
+    bundle: PathBuf,
+    created: String,
+    annotations: Value,
+
+    // The container process, once created, and those started by `exec`
+    init: Option<Process>,
+    #[serde(default)]
+    execs: Vec<Process>,
+}
+
+impl Container {
+    fn status(&self) -> Status
+    // ------------------------------------------------------------------------
+    //   The actual status, a container is stopped when its process is gone
+    // ------------------------------------------------------------------------
+    {
+        match self.init {
+            _ if self.status == Status::Creating => Status::Creating,
+            Some(init) if init.alive() => self.status,
+            _ => Status::Stopped,
+        }
+    }
+
+    fn processes(&self) -> impl Iterator<Item = &Process> {
+        self.init.iter().chain(&self.execs).filter(|process| process.alive())
+    }
+
+    fn signal(&self, signal: libc::c_int, all: bool) -> Result<()> {
+        match (all, &self.init) {
+            (true, _) => {
+                for process in self.processes() {
+                    process.signal(signal, true)?;
+                }
+                Ok(())
+            }
+            (false, Some(init)) => init.signal(signal, false),
+            (false, None) => Ok(()),
+        }
+    }
+
+    fn expect(&self, action: &str, allowed: &[Status]) -> Result<Status> {
+        let status = self.status();
+        if !allowed.contains(&status) {
+            return Err(anyhow!(
+                "Cannot {} container {}, it is {}",
+                action,
+                self.id,
+                status.name()
+            ));
+        }
+        Ok(status)
+    }
+
+    fn state(&self) -> Value
+    // ------------------------------------------------------------------------
+    //   The state of the container, as shown by `state` and `list`
+    // ------------------------------------------------------------------------
+    {
+        let status = self.status();
+        let pid = match (status, self.init) {
+            (Status::Stopped, _) | (_, None) => 0,
+            (_, Some(init)) => init.pid,
+        };
+        json!({
+            "ociVersion": "1.0.2",
+            "id": self.id,
+            "pid": pid,
+            "status": status.name(),
+            "bundle": self.bundle,
+            "created": self.created,
+            "owner": "",
+            "annotations": self.annotations,
+        })
+    }
+}
+
+fn command(process: &Value) -> Result<Command>
+// ----------------------------------------------------------------------------
+//   Prepare the command for a process described as in config.json
+// ----------------------------------------------------------------------------
+{
+    if process["terminal"].as_bool() == Some(true) {
+        return Err(Error::Unsupported {
+            message: "Terminals are not supported by the mock backend".into(),
+        }
+        .into());
+    }
+    let args: Vec<&str> = process["args"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    let (program, args) = args
+        .split_first()
+        .ok_or_else(|| anyhow!("No process arguments in mock container"))?;
+    let mut cmd = Command::new(program);
+    cmd.args(args).env_clear();
+    for var in process["env"].as_array().into_iter().flatten() {
+        if let Some((name, value)) = var.as_str().and_then(|var| var.split_once('=')) {
+            cmd.env(name, value);
+        }
+    }
+    cmd.current_dir(process["cwd"].as_str().unwrap_or("/"));
+    Ok(cmd)
+}
+
+fn highest_fd() -> RawFd {
+    fs::read_dir("/proc/self/fd")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .max()
+                .unwrap_or(0)
+        })
+        .unwrap_or(1024)
+}
+
+fn write_pid_file(pid_file: Option<&Path>, process: &Process) -> Result<()> {
+    if let Some(path) = pid_file {
+        fs::write(path, process.pid.to_string())
+            .with_context(|| format!("Writing pid file {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn wait(mut child: Child) -> Result<()> {
+    let status = reaper::wait(&mut child)?;
+    debug!("Container process status {:?}", status);
+    match Error::from_status(status, None) {
+        Some(err) => Err(err.into()),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug)]
+struct MockBackend
+// ----------------------------------------------------------------------------
+//   A backend running containers as plain processes
+// ----------------------------------------------------------------------------
+{
+    root: PathBuf,
+}
+
+impl MockBackend {
+    fn dir(&self, id: &str) -> Result<PathBuf> {
+        if id.is_empty() || id.contains('/') || id == "." || id == ".." {
+            return Err(anyhow!("Invalid container ID {:?}", id));
+        }
+        Ok(self.root.join(id))
+    }
+
+    fn load(&self, id: &str) -> Result<Container> {
+        let path = self.dir(id)?.join("state.json");
+        let text = match fs::read_to_string(&path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(anyhow!("Container {} does not exist", id))
+            }
+            text => text.with_context(|| format!("Reading {}", path.display()))?,
+        };
+        serde_json::from_str(&text).with_context(|| format!("Parsing {}", path.display()))
+    }
+
+    fn save(&self, container: &Container) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Write the state of a container, replacing the previous one at once
+    // ------------------------------------------------------------------------
+    {
+        let dir = self.dir(&container.id)?;
+        let temp = dir.join("state.json.tmp");
+        fs::write(&temp, serde_json::to_string(container)?)
+            .with_context(|| format!("Writing {}", temp.display()))?;
+        fs::rename(&temp, dir.join("state.json"))
+            .with_context(|| format!("Saving state of container {}", container.id))
+    }
+
+    fn spawn(&self, mut cmd: Command, fifo: Option<&Path>) -> Result<(Child, Process)>
+    // ------------------------------------------------------------------------
+    //   Start a process in its own session, waiting on a FIFO if one is given
+    // ------------------------------------------------------------------------
+    //   `spawn` waits until the child closes its close-on-exec fds, normally
+    //   on exec. A child waiting for `start` closes them itself, like exec
+    //   would, so that `spawn` returns without waiting for `start`.
+    {
+        let fifo = fifo
+            .map(|fifo| CString::new(fifo.as_os_str().as_bytes()))
+            .transpose()?;
+        let (floor, ceiling) = (fds::private_floor(), highest_fd() + SPAWN_FDS);
+
+        // SAFETY: the closure only makes async-signal-safe calls
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::setsid() < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let Some(fifo) = &fifo else {
+                    return Ok(());
+                };
+                for fd in floor..ceiling {
+                    let flags = libc::fcntl(fd, libc::F_GETFD);
+                    if flags >= 0 && flags & libc::FD_CLOEXEC != 0 {
+                        libc::close(fd);
+                    }
+                }
+                let fd = libc::open(fifo.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC);
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let mut byte = 0u8;
+                let read = libc::read(fd, (&mut byte as *mut u8).cast(), 1);
+                libc::close(fd);
+                match read {
+                    1 => Ok(()),
+                    _ => Err(io::Error::from_raw_os_error(libc::ECANCELED)),
+                }
+            });
+        }
+        fds::pass_through(&mut cmd)?;
+        debug!("Spawning container process {:?}", cmd);
+        let child = cmd
+            .spawn()
+            .with_context(|| format!("Running {:?}", cmd.get_program()))?;
+        let process = Process::of(child.id())?;
+        Ok((child, process))
+    }
+
+    fn create_container(
+        &self,
+        id: &str,
+        bundle: &Path,
+        console_socket: Option<&Path>,
+        pid_file: Option<&Path>,
+    ) -> Result<Child>
+    // ------------------------------------------------------------------------
+    //   Create a container with a process waiting to be started
+    // ------------------------------------------------------------------------
+    {
+        let dir = self.dir(id)?;
+        if dir.exists() {
+            return Err(anyhow!("Container {} already exists", id));
+        }
+        if console_socket.is_some() {
+            return Err(Error::Unsupported {
+                message: "Console sockets are not supported by the mock backend".into(),
+            }
+            .into());
+        }
+        let bundle = bundle
+            .canonicalize()
+            .with_context(|| format!("Finding bundle {}", bundle.display()))?;
+        let config = bundle.join("config.json");
+        let spec: Value = fs::read_to_string(&config)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .ok_or_else(|| anyhow!("Cannot read {}", config.display()))?;
+        let cmd = command(&spec["process"])?;
+
+        fs::create_dir_all(&dir).with_context(|| format!("Creating {}", dir.display()))?;
+        let mut container = Container {
+            id: id.to_string(),
+            status: Status::Creating,
+            bundle,
+            created: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true),
+            annotations: spec["annotations"].clone(),
+            init: None,
+            execs: Vec::new(),
+        };
+        self.save(&container)?;
+
+        let fifo = dir.join("exec.fifo");
+        let path = CString::new(fifo.as_os_str().as_bytes())?;
+        // SAFETY: path is a valid C string
+        if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Creating {}", fifo.display()));
+        }
+        let (child, process) = self.spawn(cmd, Some(&fifo))?;
+        container.init = Some(process);
+        container.status = Status::Created;
+        self.save(&container)?;
+        write_pid_file(pid_file, &process)?;
+        Ok(child)
+    }
+
+    fn start_container(&self, id: &str) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Release the process of a created container
+    // ------------------------------------------------------------------------
+    //   The process may not be waiting on the FIFO yet, so opening it is
+    //   retried for as long as the process is alive.
+    {
+        let mut container = self.load(id)?;
+        container.expect("start", &[Status::Created])?;
+        let init = container.init.expect("created containers have a process");
+        let fifo = self.dir(id)?.join("exec.fifo");
+        let mut writer = loop {
+            let opened = OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&fifo);
+            match opened {
+                Err(err) if err.raw_os_error() == Some(libc::ENXIO) && init.alive() => {
+                    thread::sleep(POLL_INTERVAL)
+                }
+                opened => break opened.with_context(|| format!("Starting container {}", id))?,
+            }
+        };
+        writer.write_all(&[0])?;
+        fs::remove_file(&fifo)?;
+        container.status = Status::Running;
+        self.save(&container)
+    }
+
+    fn delete_container(&self, id: &str, force: bool) -> Result<()> {
+        let container = self.load(id)?;
+        let allowed: &[Status] = if force {
+            &[Status::Creating, Status::Created, Status::Running, Status::Paused, Status::Stopped]
+        } else {
+            &[Status::Created, Status::Stopped]
+        };
+        if container.expect("delete", allowed)? != Status::Stopped {
+            container.signal(libc::SIGKILL, true)?;
+            let deadline = Instant::now() + KILL_TIMEOUT;
+            while container.processes().next().is_some() {
+                if Instant::now() > deadline {
+                    return Err(anyhow!("Processes of container {} did not terminate", id));
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+        let dir = self.dir(id)?;
+        fs::remove_dir_all(&dir).with_context(|| format!("Removing {}", dir.display()))
+    }
+
+    fn containers(&self) -> Result<Vec<Container>> {
+        let entries = match fs::read_dir(&self.root) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            entries => entries.with_context(|| format!("Reading {}", self.root.display()))?,
+        };
+        let mut ids: Vec<String> = entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .collect();
+        ids.sort();
+        Ok(ids.iter().filter_map(|id| self.load(id).ok()).collect())
+    }
+
+    fn pids(&self, container: &Container) -> Vec<i32>
+    // ------------------------------------------------------------------------
+    //   All processes in the sessions of the container, from /proc
+    // ------------------------------------------------------------------------
+    {
+        let sessions: Vec<i32> = container.processes().map(|process| process.pid).collect();
+        let mut pids: Vec<i32> = fs::read_dir("/proc")
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .filter(|pid| {
+                matches!(proc_stat(*pid),
+                         Some((state, session, _)) if state != 'Z' && sessions.contains(&session))
+            })
+            .collect();
+        pids.sort();
+        pids
+    }
+}
+
+impl Backend for MockBackend
+// ----------------------------------------------------------------------------
+//   Implement the backend interface (i.e. OCI runtime commands)
+// ----------------------------------------------------------------------------
+{
+    // ========================================================================
+    //
+    //   All standard commands (liboci_cli::StandardCmd)
+    //
+    // ========================================================================
+
+    fn create(&self, args: liboci_cli::Create) -> Result<()> {
+        self.create_container(
+            &args.container_id,
+            &args.bundle,
+            args.console_socket.as_deref(),
+            args.pid_file.as_deref(),
+        )?;
+        Ok(())
+    }
+
+    fn start(&self, args: liboci_cli::Start) -> Result<()> {
+        self.start_container(&args.container_id)
+    }
+
+    fn kill(&self, args: liboci_cli::Kill) -> Result<()> {
+        let container = self.load(&args.container_id)?;
+        container.expect("kill", &[Status::Created, Status::Running, Status::Paused])?;
+        container.signal(parse_signal(&args.signal)?, args.all)
+    }
+
+    fn delete(&self, args: liboci_cli::Delete) -> Result<()> {
+        self.delete_container(&args.container_id, args.force)
+    }
+
+    fn state(&self, args: liboci_cli::State) -> Result<()> {
+        let container = self.load(&args.container_id)?;
+        writeln!(io::stdout(), "{}", serde_json::to_string_pretty(&container.state())?)?;
+        Ok(())
+    }
+
+    // ========================================================================
+    //
+    //   All common but non-standard commands (liboci_cli::CommonCmd)
+    //
+    // ========================================================================
+
+    fn events(&self, args: liboci_cli::Events) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Report the number of processes, until the container stops
+    // ------------------------------------------------------------------------
+    {
+        let container = self.load(&args.container_id)?;
+        container.expect("get events of", &[Status::Created, Status::Running, Status::Paused])?;
+        while container.status() != Status::Stopped {
+            let event = json!({
+                "type": "stats",
+                "id": container.id,
+                "data": { "pids": { "current": self.pids(&container).len() } },
+            });
+            let mut stdout = io::stdout();
+            writeln!(stdout, "{}", event)?;
+            stdout.flush()?;
+            if args.stats {
+                break;
+            }
+            thread::sleep(Duration::from_secs(args.interval.max(1).into()));
+        }
+        Ok(())
+    }
+
+    fn exec(&self, args: liboci_cli::Exec) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Run another process, described like the one in config.json
+    // ------------------------------------------------------------------------
+    {
+        let mut container = self.load(&args.container_id)?;
+        let allowed: &[Status] = if args.ignore_paused {
+            &[Status::Running, Status::Paused]
+        } else {
+            &[Status::Running]
+        };
+        container.expect("exec in", allowed)?;
+        if args.tty || args.console_socket.is_some() {
+            return Err(Error::Unsupported {
+                message: "Terminals are not supported by the mock backend".into(),
+            }
+            .into());
+        }
+
+        let mut process = match &args.process {
+            Some(path) => fs::read_to_string(path)
+                .ok()
+                .and_then(|text| serde_json::from_str(&text).ok())
+                .ok_or_else(|| anyhow!("Cannot read process {}", path.display()))?,
+            None => {
+                let config = container.bundle.join("config.json");
+                let spec: Value = fs::read_to_string(&config)
+                    .ok()
+                    .and_then(|text| serde_json::from_str(&text).ok())
+                    .ok_or_else(|| anyhow!("Cannot read {}", config.display()))?;
+                let mut process = spec["process"].clone();
+                process["args"] = json!(args.command);
+                process
+            }
+        };
+        if !args.env.is_empty() {
+            let mut env = process["env"].as_array().cloned().unwrap_or_default();
+            env.extend(args.env.iter().map(|(name, value)| json!(format!("{}={}", name, value))));
+            process["env"] = json!(env);
+        }
+        if let Some(cwd) = &args.cwd {
+            process["cwd"] = json!(cwd);
+        }
+
+        let (child, started) = self.spawn(command(&process)?, None)?;
+        container.execs.retain(|process| process.alive());
+        container.execs.push(started);
+        self.save(&container)?;
+        write_pid_file(args.pid_file.as_deref(), &started)?;
+        if args.detach {
+            return Ok(());
+        }
+        wait(child)
+    }
+
+    fn list(&self, args: liboci_cli::List) -> Result<()> {
+        let containers = self.containers()?;
+        let mut stdout = io::stdout();
+        if args.quiet {
+            for container in &containers {
+                writeln!(stdout, "{}", container.id)?;
+            }
+            return Ok(());
+        }
+        match args.format.as_str() {
+            "json" => {
+                let states: Vec<Value> = containers.iter().map(Container::state).collect();
+                writeln!(stdout, "{}", serde_json::to_string(&states)?)?;
+            }
+            "table" => {
+                writeln!(stdout, "ID\tPID\tSTATUS\tBUNDLE\tCREATED\tOWNER")?;
+                for container in &containers {
+                    let state = container.state();
+                    writeln!(
+                        stdout,
+                        "{}\t{}\t{}\t{}\t{}\t",
+                        container.id,
+                        state["pid"],
+                        container.status().name(),
+                        container.bundle.display(),
+                        container.created
+                    )?;
+                }
+            }
+            format => return Err(anyhow!("Invalid list format {:?}", format)),
+        }
+        Ok(())
+    }
+
+    fn pause(&self, args: liboci_cli::Pause) -> Result<()> {
+        let mut container = self.load(&args.container_id)?;
+        container.expect("pause", &[Status::Running])?;
+        container.signal(libc::SIGSTOP, true)?;
+        container.status = Status::Paused;
+        self.save(&container)
+    }
+
+    fn ps(&self, args: liboci_cli::Ps) -> Result<()> {
+        let container = self.load(&args.container_id)?;
+        container.expect("list processes of", &[Status::Running, Status::Paused])?;
+        if !args.ps_options.is_empty() {
+            warn!("Ignoring ps options {:?} in mock backend", args.ps_options);
+        }
+        let pids = self.pids(&container);
+        let mut stdout = io::stdout();
+        match args.format.as_str() {
+            "json" => writeln!(stdout, "{}", serde_json::to_string(&pids)?)?,
+            "table" => {
+                writeln!(stdout, "PID\tCMD")?;
+                for pid in pids {
+                    let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default();
+                    writeln!(stdout, "{}\t{}", pid, comm.trim_end())?;
+                }
+            }
+            format => return Err(anyhow!("Invalid ps format {:?}", format)),
+        }
+        Ok(())
+    }
+
+    fn resume(&self, args: liboci_cli::Resume) -> Result<()> {
+        let mut container = self.load(&args.container_id)?;
+        container.expect("resume", &[Status::Paused])?;
+        container.signal(libc::SIGCONT, true)?;
+        container.status = Status::Running;
+        self.save(&container)
+    }
+
+    fn run(&self, args: liboci_cli::Run) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Create and start a container, and unless detached, wait for it
+    // ------------------------------------------------------------------------
+    {
+        let id = &args.container_id;
+        let child = self.create_container(
+            id,
+            &args.bundle,
+            args.console_socket.as_deref(),
+            args.pid_file.as_deref(),
+        )?;
+        self.start_container(id)?;
+        if args.detach {
+            return Ok(());
+        }
+        let result = wait(child);
+        if !args.keep {
+            self.delete_container(id, false)?;
+        }
+        result
+    }
+}
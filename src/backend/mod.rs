@@ -1,31 +1,181 @@
+use std::env;
 use std::fmt::Debug;
+use std::path::PathBuf;
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 
 use liboci_cli::{CommonCmd, GlobalOpts, StandardCmd};
 
+mod args;
 mod cli;
+mod error;
+mod fallback;
+mod faulty;
+mod layer;
+mod layered;
+mod mirror;
+mod mock;
+mod output;
+mod policy;
+mod probe;
+mod reason;
+mod record;
+mod router;
 mod shimv2;
-mod trivial;
+mod transform;
+mod translate;
+
+pub use args::Invocation;
+pub use error::{outcome, Error};
+pub use output::{attach, capture, capture_all, state_differences};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateRoot
+// ----------------------------------------------------------------------------
+//   The state root of a runtime, where it finds containers by ID
+// ----------------------------------------------------------------------------
+{
+    // The root given with --root on the ociplex command line, or default
+    Global,
+
+    // A root set in the backend configuration
+    Path(String),
+}
 
 #[derive(Debug, serde::Deserialize)]
 #[serde(tag = "backend-type")]
+#[allow(clippy::large_enum_variant)]
 pub enum Config {
-    Trivial(trivial::Config),
+    // Formerly the trivial backend, which only recorded what happened
+    #[serde(alias = "Trivial")]
+    Record(record::Config),
     Cli(cli::Config),
     ShimV2(shimv2::Config),
+    Transform(transform::Config),
+    Router(router::Config),
+    Fallback(fallback::Config),
+    Mirror(mirror::Config),
+    Mock(mock::Config),
+    Faulty(faulty::Config),
+    Policy(policy::Config),
+    Layered(layered::Config),
 }
 
 impl Config {
-    pub fn instantiate(self, global: GlobalOpts) -> Box<dyn Backend> {
+    pub fn backend_type(&self) -> &'static str {
+        match self {
+            Config::Record(_) => "Record",
+            Config::Cli(_) => "Cli",
+            Config::ShimV2(_) => "ShimV2",
+            Config::Transform(_) => "Transform",
+            Config::Router(_) => "Router",
+            Config::Fallback(_) => "Fallback",
+            Config::Mirror(_) => "Mirror",
+            Config::Mock(_) => "Mock",
+            Config::Faulty(_) => "Faulty",
+            Config::Policy(_) => "Policy",
+            Config::Layered(_) => "Layered",
+        }
+    }
+
+    pub fn state_root(&self) -> Option<StateRoot>
+    // ------------------------------------------------------------------------
+    //   Where the backend keeps container state, None if private to it
+    // ------------------------------------------------------------------------
+    {
         match self {
-            Config::Trivial(c) => c.instantiate(global),
+            Config::Cli(c) => Some(c.state_root()),
+            Config::Transform(c) => c.state_root(),
+            Config::Mirror(c) => c.state_root(),
+            Config::Record(c) => c.state_root(),
+            Config::Mock(c) => Some(c.state_root()),
+            Config::Faulty(c) => c.state_root(),
+            Config::Policy(c) => c.state_root(),
+            Config::Layered(c) => c.state_root(),
+            _ => None,
+        }
+    }
+
+    pub fn replaces_process(&self) -> bool
+    // ------------------------------------------------------------------------
+    //   Whether the backend may exec the runtime in place of ociplex
+    // ------------------------------------------------------------------------
+    {
+        match self {
+            Config::Record(c) => c.replaces_process(),
+            Config::Cli(c) => c.replaces_process(),
+            Config::ShimV2(_) | Config::Mock(_) => false,
+            Config::Transform(c) => c.replaces_process(),
+            Config::Router(c) => c.replaces_process(),
+            Config::Fallback(c) => c.replaces_process(),
+            Config::Mirror(c) => c.replaces_process(),
+            Config::Faulty(c) => c.replaces_process(),
+            Config::Policy(c) => c.replaces_process(),
+            Config::Layered(c) => c.replaces_process(),
+        }
+    }
+
+    pub fn instantiate(self, global: GlobalOpts) -> Result<Box<dyn Backend>> {
+        match self {
+            Config::Record(c) => c.instantiate(global),
             Config::Cli(c) => c.instantiate(global),
             Config::ShimV2(c) => c.instantiate(global),
+            Config::Transform(c) => c.instantiate(global),
+            Config::Router(c) => c.instantiate(global),
+            Config::Fallback(c) => c.instantiate(global),
+            Config::Mirror(c) => c.instantiate(global),
+            Config::Mock(c) => c.instantiate(global),
+            Config::Faulty(c) => c.instantiate(global),
+            Config::Policy(c) => c.instantiate(global),
+            Config::Layered(c) => c.instantiate(global),
         }
     }
 }
 
+pub fn default_state_dir() -> PathBuf
+// ----------------------------------------------------------------------------
+//   Where ociplex keeps its state, unless configured otherwise
+// ----------------------------------------------------------------------------
+{
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("ociplex"),
+        None => PathBuf::from("/run/ociplex"),
+    }
+}
+
+pub fn dry_run(configured: bool) -> bool
+// ----------------------------------------------------------------------------
+//   Whether to only print commands, from the config or OCIPLEX_DRY_RUN
+// ----------------------------------------------------------------------------
+{
+    configured || env::var_os("OCIPLEX_DRY_RUN").is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+pub fn clone_global(global: &GlobalOpts) -> GlobalOpts
+// ----------------------------------------------------------------------------
+//   Copy global options, for backends instantiating other backends
+// ----------------------------------------------------------------------------
+{
+    GlobalOpts {
+        log: global.log.clone(),
+        debug: global.debug,
+        log_format: global.log_format.clone(),
+        root: global.root.clone(),
+        systemd_cgroup: global.systemd_cgroup,
+    }
+}
+
+fn unimplemented(subcommand: &str, args: impl Debug) -> Result<()>
+// ----------------------------------------------------------------------------
+//   The error for a subcommand that a backend does not implement
+// ----------------------------------------------------------------------------
+{
+    Err(Error::Unsupported {
+        message: format!("{} subcommand unimplemented: {:?}", subcommand, args),
+    }
+    .into())
+}
+
 pub trait Backend: Debug {
     fn standard_command(&self, cmd: liboci_cli::StandardCmd) -> Result<()> {
         match cmd {
@@ -62,36 +212,36 @@ pub trait Backend: Debug {
 
     // CommonCmd in liboci-cli are not implemented by default
     fn checkpoint(&self, args: liboci_cli::Checkpoint) -> Result<()> {
-        Err(anyhow!("checkpoint subcommand unimplemented: {:?}", args))
+        unimplemented("checkpoint", args)
     }
     fn events(&self, args: liboci_cli::Events) -> Result<()> {
-        Err(anyhow!("events subcommand unimplemented: {:?}", args))
+        unimplemented("events", args)
     }
     fn exec(&self, args: liboci_cli::Exec) -> Result<()> {
-        Err(anyhow!("exec subcommand unimplemented: {:?}", args))
+        unimplemented("exec", args)
     }
     fn features(&self, args: liboci_cli::Features) -> Result<()> {
-        Err(anyhow!("features subcommand unimplemented: {:?}", args))
+        unimplemented("features", args)
     }
     fn list(&self, args: liboci_cli::List) -> Result<()> {
-        Err(anyhow!("list subcommand unimplemented: {:?}", args))
+        unimplemented("list", args)
     }
     fn pause(&self, args: liboci_cli::Pause) -> Result<()> {
-        Err(anyhow!("pause subcommand unimplemented: {:?}", args))
+        unimplemented("pause", args)
     }
     fn ps(&self, args: liboci_cli::Ps) -> Result<()> {
-        Err(anyhow!("ps subcommand unimplemented: {:?}", args))
+        unimplemented("ps", args)
     }
     fn resume(&self, args: liboci_cli::Resume) -> Result<()> {
-        Err(anyhow!("resume subcommand unimplemented: {:?}", args))
+        unimplemented("resume", args)
     }
     fn run(&self, args: liboci_cli::Run) -> Result<()> {
-        Err(anyhow!("run subcommand unimplemented: {:?}", args))
+        unimplemented("run", args)
     }
     fn update(&self, args: liboci_cli::Update) -> Result<()> {
-        Err(anyhow!("update subcommand unimplemented: {:?}", args))
+        unimplemented("update", args)
     }
     fn spec(&self, args: liboci_cli::Spec) -> Result<()> {
-        Err(anyhow!("spec subcommand unimplemented: {:?}", args))
+        unimplemented("spec", args)
     }
 }
@@ -0,0 +1,389 @@
+// ****************************************************************************
+//  output.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Normalize the JSON output of `state`, `list` and `ps`
+//
+//     Runtimes differ in what they print: extra fields, timestamp formats,
+//     missing `owner`. The output is validated against the OCI state schema
+//     and converted to the format used by runc: fields in runc's order,
+//     `created` in RFC 3339 UTC with nanoseconds, `owner` always present.
+//     The output of a backend can also be captured, to compare the state
+//     reported by two runtimes, or its standard streams attached to files.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+
+use super::args::CmdLine;
+use crate::fds;
+
+// State fields that legitimately differ between two runtimes
+const VOLATILE_STATE: &[&str] = &["id", "pid", "created"];
+
+// Status values from the OCI runtime specification, plus runc's "paused"
+const STATUSES: &[&str] = &["creating", "created", "running", "stopped", "paused"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind
+// ----------------------------------------------------------------------------
+//   The kind of JSON output a command produces
+// ----------------------------------------------------------------------------
+{
+    State,
+    List,
+    Ps,
+}
+
+impl Kind {
+    pub fn of(cmd: &CmdLine) -> Option<Kind>
+    // ------------------------------------------------------------------------
+    //   The kind of output for a command, None if not JSON
+    // ------------------------------------------------------------------------
+    {
+        let json = cmd
+            .options
+            .iter()
+            .any(|opt| opt.name == "format" && opt.value.as_deref() == Some("json".as_ref()));
+        match cmd.subcommand {
+            "state" => Some(Kind::State),
+            "list" if json => Some(Kind::List),
+            "ps" if json => Some(Kind::Ps),
+            _ => None,
+        }
+    }
+
+    pub fn subcommand(self) -> &'static str {
+        match self {
+            Kind::State => "state",
+            Kind::List => "list",
+            Kind::Ps => "ps",
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct State
+// ----------------------------------------------------------------------------
+//   The canonical state of a container, as printed by runc
+// ----------------------------------------------------------------------------
+{
+    pub oci_version: String,
+    pub id: String,
+    pub pid: i64,
+    pub status: String,
+    pub bundle: String,
+    pub rootfs: String,
+    pub created: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    pub owner: String,
+}
+
+fn string(object: &Map<String, Value>, key: &str) -> Result<Option<String>> {
+    match object.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.clone())),
+        Some(other) => Err(anyhow!("Field {} is not a string: {}", key, other)),
+    }
+}
+
+fn required(object: &Map<String, Value>, key: &str) -> Result<String> {
+    string(object, key)?.ok_or_else(|| anyhow!("Missing field {}", key))
+}
+
+fn created(value: Option<&Value>) -> Result<String>
+// ----------------------------------------------------------------------------
+//   Normalize a creation time to RFC 3339 in UTC with nanoseconds
+// ----------------------------------------------------------------------------
+//   Accept RFC 3339 with any offset, or seconds since the epoch.
+{
+    let time: DateTime<Utc> = match value {
+        None | Some(Value::Null) => return Ok(String::new()),
+        Some(Value::String(text)) => DateTime::parse_from_rfc3339(text)
+            .with_context(|| format!("Invalid creation time {:?}", text))?
+            .with_timezone(&Utc),
+        Some(Value::Number(secs)) => secs
+            .as_i64()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .ok_or_else(|| anyhow!("Invalid creation time {}", secs))?,
+        Some(other) => return Err(anyhow!("Invalid creation time {}", other)),
+    };
+    Ok(time.to_rfc3339_opts(SecondsFormat::Nanos, true))
+}
+
+fn user_name(uid: u32) -> String
+// ----------------------------------------------------------------------------
+//   The name of a user, like runc's `owner`, or the uid if unknown
+// ----------------------------------------------------------------------------
+{
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        // SAFETY: getpwuid_r only writes to the entry and buffer given to it
+        let (found, name) = unsafe {
+            let mut entry: libc::passwd = std::mem::zeroed();
+            let mut result = std::ptr::null_mut();
+            let found = libc::getpwuid_r(
+                uid,
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            );
+            let name = (!result.is_null())
+                .then(|| CStr::from_ptr(entry.pw_name).to_string_lossy().into_owned());
+            (found, name)
+        };
+        if found == libc::ERANGE && buffer.len() < 1 << 20 {
+            buffer.resize(buffer.len() * 2, 0);
+            continue;
+        }
+        return name.unwrap_or_else(|| uid.to_string());
+    }
+}
+
+impl State {
+    pub fn from_value(value: &Value) -> Result<State>
+    // ------------------------------------------------------------------------
+    //   Validate and normalize the state printed by some runtime
+    // ------------------------------------------------------------------------
+    {
+        let object = value
+            .as_object()
+            .ok_or_else(|| anyhow!("State is not an object: {}", value))?;
+
+        let status = required(object, "status")?;
+        if !STATUSES.contains(&status.as_str()) {
+            return Err(anyhow!("Invalid status {:?}", status));
+        }
+        let pid = match object.get("pid") {
+            None | Some(Value::Null) => 0,
+            Some(pid) => pid
+                .as_i64()
+                .filter(|pid| *pid >= 0)
+                .ok_or_else(|| anyhow!("Invalid pid {}", pid))?,
+        };
+        if pid == 0 && (status == "created" || status == "running") {
+            return Err(anyhow!("Missing pid for {} container", status));
+        }
+
+        let annotations = match object.get("annotations") {
+            None | Some(Value::Null) => BTreeMap::new(),
+            Some(annotations) => serde_json::from_value(annotations.clone())
+                .context("Invalid annotations")?,
+        };
+
+        // youki records the uid of the creator instead of an owner name
+        let owner = match string(object, "owner")? {
+            Some(owner) => owner,
+            None => object
+                .get("creator")
+                .and_then(Value::as_u64)
+                .and_then(|uid| u32::try_from(uid).ok())
+                .map(user_name)
+                .unwrap_or_default(),
+        };
+
+        Ok(State {
+            oci_version: required(object, "ociVersion")?,
+            id: required(object, "id")?,
+            pid,
+            status,
+            bundle: required(object, "bundle")?,
+            rootfs: string(object, "rootfs")?.unwrap_or_default(),
+            created: created(object.get("created"))?,
+            annotations,
+            owner,
+        })
+    }
+}
+
+pub fn normalize(kind: Kind, output: &[u8]) -> Result<Vec<u8>>
+// ----------------------------------------------------------------------------
+//   Parse, validate and normalize the output of a command
+// ----------------------------------------------------------------------------
+//   Like runc, `state` is indented, `list` and `ps` are on a single line.
+{
+    let value: Value = serde_json::from_slice(output).context("Output is not valid JSON")?;
+    let mut text = match kind {
+        Kind::State => serde_json::to_string_pretty(&State::from_value(&value)?)?,
+        Kind::List => {
+            // runc prints `null` when there are no containers
+            let states = match &value {
+                Value::Null => Vec::new(),
+                Value::Array(items) => items
+                    .iter()
+                    .map(State::from_value)
+                    .collect::<Result<Vec<_>>>()?,
+                other => return Err(anyhow!("Container list is not an array: {}", other)),
+            };
+            serde_json::to_string(&states)?
+        }
+        Kind::Ps => {
+            let pids = match &value {
+                Value::Null => Vec::new(),
+                Value::Array(items) => items
+                    .iter()
+                    .map(|pid| pid.as_i64().ok_or_else(|| anyhow!("Invalid pid {}", pid)))
+                    .collect::<Result<Vec<_>>>()?,
+                other => return Err(anyhow!("Process list is not an array: {}", other)),
+            };
+            serde_json::to_string(&pids)?
+        }
+    };
+    text.push('\n');
+    Ok(text.into_bytes())
+}
+
+#[derive(Default)]
+struct Redirection
+// ----------------------------------------------------------------------------
+//   Standard streams temporarily redirected, restored when dropped
+// ----------------------------------------------------------------------------
+{
+    saved: Vec<(RawFd, OwnedFd)>,
+}
+
+impl Redirection {
+    fn redirect(&mut self, fd: RawFd, file: &File) -> io::Result<()> {
+        io::stdout().flush()?;
+        // SAFETY: standard streams stay open for the life of the process
+        let saved = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+        let saved = fds::relocate(saved)?;
+        // SAFETY: both fds are open, and dup2 atomically replaces fd
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.saved.push((fd, saved));
+        Ok(())
+    }
+}
+
+impl Drop for Redirection {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        for (fd, saved) in self.saved.drain(..).rev() {
+            // SAFETY: saved is a copy of the original stream, still open
+            unsafe { libc::dup2(saved.as_raw_fd(), fd) };
+        }
+    }
+}
+
+fn contents(mut file: File) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let _ = file
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_to_end(&mut bytes));
+    bytes
+}
+
+pub fn capture<T>(action: impl FnOnce() -> T) -> io::Result<(T, Vec<u8>)>
+// ----------------------------------------------------------------------------
+//   Run an action with stdout going to a temporary file, return its contents
+// ----------------------------------------------------------------------------
+{
+    let stdout = tempfile::tempfile()?;
+    let mut redirection = Redirection::default();
+    redirection.redirect(libc::STDOUT_FILENO, &stdout)?;
+    let result = action();
+    drop(redirection);
+    Ok((result, contents(stdout)))
+}
+
+pub fn capture_all<T>(action: impl FnOnce() -> T) -> io::Result<(T, Vec<u8>, Vec<u8>)>
+// ----------------------------------------------------------------------------
+//   Run an action with stdout and stderr going to temporary files
+// ----------------------------------------------------------------------------
+{
+    let stdout = tempfile::tempfile()?;
+    let stderr = tempfile::tempfile()?;
+    let mut redirection = Redirection::default();
+    redirection.redirect(libc::STDOUT_FILENO, &stdout)?;
+    redirection.redirect(libc::STDERR_FILENO, &stderr)?;
+    let result = action();
+    drop(redirection);
+    Ok((result, contents(stdout), contents(stderr)))
+}
+
+pub fn isolate<T>(action: impl FnOnce() -> T) -> io::Result<(T, Vec<u8>)>
+// ----------------------------------------------------------------------------
+//   Run an action without access to the standard streams of the caller
+// ----------------------------------------------------------------------------
+//   Stdin reads nothing, stderr is discarded, stdout is returned.
+{
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    let stdout = tempfile::tempfile()?;
+    let mut redirection = Redirection::default();
+    redirection.redirect(libc::STDIN_FILENO, &null)?;
+    redirection.redirect(libc::STDOUT_FILENO, &stdout)?;
+    redirection.redirect(libc::STDERR_FILENO, &null)?;
+    let result = action();
+    drop(redirection);
+    Ok((result, contents(stdout)))
+}
+
+pub fn attach<T>(stdio: &[File; 3], action: impl FnOnce() -> T) -> io::Result<T>
+// ----------------------------------------------------------------------------
+//   Run an action with stdin, stdout and stderr connected to the given files
+// ----------------------------------------------------------------------------
+//   Processes started by the action, like a container, inherit these files.
+{
+    let mut redirection = Redirection::default();
+    for (fd, file) in (libc::STDIN_FILENO..).zip(stdio) {
+        redirection.redirect(fd, file)?;
+    }
+    let result = action();
+    drop(redirection);
+    Ok(result)
+}
+
+pub fn state_differences(expected: &[u8], actual: &[u8], differences: &mut Vec<String>)
+// ----------------------------------------------------------------------------
+//   Compare the normalized state output of two runtimes
+// ----------------------------------------------------------------------------
+{
+    let parse = |bytes: &[u8]| {
+        normalize(Kind::State, bytes)
+            .ok()
+            .and_then(|text| serde_json::from_slice::<Value>(&text).ok())
+    };
+    let (Some(Value::Object(expected)), Some(Value::Object(actual))) =
+        (parse(expected), parse(actual))
+    else {
+        differences.push("state: invalid output".into());
+        return;
+    };
+    for key in expected.keys().chain(actual.keys().filter(|k| !expected.contains_key(*k))) {
+        if VOLATILE_STATE.contains(&key.as_str()) {
+            continue;
+        }
+        let (left, right) = (expected.get(key), actual.get(key));
+        if left != right {
+            let show = |value: Option<&Value>| value.map_or("none".into(), Value::to_string);
+            differences.push(format!("state {}: {} vs {}", key, show(left), show(right)));
+        }
+    }
+}
@@ -0,0 +1,503 @@
+// ****************************************************************************
+//  policy.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check bundles against a policy before passing them to another backend
+//
+//     The policy backend reads the config.json of the bundle for `create`
+//     and `run`, and checks it against declarative rules on capabilities,
+//     bind mounts, namespaces and resource limits. A bundle that violates
+//     any rule is rejected with all violations in the error, and the
+//     backend is not called. Capabilities added by `exec` and limits changed
+//     by `update` are checked too. Other commands are passed unchanged.
+//     The same rules are available as a layer of the layered backend.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Result;
+use serde_json::Value;
+use tracing::debug;
+
+use liboci_cli::GlobalOpts;
+
+use super::{Backend, Error, StateRoot};
+
+// Capability sets of a process in config.json
+const CAPABILITY_SETS: &[&str] = &["bounding", "effective", "inheritable", "permitted", "ambient"];
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Rules
+// ----------------------------------------------------------------------------
+//   The rules that bundles must follow
+// ----------------------------------------------------------------------------
+{
+    // Capabilities that no capability set may contain
+    #[serde(default)]
+    forbidden_capabilities: Vec<String>,
+
+    // Host paths that bind mounts may use, with what is below them
+    allowed_host_paths: Option<Vec<PathBuf>>,
+
+    // Namespaces that the container must have
+    #[serde(default)]
+    require_user_namespace: bool,
+    #[serde(default)]
+    forbid_host_network: bool,
+
+    // Namespaces that containers may join, with what is below them
+    #[serde(default)]
+    allowed_namespace_paths: Vec<PathBuf>,
+
+    // Resource ceilings, that the limits of the container must respect
+    max_memory: Option<i64>,
+    max_cpus: Option<f64>,
+    max_pids: Option<i64>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Config
+// ----------------------------------------------------------------------------
+//   Configuration of the policy backend
+// ----------------------------------------------------------------------------
+{
+    // The backend running the commands
+    backend: Box<super::Config>,
+
+    // The rules checked before `create` and `run`
+    #[serde(flatten)]
+    rules: Rules,
+}
+
+impl Config {
+    pub fn state_root(&self) -> Option<StateRoot> {
+        self.backend.state_root()
+    }
+
+    pub fn replaces_process(&self) -> bool {
+        self.backend.replaces_process()
+    }
+
+    pub fn instantiate(self, global: GlobalOpts) -> Result<Box<dyn Backend>> {
+        Ok(Box::new(PolicyBackend {
+            backend: self.backend.instantiate(global)?,
+            rules: self.rules,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct PolicyBackend
+// ----------------------------------------------------------------------------
+//   A backend rejecting bundles that violate a policy
+// ----------------------------------------------------------------------------
+{
+    backend: Box<dyn Backend>,
+    rules: Rules,
+}
+
+fn capability(name: &str) -> String {
+    let name = name.to_uppercase();
+    match name.starts_with("CAP_") {
+        true => name,
+        false => format!("CAP_{}", name),
+    }
+}
+
+fn normalize(path: &Path) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Resolve `.` and `..` in a path, so that `/data/../etc` is not in /data
+// ----------------------------------------------------------------------------
+{
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+fn resolve(bundle: &Path, path: &Path) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Resolve symbolic links in a host path, as the runtime will when mounting
+// ----------------------------------------------------------------------------
+//   Relative paths are in the bundle. Only the part of the path that exists
+//   can be resolved, the rest is kept as is.
+{
+    let path = bundle.join(path);
+    for ancestor in path.ancestors() {
+        if let Ok(resolved) = fs::canonicalize(ancestor) {
+            let rest = path.strip_prefix(ancestor).unwrap_or(Path::new(""));
+            return normalize(&resolved.join(rest));
+        }
+    }
+    normalize(&path)
+}
+
+fn read_json(path: &Path) -> Result<Value>
+// ----------------------------------------------------------------------------
+//   Read a JSON file that the policy needs to check
+// ----------------------------------------------------------------------------
+{
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .ok_or_else(|| {
+            Error::Policy {
+                message: format!("Cannot check policy, {} is unreadable", path.display()),
+            }
+            .into()
+        })
+}
+
+fn strings(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|item| item.as_str().map(String::from))
+        .collect()
+}
+
+fn limit(name: &str, value: Option<f64>, ceiling: Option<f64>, violations: &mut Vec<String>) {
+    match (value, ceiling) {
+        (_, None) => {}
+        (None, Some(ceiling)) => {
+            violations.push(format!("no {} limit, the maximum is {}", name, ceiling))
+        }
+        (Some(value), Some(ceiling)) if value > ceiling => violations.push(format!(
+            "{} limit {} is above the maximum of {}",
+            name, value, ceiling
+        )),
+        _ => {}
+    }
+}
+
+impl Rules {
+    fn forbidden(&self, name: &str) -> bool {
+        let name = capability(name);
+        self.forbidden_capabilities.iter().any(|other| capability(other) == name)
+    }
+
+    fn capability_violations(&self, process: &Value, violations: &mut Vec<String>)
+    // ------------------------------------------------------------------------
+    //   Check the capability sets of a process against the rules
+    // ------------------------------------------------------------------------
+    {
+        for set in CAPABILITY_SETS {
+            for name in strings(&process["capabilities"][set]) {
+                if self.forbidden(&name) {
+                    violations.push(format!("capability {} in {} set", name, set));
+                }
+            }
+        }
+    }
+
+    fn violations(&self, spec: &Value, bundle: &Path) -> Vec<String>
+    // ------------------------------------------------------------------------
+    //   Check a config.json against all the rules, and list violations
+    // ------------------------------------------------------------------------
+    {
+        let mut violations = Vec::new();
+        self.capability_violations(&spec["process"], &mut violations);
+
+        if let Some(allowed) = &self.allowed_host_paths {
+            for mount in spec["mounts"].as_array().into_iter().flatten() {
+                let options = strings(&mount["options"]);
+                let bind = mount["type"] == "bind"
+                    || options.iter().any(|option| option == "bind" || option == "rbind");
+                let Some(source) = mount["source"].as_str().filter(|_| bind) else {
+                    continue;
+                };
+                let source = resolve(bundle, Path::new(source));
+                if !allowed.iter().any(|path| source.starts_with(resolve(bundle, path))) {
+                    violations.push(format!(
+                        "host path {} mounted on {} is not allowed",
+                        source.display(),
+                        mount["destination"].as_str().unwrap_or("?")
+                    ));
+                }
+            }
+        }
+
+        let namespaces = &spec["linux"]["namespaces"];
+        if self.require_user_namespace {
+            self.namespace_violations(namespaces, "user", "no user namespace", &mut violations);
+        }
+        if self.forbid_host_network {
+            let missing = "host network, without a network namespace";
+            self.namespace_violations(namespaces, "network", missing, &mut violations);
+        }
+        self.resource_violations(&spec["linux"]["resources"], false, &mut violations);
+        violations
+    }
+
+    fn namespace_violations(
+        &self,
+        namespaces: &Value,
+        kind: &str,
+        missing: &str,
+        violations: &mut Vec<String>,
+    )
+    // ------------------------------------------------------------------------
+    //   Check that the container has a namespace of its own of a given type
+    // ------------------------------------------------------------------------
+    //   A namespace joined with a path may be the one of the host, so it only
+    //   counts if the path is in `allowed_namespace_paths`.
+    {
+        let joined: Vec<Option<&str>> = namespaces
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|namespace| namespace["type"] == kind)
+            .map(|namespace| namespace["path"].as_str())
+            .collect();
+        let allowed = |path: &str| {
+            let path = normalize(Path::new(path));
+            self.allowed_namespace_paths.iter().any(|allowed| path.starts_with(normalize(allowed)))
+        };
+        if joined.iter().any(|path| path.is_none_or(allowed)) {
+            return;
+        }
+        match joined.iter().flatten().next() {
+            Some(path) => {
+                violations.push(format!("{} namespace joined from {} is not allowed", kind, path))
+            }
+            None => violations.push(missing.into()),
+        }
+    }
+
+    fn resource_violations(&self, resources: &Value, update: bool, violations: &mut Vec<String>)
+    // ------------------------------------------------------------------------
+    //   Check resource limits against the ceilings
+    // ------------------------------------------------------------------------
+    //   All limits of a bundle are checked, but only those that an update
+    //   changes, the others keep their checked value.
+    {
+        // Negative limits mean unlimited
+        let positive = |value: &Value| value.as_i64().filter(|value| *value > 0);
+        let checked = |value: &Value| !update || !value.is_null();
+        let cpus = positive(&resources["cpu"]["quota"]).map(|quota| {
+            let period = positive(&resources["cpu"]["period"]).unwrap_or(100_000);
+            quota as f64 / period as f64
+        });
+        let memory = positive(&resources["memory"]["limit"]).map(|limit| limit as f64);
+        let pids = positive(&resources["pids"]["limit"]).map(|limit| limit as f64);
+        if checked(&resources["memory"]["limit"]) {
+            limit("memory", memory, self.max_memory.map(|max| max as f64), violations);
+        }
+        if checked(&resources["cpu"]["quota"]) {
+            limit("CPU", cpus, self.max_cpus, violations);
+        }
+        if checked(&resources["pids"]["limit"]) {
+            limit("pids", pids, self.max_pids.map(|max| max as f64), violations);
+        }
+    }
+
+    fn has_ceilings(&self) -> bool {
+        self.max_memory.is_some() || self.max_cpus.is_some() || self.max_pids.is_some()
+    }
+
+    pub fn check(&self, id: &str, bundle: &Path) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Reject a bundle that violates the policy, or that cannot be checked
+    // ------------------------------------------------------------------------
+    {
+        let spec = read_json(&bundle.join("config.json"))?;
+        let violations = self.violations(&spec, bundle);
+        if !violations.is_empty() {
+            return Err(Error::Policy {
+                message: format!(
+                    "Container {} violates policy: {}",
+                    id,
+                    violations.join("; ")
+                ),
+            }
+            .into());
+        }
+        debug!("Container {} complies with policy", id);
+        Ok(())
+    }
+
+    pub fn check_exec(&self, args: &liboci_cli::Exec) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Reject an exec adding forbidden capabilities to the process
+    // ------------------------------------------------------------------------
+    //   Capabilities come from `--cap`, or from the `--process` file.
+    {
+        let mut violations: Vec<String> = args
+            .cap
+            .iter()
+            .filter(|name| self.forbidden(name))
+            .map(|name| format!("capability {} added with --cap", name))
+            .collect();
+        if let Some(path) = &args.process {
+            self.capability_violations(&read_json(path)?, &mut violations);
+        }
+        if !violations.is_empty() {
+            return Err(Error::Policy {
+                message: format!(
+                    "Exec in container {} violates policy: {}",
+                    args.container_id,
+                    violations.join("; ")
+                ),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    pub fn check_update(&self, args: &liboci_cli::Update) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Reject an update raising resource limits above the ceilings
+    // ------------------------------------------------------------------------
+    //   Options override the values in the `--resources` file, like in runc.
+    {
+        if !self.has_ceilings() {
+            return Ok(());
+        }
+        let mut resources = match &args.resources {
+            Some(path) if path.as_os_str() == "-" => {
+                return Err(Error::Policy {
+                    message: "Cannot check policy of resources read from stdin".into(),
+                }
+                .into())
+            }
+            Some(path) => read_json(path)?,
+            None => Value::Null,
+        };
+        if let Some(memory) = args.memory {
+            resources["memory"]["limit"] = memory.into();
+        }
+        if let Some(quota) = args.cpu_quota {
+            resources["cpu"]["quota"] = quota.into();
+        }
+        if let Some(period) = args.cpu_period {
+            resources["cpu"]["period"] = period.into();
+        }
+        if let Some(pids) = args.pids_limit {
+            resources["pids"]["limit"] = pids.into();
+        }
+        let mut violations = Vec::new();
+        self.resource_violations(&resources, true, &mut violations);
+        if !violations.is_empty() {
+            return Err(Error::Policy {
+                message: format!(
+                    "Update of container {} violates policy: {}",
+                    args.container_id,
+                    violations.join("; ")
+                ),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl Backend for PolicyBackend
+// ----------------------------------------------------------------------------
+//   Implement the backend interface (i.e. OCI runtime commands)
+// ----------------------------------------------------------------------------
+//   Only `create`, `run`, `exec` and `update` are checked, others are passed as is
+{
+    // ========================================================================
+    //
+    //   All standard commands (liboci_cli::StandardCmd)
+    //
+    // ========================================================================
+
+    fn create(&self, args: liboci_cli::Create) -> Result<()> {
+        self.rules.check(&args.container_id, &args.bundle)?;
+        self.backend.create(args)
+    }
+
+    fn start(&self, args: liboci_cli::Start) -> Result<()> {
+        self.backend.start(args)
+    }
+
+    fn kill(&self, args: liboci_cli::Kill) -> Result<()> {
+        self.backend.kill(args)
+    }
+
+    fn delete(&self, args: liboci_cli::Delete) -> Result<()> {
+        self.backend.delete(args)
+    }
+
+    fn state(&self, args: liboci_cli::State) -> Result<()> {
+        self.backend.state(args)
+    }
+
+    // ========================================================================
+    //
+    //   All common but non-standard commands (liboci_cli::CommonCmd)
+    //
+    // ========================================================================
+
+    fn checkpoint(&self, args: liboci_cli::Checkpoint) -> Result<()> {
+        self.backend.checkpoint(args)
+    }
+
+    fn events(&self, args: liboci_cli::Events) -> Result<()> {
+        self.backend.events(args)
+    }
+
+    fn exec(&self, args: liboci_cli::Exec) -> Result<()> {
+        self.rules.check_exec(&args)?;
+        self.backend.exec(args)
+    }
+
+    fn features(&self, args: liboci_cli::Features) -> Result<()> {
+        self.backend.features(args)
+    }
+
+    fn list(&self, args: liboci_cli::List) -> Result<()> {
+        self.backend.list(args)
+    }
+
+    fn pause(&self, args: liboci_cli::Pause) -> Result<()> {
+        self.backend.pause(args)
+    }
+
+    fn ps(&self, args: liboci_cli::Ps) -> Result<()> {
+        self.backend.ps(args)
+    }
+
+    fn resume(&self, args: liboci_cli::Resume) -> Result<()> {
+        self.backend.resume(args)
+    }
+
+    fn run(&self, args: liboci_cli::Run) -> Result<()> {
+        self.rules.check(&args.container_id, &args.bundle)?;
+        self.backend.run(args)
+    }
+
+    fn update(&self, args: liboci_cli::Update) -> Result<()> {
+        self.rules.check_update(&args)?;
+        self.backend.update(args)
+    }
+
+    fn spec(&self, args: liboci_cli::Spec) -> Result<()> {
+        self.backend.spec(args)
+    }
+}
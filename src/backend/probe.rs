@@ -0,0 +1,400 @@
+// ****************************************************************************
+//  probe.rs                                                    ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Probe what a CLI runtime supports, and filter options accordingly
+//
+//     The runtime is asked for its `features`, and optionally for the
+//     `--help` text of each subcommand, from which supported options are
+//     extracted. Results are cached in a file keyed by the path and
+//     modification time of the runtime executable, so that probing only
+//     happens again when the runtime is updated.
+//
+//     Options the runtime does not support are dropped or rejected with a
+//     clear error, depending on a per-option policy.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use super::args::{CmdLine, Opt};
+use super::Error;
+use crate::reaper;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy
+// ----------------------------------------------------------------------------
+//   What to do with an option that the runtime does not support
+// ----------------------------------------------------------------------------
+{
+    Drop,
+    Reject,
+}
+
+fn default_policy() -> Policy {
+    Policy::Reject
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Config
+// ----------------------------------------------------------------------------
+//   Configuration for probing, in the `probe` table of a CLI backend
+// ----------------------------------------------------------------------------
+{
+    // Also probe `--help` for each subcommand to find supported options
+    #[serde(default)]
+    help: bool,
+
+    // Check that the features of the runtime match the bundle at create/run
+    #[serde(default)]
+    bundle: bool,
+
+    // Where to cache results, default is $XDG_RUNTIME_DIR/ociplex or /run/ociplex
+    cache_dir: Option<PathBuf>,
+
+    // Default policy, and policy for specific `<subcommand>.<option>`
+    #[serde(default = "default_policy")]
+    unsupported: Policy,
+    #[serde(default)]
+    policy: HashMap<String, Policy>,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct Probed
+// ----------------------------------------------------------------------------
+//   The cached results of probing a runtime
+// ----------------------------------------------------------------------------
+{
+    path: PathBuf,
+    mtime: u64,
+    features: Option<Value>,
+    options: BTreeMap<String, Option<BTreeSet<String>>>,
+}
+
+#[derive(Debug)]
+pub struct Prober
+// ----------------------------------------------------------------------------
+//   Probe a runtime lazily, and filter options it does not support
+// ----------------------------------------------------------------------------
+{
+    runtime: PathBuf,
+    config: Config,
+    cache: Option<PathBuf>,
+    probed: RefCell<Option<Probed>>,
+}
+
+fn mtime(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+fn run_probe(runtime: &Path, args: &[&str]) -> Option<String>
+// ----------------------------------------------------------------------------
+//   Run the runtime with the given arguments, return its output on success
+// ----------------------------------------------------------------------------
+{
+    debug!("Probing {} {:?}", runtime.display(), args);
+    let mut child = Command::new(runtime)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    let mut stdout = Vec::new();
+    child.stdout.take()?.read_to_end(&mut stdout).ok()?;
+    let status = reaper::wait(&mut child).ok()?;
+    status
+        .success()
+        .then(|| String::from_utf8_lossy(&stdout).into_owned())
+}
+
+fn help_options(help: &str) -> BTreeSet<String>
+// ----------------------------------------------------------------------------
+//   Extract long option names from a help text
+// ----------------------------------------------------------------------------
+//   This works with the formats used by urfave/cli (runc), argp (crun) and
+//   clap (youki), which all show options as `--name`.
+{
+    let mut options = BTreeSet::new();
+    for word in help.split(|c: char| c.is_whitespace() || c == ',' || c == '[' || c == '=') {
+        if let Some(name) = word.strip_prefix("--") {
+            let name: String = name
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+                .collect();
+            if !name.is_empty() {
+                options.insert(name);
+            }
+        }
+    }
+    options
+}
+
+fn version(text: &str) -> Vec<u64>
+// ----------------------------------------------------------------------------
+//   The numeric components of a version, e.g. [1, 1, 0] for "1.1.0-rc.1"
+// ----------------------------------------------------------------------------
+{
+    text.split(['-', '+'])
+        .next()
+        .unwrap_or("")
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+fn mismatch(features: &Value, config: &Value) -> Option<String>
+// ----------------------------------------------------------------------------
+//   What a bundle configuration requires that the features do not provide
+// ----------------------------------------------------------------------------
+{
+    if let Some(wanted) = config.get("ociVersion").and_then(Value::as_str) {
+        let min = features.get("ociVersionMin").and_then(Value::as_str);
+        let max = features.get("ociVersionMax").and_then(Value::as_str);
+        if min.is_some_and(|min| version(wanted) < version(min))
+            || max.is_some_and(|max| version(wanted) > version(max))
+        {
+            return Some(format!("OCI version {}", wanted));
+        }
+    }
+
+    let supported: Option<Vec<&str>> = features
+        .pointer("/linux/namespaces")
+        .and_then(Value::as_array)
+        .map(|names| names.iter().filter_map(Value::as_str).collect());
+    let required = config
+        .pointer("/linux/namespaces")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|ns| ns.get("type").and_then(Value::as_str));
+    for namespace in required {
+        if supported
+            .as_ref()
+            .is_some_and(|supported| !supported.contains(&namespace))
+        {
+            return Some(format!("{} namespace", namespace));
+        }
+    }
+    None
+}
+
+impl Prober {
+    pub fn new(runtime: &Path, config: Config) -> Self {
+        let cache = config.cache_dir.clone().unwrap_or_else(super::default_state_dir);
+        let name: String = runtime
+            .to_string_lossy()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        Prober {
+            runtime: runtime.to_path_buf(),
+            config,
+            cache: Some(cache.join(format!("probe{}.json", name))),
+            probed: RefCell::new(None),
+        }
+    }
+
+    fn load(&self) -> Probed
+    // ------------------------------------------------------------------------
+    //   Load cached results if still valid, otherwise probe features
+    // ------------------------------------------------------------------------
+    {
+        let mtime = mtime(&self.runtime);
+        let cached = self
+            .cache
+            .as_ref()
+            .and_then(|cache| fs::read_to_string(cache).ok())
+            .and_then(|text| serde_json::from_str::<Probed>(&text).ok())
+            .filter(|probed| probed.path == self.runtime && probed.mtime == mtime);
+        if let Some(probed) = cached {
+            return probed;
+        }
+
+        let features = run_probe(&self.runtime, &["features"])
+            .and_then(|text| serde_json::from_str(&text).ok());
+        let probed = Probed {
+            path: self.runtime.clone(),
+            mtime,
+            features,
+            options: BTreeMap::new(),
+        };
+        self.save(&probed);
+        probed
+    }
+
+    fn save(&self, probed: &Probed)
+    // ------------------------------------------------------------------------
+    //   Save probe results, failure only means we will probe again
+    // ------------------------------------------------------------------------
+    {
+        let Some(cache) = &self.cache else { return };
+        let saved = cache
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(cache, serde_json::to_string(probed).unwrap_or_default()));
+        if let Err(err) = saved {
+            warn!("Cannot cache probe results in {}: {}", cache.display(), err);
+        }
+    }
+
+    fn with_probed<T>(&self, action: impl FnOnce(&mut Probed) -> T) -> T {
+        let mut probed = self.probed.borrow_mut();
+        action(probed.get_or_insert_with(|| self.load()))
+    }
+
+    fn has_features(&self) -> bool
+    // ------------------------------------------------------------------------
+    //   Check if the runtime gave a valid output for `<runtime> features`
+    // ------------------------------------------------------------------------
+    {
+        self.with_probed(|probed| probed.features.is_some())
+    }
+
+    fn supported(&self, subcommand: &str) -> Option<BTreeSet<String>>
+    // ------------------------------------------------------------------------
+    //   Options supported for a subcommand ("" for global), None if unknown
+    // ------------------------------------------------------------------------
+    {
+        if !self.config.help {
+            return None;
+        }
+        self.with_probed(|probed| {
+            if let Some(options) = probed.options.get(subcommand) {
+                return options.clone();
+            }
+            let args: &[&str] = if subcommand.is_empty() {
+                &["--help"]
+            } else {
+                &[subcommand, "--help"]
+            };
+            let options = run_probe(&self.runtime, args)
+                .map(|help| help_options(&help))
+                .filter(|options| !options.is_empty());
+            probed.options.insert(subcommand.to_string(), options.clone());
+            self.save(probed);
+            options
+        })
+    }
+
+    fn filter_options(&self, options: &mut Vec<Opt>, subcommand: &str) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Remove or reject options that the runtime does not support
+    // ------------------------------------------------------------------------
+    {
+        let Some(supported) = self.supported(subcommand) else {
+            return Ok(());
+        };
+        let scope = if subcommand.is_empty() { "global" } else { subcommand };
+        let mut result = Ok(());
+        options.retain(|opt| {
+            if opt.name.starts_with('-') || supported.contains(&opt.name) {
+                return true;
+            }
+            let key = format!("{}.{}", scope, opt.name);
+            match self.config.policy.get(&key).unwrap_or(&self.config.unsupported) {
+                Policy::Drop => {
+                    debug!("Dropping option --{} unsupported by runtime", opt.name);
+                }
+                Policy::Reject => {
+                    result = Err(Error::Unsupported {
+                        message: format!(
+                            "Option --{} of {} is not supported by backend {}",
+                            opt.name,
+                            scope,
+                            self.runtime.display()
+                        ),
+                    }
+                    .into());
+                }
+            }
+            false
+        });
+        result
+    }
+
+    pub fn check_bundle(&self, cmd: &CmdLine) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Check that the runtime features match the bundle for create and run
+    // ------------------------------------------------------------------------
+    //   Runtimes without `features` cannot be checked, and are assumed fine.
+    {
+        if !self.config.bundle || !matches!(cmd.subcommand, "create" | "run") {
+            return Ok(());
+        }
+        let Some(features) = self.with_probed(|probed| probed.features.clone()) else {
+            return Ok(());
+        };
+        let bundle = cmd
+            .options
+            .iter()
+            .find(|opt| opt.name == "bundle")
+            .and_then(|opt| opt.value.as_ref())
+            .map_or_else(|| PathBuf::from("."), PathBuf::from);
+        let path = bundle.join("config.json");
+        let config: Value = fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .ok_or_else(|| anyhow!("Cannot read bundle configuration {}", path.display()))?;
+        match mismatch(&features, &config) {
+            Some(what) => Err(Error::Features {
+                message: format!(
+                    "Bundle requires {}, not supported by backend {}",
+                    what,
+                    self.runtime.display()
+                ),
+            }
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn filter(&self, cmd: &mut CmdLine, global: &mut Vec<Opt>) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Filter global and subcommand options of a command line
+    // ------------------------------------------------------------------------
+    {
+        // Older runtimes do not implement `features` at all
+        if !self.has_features() && cmd.subcommand == "features" {
+            return Err(Error::Unsupported {
+                message: format!(
+                    "Subcommand features is not supported by backend {}",
+                    self.runtime.display()
+                ),
+            }
+            .into());
+        }
+        self.filter_options(global, "")?;
+        self.filter_options(&mut cmd.options, cmd.subcommand)
+    }
+}
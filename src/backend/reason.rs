@@ -0,0 +1,152 @@
+// ****************************************************************************
+//  reason.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Find the reason why a CLI runtime failed
+//
+//     The runtime explains its failures on stderr, or in its `--log` file,
+//     using the logrus text or JSON formats when it is runc-compatible.
+//     Stderr is copied to ociplex's stderr while keeping its tail, and the
+//     log file is read from where it was when the runtime started, so that
+//     the last error message can be included in ociplex's own error.
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+
+use serde_json::Value;
+
+// How much of stderr to keep to find the reason for a failure
+const TAIL_SIZE: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub struct Tee
+// ----------------------------------------------------------------------------
+//   Copy a pipe to our stderr in a thread, keeping the tail of the output
+// ----------------------------------------------------------------------------
+{
+    thread: JoinHandle<Vec<u8>>,
+}
+
+impl Tee {
+    pub fn start(mut pipe: impl Read + Send + 'static) -> Tee {
+        let thread = thread::spawn(move || {
+            let mut tail = Vec::new();
+            let mut buffer = [0u8; 4096];
+            while let Ok(size) = pipe.read(&mut buffer) {
+                if size == 0 {
+                    break;
+                }
+                let _ = io::stderr().write_all(&buffer[..size]);
+                tail.extend_from_slice(&buffer[..size]);
+                if tail.len() > 2 * TAIL_SIZE {
+                    tail.drain(..tail.len() - TAIL_SIZE);
+                }
+            }
+            tail
+        });
+        Tee { thread }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.thread.join().unwrap_or_default()
+    }
+}
+
+fn logfmt_value(line: &str, key: &str) -> Option<String>
+// ----------------------------------------------------------------------------
+//   Extract a value from a logrus text line, e.g. `level=error msg="..."`
+// ----------------------------------------------------------------------------
+{
+    let start = line
+        .match_indices(key)
+        .map(|(index, _)| index)
+        .find(|index| *index == 0 || line.as_bytes()[index - 1] == b' ')?;
+    let rest = line[start + key.len()..].strip_prefix('=')?;
+    match rest.strip_prefix('"') {
+        Some(quoted) => {
+            let mut value = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => return Some(value),
+                    '\\' => match chars.next()? {
+                        'n' => value.push('\n'),
+                        't' => value.push('\t'),
+                        other => value.push(other),
+                    },
+                    c => value.push(c),
+                }
+            }
+            None
+        }
+        None => Some(rest.split(' ').next().unwrap_or("").to_string()),
+    }
+}
+
+fn error_message(line: &str) -> Option<String>
+// ----------------------------------------------------------------------------
+//   The message of an error or fatal log line, in JSON or text format
+// ----------------------------------------------------------------------------
+{
+    let is_error = |level: &str| level == "error" || level == "fatal" || level == "panic";
+    if let Ok(Value::Object(record)) = serde_json::from_str::<Value>(line) {
+        let level = record.get("level").and_then(Value::as_str)?;
+        return is_error(level)
+            .then(|| record.get("msg").and_then(Value::as_str).map(String::from))
+            .flatten();
+    }
+    let level = logfmt_value(line, "level")?;
+    is_error(&level).then(|| logfmt_value(line, "msg")).flatten()
+}
+
+pub fn log_offset(log: &Path) -> u64
+// ----------------------------------------------------------------------------
+//   The current size of a log file, to only read what is written after it
+// ----------------------------------------------------------------------------
+{
+    fs::metadata(log).map(|meta| meta.len()).unwrap_or(0)
+}
+
+pub fn from_log(log: &Path, offset: u64) -> Option<String>
+// ----------------------------------------------------------------------------
+//   The last error message written to a log file after the given offset
+// ----------------------------------------------------------------------------
+{
+    let mut file = File::open(log).ok()?;
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    let mut text = String::new();
+    file.read_to_string(&mut text).ok()?;
+    text.lines().rev().find_map(error_message)
+}
+
+pub fn from_stderr(stderr: &[u8]) -> Option<String>
+// ----------------------------------------------------------------------------
+//   The last error message on stderr, or its last line if not a log record
+// ----------------------------------------------------------------------------
+{
+    let text = String::from_utf8_lossy(stderr);
+    let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+    if let Some(message) = lines.clone().rev().find_map(error_message) {
+        return Some(message);
+    }
+    lines.next_back().map(String::from)
+}
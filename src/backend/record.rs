@@ -0,0 +1,359 @@
+// ****************************************************************************
+//  record.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     The record backend is recording what is happening in a journal
+//
+//     Each invocation is appended to a journal file as a line of JSON, with
+//     its arguments, the global options, selected environment variables,
+//     the digest and a copy of the config.json of the bundle, its duration,
+//     its result and the output of `state`. Commands are then passed to
+//     another backend, by default a mock one. This gives an exact trace of
+//     what a container engine asked.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <christophe@dinechin.org>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use liboci_cli::GlobalOpts;
+
+use super::args::{CmdLine, ToCmdLine};
+use super::error::outcome;
+use super::output::capture;
+use super::mock;
+use super::{Backend, StateRoot};
+
+// Environment variables recorded by default, when they are set
+const DEFAULT_ENV: &[&str] = &[
+    "NOTIFY_SOCKET",
+    "LISTEN_FDS",
+    "LISTEN_PID",
+    "XDG_RUNTIME_DIR",
+];
+
+fn default_snapshot() -> bool {
+    true
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Config
+// ----------------------------------------------------------------------------
+//   Configuration of the record backend
+// ----------------------------------------------------------------------------
+{
+    // The backend running the commands, default is a mock backend
+    backend: Option<Box<super::Config>>,
+
+    // The journal, default is journal.jsonl in $XDG_RUNTIME_DIR/ociplex
+    journal: Option<PathBuf>,
+
+    // Environment variables to record, when they are set
+    env: Option<Vec<String>>,
+
+    // Record a copy of config.json in addition to its digest
+    #[serde(default = "default_snapshot")]
+    snapshot: bool,
+}
+
+impl Config
+// ----------------------------------------------------------------------------
+//    Implementation of the ociplex configuration interface
+// ----------------------------------------------------------------------------
+{
+    pub fn state_root(&self) -> Option<StateRoot> {
+        match &self.backend {
+            Some(backend) => backend.state_root(),
+            None => Some(mock::Config::default().state_root()),
+        }
+    }
+
+    pub fn replaces_process(&self) -> bool {
+        self.backend.as_ref().is_some_and(|backend| backend.replaces_process())
+    }
+
+    pub fn instantiate(self, global: GlobalOpts) -> Result<Box<dyn Backend>>
+    // ------------------------------------------------------------------------
+    //   Instantiate a record backend from given OCI command-line options
+    // ------------------------------------------------------------------------
+    {
+        debug!("Record backend global options {:?}", global);
+        // The journal is written after the command, which exec never returns to
+        if self.replaces_process() {
+            return Err(anyhow!("Recorded backends cannot use exec"));
+        }
+        let options = json!({
+            "debug": global.debug,
+            "log": global.log,
+            "log_format": global.log_format,
+            "root": global.root,
+            "systemd_cgroup": global.systemd_cgroup,
+        });
+        let backend = match self.backend {
+            Some(config) => config.instantiate(global)?,
+            None => mock::Config::default().instantiate(global)?,
+        };
+        Ok(Box::new(RecordBackend {
+            backend,
+            journal: self
+                .journal
+                .unwrap_or_else(|| super::default_state_dir().join("journal.jsonl")),
+            env: self
+                .env
+                .unwrap_or_else(|| DEFAULT_ENV.iter().map(|name| name.to_string()).collect()),
+            snapshot: self.snapshot,
+            global: options,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct RecordBackend
+// ----------------------------------------------------------------------------
+//   Data specific to the record backend
+// ----------------------------------------------------------------------------
+{
+    backend: Box<dyn Backend>,
+    journal: PathBuf,
+    env: Vec<String>,
+    snapshot: bool,
+    global: Value,
+}
+
+fn lossy(values: &[OsString]) -> Vec<String> {
+    values
+        .iter()
+        .map(|value| value.to_string_lossy().into_owned())
+        .collect()
+}
+
+fn args_json(cmd: &CmdLine) -> Value
+// ----------------------------------------------------------------------------
+//   The parsed arguments, with options as `[name, value]` pairs in order
+// ----------------------------------------------------------------------------
+{
+    let options: Vec<Value> = cmd
+        .options
+        .iter()
+        .map(|opt| json!([opt.name, opt.value.as_ref().map(|v| v.to_string_lossy())]))
+        .collect();
+    json!({
+        "options": options,
+        "operands": lossy(&cmd.operands),
+        "trailing": lossy(&cmd.trailing),
+    })
+}
+
+impl RecordBackend {
+    fn bundle(&self, cmd: &CmdLine) -> Option<Value>
+    // ------------------------------------------------------------------------
+    //   The absolute path, digest and contents of config.json for create/run
+    // ------------------------------------------------------------------------
+    {
+        if !matches!(cmd.subcommand, "create" | "run") {
+            return None;
+        }
+        let bundle = cmd
+            .options
+            .iter()
+            .find(|opt| opt.name == "bundle")
+            .and_then(|opt| opt.value.as_ref())
+            .map_or_else(|| PathBuf::from("."), PathBuf::from);
+        let bundle = env::current_dir().map_or(bundle.clone(), |cwd| cwd.join(&bundle));
+        let mut recorded = json!({ "path": bundle });
+        match fs::read(bundle.join("config.json")) {
+            Ok(config) => {
+                let digest: String = Sha256::digest(&config)
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                recorded["digest"] = format!("sha256:{}", digest).into();
+                if self.snapshot {
+                    recorded["config"] = serde_json::from_slice(&config).unwrap_or(Value::Null);
+                }
+            }
+            Err(err) => recorded["error"] = err.to_string().into(),
+        }
+        Some(recorded)
+    }
+
+    fn env(&self) -> Map<String, Value> {
+        self.env
+            .iter()
+            .filter_map(|name| {
+                env::var_os(name).map(|value| (name.clone(), value.to_string_lossy().into()))
+            })
+            .collect()
+    }
+
+    fn append(&self, entry: &Value)
+    // ------------------------------------------------------------------------
+    //   Append an entry to the journal, failing only means it is not recorded
+    // ------------------------------------------------------------------------
+    {
+        let written = self
+            .journal
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.journal)
+            })
+            .and_then(|mut journal| journal.write_all(format!("{}\n", entry).as_bytes()));
+        if let Err(err) = written {
+            warn!("Cannot write journal {}: {}", self.journal.display(), err);
+        }
+    }
+
+    fn record<A: ToCmdLine>(
+        &self,
+        args: A,
+        call: impl FnOnce(&dyn Backend, A) -> Result<()>,
+    ) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Run a command with the backend, and record it in the journal
+    // ------------------------------------------------------------------------
+    {
+        let cmd = args.to_cmdline();
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let bundle = self.bundle(&cmd);
+        let start = Instant::now();
+        let run = || call(self.backend.as_ref(), args);
+
+        // The state output is recorded, so that a replay can compare it
+        let (result, output) = if cmd.subcommand == "state" {
+            let (result, output) = capture(run)?;
+            io::stdout().write_all(&output)?;
+            (result, Some(String::from_utf8_lossy(&output).into_owned()))
+        } else {
+            (run(), None)
+        };
+        let (class, code) = outcome(&result);
+        let entry = json!({
+            "time": time,
+            "subcommand": cmd.subcommand,
+            "args": args_json(&cmd),
+            "global": self.global,
+            "env": self.env(),
+            "bundle": bundle,
+            "duration": start.elapsed().as_secs_f64(),
+            "output": output,
+            "result": {
+                "outcome": class,
+                "code": code,
+                "error": result.as_ref().err().map(|err| format!("{:#}", err)),
+            },
+        });
+        self.append(&entry);
+        result
+    }
+}
+
+impl Backend for RecordBackend
+// ----------------------------------------------------------------------------
+//   Implement the backend interface (i.e. OCI runtime commands)
+// ----------------------------------------------------------------------------
+//   All commands are recorded, then run by the backend
+{
+    // ========================================================================
+    //
+    //   All standard commands (liboci_cli::StandardCmd)
+    //
+    // ========================================================================
+
+    fn create(&self, args: liboci_cli::Create) -> Result<()> {
+        self.record(args, |backend, args| backend.create(args))
+    }
+
+    fn start(&self, args: liboci_cli::Start) -> Result<()> {
+        self.record(args, |backend, args| backend.start(args))
+    }
+
+    fn kill(&self, args: liboci_cli::Kill) -> Result<()> {
+        self.record(args, |backend, args| backend.kill(args))
+    }
+
+    fn delete(&self, args: liboci_cli::Delete) -> Result<()> {
+        self.record(args, |backend, args| backend.delete(args))
+    }
+
+    fn state(&self, args: liboci_cli::State) -> Result<()> {
+        self.record(args, |backend, args| backend.state(args))
+    }
+
+    // ========================================================================
+    //
+    //   All common but non-standard commands (liboci_cli::CommonCmd)
+    //
+    // ========================================================================
+
+    fn checkpoint(&self, args: liboci_cli::Checkpoint) -> Result<()> {
+        self.record(args, |backend, args| backend.checkpoint(args))
+    }
+
+    fn events(&self, args: liboci_cli::Events) -> Result<()> {
+        self.record(args, |backend, args| backend.events(args))
+    }
+
+    fn exec(&self, args: liboci_cli::Exec) -> Result<()> {
+        self.record(args, |backend, args| backend.exec(args))
+    }
+
+    fn features(&self, args: liboci_cli::Features) -> Result<()> {
+        self.record(args, |backend, args| backend.features(args))
+    }
+
+    fn list(&self, args: liboci_cli::List) -> Result<()> {
+        self.record(args, |backend, args| backend.list(args))
+    }
+
+    fn pause(&self, args: liboci_cli::Pause) -> Result<()> {
+        self.record(args, |backend, args| backend.pause(args))
+    }
+
+    fn ps(&self, args: liboci_cli::Ps) -> Result<()> {
+        self.record(args, |backend, args| backend.ps(args))
+    }
+
+    fn resume(&self, args: liboci_cli::Resume) -> Result<()> {
+        self.record(args, |backend, args| backend.resume(args))
+    }
+
+    fn run(&self, args: liboci_cli::Run) -> Result<()> {
+        self.record(args, |backend, args| backend.run(args))
+    }
+
+    fn update(&self, args: liboci_cli::Update) -> Result<()> {
+        self.record(args, |backend, args| backend.update(args))
+    }
+
+    fn spec(&self, args: liboci_cli::Spec) -> Result<()> {
+        self.record(args, |backend, args| backend.spec(args))
+    }
+}
@@ -0,0 +1,505 @@
+// ****************************************************************************
+//  router.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Select the backend for each container
+//
+//     The router backend contains named backends, and rules matching the
+//     annotations in the config.json of a bundle. At `create` or `run`, the
+//     first matching rule selects the backend, and the choice is recorded
+//     in the state directory, so that later commands for the same container
+//     go to the same backend. `list` merges the containers of all backends,
+//     and other commands for no specific container go to the default backend.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use liboci_cli::GlobalOpts;
+
+use super::output::capture;
+use super::{args, Backend, StateRoot};
+
+// Subcommands that do not look at containers
+const GLOBAL_SUBCOMMANDS: &[&str] = &["features", "spec"];
+
+// Annotation naming the backend explicitly
+const BACKEND_ANNOTATION: &str = "io.ociplex.backend";
+
+// Annotations where container engines record the image name
+const IMAGE_ANNOTATIONS: &[&str] = &[
+    "io.kubernetes.cri.image-name",
+    "io.kubernetes.cri-o.ImageName",
+    "org.opencontainers.image.ref.name",
+    "org.opencontainers.image.base.name",
+];
+
+// Annotation where CRI-O records the labels of a container, as JSON
+const LABELS_ANNOTATION: &str = "io.kubernetes.cri-o.Labels";
+
+#[derive(Debug, serde::Deserialize)]
+struct Rule
+// ----------------------------------------------------------------------------
+//   Select a backend if all the given patterns match
+// ----------------------------------------------------------------------------
+//   Patterns can start or end with `*` to match any prefix or suffix.
+{
+    backend: String,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+    image: Option<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Config
+// ----------------------------------------------------------------------------
+//   Configuration of the router backend
+// ----------------------------------------------------------------------------
+{
+    // The backends to choose from, by name
+    backends: HashMap<String, super::Config>,
+
+    // The backend when no rule matches, and for commands without container
+    default: Option<String>,
+
+    // Where choices are recorded, in a `routes` subdirectory
+    state_dir: Option<PathBuf>,
+
+    // Rules, checked in order
+    #[serde(default)]
+    rules: Vec<Rule>,
+
+    // Backends for specific subcommands, whatever the container
+    #[serde(default)]
+    commands: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn replaces_process(&self) -> bool {
+        self.backends.values().any(super::Config::replaces_process)
+    }
+
+    fn check_commands(&self) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Check that backends handling the same containers share their state
+    // ------------------------------------------------------------------------
+    //   When subcommands for a container go to another backend than the one
+    //   that created it, both must find the container in the same state root.
+    //   Only CLI backends can share state, using the same explicit `--root`,
+    //   since runtimes have different default roots.
+    {
+        let mut shared: Vec<(&str, &String)> = Vec::new();
+        for (subcommand, name) in &self.commands {
+            if !args::SUBCOMMANDS.contains(&subcommand.as_str()) {
+                return Err(anyhow!("Unknown subcommand {:?} in router config", subcommand));
+            }
+            if !GLOBAL_SUBCOMMANDS.contains(&subcommand.as_str()) {
+                shared.push((subcommand.as_str(), name));
+            }
+        }
+        let creators: Vec<&String> = self
+            .rules
+            .iter()
+            .map(|rule| &rule.backend)
+            .chain(self.default.as_ref())
+            .chain(self.commands.get("create"))
+            .chain(self.commands.get("run"))
+            .collect();
+        for (subcommand, name) in shared {
+            let root = self.backends[name].state_root();
+            let explicit = matches!(root, Some(StateRoot::Path(_)));
+            for creator in &creators {
+                let other = self.backends[*creator].state_root();
+                if *creator != name && (!explicit || other != root) {
+                    return Err(anyhow!(
+                        "Backend {:?} for {} does not share the state root of backend {:?}",
+                        name,
+                        subcommand,
+                        creator
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn instantiate(self, global: GlobalOpts) -> Result<Box<dyn Backend>> {
+        let names = self
+            .rules
+            .iter()
+            .map(|rule| &rule.backend)
+            .chain(self.default.as_ref())
+            .chain(self.commands.values());
+        for name in names {
+            if !self.backends.contains_key(name) {
+                return Err(anyhow!("Unknown backend {:?} in router config", name));
+            }
+        }
+        self.check_commands()?;
+        // Routes are forgotten after `delete`, which exec never returns to
+        if self.replaces_process() {
+            return Err(anyhow!("Routed backends cannot use exec"));
+        }
+        let state_dir = self.state_dir.unwrap_or_else(super::default_state_dir);
+        Ok(Box::new(RouterBackend {
+            configs: RefCell::new(self.backends),
+            backends: RefCell::new(HashMap::new()),
+            default: self.default,
+            commands: self.commands,
+            routes: state_dir.join("routes"),
+            rules: self.rules,
+            global,
+        }))
+    }
+}
+
+impl Rule {
+    fn matches(&self, annotations: &BTreeMap<String, String>) -> bool {
+        let annotation = |key: &str, pattern: &str| {
+            annotations
+                .get(key)
+                .is_some_and(|value| args::matches(pattern, value))
+        };
+        let image = |pattern: &str| {
+            IMAGE_ANNOTATIONS
+                .iter()
+                .any(|key| annotation(key, pattern))
+        };
+        let labels: BTreeMap<String, String> = annotations
+            .get(LABELS_ANNOTATION)
+            .and_then(|labels| serde_json::from_str(labels).ok())
+            .unwrap_or_default();
+        let label = |key: &String, pattern: &String| {
+            labels
+                .get(key)
+                .is_some_and(|value| args::matches(pattern, value))
+        };
+
+        self.annotations
+            .iter()
+            .all(|(key, pattern)| annotation(key, pattern))
+            && self.image.as_deref().is_none_or(image)
+            && self.labels.iter().all(|(key, pattern)| label(key, pattern))
+    }
+}
+
+fn annotations(bundle: &Path) -> Result<BTreeMap<String, String>>
+// ----------------------------------------------------------------------------
+//   Read the annotations in the configuration of a bundle
+// ----------------------------------------------------------------------------
+{
+    let config = bundle.join("config.json");
+    let text =
+        fs::read_to_string(&config).with_context(|| format!("Reading {}", config.display()))?;
+    let doc: Value =
+        serde_json::from_str(&text).with_context(|| format!("Parsing {}", config.display()))?;
+    Ok(doc
+        .get("annotations")
+        .and_then(|annotations| serde_json::from_value(annotations.clone()).ok())
+        .unwrap_or_default())
+}
+
+#[derive(Debug)]
+struct RouterBackend
+// ----------------------------------------------------------------------------
+//   A backend dispatching each container to one of several backends
+// ----------------------------------------------------------------------------
+//   Backends are only instantiated when used.
+{
+    configs: RefCell<HashMap<String, super::Config>>,
+    backends: RefCell<HashMap<String, Box<dyn Backend>>>,
+    default: Option<String>,
+    commands: HashMap<String, String>,
+    routes: PathBuf,
+    rules: Vec<Rule>,
+    global: GlobalOpts,
+}
+
+impl RouterBackend {
+    fn with<T>(&self, name: &str, action: impl FnOnce(&dyn Backend) -> Result<T>) -> Result<T>
+    // ------------------------------------------------------------------------
+    //   Run an action with a backend, instantiating it if necessary
+    // ------------------------------------------------------------------------
+    {
+        if !self.backends.borrow().contains_key(name) {
+            let config = self
+                .configs
+                .borrow_mut()
+                .remove(name)
+                .ok_or_else(|| anyhow!("Unknown backend {:?}", name))?;
+            debug!("Instantiating backend {} ({})", name, config.backend_type());
+            let backend = config.instantiate(super::clone_global(&self.global))?;
+            self.backends.borrow_mut().insert(name.to_string(), backend);
+        }
+        let backends = self.backends.borrow();
+        action(backends[name].as_ref())
+    }
+
+    fn default(&self) -> Result<&str> {
+        self.default
+            .as_deref()
+            .ok_or_else(|| anyhow!("No default backend in router config"))
+    }
+
+    fn route_file(&self, id: &str) -> Result<PathBuf> {
+        if id.is_empty() || id.contains('/') || id == "." || id == ".." {
+            return Err(anyhow!("Invalid container ID {:?}", id));
+        }
+        Ok(self.routes.join(id))
+    }
+
+    fn global_route(&self, subcommand: &str) -> Result<&str>
+    // ------------------------------------------------------------------------
+    //   The backend for a subcommand that is not about a specific container
+    // ------------------------------------------------------------------------
+    {
+        match self.commands.get(subcommand) {
+            Some(name) => Ok(name),
+            None => self.default(),
+        }
+    }
+
+    fn choose(&self, subcommand: &str, bundle: &Path, id: &str) -> Result<String>
+    // ------------------------------------------------------------------------
+    //   Choose the backend for a new container, and record the choice
+    // ------------------------------------------------------------------------
+    {
+        let annotations = annotations(bundle)?;
+        let name = match annotations.get(BACKEND_ANNOTATION) {
+            _ if self.commands.contains_key(subcommand) => {
+                // A backend for all create or run takes precedence
+                self.commands[subcommand].clone()
+            }
+            Some(name) if self.configs.borrow().contains_key(name) => name.clone(),
+            Some(name) => return Err(anyhow!("Unknown backend {:?} in annotation", name)),
+            None => match self.rules.iter().find(|rule| rule.matches(&annotations)) {
+                Some(rule) => rule.backend.clone(),
+                None => self.default()?.to_string(),
+            },
+        };
+        debug!("Routing container {} to backend {}", id, name);
+
+        let route = self.route_file(id)?;
+        fs::create_dir_all(&self.routes)
+            .with_context(|| format!("Creating {}", self.routes.display()))?;
+        fs::write(&route, format!("{}\n", name))
+            .with_context(|| format!("Recording backend in {}", route.display()))?;
+        Ok(name)
+    }
+
+    fn route(&self, subcommand: &str, id: &str) -> Result<String>
+    // ------------------------------------------------------------------------
+    //   The backend for a subcommand, or the one recorded for a container
+    // ------------------------------------------------------------------------
+    //   A container without a record was not created here, and guessing its
+    //   backend could send the command to the wrong runtime.
+    {
+        if let Some(name) = self.commands.get(subcommand) {
+            return Ok(name.clone());
+        }
+        let name = fs::read_to_string(self.route_file(id)?)
+            .with_context(|| format!("No backend recorded for container {}", id))?;
+        Ok(name.trim().to_string())
+    }
+
+    fn list_all(&self, args: liboci_cli::List) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   List the containers of all backends, in the format asked for
+    // ------------------------------------------------------------------------
+    //   Backends are asked for JSON, and the first one listing a container
+    //   wins. A backend that cannot list is skipped, unless all fail.
+    {
+        let mut names: Vec<String> = self.configs.borrow().keys().cloned().collect();
+        names.extend(self.backends.borrow().keys().cloned());
+        names.sort();
+        names.sort_by_key(|name| Some(name) != self.default.as_ref());
+
+        let mut states: Vec<Value> = Vec::new();
+        let mut failure = None;
+        for name in &names {
+            let list = liboci_cli::List {
+                format: "json".into(),
+                quiet: false,
+            };
+            let (result, output) = capture(|| self.with(name, |backend| backend.list(list)))?;
+            let listed = result.and_then(|()| {
+                let value: Value = serde_json::from_slice(&output)
+                    .with_context(|| format!("Invalid list output from backend {}", name))?;
+                Ok(value.as_array().cloned().unwrap_or_default())
+            });
+            match listed {
+                Ok(listed) => {
+                    for state in listed {
+                        if !states.iter().any(|other| other["id"] == state["id"]) {
+                            states.push(state);
+                        }
+                    }
+                }
+                Err(err) => {
+                    warn!("Cannot list containers of backend {}: {:#}", name, err);
+                    failure = Some(err);
+                }
+            }
+        }
+        if let Some(err) = failure.filter(|_| states.is_empty()) {
+            return Err(err);
+        }
+
+        let mut stdout = io::stdout();
+        if args.quiet {
+            for state in &states {
+                writeln!(stdout, "{}", state["id"].as_str().unwrap_or_default())?;
+            }
+            return Ok(());
+        }
+        match args.format.as_str() {
+            "json" => writeln!(stdout, "{}", serde_json::to_string(&states)?)?,
+            "table" => {
+                writeln!(stdout, "ID\tPID\tSTATUS\tBUNDLE\tCREATED\tOWNER")?;
+                for state in &states {
+                    let field = |key: &str| match &state[key] {
+                        Value::String(text) => text.clone(),
+                        other => other.to_string(),
+                    };
+                    writeln!(
+                        stdout,
+                        "{}\t{}\t{}\t{}\t{}\t{}",
+                        field("id"),
+                        field("pid"),
+                        field("status"),
+                        field("bundle"),
+                        field("created"),
+                        field("owner")
+                    )?;
+                }
+            }
+            format => return Err(anyhow!("Invalid list format {:?}", format)),
+        }
+        Ok(())
+    }
+
+    fn forget(&self, id: &str) {
+        if let Ok(route) = self.route_file(id) {
+            let _ = fs::remove_file(route);
+        }
+    }
+}
+
+impl Backend for RouterBackend {
+    // Standard commands (from liboci_cli::StandardCmd)
+    fn create(&self, args: liboci_cli::Create) -> Result<()> {
+        let name = self.choose("create", &args.bundle, &args.container_id)?;
+        self.with(&name, |backend| backend.create(args))
+    }
+
+    fn start(&self, args: liboci_cli::Start) -> Result<()> {
+        let name = self.route("start", &args.container_id)?;
+        self.with(&name, |backend| backend.start(args))
+    }
+
+    fn kill(&self, args: liboci_cli::Kill) -> Result<()> {
+        let name = self.route("kill", &args.container_id)?;
+        self.with(&name, |backend| backend.kill(args))
+    }
+
+    fn delete(&self, args: liboci_cli::Delete) -> Result<()> {
+        let id = args.container_id.clone();
+        let name = self.route("delete", &id)?;
+        self.with(&name, |backend| backend.delete(args))?;
+        self.forget(&id);
+        Ok(())
+    }
+
+    fn state(&self, args: liboci_cli::State) -> Result<()> {
+        let name = self.route("state", &args.container_id)?;
+        self.with(&name, |backend| backend.state(args))
+    }
+
+    // Common non-standard commands (from liboci_cli::CommonCmd)
+    fn checkpoint(&self, args: liboci_cli::Checkpoint) -> Result<()> {
+        let name = self.route("checkpoint", &args.container_id)?;
+        self.with(&name, |backend| backend.checkpoint(args))
+    }
+
+    fn events(&self, args: liboci_cli::Events) -> Result<()> {
+        let name = self.route("events", &args.container_id)?;
+        self.with(&name, |backend| backend.events(args))
+    }
+
+    fn exec(&self, args: liboci_cli::Exec) -> Result<()> {
+        let name = self.route("exec", &args.container_id)?;
+        self.with(&name, |backend| backend.exec(args))
+    }
+
+    fn features(&self, args: liboci_cli::Features) -> Result<()> {
+        self.with(self.global_route("features")?, |backend| backend.features(args))
+    }
+
+    fn list(&self, args: liboci_cli::List) -> Result<()> {
+        match self.commands.get("list") {
+            Some(name) => self.with(name, |backend| backend.list(args)),
+            None => self.list_all(args),
+        }
+    }
+
+    fn pause(&self, args: liboci_cli::Pause) -> Result<()> {
+        let name = self.route("pause", &args.container_id)?;
+        self.with(&name, |backend| backend.pause(args))
+    }
+
+    fn ps(&self, args: liboci_cli::Ps) -> Result<()> {
+        let name = self.route("ps", &args.container_id)?;
+        self.with(&name, |backend| backend.ps(args))
+    }
+
+    fn resume(&self, args: liboci_cli::Resume) -> Result<()> {
+        let name = self.route("resume", &args.container_id)?;
+        self.with(&name, |backend| backend.resume(args))
+    }
+
+    fn run(&self, args: liboci_cli::Run) -> Result<()> {
+        let id = args.container_id.clone();
+        let detach = args.detach;
+        let name = self.choose("run", &args.bundle, &id)?;
+
+        // Without --detach, the container is deleted when run returns
+        let result = self.with(&name, |backend| backend.run(args));
+        if !detach {
+            self.forget(&id);
+        }
+        result
+    }
+
+    fn update(&self, args: liboci_cli::Update) -> Result<()> {
+        let name = self.route("update", &args.container_id)?;
+        self.with(&name, |backend| backend.update(args))
+    }
+
+    fn spec(&self, args: liboci_cli::Spec) -> Result<()> {
+        self.with(self.global_route("spec")?, |backend| backend.spec(args))
+    }
+}
@@ -1,30 +1,34 @@
 use containerd_shim_protos as shim;
 
 use protobuf::{
+    text_format,
     well_known_types::{
         any::Any,
         struct_::{value::Kind, Struct, Value},
     },
-    MessageField,
+    MessageDyn, MessageField,
 };
 use shim::ttrpc::context::Context;
 use shim::{api, api::ConnectResponse, Client, TaskClient};
 
+use std::cell::RefCell;
 use std::env;
 use std::ffi::OsString;
+use std::fmt::{self, Debug};
 use std::fs::File;
-use std::io::prelude::*;
+use std::io::{self, prelude::*};
 use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use anyhow::{anyhow, Result};
+use serde_json::json;
+use tracing::{debug, warn};
 
 use liboci_cli::GlobalOpts;
 
-use super::Backend;
-
-const TTRPC_ADDRESS: &str = "TTPRC_ADDRESS";
+use super::{Backend, Error};
+use crate::reaper;
 
 #[derive(Debug, serde::Deserialize)]
 pub struct Config {
@@ -33,18 +37,22 @@ pub struct Config {
     events: PathBuf,
     bundle_dir: PathBuf,
     debug_shim: bool,
+
+    // Print the shim command and the requests instead of sending them
+    #[serde(default)]
+    dry_run: bool,
 }
 
 impl Config {
-    pub fn instantiate(self, opts: GlobalOpts) -> Box<dyn Backend> {
-        Box::new(ShimV2Backend::new(
+    pub fn instantiate(self, _global: GlobalOpts) -> Result<Box<dyn Backend>> {
+        Ok(Box::new(ShimV2Backend::new(
             self.shim,
             self.socket,
             self.events,
             self.bundle_dir,
             self.debug_shim,
-            opts,
-        ))
+            super::dry_run(self.dry_run),
+        )))
     }
 }
 
@@ -55,8 +63,18 @@ struct ShimV2Backend {
     events: PathBuf,
     bundle_dir: PathBuf,
     debug_shim: bool,
-    global_opts: GlobalOpts,
-    container_id: String,
+    dry_run: bool,
+    connection: Connection,
+}
+
+#[derive(Default)]
+struct Connection(RefCell<Option<TaskClient>>);
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let connected = self.0.borrow().is_some();
+        f.debug_struct("Connection").field("connected", &connected).finish()
+    }
 }
 
 fn path_buf_to_str<'a>(kind: &str, path: &'a Path) -> Result<&'a str> {
@@ -98,6 +116,16 @@ fn u32_value(value: u32) -> Value {
     }
 }
 
+fn rpc(err: shim::ttrpc::Error) -> Error
+// ----------------------------------------------------------------------------
+//   Classify the failure of a call to the shim
+// ----------------------------------------------------------------------------
+{
+    Error::Rpc {
+        message: err.to_string(),
+    }
+}
+
 fn add_option(opts: &mut Struct, kind: &str, value: Value) {
     opts.fields.insert(kind.to_string(), value);
 }
@@ -127,7 +155,7 @@ impl ShimV2Backend {
         events: PathBuf,
         bundle_dir: PathBuf,
         debug_shim: bool,
-        global_opts: GlobalOpts,
+        dry_run: bool,
     ) -> Self {
         ShimV2Backend {
             shim,
@@ -135,30 +163,26 @@ impl ShimV2Backend {
             events,
             bundle_dir,
             debug_shim,
-            global_opts,
-            container_id: "".to_string(),
+            dry_run,
+            connection: Connection::default(),
         }
     }
 
-    fn launch(&self, socket_path: &str, pid: &str) -> Result<Client> {
-        // Need to switch to the correct directory for the shim
+    fn launch_command(&self, pid: &str) -> Result<(String, Vec<OsString>)>
+    // ------------------------------------------------------------------------
+    //   The directory to run the shim in, and the arguments to launch it
+    // ------------------------------------------------------------------------
+    {
         let bundle_dir = self.bundle_dir.as_os_str();
         let bundle_str = bundle_dir.to_str().ok_or(anyhow!(
             "The bundle_dir option {:?} contains invalid characters",
             bundle_dir
         ))?;
-        let offset = bundle_str
-            .find("{container-id}")
-            .ok_or(anyhow!("The bundle_dir option is missing container-id"))?;
-        let bundle_dir = bundle_str.replace("{container-id}", pid);
-        if self.debug_shim {
-            println!("bundle dir after replacement is {:?}", bundle_dir);
+        if !bundle_str.contains("{container-id}") {
+            return Err(anyhow!("The bundle_dir option is missing container-id"));
         }
-        env::set_current_dir(bundle_dir)?;
-
-        // Need to create a `log` file to log output of target task
-        let mut file = File::create("log")?;
-        file.write_all(b"")?;
+        let bundle_dir = bundle_str.replace("{container-id}", pid);
+        debug!("bundle dir after replacement is {:?}", bundle_dir);
 
         let mut cmdargs = Vec::<OsString>::new();
 
@@ -174,148 +198,216 @@ impl ShimV2Backend {
         cmdargs.push("-publish-binary".into());
         cmdargs.push(self.events.clone().into());
         cmdargs.push("start".into());
+        Ok((bundle_dir, cmdargs))
+    }
+
+    fn launch(&self, socket_path: &str, pid: &str) -> Result<Client> {
+        // Need to switch to the correct directory for the shim
+        let (bundle_dir, cmdargs) = self.launch_command(pid)?;
+        env::set_current_dir(bundle_dir)?;
+
+        // Need to create a `log` file to log output of target task
+        let mut file = File::create("log")?;
+        file.write_all(b"")?;
 
-        let status = Command::new(&self.shim).args(cmdargs).status()?;
+        let status = Command::new(&self.shim)
+            .args(cmdargs)
+            .spawn()
+            .and_then(|mut child| reaper::wait(&mut child))
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::NotFound => Error::NotFound {
+                    path: self.shim.display().to_string(),
+                }
+                .into(),
+                _ => anyhow::Error::from(err),
+            })?;
 
         if status.success() {
-            return shim::Client::connect(socket_path).map_err(anyhow::Error::from);
+            return shim::Client::connect(socket_path).map_err(|err| rpc(err).into());
         }
 
         let path = &self.shim;
-        Err(if let Some(sig) = status.signal() {
-            anyhow!("ShimV2 backend {:?} terminated with signal {:?}", path, sig)
+        let message = if let Some(sig) = status.signal() {
+            format!("ShimV2 backend {:?} terminated with signal {:?}", path, sig)
         } else if let Some(code) = status.code() {
-            anyhow!("ShimV2 backend {:?} failed with status code {}", path, code)
+            format!("ShimV2 backend {:?} failed with status code {}", path, code)
         } else {
-            anyhow!("Unidentified failure in ShimV2 backend")
-        })
+            "Unidentified failure in ShimV2 backend".to_string()
+        };
+        Err(Error::Rpc { message }.into())
     }
 
-    fn invoke(&self, pid: &str) -> Result<(TaskClient, Context, ConnectResponse)> {
-        let socket_path = path_buf_to_str("socket", &self.socket)?;
-        let client =
-            shim::Client::connect(socket_path).or_else(|_| self.launch(socket_path, pid))?;
-        let task_client = shim::TaskClient::new(client);
+    fn invoke(&self, pid: &str) -> Result<(TaskClient, Context, ConnectResponse)>
+    // ------------------------------------------------------------------------
+    //   Connect to the shim for a container, launching it if needed
+    // ------------------------------------------------------------------------
+    //   The connection is kept for later commands to the same backend, which
+    //   only matters when the backend lives in the ociplex daemon.
+    {
+        let pooled = self.connection.0.borrow().clone();
+        let task_client = match pooled {
+            Some(task_client) => task_client,
+            None => {
+                let socket_path = path_buf_to_str("socket", &self.socket)?;
+                let client = shim::Client::connect(socket_path)
+                    .or_else(|_| self.launch(socket_path, pid))?;
+                let task_client = shim::TaskClient::new(client);
+                *self.connection.0.borrow_mut() = Some(task_client.clone());
+                task_client
+            }
+        };
         let context = Context::default();
         let req = api::ConnectRequest {
             id: pid.to_string(),
             ..Default::default()
         };
-        let resp = task_client.connect(context.clone(), &req)?;
+        let resp = task_client
+            .connect(context.clone(), &req)
+            .map_err(|err| self.disconnect(err))?;
         Ok((task_client, context, resp))
     }
+
+    fn disconnect(&self, err: shim::ttrpc::Error) -> Error
+    // ------------------------------------------------------------------------
+    //   Drop the connection after a failed call, in case the shim went away
+    // ------------------------------------------------------------------------
+    {
+        self.connection.0.borrow_mut().take();
+        rpc(err)
+    }
+
+    fn show(&self, pid: &str, name: &str, req: &dyn MessageDyn) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Print the shim command and the requests for a command, for a dry run
+    // ------------------------------------------------------------------------
+    //   The shim is only launched if it does not already listen on the
+    //   socket, which a dry run does not check.
+    {
+        let (bundle_dir, cmdargs) = self.launch_command(pid)?;
+        let command: Vec<_> = std::iter::once(self.shim.as_os_str())
+            .chain(cmdargs.iter().map(OsString::as_os_str))
+            .map(|arg| arg.to_string_lossy())
+            .collect();
+        let connect = api::ConnectRequest {
+            id: pid.to_string(),
+            ..Default::default()
+        };
+        let mut stdout = io::stdout();
+        writeln!(stdout, "{}", json!({ "command": command, "cwd": bundle_dir }))?;
+        for (name, req) in [("Connect", &connect as &dyn MessageDyn), (name, req)] {
+            let message = text_format::print_to_string(req);
+            writeln!(stdout, "{}", json!({ "request": name, "message": message }))?;
+        }
+        Ok(())
+    }
+
+    fn call<Req, Resp>(
+        &self,
+        pid: &str,
+        name: &str,
+        req: Req,
+        send: impl FnOnce(&TaskClient, Context, &Req) -> shim::ttrpc::Result<Resp>,
+    ) -> Result<()>
+    where
+        Req: MessageDyn,
+        Resp: Debug,
+    // ------------------------------------------------------------------------
+    //   Send a request to the shim for a container, or print it for a dry run
+    // ------------------------------------------------------------------------
+    {
+        if self.dry_run {
+            return self.show(pid, name, &req);
+        }
+        let (task, context, connect_response) = self.invoke(pid)?;
+        let resp = send(&task, context, &req).map_err(|err| self.disconnect(err))?;
+        debug!("{} connect response {:?}", name, connect_response);
+        debug!("{} response {:?}", name, resp);
+        Ok(())
+    }
 }
 
 impl Backend for ShimV2Backend {
     // Standard commands (from liboci_cli::StandardCmd)
     fn create(&self, args: liboci_cli::Create) -> Result<()> {
-        if self.debug_shim {
-            println!("Bundle argument is {:?}", args.bundle);
-        }
-        let (task, context, connect_response) = self.invoke(&args.container_id)?;
+        debug!("Bundle argument is {:?}", args.bundle);
         let bundle = path_buf_to_str("bundle", &args.bundle)?;
 
         if let Some(socket) = args.console_socket {
-            println!(
+            warn!(
                 "Console socket {} option not implemented, ignored",
                 socket.display()
             );
         }
         if let Some(pid_file) = args.pid_file {
-            println!(
+            warn!(
                 "pid_file option {} not implemented, ignored",
                 pid_file.display()
             );
         }
         if args.no_pivot {
-            eprintln!("no-pivot option not implemented, ignored");
+            warn!("no-pivot option not implemented, ignored");
         }
         if args.no_new_keyring {
-            eprintln!("no-new-keyring option not implemented, ignored");
+            warn!("no-new-keyring option not implemented, ignored");
         }
         if args.preserve_fds > 0 {
-            eprintln!("preserve-fds option not implemented, ignored");
+            warn!("preserve-fds option not implemented, ignored");
         }
         let req = api::CreateTaskRequest {
-            id: args.container_id,
+            id: args.container_id.clone(),
             bundle: bundle.to_owned(),
             ..Default::default()
         };
-        let resp = task.create(context, &req)?;
-        if self.global_opts.debug {
-            println!("Create connect response {:?}", connect_response);
-            println!("Create response {:?}", resp);
-        }
-        Ok(())
+        self.call(&args.container_id, "Create", req, |task, context, req| {
+            task.create(context, req)
+        })
     }
 
     fn start(&self, args: liboci_cli::Start) -> Result<()> {
-        let (task, context, connect_response) = self.invoke(&args.container_id)?;
         let req = api::StartRequest {
-            id: args.container_id,
+            id: args.container_id.clone(),
             ..Default::default()
         };
-        let resp = task.start(context, &req)?;
-        if self.global_opts.debug {
-            println!("Start connect response {:?}", connect_response);
-            println!("Start response {:?}", resp);
-        }
-
-        Ok(())
+        self.call(&args.container_id, "Start", req, |task, context, req| {
+            task.start(context, req)
+        })
     }
 
     fn kill(&self, args: liboci_cli::Kill) -> Result<()> {
-        let (task, context, connect_response) = self.invoke(&args.container_id)?;
         let signal = args.signal.parse::<u32>()?;
         let req = api::KillRequest {
-            id: args.container_id,
-            signal: signal,
+            id: args.container_id.clone(),
+            signal,
             all: args.all,
             ..Default::default()
         };
-        let resp = task.kill(context, &req)?;
-        if self.global_opts.debug {
-            println!(
-                "Kill connect response {:?} task response {:?}",
-                connect_response, resp
-            );
-        }
-        Ok(())
+        self.call(&args.container_id, "Kill", req, |task, context, req| {
+            task.kill(context, req)
+        })
     }
 
     fn delete(&self, args: liboci_cli::Delete) -> Result<()> {
-        let (task, context, connect_response) = self.invoke(&args.container_id)?;
         let req = api::DeleteRequest {
-            id: args.container_id,
+            id: args.container_id.clone(),
             ..Default::default()
         };
-        let resp = task.delete(context, &req)?;
-        if self.global_opts.debug {
-            println!("Delete connect response {:?}", connect_response);
-            println!("Delete response {:?}", resp);
-        }
-
-        Ok(())
+        self.call(&args.container_id, "Delete", req, |task, context, req| {
+            task.delete(context, req)
+        })
     }
 
     fn state(&self, args: liboci_cli::State) -> Result<()> {
-        let (task, context, connect_response) = self.invoke(&args.container_id)?;
         let req = api::StateRequest {
-            id: args.container_id,
+            id: args.container_id.clone(),
             ..Default::default()
         };
-        let resp = task.state(context, &req)?;
-        if self.global_opts.debug {
-            println!("State connect response {:?}", connect_response);
-            println!("State response {:?}", resp);
-        }
-
-        Ok(())
+        self.call(&args.container_id, "State", req, |task, context, req| {
+            task.state(context, req)
+        })
     }
 
     // Common non-standard commands (from liboci_cli::CommonCmd)
     fn checkpoint(&self, args: liboci_cli::Checkpoint) -> Result<()> {
-        let (task, context, connect_response) = self.invoke(&args.container_id)?;
         let image_path = path_buf_to_str("image_path", &args.image_path)?;
         let mut opts = Struct::new();
 
@@ -364,58 +456,13 @@ impl Backend for ShimV2Backend {
 
         let options = Any::pack(&opts)?;
         let req = api::CheckpointTaskRequest {
-            id: args.container_id,
+            id: args.container_id.clone(),
             path: image_path.to_owned(),
             options: MessageField::some(options),
             ..Default::default()
         };
-
-        let resp = task.checkpoint(context, &req)?;
-        if self.global_opts.debug {
-            println!("Checkpoint connect response {:?}", connect_response);
-            println!("Checkpoint response {:?}", resp);
-        }
-
-        Ok(())
-    }
-
-    fn events(&self, args: liboci_cli::Events) -> Result<()> {
-        Ok(())
-    }
-
-    fn exec(&self, args: liboci_cli::Exec) -> Result<()> {
-        Ok(())
-    }
-
-    fn features(&self, _args: liboci_cli::Features) -> Result<()> {
-        Ok(())
-    }
-
-    fn list(&self, args: liboci_cli::List) -> Result<()> {
-        Ok(())
-    }
-
-    fn pause(&self, args: liboci_cli::Pause) -> Result<()> {
-        Ok(())
-    }
-
-    fn ps(&self, args: liboci_cli::Ps) -> Result<()> {
-        Ok(())
-    }
-
-    fn resume(&self, args: liboci_cli::Resume) -> Result<()> {
-        Ok(())
-    }
-
-    fn run(&self, args: liboci_cli::Run) -> Result<()> {
-        Ok(())
-    }
-
-    fn update(&self, args: liboci_cli::Update) -> Result<()> {
-        Ok(())
-    }
-
-    fn spec(&self, args: liboci_cli::Spec) -> Result<()> {
-        Ok(())
+        self.call(&args.container_id, "Checkpoint", req, |task, context, req| {
+            task.checkpoint(context, req)
+        })
     }
 }
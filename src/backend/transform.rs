@@ -0,0 +1,457 @@
+// ****************************************************************************
+//  transform.rs                                                ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Rewrite the bundle configuration before `create` and `run`
+//
+//     The transform backend applies a pipeline of JSON-patch operations
+//     (RFC 6902) to config.json, each one optionally conditioned on values
+//     in the configuration, then passes a shadow bundle to another backend.
+//     The shadow bundle contains the rewritten config.json and links to
+//     everything else in the original bundle, which is never modified. It
+//     is only created if some operation applies, and removed on `delete`.
+//     `state` reports the original bundle rather than the shadow one.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use tracing::debug;
+
+use liboci_cli::GlobalOpts;
+
+use super::{capture, Backend};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation
+// ----------------------------------------------------------------------------
+//   A JSON-patch operation, with paths given as JSON pointers
+// ----------------------------------------------------------------------------
+{
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Step
+// ----------------------------------------------------------------------------
+//   An operation, applied only if the configuration has the given values
+// ----------------------------------------------------------------------------
+{
+    #[serde(flatten)]
+    operation: Operation,
+    #[serde(default)]
+    when: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Config
+// ----------------------------------------------------------------------------
+//   Configuration of the transform backend
+// ----------------------------------------------------------------------------
+{
+    // The backend receiving the rewritten bundle
+    backend: Box<super::Config>,
+
+    // Where shadow bundles are created, in a `bundles` subdirectory, with
+    // the original bundle paths in `origins`
+    state_dir: Option<PathBuf>,
+
+    // Operations on config.json, applied in order
+    #[serde(default)]
+    transform: Vec<Step>,
+}
+
+impl Config {
+    pub fn state_root(&self) -> Option<super::StateRoot> {
+        self.backend.state_root()
+    }
+
+    pub fn replaces_process(&self) -> bool {
+        self.backend.replaces_process()
+    }
+
+    pub fn instantiate(self, global: GlobalOpts) -> Result<Box<dyn Backend>> {
+        for step in &self.transform {
+            for path in step.when.keys().chain(step.operation.paths()) {
+                if !path.is_empty() && !path.starts_with('/') {
+                    return Err(anyhow!("Invalid JSON pointer {:?} in transform", path));
+                }
+            }
+        }
+        // Shadow bundles are removed after the command, which exec never returns to
+        if self.replaces_process() {
+            return Err(anyhow!("Transformed backends cannot use exec"));
+        }
+        let state_dir = self.state_dir.unwrap_or_else(super::default_state_dir);
+        Ok(Box::new(TransformBackend {
+            backend: self.backend.instantiate(global)?,
+            bundles: state_dir.join("bundles"),
+            origins: state_dir.join("origins"),
+            steps: self.transform,
+        }))
+    }
+}
+
+fn tokens(pointer: &str) -> Vec<String>
+// ----------------------------------------------------------------------------
+//   Split a JSON pointer into unescaped reference tokens
+// ----------------------------------------------------------------------------
+{
+    pointer
+        .split('/')
+        .skip(1)
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect()
+}
+
+fn parent<'a>(doc: &'a mut Value, pointer: &str) -> Result<(&'a mut Value, String)>
+// ----------------------------------------------------------------------------
+//   Find the parent of the target of a pointer, creating missing objects
+// ----------------------------------------------------------------------------
+//   Unlike RFC 6902, missing parents are created, so that adding a hook
+//   does not require first checking if there is a `hooks` object. They are
+//   arrays if followed by an index or `-`, objects otherwise.
+{
+    let mut tokens = tokens(pointer);
+    let last = tokens
+        .pop()
+        .ok_or_else(|| anyhow!("Cannot modify the whole document"))?;
+    let mut node = doc;
+    let next: Vec<String> = tokens.iter().skip(1).cloned().chain([last.clone()]).collect();
+    for (token, next) in tokens.into_iter().zip(next) {
+        node = match node {
+            Value::Object(map) => map.entry(token).or_insert_with(|| {
+                if next == "-" || next.parse::<usize>().is_ok() {
+                    Value::Array(Vec::new())
+                } else {
+                    Value::Object(Default::default())
+                }
+            }),
+            Value::Array(items) => {
+                let index: usize = token
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid array index {:?}", token))?;
+                items
+                    .get_mut(index)
+                    .ok_or_else(|| anyhow!("Array index {} out of range", index))?
+            }
+            _ => return Err(anyhow!("Cannot find {:?} in a scalar value", token)),
+        };
+    }
+    Ok((node, last))
+}
+
+fn add(doc: &mut Value, pointer: &str, value: Value) -> Result<()> {
+    let (node, last) = parent(doc, pointer)?;
+    match node {
+        Value::Object(map) => {
+            map.insert(last, value);
+        }
+        Value::Array(items) if last == "-" => items.push(value),
+        Value::Array(items) => {
+            let index: usize = last
+                .parse()
+                .ok()
+                .filter(|index| *index <= items.len())
+                .ok_or_else(|| anyhow!("Invalid array index {:?}", last))?;
+            items.insert(index, value);
+        }
+        _ => return Err(anyhow!("Cannot add {:?} to a scalar value", last)),
+    }
+    Ok(())
+}
+
+fn remove(doc: &mut Value, pointer: &str) -> Result<Value> {
+    let (node, last) = parent(doc, pointer)?;
+    let removed = match node {
+        Value::Object(map) => map.remove(&last),
+        Value::Array(items) => last
+            .parse::<usize>()
+            .ok()
+            .filter(|index| *index < items.len())
+            .map(|index| items.remove(index)),
+        _ => None,
+    };
+    removed.ok_or_else(|| anyhow!("Nothing to remove"))
+}
+
+impl Operation {
+    fn paths(&self) -> Vec<&String> {
+        match self {
+            Operation::Add { path, .. }
+            | Operation::Remove { path }
+            | Operation::Replace { path, .. }
+            | Operation::Test { path, .. } => vec![path],
+            Operation::Move { from, path } | Operation::Copy { from, path } => vec![from, path],
+        }
+    }
+
+    fn apply(&self, doc: &mut Value) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Apply a single operation to a document
+    // ------------------------------------------------------------------------
+    {
+        match self {
+            Operation::Add { path, value } => add(doc, path, value.clone()),
+            Operation::Remove { path } => remove(doc, path).map(|_| ()),
+            Operation::Replace { path, value } => {
+                let target = doc
+                    .pointer_mut(path)
+                    .ok_or_else(|| anyhow!("Nothing to replace"))?;
+                *target = value.clone();
+                Ok(())
+            }
+            Operation::Move { from, path } => {
+                let value = remove(doc, from)?;
+                add(doc, path, value)
+            }
+            Operation::Copy { from, path } => {
+                let value = doc
+                    .pointer(from)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("Nothing to copy from {}", from))?;
+                add(doc, path, value)
+            }
+            Operation::Test { path, value } => match doc.pointer(path) {
+                Some(actual) if actual == value => Ok(()),
+                actual => Err(anyhow!("Test failed, found {:?}", actual)),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TransformBackend
+// ----------------------------------------------------------------------------
+//   A backend rewriting bundles before passing them to another one
+// ----------------------------------------------------------------------------
+{
+    backend: Box<dyn Backend>,
+    bundles: PathBuf,
+    origins: PathBuf,
+    steps: Vec<Step>,
+}
+
+impl TransformBackend {
+    fn transform(&self, doc: &mut Value) -> Result<bool>
+    // ------------------------------------------------------------------------
+    //   Apply the pipeline to a configuration, return true if any step did
+    // ------------------------------------------------------------------------
+    {
+        let mut applied = false;
+        for (index, step) in self.steps.iter().enumerate() {
+            let matches = step
+                .when
+                .iter()
+                .all(|(pointer, expected)| doc.pointer(pointer) == Some(expected));
+            if !matches {
+                continue;
+            }
+            debug!("Applying transform {:?}", step.operation);
+            step.operation
+                .apply(doc)
+                .with_context(|| format!("Transform step {} ({:?})", index + 1, step.operation))?;
+            applied = true;
+        }
+        Ok(applied)
+    }
+
+    fn shadow(&self, bundle: &Path, id: &str) -> Result<Option<PathBuf>>
+    // ------------------------------------------------------------------------
+    //   Create a shadow bundle for a container, None if nothing changes
+    // ------------------------------------------------------------------------
+    {
+        if id.is_empty() || id.contains('/') || id == "." || id == ".." {
+            return Err(anyhow!("Invalid container ID {:?}", id));
+        }
+        let bundle = bundle
+            .canonicalize()
+            .with_context(|| format!("Finding bundle {}", bundle.display()))?;
+        let config = bundle.join("config.json");
+        let text = fs::read_to_string(&config)
+            .with_context(|| format!("Reading {}", config.display()))?;
+        let mut doc: Value = serde_json::from_str(&text)
+            .with_context(|| format!("Parsing {}", config.display()))?;
+        if !self.transform(&mut doc)? {
+            return Ok(None);
+        }
+
+        // The root filesystem stays where it is, even if given relative
+        if let Some(Value::String(root)) = doc.pointer_mut("/root/path") {
+            *root = bundle.join(&*root).to_string_lossy().into_owned();
+        }
+
+        let shadow = self.bundles.join(id);
+        let _ = fs::remove_dir_all(&shadow);
+        fs::create_dir_all(&shadow)
+            .with_context(|| format!("Creating shadow bundle {}", shadow.display()))?;
+        for entry in fs::read_dir(&bundle)? {
+            let entry = entry?;
+            if entry.file_name() != "config.json" {
+                symlink(entry.path(), shadow.join(entry.file_name()))?;
+            }
+        }
+        fs::write(shadow.join("config.json"), serde_json::to_string_pretty(&doc)?)?;
+        fs::create_dir_all(&self.origins)
+            .with_context(|| format!("Creating {}", self.origins.display()))?;
+        fs::write(self.origin_file(id), bundle.as_os_str().as_encoded_bytes())?;
+        debug!("Created shadow bundle {} for {}", shadow.display(), bundle.display());
+        Ok(Some(shadow))
+    }
+
+    fn origin_file(&self, id: &str) -> PathBuf {
+        self.origins.join(id)
+    }
+
+    fn remove_shadow(&self, id: &str) {
+        let shadow = self.bundles.join(id);
+        if shadow.exists() {
+            debug!("Removing shadow bundle {}", shadow.display());
+            let _ = fs::remove_dir_all(&shadow);
+        }
+        let _ = fs::remove_file(self.origin_file(id));
+    }
+
+    fn original_bundle(&self, id: &str, output: Vec<u8>) -> Vec<u8>
+    // ------------------------------------------------------------------------
+    //   Replace the shadow bundle with the original one in a state output
+    // ------------------------------------------------------------------------
+    {
+        let Ok(origin) = fs::read_to_string(self.origin_file(id)) else {
+            return output;
+        };
+        let Ok(mut state) = serde_json::from_slice::<Value>(&output) else {
+            return output;
+        };
+        let shadow = self.bundles.join(id);
+        if state["bundle"].as_str().map(Path::new) != Some(&shadow) {
+            return output;
+        }
+        state["bundle"] = origin.into();
+        let mut output = serde_json::to_vec_pretty(&state).unwrap_or(output);
+        output.push(b'\n');
+        output
+    }
+}
+
+impl Backend for TransformBackend {
+    // Standard commands (from liboci_cli::StandardCmd)
+    fn create(&self, mut args: liboci_cli::Create) -> Result<()> {
+        let id = args.container_id.clone();
+        if let Some(shadow) = self.shadow(&args.bundle, &id)? {
+            args.bundle = shadow;
+        }
+        let result = self.backend.create(args);
+        if result.is_err() {
+            self.remove_shadow(&id);
+        }
+        result
+    }
+
+    fn start(&self, args: liboci_cli::Start) -> Result<()> {
+        self.backend.start(args)
+    }
+
+    fn kill(&self, args: liboci_cli::Kill) -> Result<()> {
+        self.backend.kill(args)
+    }
+
+    fn delete(&self, args: liboci_cli::Delete) -> Result<()> {
+        let id = args.container_id.clone();
+        self.backend.delete(args)?;
+        self.remove_shadow(&id);
+        Ok(())
+    }
+
+    fn state(&self, args: liboci_cli::State) -> Result<()> {
+        let id = args.container_id.clone();
+        let (result, output) = capture(|| self.backend.state(args))?;
+        io::stdout().write_all(&self.original_bundle(&id, output))?;
+        result
+    }
+
+    // Common non-standard commands (from liboci_cli::CommonCmd)
+    fn checkpoint(&self, args: liboci_cli::Checkpoint) -> Result<()> {
+        self.backend.checkpoint(args)
+    }
+
+    fn events(&self, args: liboci_cli::Events) -> Result<()> {
+        self.backend.events(args)
+    }
+
+    fn exec(&self, args: liboci_cli::Exec) -> Result<()> {
+        self.backend.exec(args)
+    }
+
+    fn features(&self, args: liboci_cli::Features) -> Result<()> {
+        self.backend.features(args)
+    }
+
+    fn list(&self, args: liboci_cli::List) -> Result<()> {
+        self.backend.list(args)
+    }
+
+    fn pause(&self, args: liboci_cli::Pause) -> Result<()> {
+        self.backend.pause(args)
+    }
+
+    fn ps(&self, args: liboci_cli::Ps) -> Result<()> {
+        self.backend.ps(args)
+    }
+
+    fn resume(&self, args: liboci_cli::Resume) -> Result<()> {
+        self.backend.resume(args)
+    }
+
+    fn run(&self, mut args: liboci_cli::Run) -> Result<()> {
+        let id = args.container_id.clone();
+        let detach = args.detach;
+        if let Some(shadow) = self.shadow(&args.bundle, &id)? {
+            args.bundle = shadow;
+        }
+
+        // Without --detach, the container is deleted when run returns,
+        // unless --keep is given
+        let keep = args.keep;
+        let result = self.backend.run(args);
+        let remove = if detach { result.is_err() } else { !keep };
+        if remove {
+            self.remove_shadow(&id);
+        }
+        result
+    }
+
+    fn update(&self, args: liboci_cli::Update) -> Result<()> {
+        self.backend.update(args)
+    }
+
+    fn spec(&self, args: liboci_cli::Spec) -> Result<()> {
+        self.backend.spec(args)
+    }
+}
@@ -0,0 +1,216 @@
+// ****************************************************************************
+//  translate.rs                                                ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Declarative translation of options for CLI runtimes
+//
+//     Options are generated with runc's spelling. A translation table maps
+//     each option to the spelling of another runtime, drops it, or rejects
+//     it. Keys are `<subcommand>.<option>`, `*.<option>` for all subcommands,
+//     `global.<option>` for global options, or `<subcommand>` for a whole
+//     subcommand. Values are `"drop"`, `"reject"`, or a new option name,
+//     e.g. `"--cpu-shares"` or `"-q"`.
+//
+//     Built-in profiles for well-known runtimes are in the `profiles`
+//     directory. They can be extended in the backend configuration.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use tracing::debug;
+
+use super::args::{CmdLine, Opt};
+use super::Error;
+
+// Built-in translation profiles
+const PROFILES: &[(&str, &str)] = &[
+    ("runc", include_str!("profiles/runc.toml")),
+    ("crun", include_str!("profiles/crun.toml")),
+    ("youki", include_str!("profiles/youki.toml")),
+    ("runsc", include_str!("profiles/runsc.toml")),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum Action
+// ----------------------------------------------------------------------------
+//   What to do with an option or subcommand
+// ----------------------------------------------------------------------------
+{
+    Drop,
+    Reject,
+    Rename(String),
+}
+
+impl TryFrom<String> for Action {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "drop" => Ok(Action::Drop),
+            "reject" => Ok(Action::Reject),
+            name if name.starts_with('-') => {
+                // Long options are stored without dashes, like generated ones
+                let name = name.strip_prefix("--").unwrap_or(name);
+                Ok(Action::Rename(name.to_string()))
+            }
+            _ => Err(format!(
+                "Invalid translation {:?}, expected \"drop\", \"reject\" or an option",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct Profile
+// ----------------------------------------------------------------------------
+//   The contents of a translation profile
+// ----------------------------------------------------------------------------
+{
+    #[serde(default)]
+    flags: HashMap<String, Action>,
+}
+
+#[derive(Debug)]
+pub struct Translator
+// ----------------------------------------------------------------------------
+//   Translate generated options for a given runtime
+// ----------------------------------------------------------------------------
+{
+    runtime: String,
+    flags: HashMap<String, Action>,
+}
+
+impl Translator {
+    pub fn new(
+        profile: Option<&str>,
+        runtime: &Path,
+        flags: HashMap<String, Action>,
+    ) -> Result<Self>
+    // ------------------------------------------------------------------------
+    //   Load a profile and add the configured translations
+    // ------------------------------------------------------------------------
+    //   Without an explicit profile, guess from the name of the runtime,
+    //   and default to runc. A profile containing `/` is a file name.
+    {
+        let guessed = runtime
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| PROFILES.iter().any(|(known, _)| known == name))
+            .unwrap_or("runc");
+        let name = profile.unwrap_or(guessed);
+
+        let source = if name.contains('/') {
+            fs::read_to_string(name).with_context(|| format!("Reading profile {}", name))?
+        } else {
+            PROFILES
+                .iter()
+                .find(|(known, _)| *known == name)
+                .map(|(_, source)| source.to_string())
+                .ok_or_else(|| anyhow!("Unknown translation profile {:?}", name))?
+        };
+        let mut loaded: Profile =
+            toml::from_str(&source).with_context(|| format!("Parsing profile {}", name))?;
+        debug!("Using translation profile {}", name);
+
+        loaded.flags.extend(flags);
+        Ok(Translator {
+            runtime: runtime.display().to_string(),
+            flags: loaded.flags,
+        })
+    }
+
+    fn translate_options(&self, options: &mut Vec<Opt>, scopes: &[&str]) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Translate options, looking them up in the given scopes in order
+    // ------------------------------------------------------------------------
+    {
+        let mut translated = Vec::with_capacity(options.len());
+        for mut opt in options.drain(..) {
+            let action = scopes
+                .iter()
+                .find_map(|scope| self.flags.get(&format!("{}.{}", scope, opt.name)));
+            match action {
+                None => translated.push(opt),
+                Some(Action::Drop) => debug!("Dropping option --{}", opt.name),
+                Some(Action::Reject) if scopes[0] == "global" => {
+                    return Err(Error::Unsupported {
+                        message: format!(
+                            "Global option --{} is not supported by backend {}",
+                            opt.name,
+                            self.runtime
+                        ),
+                    }
+                    .into())
+                }
+                Some(Action::Reject) => {
+                    return Err(Error::Unsupported {
+                        message: format!(
+                            "Option --{} of {} is not supported by backend {}",
+                            opt.name,
+                            scopes[0],
+                            self.runtime
+                        ),
+                    }
+                    .into())
+                }
+                Some(Action::Rename(name)) => {
+                    opt.name = name.clone();
+                    translated.push(opt);
+                }
+            }
+        }
+        *options = translated;
+        Ok(())
+    }
+
+    pub fn translate(&self, cmd: &mut CmdLine, global: &mut Vec<Opt>) -> Result<bool>
+    // ------------------------------------------------------------------------
+    //   Translate a command line, return false if it should not be run
+    // ------------------------------------------------------------------------
+    {
+        match self.flags.get(cmd.subcommand) {
+            Some(Action::Drop) => {
+                debug!("Dropping subcommand {}", cmd.subcommand);
+                return Ok(false);
+            }
+            Some(Action::Reject) => {
+                return Err(Error::Unsupported {
+                    message: format!(
+                        "Subcommand {} is not supported by backend {}",
+                        cmd.subcommand,
+                        self.runtime
+                    ),
+                }
+                .into())
+            }
+            Some(Action::Rename(_)) => {
+                return Err(anyhow!("Subcommand {} cannot be renamed", cmd.subcommand))
+            }
+            None => {}
+        }
+
+        self.translate_options(global, &["global"])?;
+        self.translate_options(&mut cmd.options, &[cmd.subcommand, "*"])?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,300 @@
+// ****************************************************************************
+//  fds.rs                                                      ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Pass file descriptors through to the backend runtime intact
+//
+//     Like runc, fds 3 to 3 + LISTEN_FDS + --preserve-fds are passed to the
+//     container with the same numbers. ociplex reserves that range early:
+//     holes are filled with close-on-exec placeholders, so that nothing
+//     ociplex opens can land in it, and its own long-lived fds are moved
+//     above it. Before executing the runtime, the inherited fds in the range
+//     are made inheritable, and LISTEN_PID is set to the pid of the runtime,
+//     since socket activation checks it.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::process::{self, Command};
+use std::sync::OnceLock;
+
+use tracing::{debug, warn};
+
+// First fd passed to the runtime after stdio
+const FIRST_FD: RawFd = 3;
+
+// Room for the decimal digits of a pid in LISTEN_PID
+const PID_DIGITS: usize = 20;
+
+#[derive(Debug)]
+struct Reserved
+// ----------------------------------------------------------------------------
+//   The range of fds reserved for the runtime
+// ----------------------------------------------------------------------------
+{
+    // One past the last reserved fd
+    end: RawFd,
+
+    // Whether each reserved fd was inherited, or is one of our placeholders
+    inherited: Vec<bool>,
+
+    // Whether LISTEN_PID designates us, and must designate the runtime
+    listen_pid: bool,
+
+    // Placeholders, closed on exec
+    _placeholders: Vec<OwnedFd>,
+}
+
+static RESERVED: OnceLock<Reserved> = OnceLock::new();
+
+fn is_open(fd: RawFd) -> bool {
+    // SAFETY: F_GETFD has no effect on the fd
+    unsafe { libc::fcntl(fd, libc::F_GETFD) >= 0 }
+}
+
+fn listen_fds() -> (usize, bool)
+// ----------------------------------------------------------------------------
+//   The number of socket activation fds meant for us, and if LISTEN_PID is
+// ----------------------------------------------------------------------------
+{
+    let ours = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == process::id());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .filter(|_| ours)
+        .unwrap_or(0);
+    (count, ours)
+}
+
+pub fn reserve(preserve_fds: usize) -> io::Result<()>
+// ----------------------------------------------------------------------------
+//   Reserve fds for the runtime, must be called before opening any file
+// ----------------------------------------------------------------------------
+{
+    let (listen, listen_pid) = listen_fds();
+    let end = FIRST_FD + (listen + preserve_fds) as RawFd;
+    let mut inherited = Vec::new();
+    let mut placeholders = Vec::new();
+    for fd in FIRST_FD..end {
+        let open = is_open(fd);
+        inherited.push(open);
+        if !open {
+            // SAFETY: open returns a new fd that we own
+            let null = unsafe {
+                libc::open(c"/dev/null".as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC)
+            };
+            if null < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: the fd was just opened, and nothing else owns it
+            let null = unsafe { OwnedFd::from_raw_fd(null) };
+            if null.as_raw_fd() == fd {
+                placeholders.push(null);
+                continue;
+            }
+            // SAFETY: dup3 only creates fd, which is not open
+            if unsafe { libc::dup3(null.as_raw_fd(), fd, libc::O_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: fd was not open, dup3 made it ours
+            placeholders.push(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+    }
+    let reserved = Reserved {
+        end,
+        inherited,
+        listen_pid,
+        _placeholders: placeholders,
+    };
+    let _ = RESERVED.set(reserved);
+    Ok(())
+}
+
+pub fn private_floor() -> RawFd
+// ----------------------------------------------------------------------------
+//   The first fd that is our own, above those reserved for the runtime
+// ----------------------------------------------------------------------------
+{
+    RESERVED.get().map_or(FIRST_FD, |reserved| reserved.end)
+}
+
+pub fn relocate<F: From<OwnedFd> + Into<OwnedFd>>(file: F) -> io::Result<F>
+// ----------------------------------------------------------------------------
+//   Move one of our own fds above the reserved range, with close-on-exec
+// ----------------------------------------------------------------------------
+{
+    let floor = private_floor();
+    let fd: OwnedFd = file.into();
+    if fd.as_raw_fd() >= floor {
+        return Ok(F::from(fd));
+    }
+    // SAFETY: fcntl only reads fd, which is open, and returns a new fd
+    let moved = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, floor) };
+    if moved < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: moved is the new fd returned by fcntl, and nothing else owns it
+    Ok(F::from(unsafe { OwnedFd::from_raw_fd(moved) }))
+}
+
+fn cstring(text: &OsStr) -> io::Result<CString> {
+    CString::new(text.as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+struct Exec
+// ----------------------------------------------------------------------------
+//   Arguments for execvpe, prepared before fork since it cannot allocate
+// ----------------------------------------------------------------------------
+{
+    program: CString,
+    _args: Vec<CString>,
+    _env: Vec<CString>,
+    argv: Vec<*const libc::c_char>,
+    envp: Vec<*const libc::c_char>,
+    listen_pid: Vec<u8>,
+}
+
+// SAFETY: the pointers only designate the strings owned by the structure
+unsafe impl Send for Exec {}
+unsafe impl Sync for Exec {}
+
+impl Exec {
+    fn new(cmd: &Command) -> io::Result<Exec>
+    // ------------------------------------------------------------------------
+    //   Prepare arguments and environment, with a LISTEN_PID to fill in
+    // ------------------------------------------------------------------------
+    {
+        let program = cstring(cmd.get_program())?;
+        let mut args = vec![program.clone()];
+        for arg in cmd.get_args() {
+            args.push(cstring(arg)?);
+        }
+
+        let mut vars: Vec<(OsString, OsString)> = env::vars_os().collect();
+        for (key, value) in cmd.get_envs() {
+            vars.retain(|(other, _)| other != key);
+            if let Some(value) = value {
+                vars.push((key.to_os_string(), value.to_os_string()));
+            }
+        }
+        vars.retain(|(key, _)| key != "LISTEN_PID");
+        let mut env = Vec::new();
+        for (key, value) in vars {
+            let mut var = key;
+            var.push("=");
+            var.push(value);
+            env.push(cstring(&var)?);
+        }
+
+        // The digits of the pid are written after the prefix in the child
+        let mut listen_pid = b"LISTEN_PID=".to_vec();
+        listen_pid.resize(listen_pid.len() + PID_DIGITS + 1, 0);
+
+        let argv = args.iter().map(|arg| arg.as_ptr()).chain([std::ptr::null()]).collect();
+        let envp = env
+            .iter()
+            .map(|var| var.as_ptr())
+            .chain([listen_pid.as_ptr().cast(), std::ptr::null()])
+            .collect();
+        Ok(Exec {
+            program,
+            _args: args,
+            _env: env,
+            argv,
+            envp,
+            listen_pid,
+        })
+    }
+
+    fn exec(&mut self) -> io::Error
+    // ------------------------------------------------------------------------
+    //   Execute the program with our own pid in LISTEN_PID, in the child
+    // ------------------------------------------------------------------------
+    //   This must be async-signal-safe, so it writes digits in place.
+    {
+        // SAFETY: getpid is async-signal-safe
+        let mut pid = unsafe { libc::getpid() } as u64;
+        let mut digits = [0u8; PID_DIGITS];
+        let mut count = 0;
+        loop {
+            digits[PID_DIGITS - 1 - count] = b'0' + (pid % 10) as u8;
+            count += 1;
+            pid /= 10;
+            if pid == 0 {
+                break;
+            }
+        }
+        let prefix = b"LISTEN_PID=".len();
+        self.listen_pid[prefix..prefix + count].copy_from_slice(&digits[PID_DIGITS - count..]);
+        self.listen_pid[prefix + count] = 0;
+
+        // SAFETY: argv and envp are null-terminated arrays of C strings
+        unsafe { libc::execvpe(self.program.as_ptr(), self.argv.as_ptr(), self.envp.as_ptr()) };
+        io::Error::last_os_error()
+    }
+}
+
+pub fn pass_through(cmd: &mut Command) -> io::Result<()>
+// ----------------------------------------------------------------------------
+//   Make sure the runtime receives the reserved fds and a valid LISTEN_PID
+// ----------------------------------------------------------------------------
+//   Must be called after the arguments and environment are set.
+{
+    let Some(reserved) = RESERVED.get() else {
+        return Ok(());
+    };
+    let end = reserved.end;
+    let inherited = reserved.inherited.clone();
+    for (fd, open) in (FIRST_FD..end).zip(&inherited) {
+        if !open {
+            warn!("File descriptor {} to pass to the runtime is not open", fd);
+        }
+    }
+    let mut exec = if reserved.listen_pid {
+        debug!("Setting LISTEN_PID for the runtime");
+        Some(Exec::new(cmd)?)
+    } else {
+        None
+    };
+
+    // SAFETY: the closure only makes async-signal-safe calls
+    unsafe {
+        cmd.pre_exec(move || {
+            for (fd, open) in (FIRST_FD..end).zip(&inherited) {
+                if *open {
+                    let flags = libc::fcntl(fd, libc::F_GETFD);
+                    if flags >= 0 {
+                        libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC);
+                    }
+                }
+            }
+            match exec.as_mut() {
+                Some(exec) => Err(exec.exec()),
+                None => Ok(()),
+            }
+        });
+    }
+    Ok(())
+}
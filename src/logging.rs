@@ -0,0 +1,200 @@
+// ****************************************************************************
+//  logging.rs                                                  ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Diagnostics output for ociplex, in the format expected from runc
+//
+//     Container engines like `podman` pass `--log` and `--log-format` to the
+//     runtime, and read the resulting file to report errors to the user.
+//     Each line is either a JSON object or a logfmt-style text record with
+//     `level`, `msg` and `time` keys, as produced by runc's logrus logger.
+//     Diagnostics never go to stdout, since that would corrupt the output of
+//     commands like `state` or `list` that are parsed by the caller.
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fmt::{self, Write as _};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{Local, SecondsFormat};
+use serde_json::{Map, Value};
+use tracing::{field::Field, Event, Level, Subscriber};
+use tracing_subscriber::{field::Visit, layer::Context as LayerContext, Layer};
+
+use crate::fds;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format
+// ----------------------------------------------------------------------------
+//   Log formats accepted by the --log-format option
+// ----------------------------------------------------------------------------
+{
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn parse(format: Option<&str>) -> Result<Format>
+    // ------------------------------------------------------------------------
+    //   Parse the --log-format option, defaulting to text like runc
+    // ------------------------------------------------------------------------
+    {
+        match format {
+            None | Some("text") => Ok(Format::Text),
+            Some("json") => Ok(Format::Json),
+            Some(other) => Err(anyhow!("Unknown log format {:?}", other)),
+        }
+    }
+}
+
+pub struct OciLogLayer
+// ----------------------------------------------------------------------------
+//   A tracing layer writing records the way runc does
+// ----------------------------------------------------------------------------
+{
+    format: Format,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl OciLogLayer {
+    pub fn new(log: Option<&Path>, format: Format) -> Result<Self>
+    // ------------------------------------------------------------------------
+    //   Create a layer writing to the given log file, or stderr by default
+    // ------------------------------------------------------------------------
+    {
+        let writer: Box<dyn Write + Send> = match log {
+            Some(path) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(fds::relocate)
+                    .with_context(|| format!("Opening log file {}", path.display()))?,
+            ),
+            None => Box::new(io::stderr()),
+        };
+        Ok(OciLogLayer {
+            format,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    fn format_record(&self, level: &Level, fields: Map<String, Value>) -> String
+    // ------------------------------------------------------------------------
+    //   Build a single log line from the event level and fields
+    // ------------------------------------------------------------------------
+    {
+        let level = level_name(level);
+        let time = Local::now().to_rfc3339_opts(SecondsFormat::Nanos, false);
+        match self.format {
+            Format::Json => {
+                let mut record = fields;
+                record.insert("level".into(), Value::from(level));
+                record.insert("time".into(), Value::from(time));
+                record.entry("msg").or_insert_with(|| Value::from(""));
+                Value::Object(record).to_string()
+            }
+            Format::Text => {
+                let mut fields = fields;
+                let msg = fields.remove("msg").unwrap_or_else(|| Value::from(""));
+                let mut line = format!("time={:?} level={} msg={}", time, level, msg);
+                for (key, value) in fields {
+                    let _ = write!(line, " {}={}", key, value);
+                }
+                line
+            }
+        }
+    }
+}
+
+fn level_name(level: &Level) -> &'static str
+// ----------------------------------------------------------------------------
+//   Return the logrus name for a given tracing level
+// ----------------------------------------------------------------------------
+{
+    match *level {
+        Level::ERROR => "error",
+        Level::WARN => "warning",
+        Level::INFO => "info",
+        Level::DEBUG => "debug",
+        Level::TRACE => "trace",
+    }
+}
+
+impl<S: Subscriber> Layer<S> for OciLogLayer
+// ----------------------------------------------------------------------------
+//   Write each event as a single line in the log file
+// ----------------------------------------------------------------------------
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let line = self.format_record(event.metadata().level(), visitor.fields);
+
+        // Errors while logging have nowhere to go, so ignore them
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = writeln!(writer, "{}", line);
+            let _ = writer.flush();
+        }
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor
+// ----------------------------------------------------------------------------
+//   Collect the fields of an event, renaming `message` as `msg`
+// ----------------------------------------------------------------------------
+{
+    fields: Map<String, Value>,
+}
+
+impl FieldVisitor {
+    fn insert(&mut self, field: &Field, value: Value) {
+        let name = match field.name() {
+            "message" => "msg",
+            name => name,
+        };
+        self.fields.insert(name.to_string(), value);
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, Value::from(format!("{:?}", value)));
+    }
+}
@@ -27,18 +27,29 @@
 //   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
 // ****************************************************************************
 
+use std::fs;
 use std::path::PathBuf;
-use std::{fs, str::FromStr};
+use std::process;
 
 use anyhow::{Context, Result};
 use clap::{crate_version, Parser};
 use liboci_cli::{CommonCmd, GlobalOpts, StandardCmd};
-use tracing::{debug, error, info, instrument, Level};
-use tracing_subscriber::{filter::LevelFilter, fmt::format::FmtSpan, EnvFilter};
+use tracing::{debug, error, field, info, info_span, instrument, Level, Span};
+use tracing_subscriber::{prelude::*, EnvFilter};
+
+use backend::Invocation;
 
 mod backend;
+mod fds;
+mod logging;
+mod reaper;
+mod replay;
+mod serve;
+mod shim;
+mod syslog;
 
 #[derive(Parser, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum Subcommand
 // ----------------------------------------------------------------------------
 //   Subcommands for the runtime
@@ -53,6 +64,53 @@ enum Subcommand
     // but not considered mandatory to be OCI-compliant.
     #[clap(flatten)]
     CommonCmd(CommonCmd),
+
+    // Replay a journal written by the Record backend, specific to ociplex
+    Replay(replay::Replay),
+
+    // Serve commands from other ociplex invocations, specific to ociplex
+    Serve(serve::Serve),
+}
+
+impl Subcommand {
+    fn name(&self) -> &'static str
+    // ------------------------------------------------------------------------
+    //   Return the name of the subcommand, as used on the command line
+    // ------------------------------------------------------------------------
+    {
+        match self {
+            Subcommand::Standard(cmd) => cmd.name(),
+            Subcommand::CommonCmd(cmd) => cmd.name(),
+            Subcommand::Replay(_) => "replay",
+            Subcommand::Serve(_) => "serve",
+        }
+    }
+
+    fn container_id(&self) -> Option<&str>
+    // ------------------------------------------------------------------------
+    //   Return the container ID the subcommand applies to, if any
+    // ------------------------------------------------------------------------
+    {
+        match self {
+            Subcommand::Standard(cmd) => cmd.container_id(),
+            Subcommand::CommonCmd(cmd) => cmd.container_id(),
+            Subcommand::Replay(_) | Subcommand::Serve(_) => None,
+        }
+    }
+
+    fn preserve_fds(&self) -> usize
+    // ------------------------------------------------------------------------
+    //   Return the number of additional fds to pass to the container
+    // ------------------------------------------------------------------------
+    {
+        let count = match self {
+            Subcommand::Standard(StandardCmd::Create(args)) => args.preserve_fds,
+            Subcommand::CommonCmd(CommonCmd::Exec(args)) => args.preserve_fds,
+            Subcommand::CommonCmd(CommonCmd::Run(args)) => args.preserve_fds,
+            _ => 0,
+        };
+        count.max(0) as usize
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -83,6 +141,10 @@ struct Opts
     #[clap(long)]
     syslog: bool,
 
+    /// Socket of the ociplex daemon, to serve or forward commands to
+    #[clap(long)]
+    socket: Option<PathBuf>,
+
     // Subcommand and its associated options if any
     #[clap(subcommand)]
     subcmd: Subcommand,
@@ -95,7 +157,7 @@ const DEFAULT_TRACING_LEVEL: Level = Level::DEBUG;
 #[cfg(not(debug_assertions))]
 const DEFAULT_TRACING_LEVEL: Level = Level::ERROR;
 
-fn set_tracing_level() -> Result<()>
+fn set_tracing_level(opts: &Opts) -> Result<()>
 // ----------------------------------------------------------------------------
 //  Select tracing options and install a global tracing collector
 // ----------------------------------------------------------------------------
@@ -103,14 +165,8 @@ fn set_tracing_level() -> Result<()>
 //  1. The OCIPLEX_LOG environment variable
 //  2. The --debug option
 //  3. The --log-level option, which overrides --debug
-//  4. The --log, --log-format and --log-to-syslog
+//  4. The --log, --log-format and --syslog options select destinations
 {
-    // We need options to define where the tracing goes
-    let opts = match Opts::try_parse() {
-        Ok(opts) => opts,
-        Err(e) => e.exit(),
-    };
-
     // Select actual tracing level
     let env_filter = EnvFilter::try_from_env("OCIPLEX_LOG");
     let tracing_level = if let Some(level) = opts.log_level {
@@ -129,9 +185,21 @@ fn set_tracing_level() -> Result<()>
         env_filter.unwrap()
     };
 
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_filter)
-        .with_span_events(FmtSpan::ENTER | FmtSpan::EXIT)
+    // Write diagnostics where runc would, never to stdout
+    let format = logging::Format::parse(opts.global.log_format.as_deref())?;
+    let log_layer = logging::OciLogLayer::new(opts.global.log.as_deref(), format)?;
+
+    // Optionally send a copy to the system logger
+    let syslog_layer = if opts.syslog {
+        Some(syslog::SyslogLayer::new()?)
+    } else {
+        None
+    };
+
+    tracing_subscriber::registry()
+        .with(tracing_filter)
+        .with(log_layer)
+        .with(syslog_layer)
         .init();
 
     Ok(())
@@ -147,32 +215,110 @@ fn instrumented(x: i32)
     debug!("Inside instrumented x={}", x)
 }
 
-fn main() -> Result<()>
+fn main()
 // ----------------------------------------------------------------------------
 //  Main entry point for the tool
 // ----------------------------------------------------------------------------
 {
-    // Setup global tracing
-    set_tracing_level()?;
+    // When invoked by containerd as a shim, follow its conventions instead
+    if shim::invoked() {
+        process::exit(shim::main());
+    }
 
     // Parse options with clap
-    info!("Parsing options");
     let opts = match Opts::try_parse() {
         Ok(opts) => opts,
         Err(e) => e.exit(),
     };
 
+    // Keep fds for the runtime out of reach before opening anything
+    if let Err(e) = fds::reserve(opts.subcmd.preserve_fds()) {
+        eprintln!("ociplex: Reserving file descriptors: {}", e);
+        process::exit(1);
+    }
+
+    // Setup global tracing. Without it, we can only complain on stderr
+    if let Err(e) = set_tracing_level(&opts) {
+        eprintln!("ociplex: {:#}", e);
+        process::exit(1);
+    }
+
+    // Structured fields for the system logger
+    let span = info_span!(
+        "ociplex",
+        subcommand = opts.subcmd.name(),
+        container_id = opts.subcmd.container_id(),
+        backend = field::Empty,
+    );
+    let _entered = span.enter();
+
+    // Report errors in the log, where the container engine will look for them
+    if let Err(e) = run(opts) {
+        error!("{:#}", e);
+        exit_like_backend(&e);
+    }
+}
+
+fn exit_like_backend(err: &anyhow::Error) -> !
+// ----------------------------------------------------------------------------
+//  Exit with the same status as the backend runtime, if there is one
+// ----------------------------------------------------------------------------
+//  When the backend died from a signal, re-raise it so that our own parent
+//  sees the same wait status as if it had invoked the backend directly.
+{
+    match err.downcast_ref::<backend::Error>() {
+        Some(backend::Error::Exited { code, .. }) => process::exit(*code),
+        Some(backend::Error::Signaled { signal, .. }) => {
+            // SAFETY: Plain libc calls on local data, as we are about to die
+            unsafe {
+                let no_core = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+                libc::signal(*signal, libc::SIG_DFL);
+                let mut mask: libc::sigset_t = std::mem::zeroed();
+                libc::sigemptyset(&mut mask);
+                libc::sigaddset(&mut mask, *signal);
+                libc::sigprocmask(libc::SIG_UNBLOCK, &mask, std::ptr::null_mut());
+                libc::raise(*signal);
+            }
+
+            // Signals like SIGCHLD do not kill us, use the shell convention
+            process::exit(128 + signal)
+        }
+        _ => process::exit(1),
+    }
+}
+
+fn run(opts: Opts) -> Result<()>
+// ----------------------------------------------------------------------------
+//  Run the subcommand using the selected backend
+// ----------------------------------------------------------------------------
+{
+    info!("Running {:?}", opts.subcmd);
     instrumented(42);
 
+    // Let a running daemon serve the command, if it can
+    if let Some(result) = serve::forward(&opts) {
+        return result;
+    }
+
     // Read backend configuration from file specified with --backend option
-    let config = fs::read_to_string(&opts.backend).context("Reading backend config")?;
-    let config: backend::Config = toml::from_str(&config).context("Parsing backend config")?;
+    let text = fs::read_to_string(&opts.backend).context("Reading backend config")?;
+    let config: backend::Config = toml::from_str(&text).context("Parsing backend config")?;
+    Span::current().record("backend", config.backend_type());
 
     // Instantiate the backend and delegate the rest of the work to it
-    let backend = config.instantiate(opts.global);
     match opts.subcmd {
-        Subcommand::Standard(std) => backend.standard_command(std)?,
-        Subcommand::CommonCmd(common) => backend.common_command(common)?,
+        Subcommand::Standard(std) => config.instantiate(opts.global)?.standard_command(std)?,
+        Subcommand::CommonCmd(common) => config.instantiate(opts.global)?.common_command(common)?,
+
+        // A replay instantiates the backend for each command, like ociplex
+        Subcommand::Replay(replay) => replay::run(replay, &text, &opts.global)?,
+
+        // A daemon keeps backend instances across commands
+        Subcommand::Serve(ref serve) => serve::run(serve, &opts, &text)?,
     }
 
     Ok(())
@@ -0,0 +1,207 @@
+// ****************************************************************************
+//  reaper.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Reap all the children of a subreaper, and keep their exit status
+//
+//     The containerd shim adopts the processes that runtimes leave behind,
+//     and must reap all its children, including those it does not know
+//     about. Once a reaper is started, it is the only one to wait for
+//     children, and the rest of ociplex gets their exit status from it.
+//     Without a reaper, or in a child forked without the reaper thread,
+//     children are waited for directly.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::collections::HashMap;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{self, Child, ExitStatus};
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+// How long to wait for a first child when there is none
+const IDLE_INTERVAL: Duration = Duration::from_millis(50);
+
+// How long the status of a process nobody waits for is kept
+const UNCLAIMED: Duration = Duration::from_secs(60);
+
+// Wait status of a process whose actual status cannot be known, exit code 255
+const UNKNOWN_STATUS: i32 = 255 << 8;
+
+static REAPER: OnceLock<Reaper> = OnceLock::new();
+
+#[derive(Debug)]
+pub struct Reaper
+// ----------------------------------------------------------------------------
+//   The exit status of reaped children, until they are claimed
+// ----------------------------------------------------------------------------
+{
+    // The process whose children are reaped
+    owner: u32,
+
+    exits: Mutex<HashMap<libc::pid_t, (ExitStatus, Instant)>>,
+    exited: Condvar,
+}
+
+pub fn start() -> &'static Reaper
+// ----------------------------------------------------------------------------
+//   Start reaping all children in a thread
+// ----------------------------------------------------------------------------
+{
+    let mut started = false;
+    let reaper = REAPER.get_or_init(|| {
+        started = true;
+        Reaper {
+            owner: process::id(),
+            exits: Mutex::default(),
+            exited: Condvar::new(),
+        }
+    });
+    if started {
+        thread::spawn(move || reaper.run());
+    }
+    reaper
+}
+
+impl Reaper {
+    fn run(&self)
+    // ------------------------------------------------------------------------
+    //   Wait for children to exit, and reap them with the status locked
+    // ------------------------------------------------------------------------
+    //   Waiting with WNOWAIT leaves the child a zombie, so that it still
+    //   exists for `exit_status` until its status is recorded.
+    {
+        loop {
+            let mut info = MaybeUninit::<libc::siginfo_t>::zeroed();
+            let flags = libc::WEXITED | libc::WNOWAIT;
+            // SAFETY: info is a valid siginfo_t to fill
+            if unsafe { libc::waitid(libc::P_ALL, 0, info.as_mut_ptr(), flags) } < 0 {
+                if io::Error::last_os_error().raw_os_error() == Some(libc::ECHILD) {
+                    thread::sleep(IDLE_INTERVAL);
+                }
+                continue;
+            }
+
+            let mut exits = self.exits.lock().unwrap();
+            let now = Instant::now();
+            exits.retain(|_, (_, at)| now.duration_since(*at) < UNCLAIMED);
+            loop {
+                let mut status = 0;
+                // SAFETY: plain libc call on local data
+                let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+                if pid <= 0 {
+                    break;
+                }
+                debug!("Reaped process {} with status {:#x}", pid, status);
+                exits.insert(pid, (ExitStatus::from_raw(status), now));
+            }
+            drop(exits);
+            self.exited.notify_all();
+        }
+    }
+
+    pub fn exit_status(&self, pid: u32) -> Option<ExitStatus>
+    // ------------------------------------------------------------------------
+    //   Claim the status of a process if it exited, unknown if not a child
+    // ------------------------------------------------------------------------
+    //   Processes that are not our children can only be checked for
+    //   existence. Children are reaped with the status locked, so a child
+    //   that is gone has its status recorded.
+    {
+        let pid = pid as libc::pid_t;
+        let mut exits = self.exits.lock().unwrap();
+        if let Some((status, _)) = exits.remove(&pid) {
+            return Some(status);
+        }
+        // SAFETY: signal 0 only checks that the process exists
+        let gone = unsafe { libc::kill(pid, 0) } < 0
+            && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH);
+        gone.then(|| ExitStatus::from_raw(UNKNOWN_STATUS))
+    }
+
+    fn wait(&self, pid: libc::pid_t) -> ExitStatus
+    // ------------------------------------------------------------------------
+    //   Wait until a child is reaped, and claim its status
+    // ------------------------------------------------------------------------
+    {
+        let mut exits = self.exits.lock().unwrap();
+        loop {
+            if let Some((status, _)) = exits.remove(&pid) {
+                return status;
+            }
+            exits = self.exited.wait(exits).unwrap();
+        }
+    }
+}
+
+fn reaper() -> Option<&'static Reaper> {
+    REAPER.get().filter(|reaper| reaper.owner == process::id())
+}
+
+pub fn wait(child: &mut Child) -> io::Result<ExitStatus>
+// ----------------------------------------------------------------------------
+//   Wait for a child, like Child::wait
+// ----------------------------------------------------------------------------
+{
+    match reaper() {
+        Some(reaper) => {
+            drop(child.stdin.take());
+            Ok(reaper.wait(child.id() as libc::pid_t))
+        }
+        None => child.wait(),
+    }
+}
+
+pub fn try_wait(child: &mut Child) -> io::Result<Option<ExitStatus>>
+// ----------------------------------------------------------------------------
+//   Check if a child exited, like Child::try_wait
+// ----------------------------------------------------------------------------
+{
+    match reaper() {
+        Some(reaper) => {
+            let pid = child.id() as libc::pid_t;
+            Ok(reaper.exits.lock().unwrap().remove(&pid).map(|(status, _)| status))
+        }
+        None => child.try_wait(),
+    }
+}
+
+pub fn wait_pid(pid: libc::pid_t, block: bool) -> io::Result<Option<ExitStatus>>
+// ----------------------------------------------------------------------------
+//   Wait for a child known by its pid, None if it has not exited yet
+// ----------------------------------------------------------------------------
+{
+    if let Some(reaper) = reaper() {
+        return Ok(match block {
+            true => Some(reaper.wait(pid)),
+            false => reaper.exits.lock().unwrap().remove(&pid).map(|(status, _)| status),
+        });
+    }
+    let mut status = 0;
+    let flags = if block { 0 } else { libc::WNOHANG };
+    // SAFETY: plain libc call on local data
+    match unsafe { libc::waitpid(pid, &mut status, flags) } {
+        reaped if reaped < 0 => Err(io::Error::last_os_error()),
+        0 => Ok(None),
+        _ => Ok(Some(ExitStatus::from_raw(status))),
+    }
+}
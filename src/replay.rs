@@ -0,0 +1,235 @@
+// ****************************************************************************
+//  replay.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Replay a journal written by the record backend against any backend
+//
+//     Each command in the journal is parsed again, with container IDs and
+//     bundle paths optionally remapped, and run with a fresh instance of
+//     the configured backend, like separate ociplex invocations would be.
+//     Commands whose exit status or state output differ from what was
+//     recorded are reported as JSON lines.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use liboci_cli::GlobalOpts;
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+use crate::backend;
+use crate::Subcommand;
+
+// Subcommands that are not about a specific container
+const GLOBAL_SUBCOMMANDS: &[&str] = &["features", "list", "spec"];
+
+fn parse_mapping(text: &str) -> Result<(String, String), String> {
+    text.split_once('=')
+        .map(|(old, new)| (old.to_string(), new.to_string()))
+        .ok_or_else(|| format!("Invalid mapping {:?}, expected OLD=NEW", text))
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Replay
+// ----------------------------------------------------------------------------
+//   Arguments of the `replay` subcommand
+// ----------------------------------------------------------------------------
+{
+    /// Journal written by the Record backend
+    journal: PathBuf,
+
+    /// Replace a container ID, as OLD=NEW
+    #[clap(long, value_parser = parse_mapping)]
+    map_id: Vec<(String, String)>,
+
+    /// Replace the start of bundle paths, as OLD=NEW
+    #[clap(long, value_parser = parse_mapping)]
+    map_bundle: Vec<(String, String)>,
+
+    /// Write differences to a file instead of stdout
+    #[clap(long)]
+    report: Option<PathBuf>,
+}
+
+impl Replay {
+    fn map_bundle(&self, bundle: &str) -> String {
+        for (old, new) in &self.map_bundle {
+            if let Ok(rest) = Path::new(bundle).strip_prefix(old) {
+                return Path::new(new).join(rest).to_string_lossy().into_owned();
+            }
+        }
+        bundle.to_string()
+    }
+
+    fn argv(&self, subcommand: &str, entry: &Value) -> Result<Vec<String>>
+    // ------------------------------------------------------------------------
+    //   Rebuild the command line of a journal entry, remapped
+    // ------------------------------------------------------------------------
+    //   Console sockets, PID files and preserved fds belong to the recorded
+    //   container engine, and are dropped, since nothing would listen on the
+    //   sockets, the files would overwrite the engine's, and the fds are not
+    //   open. The bundle is the absolute path recorded for create and run,
+    //   since a relative one depends on the directory ociplex ran in.
+    {
+        let args = &entry["args"];
+        let strings = |key: &str| -> Vec<String> {
+            args[key]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|value| value.as_str().map(String::from))
+                .collect()
+        };
+        let mut argv = vec!["ociplex".to_string(), subcommand.to_string()];
+        let mut bundle = entry["bundle"]["path"].as_str();
+        for opt in args["options"].as_array().into_iter().flatten() {
+            let name = opt[0]
+                .as_str()
+                .ok_or_else(|| anyhow!("Invalid option {} in journal", opt))?;
+            let value = opt[1].as_str();
+            match (name, value) {
+                ("console-socket" | "pid-file" | "preserve-fds", _) => {
+                    debug!("Dropping {} in replay", name)
+                }
+                ("bundle", Some(path)) => bundle = bundle.or(Some(path)),
+                (name, Some(value)) => argv.push(format!("--{}={}", name, value)),
+                (name, None) => argv.push(format!("--{}", name)),
+            }
+        }
+
+        if let Some(bundle) = bundle {
+            argv.push(format!("--bundle={}", self.map_bundle(bundle)));
+        }
+
+        let mut operands = strings("operands");
+        if !GLOBAL_SUBCOMMANDS.contains(&subcommand) {
+            if let Some(id) = operands.first_mut() {
+                if let Some((_, new)) = self.map_id.iter().find(|(old, _)| old == id) {
+                    *id = new.clone();
+                }
+            }
+        }
+        argv.extend(operands);
+        let trailing = strings("trailing");
+        if !trailing.is_empty() {
+            argv.push("--".into());
+            argv.extend(trailing);
+        }
+        Ok(argv)
+    }
+}
+
+fn execute(config: &str, global: &GlobalOpts, cmd: Subcommand) -> Result<()>
+// ----------------------------------------------------------------------------
+//   Run a command with a new instance of the backend
+// ----------------------------------------------------------------------------
+{
+    let config: backend::Config = toml::from_str(config).context("Parsing backend config")?;
+    let backend = config.instantiate(backend::clone_global(global))?;
+    match cmd {
+        Subcommand::Standard(std) => backend.standard_command(std),
+        Subcommand::CommonCmd(common) => backend.common_command(common),
+        Subcommand::Replay(_) => Err(anyhow!("Cannot replay a replay")),
+        Subcommand::Serve(_) => Err(anyhow!("Cannot replay a daemon")),
+    }
+}
+
+pub fn run(replay: Replay, config: &str, global: &GlobalOpts) -> Result<()>
+// ----------------------------------------------------------------------------
+//   Replay all commands of a journal, and report differences
+// ----------------------------------------------------------------------------
+{
+    let journal = fs::read_to_string(&replay.journal)
+        .with_context(|| format!("Reading journal {}", replay.journal.display()))?;
+    let mut report: Box<dyn Write> = match &replay.report {
+        Some(path) => Box::new(
+            File::create(path).with_context(|| format!("Creating {}", path.display()))?,
+        ),
+        None => Box::new(io::stdout()),
+    };
+
+    let (mut replayed, mut diverged) = (0, 0);
+    for (index, line) in journal.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_number = index + 1;
+        let entry: Value = serde_json::from_str(line)
+            .with_context(|| format!("Parsing journal line {}", line_number))?;
+        let subcommand = entry["subcommand"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No subcommand in journal line {}", line_number))?;
+        let argv = replay.argv(subcommand, &entry)?;
+        debug!("Replaying {:?}", &argv[1..]);
+        let cmd = Subcommand::try_parse_from(&argv)
+            .with_context(|| format!("Parsing command in journal line {}", line_number))?;
+        let container = cmd.container_id().map(String::from);
+
+        // Output is kept for comparison, and is not part of the report
+        let (result, output) = backend::capture(|| execute(config, global, cmd))?;
+        if let Err(err) = &result {
+            debug!("Replayed {} failed: {:#}", subcommand, err);
+        }
+        replayed += 1;
+
+        let mut differences = Vec::new();
+        let recorded = (
+            entry["result"]["outcome"].as_str().unwrap_or("unknown"),
+            entry["result"]["code"].as_i64().unwrap_or(-1),
+        );
+        let (outcome, code) = backend::outcome(&result);
+        if recorded.0 != outcome {
+            differences.push(format!("outcome: {} vs {}", recorded.0, outcome));
+        }
+        if recorded.1 != i64::from(code) {
+            differences.push(format!("exit code: {} vs {}", recorded.1, code));
+        }
+        if let (Some(expected), Ok(())) = (entry["output"].as_str(), &result) {
+            if subcommand == "state" && recorded.0 == "ok" {
+                backend::state_differences(expected.as_bytes(), &output, &mut differences);
+            }
+        }
+        if !differences.is_empty() {
+            diverged += 1;
+            let divergence = json!({
+                "line": line_number,
+                "subcommand": subcommand,
+                "container": container,
+                "recorded": { "outcome": recorded.0, "code": recorded.1 },
+                "replayed": {
+                    "outcome": outcome,
+                    "code": code,
+                    "error": result.as_ref().err().map(|err| format!("{:#}", err)),
+                },
+                "differences": differences,
+            });
+            writeln!(report, "{}", divergence)?;
+        }
+    }
+
+    if diverged > 0 {
+        warn!("{} of {} replayed commands diverged", diverged, replayed);
+        return Err(anyhow!("{} of {} replayed commands diverged", diverged, replayed));
+    }
+    debug!("Replayed {} commands without divergence", replayed);
+    Ok(())
+}
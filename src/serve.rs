@@ -0,0 +1,388 @@
+// ****************************************************************************
+//  serve.rs                                                    ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Serve commands from a daemon, and forward commands to it
+//
+//     `ociplex serve` listens on a unix socket and keeps backend instances,
+//     along with their connections to shims, across commands. Each
+//     invocation of ociplex first tries to forward its command line to the
+//     daemon, and runs the command itself if no daemon is running, or if
+//     the daemon refuses the command.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use tracing::{debug, info, warn};
+
+use crate::backend::{self, Backend};
+use crate::{Opts, Subcommand};
+
+// Subcommands that the daemon can run for a client. Others need the
+// standard streams, file descriptors or lifetime of the client process.
+const FORWARDED: &[&str] = &[
+    "start", "state", "kill", "delete", "pause", "resume", "ps", "list", "features",
+];
+
+// Environment variables of the client that commands run with
+const FORWARDED_ENV: &[&str] = &["XDG_RUNTIME_DIR", "OCIPLEX_DRY_RUN"];
+
+// Prefixes of variables read by the runtimes themselves
+const FORWARDED_ENV_PREFIXES: &[&str] = &["RUNC_", "CRUN_", "YOUKI_", "KATA_"];
+
+// How long the daemon waits for a client to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a client waits for the daemon to run its command
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(clap::Args, Debug)]
+pub struct Serve
+// ----------------------------------------------------------------------------
+//   Arguments of the `serve` subcommand
+// ----------------------------------------------------------------------------
+{
+    /// Replace the socket of a daemon that no longer runs
+    #[clap(long)]
+    replace: bool,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Request
+// ----------------------------------------------------------------------------
+//   A command line forwarded by a client, with where it was given
+// ----------------------------------------------------------------------------
+{
+    backend: PathBuf,
+    cwd: PathBuf,
+    argv: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Failure
+// ----------------------------------------------------------------------------
+//   An error sent back to a client, so that it exits as if it ran the command
+// ----------------------------------------------------------------------------
+{
+    // Messages of the error chain, outermost first, before the backend error
+    context: Vec<String>,
+    error: Option<backend::Error>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Response
+// ----------------------------------------------------------------------------
+//   What the daemon replies, a refusal letting the client run the command
+// ----------------------------------------------------------------------------
+{
+    Refused { reason: String },
+    Done {
+        output: String,
+        #[serde(default)]
+        errors: String,
+        failure: Option<Failure>,
+    },
+}
+
+impl Failure {
+    fn new(err: &anyhow::Error) -> Self {
+        let mut context = Vec::new();
+        for cause in err.chain() {
+            if let Some(error) = cause.downcast_ref::<backend::Error>() {
+                let error = Some(error.clone());
+                return Failure { context, error };
+            }
+            context.push(cause.to_string());
+        }
+        Failure {
+            context,
+            error: None,
+        }
+    }
+
+    fn into_error(mut self) -> anyhow::Error {
+        let mut err = match self.error {
+            Some(error) => anyhow::Error::new(error),
+            None => anyhow!(self.context.pop().unwrap_or_else(|| "Unknown failure".into())),
+        };
+        for message in self.context.into_iter().rev() {
+            err = err.context(message);
+        }
+        err
+    }
+}
+
+fn forwarded_env(name: &str) -> bool {
+    FORWARDED_ENV.contains(&name)
+        || FORWARDED_ENV_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+}
+
+fn environment() -> BTreeMap<String, String>
+// ----------------------------------------------------------------------------
+//   The forwarded variables of the current environment
+// ----------------------------------------------------------------------------
+{
+    env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .filter(|(name, _)| forwarded_env(name))
+        .collect()
+}
+
+fn set_environment(vars: &BTreeMap<String, String>)
+// ----------------------------------------------------------------------------
+//   Make the forwarded variables of the environment exactly the given ones
+// ----------------------------------------------------------------------------
+{
+    for name in environment().keys().filter(|name| !vars.contains_key(*name)) {
+        env::remove_var(name);
+    }
+    for (name, value) in vars {
+        env::set_var(name, value);
+    }
+}
+
+pub fn socket_path(opts: &Opts) -> PathBuf {
+    opts.socket
+        .clone()
+        .unwrap_or_else(|| backend::default_state_dir().join("ociplex.sock"))
+}
+
+fn exchange(mut stream: UnixStream, request: &Request) -> Result<Response> {
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    writeln!(stream, "{}", serde_json::to_string(request)?)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+pub fn forward(opts: &Opts) -> Option<Result<()>>
+// ----------------------------------------------------------------------------
+//   Let a running daemon run the command, None if the client must run it
+// ----------------------------------------------------------------------------
+{
+    if !FORWARDED.contains(&opts.subcmd.name()) {
+        return None;
+    }
+    let socket = socket_path(opts);
+    let stream = UnixStream::connect(&socket).ok()?;
+    let argv = env::args_os()
+        .skip(1)
+        .map(|arg| arg.into_string().ok())
+        .collect::<Option<_>>()?;
+    let request = Request {
+        backend: fs::canonicalize(&opts.backend).ok()?,
+        cwd: env::current_dir().ok()?,
+        argv,
+        env: environment(),
+    };
+    debug!("Forwarding {} to daemon at {}", opts.subcmd.name(), socket.display());
+
+    // Once the request is sent, running the command again would be wrong
+    let response = exchange(stream, &request)
+        .with_context(|| format!("Forwarding to ociplex daemon at {}", socket.display()));
+    match response {
+        Ok(Response::Refused { reason }) => {
+            debug!("Daemon refused the command: {}", reason);
+            None
+        }
+        Ok(Response::Done {
+            output,
+            errors,
+            failure,
+        }) => {
+            let written = io::stderr()
+                .write_all(errors.as_bytes())
+                .and_then(|_| io::stdout().write_all(output.as_bytes()));
+            if let Err(err) = written {
+                return Some(Err(err.into()));
+            }
+            Some(match failure {
+                Some(failure) => Err(failure.into_error()),
+                None => Ok(()),
+            })
+        }
+        Err(err) => Some(Err(err)),
+    }
+}
+
+struct Daemon
+// ----------------------------------------------------------------------------
+//   The state of the daemon, kept across commands
+// ----------------------------------------------------------------------------
+{
+    // The backend configuration, and the file it was read from
+    config: String,
+    path: PathBuf,
+
+    // Backend instances, by global options and environment of the commands
+    backends: HashMap<String, Box<dyn Backend>>,
+}
+
+impl Daemon {
+    fn execute(&mut self, opts: Opts) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Run a command with the backend instance for its options and variables
+    // ------------------------------------------------------------------------
+    //   Backends read variables like XDG_RUNTIME_DIR when instantiated.
+    {
+        let key = format!("{:?} {:?}", opts.global, environment());
+        let backend = match self.backends.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let config: backend::Config =
+                    toml::from_str(&self.config).context("Parsing backend config")?;
+                entry.insert(config.instantiate(opts.global)?)
+            }
+        };
+        match opts.subcmd {
+            Subcommand::Standard(cmd) => backend.standard_command(cmd),
+            Subcommand::CommonCmd(cmd) => backend.common_command(cmd),
+            _ => Err(anyhow!("Subcommand {} cannot be served", opts.subcmd.name())),
+        }
+    }
+
+    fn handle(&mut self, request: Request) -> Response
+    // ------------------------------------------------------------------------
+    //   Run a command for a client, or refuse it
+    // ------------------------------------------------------------------------
+    {
+        let refuse = |reason: String| {
+            debug!("Refusing command: {}", reason);
+            Response::Refused { reason }
+        };
+        if request.backend != self.path {
+            return refuse(format!("Serving {}", self.path.display()));
+        }
+        if fs::read_to_string(&self.path).ok().as_deref() != Some(self.config.as_str()) {
+            return refuse(format!("{} changed since the daemon started", self.path.display()));
+        }
+        let argv = std::iter::once("ociplex".to_string()).chain(request.argv);
+        let opts = match Opts::try_parse_from(argv) {
+            Ok(opts) => opts,
+            Err(err) => return refuse(err.to_string()),
+        };
+        let name = opts.subcmd.name();
+        if !FORWARDED.contains(&name) {
+            return refuse(format!("Subcommand {} is not served", name));
+        }
+        if let Err(err) = env::set_current_dir(&request.cwd) {
+            return refuse(format!("Directory {}: {}", request.cwd.display(), err));
+        }
+
+        info!("Serving {} {}", name, opts.subcmd.container_id().unwrap_or(""));
+        let daemon_env = environment();
+        set_environment(&request.env);
+        let (result, output, errors) = match backend::capture_all(|| self.execute(opts)) {
+            Ok((result, output, errors)) => (result, output, errors),
+            Err(err) => (Err(err.into()), Vec::new(), Vec::new()),
+        };
+        set_environment(&daemon_env);
+        if let Err(err) = &result {
+            debug!("Served {} failed: {:#}", name, err);
+        }
+        Response::Done {
+            output: String::from_utf8_lossy(&output).into_owned(),
+            errors: String::from_utf8_lossy(&errors).into_owned(),
+            failure: result.err().map(|err| Failure::new(&err)),
+        }
+    }
+
+    fn serve(&mut self, stream: UnixStream) -> Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        let request: Request = serde_json::from_str(&line).context("Parsing request")?;
+        let response = self.handle(request);
+        writeln!(&stream, "{}", serde_json::to_string(&response)?)?;
+        Ok(())
+    }
+}
+
+fn listen(socket: &Path, replace: bool) -> Result<UnixListener>
+// ----------------------------------------------------------------------------
+//   Listen on the socket, unless another daemon already does
+// ----------------------------------------------------------------------------
+{
+    if let Some(dir) = socket.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Creating {}", dir.display()))?;
+    }
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(anyhow!("A daemon already listens on {}", socket.display()));
+        }
+        if !replace {
+            return Err(anyhow!("Socket {} exists, use --replace", socket.display()));
+        }
+        fs::remove_file(socket)?;
+    }
+
+    // Clients can run any command with the privileges of the daemon, so the
+    // socket must be private to its user from the moment it exists
+    // SAFETY: the daemon does not create files in other threads yet
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket);
+    // SAFETY: restoring the mask saved above
+    unsafe { libc::umask(umask) };
+    listener.with_context(|| format!("Listening on {}", socket.display()))
+}
+
+pub fn run(serve: &Serve, opts: &Opts, config: &str) -> Result<()>
+// ----------------------------------------------------------------------------
+//   Serve commands from clients until killed
+// ----------------------------------------------------------------------------
+//   Commands are served one at a time, since running one changes the
+//   current directory, environment and standard streams of the process.
+{
+    // Check the configuration now rather than at the first command
+    let checked: backend::Config = toml::from_str(config).context("Parsing backend config")?;
+    if checked.replaces_process() {
+        return Err(anyhow!("The daemon needs a backend not using exec"));
+    }
+    let mut daemon = Daemon {
+        config: config.to_string(),
+        path: fs::canonicalize(&opts.backend)?,
+        backends: HashMap::new(),
+    };
+    let socket = socket_path(opts);
+    let listener = listen(&socket, serve.replace)?;
+    info!("Serving {} on {}", daemon.path.display(), socket.display());
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(err) = daemon.serve(stream) {
+                    warn!("Cannot serve client: {:#}", err);
+                }
+            }
+            Err(err) => warn!("Cannot accept client: {}", err),
+        }
+    }
+    Ok(())
+}
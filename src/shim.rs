@@ -0,0 +1,1476 @@
+// ****************************************************************************
+//  shim.rs                                                     ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Act as a containerd shim v2, running tasks with any ociplex backend
+//
+//     When invoked as `containerd-shim-<name>-v2`, ociplex follows the shim
+//     conventions of containerd: the `start` action launches a shim serving
+//     the Task ttrpc service on a unix socket and prints its address, the
+//     `delete` action cleans up after a shim that is gone. The shim turns
+//     each RPC into commands for the backend configured in the file given
+//     by OCIPLEX_BACKEND, reaps all its children, and publishes task
+//     events with the publish binary given by containerd.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use containerd_shim_protos as protos;
+
+use protobuf::{
+    well_known_types::{any::Any, timestamp::Timestamp},
+    Message, MessageField, MessageFull,
+};
+use protos::events::task::{
+    TaskCreate, TaskDelete, TaskExecAdded, TaskExecStarted, TaskExit, TaskIO, TaskPaused,
+    TaskResumed, TaskStart,
+};
+use protos::cgroups::metrics::{
+    BlkIOEntry, BlkIOStat, CPUStat, CPUUsage, HugetlbStat, MemoryEntry, MemoryStat, Metrics,
+    PidsStat, Throttle,
+};
+use protos::ttrpc::{self, Code, TtrpcContext};
+use protos::{api, topics, Task};
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::iter;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use liboci_cli::GlobalOpts;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::{prelude::*, EnvFilter};
+
+use crate::backend::{self, Backend};
+use crate::{fds, logging, reaper, Subcommand};
+
+// Backend configuration, unless given by OCIPLEX_BACKEND
+const DEFAULT_BACKEND: &str = "/etc/ociplex.toml";
+
+// The listening socket, passed by the start action to the shim it launches
+const LISTENER_FD: RawFd = 3;
+
+// How often the shim checks if the processes of its tasks have exited
+const REAP_INTERVAL: Duration = Duration::from_millis(50);
+
+// How long the reply to a shutdown request has to reach containerd
+const SHUTDOWN_DELAY: Duration = Duration::from_millis(100);
+
+// Exit status of a task whose actual status cannot be known
+const UNKNOWN_EXIT: u32 = 255;
+
+// Exit status reported by the delete action, as if the task was killed
+const KILLED_EXIT: u32 = 128 + libc::SIGKILL as u32;
+
+// Type of the statistics of a task, as containerd decodes them
+const METRICS_TYPE: &str = "io.containerd.cgroups.v1.Metrics";
+
+pub fn invoked() -> bool
+// ----------------------------------------------------------------------------
+//   Check if ociplex was invoked by containerd as a shim
+// ----------------------------------------------------------------------------
+{
+    env::args_os().next().is_some_and(|arg0| {
+        Path::new(&arg0)
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("containerd-shim-"))
+    })
+}
+
+#[derive(Debug, Default)]
+struct Flags
+// ----------------------------------------------------------------------------
+//   The flags containerd passes to shims, Go style, and the action
+// ----------------------------------------------------------------------------
+{
+    namespace: String,
+    address: String,
+    publish_binary: String,
+    id: String,
+    bundle: Option<PathBuf>,
+    debug: bool,
+
+    // `start`, `delete`, or none to serve the Task service
+    action: Option<String>,
+}
+
+impl Flags {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Flags>
+    // ------------------------------------------------------------------------
+    //   Parse `-flag value`, `-flag=value` or `--flag value`, then the action
+    // ------------------------------------------------------------------------
+    //   Unknown flags are assumed to take a value, which is ignored.
+    {
+        let mut flags = Flags::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix('-') else {
+                flags.action = Some(arg);
+                continue;
+            };
+            let name = name.trim_start_matches('-');
+            let (name, inline) = match name.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (name, None),
+            };
+            if name == "debug" {
+                flags.debug = inline.is_none_or(|value| value == "true");
+                continue;
+            }
+            let value = match inline {
+                Some(value) => value,
+                None => args
+                    .next()
+                    .ok_or_else(|| anyhow!("Flag -{} needs a value", name))?,
+            };
+            match name {
+                "namespace" => flags.namespace = value,
+                "address" => flags.address = value,
+                "publish-binary" => flags.publish_binary = value,
+                "id" => flags.id = value,
+                "bundle" => flags.bundle = Some(PathBuf::from(value)),
+                _ => {}
+            }
+        }
+        if flags.id.is_empty() {
+            return Err(anyhow!("Missing -id flag"));
+        }
+        Ok(flags)
+    }
+
+    fn bundle(&self) -> Result<PathBuf>
+    // ------------------------------------------------------------------------
+    //   The bundle of the task, which containerd also makes our directory
+    // ------------------------------------------------------------------------
+    {
+        match &self.bundle {
+            Some(bundle) => Ok(bundle.clone()),
+            None => env::current_dir().context("Finding the bundle directory"),
+        }
+    }
+
+    fn args(&self) -> Vec<String>
+    // ------------------------------------------------------------------------
+    //   The flags for the shim launched by the start action
+    // ------------------------------------------------------------------------
+    {
+        let mut args = Vec::new();
+        for (name, value) in [
+            ("-namespace", &self.namespace),
+            ("-address", &self.address),
+            ("-publish-binary", &self.publish_binary),
+            ("-id", &self.id),
+        ] {
+            args.push(name.to_string());
+            args.push(value.clone());
+        }
+        if let Some(bundle) = &self.bundle {
+            args.push("-bundle".to_string());
+            args.push(bundle.to_string_lossy().into_owned());
+        }
+        if self.debug {
+            args.push("-debug".to_string());
+        }
+        args
+    }
+
+    fn socket(&self) -> PathBuf
+    // ------------------------------------------------------------------------
+    //   The socket of the shim for this task, short enough for a unix socket
+    // ------------------------------------------------------------------------
+    {
+        let key = format!("{}\0{}\0{}", self.address, self.namespace, self.id);
+        let digest: String = Sha256::digest(key.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        backend::default_state_dir().join("s").join(&digest[..32])
+    }
+
+    fn global(&self) -> GlobalOpts
+    // ------------------------------------------------------------------------
+    //   Global options for the backend, as if given on the command line
+    // ------------------------------------------------------------------------
+    {
+        GlobalOpts {
+            log: None,
+            debug: self.debug,
+            log_format: None,
+            root: None,
+            systemd_cgroup: false,
+        }
+    }
+}
+
+fn load_config() -> Result<backend::Config>
+// ----------------------------------------------------------------------------
+//   Read the backend config, which must leave the shim process in place
+// ----------------------------------------------------------------------------
+{
+    let path = env::var_os("OCIPLEX_BACKEND").map_or_else(|| PathBuf::from(DEFAULT_BACKEND), PathBuf::from);
+    let text = fs::read_to_string(&path)
+        .with_context(|| format!("Reading backend config {}", path.display()))?;
+    let config: backend::Config = toml::from_str(&text)
+        .with_context(|| format!("Parsing backend config {}", path.display()))?;
+    if config.replaces_process() {
+        return Err(anyhow!("The shim needs a backend not using exec"));
+    }
+    Ok(config)
+}
+
+fn command<S: AsRef<OsStr>>(args: &[S]) -> Result<Subcommand>
+// ----------------------------------------------------------------------------
+//   Build a backend command from its command-line arguments
+// ----------------------------------------------------------------------------
+{
+    let argv = iter::once(OsString::from("ociplex")).chain(args.iter().map(OsString::from));
+    Subcommand::try_parse_from(argv).context("Building backend command")
+}
+
+fn execute(backend: &dyn Backend, cmd: Subcommand) -> Result<()> {
+    match cmd {
+        Subcommand::Standard(cmd) => backend.standard_command(cmd),
+        Subcommand::CommonCmd(cmd) => backend.common_command(cmd),
+        _ => Err(anyhow!("Not a runtime command")),
+    }
+}
+
+fn set_tracing(flags: &Flags, log: Option<&Path>) -> Result<()>
+// ----------------------------------------------------------------------------
+//   Log to the given file, or stderr where containerd reports it for actions
+// ----------------------------------------------------------------------------
+{
+    let level = if flags.debug { "debug" } else { "warn" };
+    let filter = EnvFilter::try_from_env("OCIPLEX_LOG").unwrap_or_else(|_| EnvFilter::from(level));
+    let log_layer = logging::OciLogLayer::new(log, logging::Format::Text)?;
+    tracing_subscriber::registry().with(filter).with(log_layer).init();
+    Ok(())
+}
+
+pub fn main() -> i32
+// ----------------------------------------------------------------------------
+//   Entry point when invoked as a shim, returns the exit status
+// ----------------------------------------------------------------------------
+{
+    if let Err(e) = fds::reserve(0) {
+        eprintln!("ociplex shim: Reserving file descriptors: {}", e);
+        return 1;
+    }
+    let flags = match Flags::parse(env::args().skip(1)) {
+        Ok(flags) => flags,
+        Err(e) => {
+            eprintln!("ociplex shim: {:#}", e);
+            return 1;
+        }
+    };
+
+    // containerd only reads the log FIFO of the bundle while the shim serves
+    let bundle = flags.bundle();
+    let log = match (&flags.action, &bundle) {
+        (None, Ok(bundle)) => Some(bundle.join("log")),
+        _ => None,
+    };
+    if let Err(e) = set_tracing(&flags, log.as_deref()) {
+        eprintln!("ociplex shim: {:#}", e);
+        return 1;
+    }
+
+    let result = bundle.and_then(|bundle| match flags.action.as_deref() {
+        Some("start") => start(&flags),
+        Some("delete") => delete(&flags, &bundle),
+        Some(action) => Err(anyhow!("Unknown shim action {}", action)),
+        None => serve(&flags, &bundle),
+    });
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("{:#}", e);
+            1
+        }
+    }
+}
+
+fn start(flags: &Flags) -> Result<()>
+// ----------------------------------------------------------------------------
+//   Launch a shim listening on the socket for the task, print its address
+// ----------------------------------------------------------------------------
+{
+    // Report a bad config to containerd now, rather than at the first RPC
+    load_config()?;
+
+    let socket = flags.socket();
+    let address = format!("unix://{}", socket.display());
+    if UnixStream::connect(&socket).is_ok() {
+        // A shim already serves this ID, containerd can reuse it
+        println!("{}", address);
+        return Ok(());
+    }
+    if let Some(dir) = socket.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Creating {}", dir.display()))?;
+    }
+    let _ = fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket)
+        .with_context(|| format!("Listening on {}", socket.display()))?;
+    fs::set_permissions(&socket, fs::Permissions::from_mode(0o600))?;
+
+    // Keep the name we were invoked with, which selects shim mode
+    let mut cmd = Command::new(env::current_exe()?);
+    if let Some(arg0) = env::args_os().next() {
+        cmd.arg0(arg0);
+    }
+    cmd.args(flags.args())
+        .current_dir(flags.bundle()?)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    let fd = listener.as_raw_fd();
+    // SAFETY: only async-signal-safe calls between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::setsid() < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // dup2 clears close-on-exec, except on the fd itself
+            let moved = if fd == LISTENER_FD {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, LISTENER_FD)
+            };
+            if moved < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = cmd.spawn().context("Launching the shim")?;
+    info!("Shim {} serves {} on {}", child.id(), flags.id, socket.display());
+    println!("{}", address);
+    Ok(())
+}
+
+fn delete(flags: &Flags, bundle: &Path) -> Result<()>
+// ----------------------------------------------------------------------------
+//   Clean up after a shim that is gone, print the result for containerd
+// ----------------------------------------------------------------------------
+{
+    let backend = load_config()?.instantiate(flags.global())?;
+    let cmd = command(&["delete", "--force", &flags.id])?;
+    match backend::capture(|| execute(backend.as_ref(), cmd)) {
+        Ok((Ok(()), _)) => {}
+        Ok((Err(err), _)) => warn!("Cannot delete {}: {:#}", flags.id, err),
+        Err(err) => warn!("Cannot delete {}: {}", flags.id, err),
+    }
+    unmount(&bundle.join("rootfs"));
+    let response = api::DeleteResponse {
+        exit_status: KILLED_EXIT,
+        exited_at: MessageField::some(Timestamp::now()),
+        ..Default::default()
+    };
+    io::stdout().write_all(&response.write_to_bytes()?)?;
+    Ok(())
+}
+
+fn serve(flags: &Flags, bundle: &Path) -> Result<()>
+// ----------------------------------------------------------------------------
+//   Serve the Task service on the socket passed by the start action
+// ----------------------------------------------------------------------------
+{
+    // SAFETY: plain libc calls, the fd was passed by the start action
+    unsafe {
+        if libc::fcntl(LISTENER_FD, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error()).context("Finding the shim socket");
+        }
+        // Adopt containers whose runtime exits once they are started
+        if libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) < 0 {
+            warn!("Cannot become a subreaper: {}", io::Error::last_os_error());
+        }
+    }
+
+    // Backend commands get the status of the runtimes they run from the reaper
+    let reaper = reaper::start();
+    let worker = Worker::start(load_config()?, flags.global())?;
+    let shared = Arc::new(Shared {
+        containers: Mutex::default(),
+        exited: Condvar::new(),
+        events: Events::start(flags),
+    });
+    let reaped = shared.clone();
+    thread::spawn(move || reap(reaper, &reaped));
+
+    let (shutdown, stopped) = mpsc::channel();
+    let service: Box<dyn Task + Send + Sync> = Box::new(Service {
+        worker,
+        shared,
+        shutdown: Mutex::new(shutdown),
+    });
+    let mut server = ttrpc::Server::new()
+        .add_listener(LISTENER_FD)?
+        .register_service(protos::create_task(Arc::new(service)));
+    server.start()?;
+    info!("Serving task {} of {}", flags.id, bundle.display());
+
+    let _ = stopped.recv();
+    thread::sleep(SHUTDOWN_DELAY);
+    server.shutdown();
+    let _ = fs::remove_file(flags.socket());
+    info!("Shim for task {} shut down", flags.id);
+    Ok(())
+}
+
+type Job = Box<dyn FnOnce(&dyn Backend) + Send>;
+
+struct Worker
+// ----------------------------------------------------------------------------
+//   A thread owning the backend, running commands one at a time
+// ----------------------------------------------------------------------------
+//   Backends are not thread-safe, and commands redirect the standard
+//   streams of the process, so RPCs hand their commands to this thread.
+{
+    jobs: Mutex<Sender<Job>>,
+}
+
+impl Worker {
+    fn start(config: backend::Config, global: GlobalOpts) -> Result<Worker> {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let (ready, started) = mpsc::channel();
+        thread::spawn(move || {
+            let backend = match config.instantiate(global) {
+                Ok(backend) => backend,
+                Err(err) => {
+                    let _ = ready.send(Err(err));
+                    return;
+                }
+            };
+            let _ = ready.send(Ok(()));
+            for job in queue {
+                job(backend.as_ref());
+            }
+        });
+        started
+            .recv()
+            .map_err(|_| anyhow!("Backend worker stopped"))??;
+        Ok(Worker {
+            jobs: Mutex::new(jobs),
+        })
+    }
+
+    fn call<T: Send + 'static>(
+        &self,
+        job: impl FnOnce(&dyn Backend) -> T + Send + 'static,
+    ) -> Result<T> {
+        let (reply, result) = mpsc::channel();
+        let job: Job = Box::new(move |backend| {
+            let _ = reply.send(job(backend));
+        });
+        let stopped = || anyhow!("Backend worker stopped");
+        self.jobs.lock().unwrap().send(job).map_err(|_| stopped())?;
+        result.recv().map_err(|_| stopped())
+    }
+
+    fn run(&self, cmd: Subcommand) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Run a command in the backend
+    // ------------------------------------------------------------------------
+    {
+        self.call(move |backend| execute(backend, cmd))?
+    }
+
+    fn output(&self, cmd: Subcommand) -> Result<Vec<u8>>
+    // ------------------------------------------------------------------------
+    //   Run a command in the backend, and return what it prints
+    // ------------------------------------------------------------------------
+    {
+        let (result, output) = self.call(move |backend| backend::capture(|| execute(backend, cmd)))??;
+        result.map(|()| output)
+    }
+
+    fn spawn(&self, cmd: Subcommand, io: &Io) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Run a command starting a process with the standard streams of a task
+    // ------------------------------------------------------------------------
+    {
+        let stdio = io.open()?;
+        self.call(move |backend| backend::attach(&stdio, || execute(backend, cmd)))??
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Io
+// ----------------------------------------------------------------------------
+//   The standard streams containerd gives a task, usually FIFOs
+// ----------------------------------------------------------------------------
+{
+    stdin: String,
+    stdout: String,
+    stderr: String,
+}
+
+impl Io {
+    fn open(&self) -> Result<[File; 3]>
+    // ------------------------------------------------------------------------
+    //   Open the streams, /dev/null for those containerd did not set
+    // ------------------------------------------------------------------------
+    {
+        let open = |path: &str, options: &mut OpenOptions| {
+            let path = if path.is_empty() { "/dev/null" } else { path };
+            options
+                .open(path)
+                .with_context(|| format!("Opening {}", path))
+        };
+
+        // Opening a FIFO for reading would wait for a writer
+        let stdin = open(
+            &self.stdin,
+            OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK),
+        )?;
+        // SAFETY: plain libc call on an open fd
+        if unsafe { libc::fcntl(stdin.as_raw_fd(), libc::F_SETFL, 0) } < 0 {
+            return Err(io::Error::last_os_error()).context("Opening stdin");
+        }
+        let stdout = open(&self.stdout, OpenOptions::new().append(true))?;
+        let stderr = open(&self.stderr, OpenOptions::new().append(true))?;
+        Ok([stdin, stdout, stderr])
+    }
+
+    fn task_io(&self) -> TaskIO {
+        TaskIO {
+            stdin: self.stdin.clone(),
+            stdout: self.stdout.clone(),
+            stderr: self.stderr.clone(),
+            terminal: false,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Process
+// ----------------------------------------------------------------------------
+//   The init or exec process of a task
+// ----------------------------------------------------------------------------
+{
+    // Zero until the process is started
+    pid: u32,
+    io: Io,
+    exit: Option<(u32, Timestamp)>,
+}
+
+impl Process {
+    fn exited(&self) -> (u32, MessageField<Timestamp>) {
+        match &self.exit {
+            Some((status, at)) => (*status, MessageField::some(at.clone())),
+            None => (0, MessageField::none()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Container
+// ----------------------------------------------------------------------------
+//   A task created by containerd, and the processes running in it
+// ----------------------------------------------------------------------------
+{
+    bundle: PathBuf,
+
+    // Whether the shim mounted the root filesystem of the bundle
+    mounted: bool,
+
+    init: Process,
+    execs: HashMap<String, Process>,
+}
+
+impl Container {
+    fn spec(&self, exec_id: &str) -> PathBuf {
+        self.bundle.join(format!("exec-{}.json", exec_id))
+    }
+
+    fn pid_file(&self, exec_id: &str) -> PathBuf {
+        match exec_id {
+            "" => self.bundle.join("init.pid"),
+            _ => self.bundle.join(format!("exec-{}.pid", exec_id)),
+        }
+    }
+}
+
+struct Shared
+// ----------------------------------------------------------------------------
+//   State shared by the service and the thread reaping processes
+// ----------------------------------------------------------------------------
+{
+    containers: Mutex<HashMap<String, Container>>,
+    exited: Condvar,
+    events: Events,
+}
+
+struct Events
+// ----------------------------------------------------------------------------
+//   Publish events to containerd from a thread, keeping them in order
+// ----------------------------------------------------------------------------
+{
+    queue: Mutex<Sender<(&'static str, Vec<u8>)>>,
+}
+
+impl Events {
+    fn start(flags: &Flags) -> Events {
+        let (queue, pending) = mpsc::channel::<(&'static str, Vec<u8>)>();
+        let publisher = Publisher {
+            binary: flags.publish_binary.clone(),
+            address: flags.address.clone(),
+            namespace: flags.namespace.clone(),
+        };
+        thread::spawn(move || {
+            for (topic, event) in pending {
+                debug!("Publishing {}", topic);
+                if let Err(err) = publisher.send(topic, &event) {
+                    warn!("Cannot publish {}: {:#}", topic, err);
+                }
+            }
+        });
+        Events {
+            queue: Mutex::new(queue),
+        }
+    }
+
+    fn publish<M: MessageFull>(&self, topic: &'static str, event: &M) {
+        match Any::pack(event).and_then(|any| any.write_to_bytes()) {
+            Ok(event) => {
+                let _ = self.queue.lock().unwrap().send((topic, event));
+            }
+            Err(err) => warn!("Cannot encode {}: {}", topic, err),
+        }
+    }
+}
+
+struct Publisher
+// ----------------------------------------------------------------------------
+//   How to reach containerd for events, as given in the shim flags
+// ----------------------------------------------------------------------------
+{
+    binary: String,
+    address: String,
+    namespace: String,
+}
+
+impl Publisher {
+    fn send(&self, topic: &str, event: &[u8]) -> Result<()>
+    // ------------------------------------------------------------------------
+    //   Run the publish binary with the event on its stdin
+    // ------------------------------------------------------------------------
+    {
+        if self.binary.is_empty() {
+            return Ok(());
+        }
+        let mut child = Command::new(&self.binary)
+            .args(["--address", &self.address, "publish"])
+            .args(["--topic", topic, "--namespace", &self.namespace])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Running {}", self.binary))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(event)?;
+        }
+        let mut stderr = String::new();
+        if let Some(mut pipe) = child.stderr.take() {
+            pipe.read_to_string(&mut stderr)?;
+        }
+        let status = reaper::wait(&mut child)?;
+        if !status.success() {
+            return Err(anyhow!("{} {}: {}", self.binary, status, stderr.trim()));
+        }
+        Ok(())
+    }
+}
+
+fn reap(reaper: &reaper::Reaper, shared: &Shared)
+// ----------------------------------------------------------------------------
+//   Record the exit of the processes of tasks, and wake up waiters
+// ----------------------------------------------------------------------------
+//   All children are reaped by the reaper, the processes of tasks are
+//   matched with the statuses it keeps.
+{
+    loop {
+        thread::sleep(REAP_INTERVAL);
+        let mut exits = Vec::new();
+        let mut containers = shared.containers.lock().unwrap();
+        for (id, container) in containers.iter_mut() {
+            let execs = container
+                .execs
+                .iter_mut()
+                .map(|(exec_id, process)| (exec_id.as_str(), process));
+            for (exec_id, process) in iter::once((id.as_str(), &mut container.init)).chain(execs) {
+                if process.pid == 0 || process.exit.is_some() {
+                    continue;
+                }
+                if let Some(status) = reaper.exit_status(process.pid).map(exit_code) {
+                    debug!("Process {} of {} exited with {}", exec_id, id, status);
+                    let exited_at = Timestamp::now();
+                    exits.push(TaskExit {
+                        container_id: id.clone(),
+                        id: exec_id.to_string(),
+                        pid: process.pid,
+                        exit_status: status,
+                        exited_at: MessageField::some(exited_at.clone()),
+                        ..Default::default()
+                    });
+                    process.exit = Some((status, exited_at));
+                }
+            }
+        }
+        drop(containers);
+        if !exits.is_empty() {
+            shared.exited.notify_all();
+            for exit in exits {
+                shared.events.publish(topics::TASK_EXIT_EVENT_TOPIC, &exit);
+            }
+        }
+    }
+}
+
+fn exit_code(status: ExitStatus) -> u32
+// ----------------------------------------------------------------------------
+//   The exit status of a process, like a shell reports it
+// ----------------------------------------------------------------------------
+{
+    match status.signal() {
+        Some(signal) => 128 + signal as u32,
+        None => status.code().map_or(UNKNOWN_EXIT, |code| code as u32),
+    }
+}
+
+fn mount_options(options: &[String]) -> (libc::c_ulong, String)
+// ----------------------------------------------------------------------------
+//   Split mount options into flags and filesystem data
+// ----------------------------------------------------------------------------
+{
+    let mut flags = 0;
+    let mut data = Vec::new();
+    for option in options {
+        match option.as_str() {
+            "ro" => flags |= libc::MS_RDONLY,
+            "rw" | "defaults" => {}
+            "nosuid" => flags |= libc::MS_NOSUID,
+            "nodev" => flags |= libc::MS_NODEV,
+            "noexec" => flags |= libc::MS_NOEXEC,
+            "bind" => flags |= libc::MS_BIND,
+            "rbind" => flags |= libc::MS_BIND | libc::MS_REC,
+            other => data.push(other),
+        }
+    }
+    (flags, data.join(","))
+}
+
+fn mount_all(mounts: &[api::Mount], target: &Path) -> Result<()>
+// ----------------------------------------------------------------------------
+//   Mount the root filesystem of a task, as given by containerd
+// ----------------------------------------------------------------------------
+{
+    let target_path = CString::new(target.as_os_str().as_bytes())?;
+    for (index, mount) in mounts.iter().enumerate() {
+        let (flags, data) = mount_options(&mount.options);
+        let source = CString::new(mount.source.as_str())?;
+        let fstype = CString::new(mount.type_.as_str())?;
+        let data = CString::new(data)?;
+        // SAFETY: all arguments are valid C strings
+        let mounted = unsafe {
+            libc::mount(
+                source.as_ptr(),
+                target_path.as_ptr(),
+                fstype.as_ptr(),
+                flags,
+                data.as_ptr().cast(),
+            )
+        };
+        if mounted < 0 {
+            let err = io::Error::last_os_error();
+            if index > 0 {
+                unmount(target);
+            }
+            return Err(err)
+                .with_context(|| format!("Mounting {} on {}", mount.source, target.display()));
+        }
+    }
+    Ok(())
+}
+
+fn unmount(target: &Path) {
+    let Ok(path) = CString::new(target.as_os_str().as_bytes()) else {
+        return;
+    };
+    // SAFETY: path is a valid C string
+    if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } < 0 {
+        debug!("Cannot unmount {}: {}", target.display(), io::Error::last_os_error());
+    }
+}
+
+fn read_pid(path: &Path) -> Result<u32> {
+    let text = fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+    text.trim()
+        .parse()
+        .with_context(|| format!("Invalid pid in {}", path.display()))
+}
+
+fn rpc_error(code: Code, message: impl ToString) -> ttrpc::Error {
+    ttrpc::Error::RpcStatus(ttrpc::get_status(code, message))
+}
+
+fn failed(err: anyhow::Error) -> ttrpc::Error
+// ----------------------------------------------------------------------------
+//   Report a backend failure to containerd
+// ----------------------------------------------------------------------------
+{
+    let code = match err.downcast_ref::<backend::Error>() {
+        Some(backend::Error::Unsupported { .. }) => Code::UNIMPLEMENTED,
+        _ => Code::UNKNOWN,
+    };
+    rpc_error(code, format!("{:#}", err))
+}
+
+fn not_found(id: &str, exec_id: &str) -> ttrpc::Error {
+    match exec_id {
+        "" => rpc_error(Code::NOT_FOUND, format!("Task {} not found", id)),
+        _ => rpc_error(Code::NOT_FOUND, format!("Process {} of task {} not found", exec_id, id)),
+    }
+}
+
+fn find<'a>(
+    containers: &'a HashMap<String, Container>,
+    id: &str,
+    exec_id: &str,
+) -> ttrpc::Result<&'a Process> {
+    let container = containers.get(id).ok_or_else(|| not_found(id, ""))?;
+    match exec_id {
+        "" => Ok(&container.init),
+        _ => container.execs.get(exec_id).ok_or_else(|| not_found(id, exec_id)),
+    }
+}
+
+fn status(state: &[u8]) -> Result<api::Status>
+// ----------------------------------------------------------------------------
+//   The task status for the output of the `state` command
+// ----------------------------------------------------------------------------
+{
+    let state: Value = serde_json::from_slice(state).context("Parsing container state")?;
+    Ok(match state["status"].as_str() {
+        Some("created") => api::Status::CREATED,
+        Some("running") => api::Status::RUNNING,
+        Some("stopped") => api::Status::STOPPED,
+        Some("paused") => api::Status::PAUSED,
+        Some("pausing") => api::Status::PAUSING,
+        _ => api::Status::UNKNOWN,
+    })
+}
+
+fn metrics(events: &[u8]) -> Result<Metrics>
+// ----------------------------------------------------------------------------
+//   The cgroup metrics for the statistics printed by `events --stats`
+// ----------------------------------------------------------------------------
+//   Runtimes print the statistics of runc, whose memory details are the
+//   raw contents of memory.stat. Missing values are left at zero.
+{
+    let text = String::from_utf8_lossy(events);
+    let line = text.lines().next().ok_or_else(|| anyhow!("No statistics"))?;
+    let event: Value = serde_json::from_str(line).context("Parsing statistics")?;
+    let data = &event["data"];
+    let number = |value: &Value, key: &str| value[key].as_u64().unwrap_or(0);
+
+    let mut metrics = Metrics::new();
+    if let Some(pids) = data.get("pids") {
+        metrics.pids = MessageField::some(PidsStat {
+            current: number(pids, "current"),
+            limit: number(pids, "limit"),
+            ..Default::default()
+        });
+    }
+    if let Some(cpu) = data.get("cpu") {
+        let (usage, throttling) = (&cpu["usage"], &cpu["throttling"]);
+        let per_cpu = usage["percpu"].as_array().into_iter().flatten();
+        metrics.cpu = MessageField::some(CPUStat {
+            usage: MessageField::some(CPUUsage {
+                total: number(usage, "total"),
+                kernel: number(usage, "kernel"),
+                user: number(usage, "user"),
+                per_cpu: per_cpu.filter_map(Value::as_u64).collect(),
+                ..Default::default()
+            }),
+            throttling: MessageField::some(Throttle {
+                periods: number(throttling, "periods"),
+                throttled_periods: number(throttling, "throttledPeriods"),
+                throttled_time: number(throttling, "throttledTime"),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+    }
+    if let Some(memory) = data.get("memory") {
+        let entry = |key: &str| {
+            let entry = &memory[key];
+            MessageField::some(MemoryEntry {
+                limit: number(entry, "limit"),
+                usage: number(entry, "usage"),
+                max: number(entry, "max"),
+                failcnt: number(entry, "failcnt"),
+                ..Default::default()
+            })
+        };
+        let raw = |key: &str| number(&memory["raw"], key);
+        metrics.memory = MessageField::some(MemoryStat {
+            cache: number(memory, "cache"),
+            rss: raw("rss"),
+            rss_huge: raw("rss_huge"),
+            mapped_file: raw("mapped_file"),
+            dirty: raw("dirty"),
+            writeback: raw("writeback"),
+            pg_pg_in: raw("pgpgin"),
+            pg_pg_out: raw("pgpgout"),
+            pg_fault: raw("pgfault"),
+            pg_maj_fault: raw("pgmajfault"),
+            inactive_anon: raw("inactive_anon"),
+            active_anon: raw("active_anon"),
+            inactive_file: raw("inactive_file"),
+            active_file: raw("active_file"),
+            unevictable: raw("unevictable"),
+            hierarchical_memory_limit: raw("hierarchical_memory_limit"),
+            hierarchical_swap_limit: raw("hierarchical_memsw_limit"),
+            total_cache: raw("total_cache"),
+            total_rss: raw("total_rss"),
+            total_rss_huge: raw("total_rss_huge"),
+            total_mapped_file: raw("total_mapped_file"),
+            total_dirty: raw("total_dirty"),
+            total_writeback: raw("total_writeback"),
+            total_pg_pg_in: raw("total_pgpgin"),
+            total_pg_pg_out: raw("total_pgpgout"),
+            total_pg_fault: raw("total_pgfault"),
+            total_pg_maj_fault: raw("total_pgmajfault"),
+            total_inactive_anon: raw("total_inactive_anon"),
+            total_active_anon: raw("total_active_anon"),
+            total_inactive_file: raw("total_inactive_file"),
+            total_active_file: raw("total_active_file"),
+            total_unevictable: raw("total_unevictable"),
+            usage: entry("usage"),
+            swap: entry("swap"),
+            kernel: entry("kernel"),
+            kernel_tcp: entry("kernelTCP"),
+            ..Default::default()
+        });
+    }
+    if let Some(hugetlb) = data["hugetlb"].as_object() {
+        for (pagesize, stat) in hugetlb {
+            metrics.hugetlb.push(HugetlbStat {
+                usage: number(stat, "usage"),
+                max: number(stat, "max"),
+                failcnt: number(stat, "failcnt"),
+                pagesize: pagesize.clone(),
+                ..Default::default()
+            });
+        }
+    }
+    if let Some(blkio) = data.get("blkio") {
+        let entries = |key: &str| -> Vec<BlkIOEntry> {
+            let entries = blkio[key].as_array().into_iter().flatten();
+            entries
+                .map(|entry| BlkIOEntry {
+                    op: entry["op"].as_str().unwrap_or_default().to_string(),
+                    major: number(entry, "major"),
+                    minor: number(entry, "minor"),
+                    value: number(entry, "value"),
+                    ..Default::default()
+                })
+                .collect()
+        };
+        metrics.blkio = MessageField::some(BlkIOStat {
+            io_service_bytes_recursive: entries("ioServiceBytesRecursive"),
+            io_serviced_recursive: entries("ioServicedRecursive"),
+            io_queued_recursive: entries("ioQueueRecursive"),
+            io_service_time_recursive: entries("ioServiceTimeRecursive"),
+            io_wait_time_recursive: entries("ioWaitTimeRecursive"),
+            io_merged_recursive: entries("ioMergedRecursive"),
+            io_time_recursive: entries("ioTimeRecursive"),
+            sectors_recursive: entries("sectorsRecursive"),
+            ..Default::default()
+        });
+    }
+    Ok(metrics)
+}
+
+struct Service
+// ----------------------------------------------------------------------------
+//   The Task service, running tasks with the backend
+// ----------------------------------------------------------------------------
+{
+    worker: Worker,
+    shared: Arc<Shared>,
+    shutdown: Mutex<Sender<()>>,
+}
+
+impl Service {
+    fn container<T>(&self, id: &str, get: impl FnOnce(&Container) -> T) -> ttrpc::Result<T> {
+        let containers = self.shared.containers.lock().unwrap();
+        containers.get(id).map(get).ok_or_else(|| not_found(id, ""))
+    }
+
+    fn start_exec(&self, id: &str, exec_id: &str) -> ttrpc::Result<u32>
+    // ------------------------------------------------------------------------
+    //   Run an exec process added earlier, with its own standard streams
+    // ------------------------------------------------------------------------
+    {
+        let (spec, pid_file, io) = {
+            let containers = self.shared.containers.lock().unwrap();
+            let process = find(&containers, id, exec_id)?;
+            if process.pid != 0 {
+                return Err(rpc_error(Code::FAILED_PRECONDITION, "Process already started"));
+            }
+            let container = &containers[id];
+            (container.spec(exec_id), container.pid_file(exec_id), process.io.clone())
+        };
+        let args = [
+            OsStr::new("exec"),
+            OsStr::new("--process"),
+            spec.as_os_str(),
+            OsStr::new("--detach"),
+            OsStr::new("--pid-file"),
+            pid_file.as_os_str(),
+            OsStr::new(id),
+        ];
+        let pid = command(&args)
+            .and_then(|cmd| self.worker.spawn(cmd, &io))
+            .and_then(|()| read_pid(&pid_file))
+            .map_err(failed)?;
+
+        let mut containers = self.shared.containers.lock().unwrap();
+        let container = containers.get_mut(id).ok_or_else(|| not_found(id, ""))?;
+        let process = container.execs.get_mut(exec_id).ok_or_else(|| not_found(id, exec_id))?;
+        process.pid = pid;
+        Ok(pid)
+    }
+}
+
+impl Task for Service {
+    fn create(
+        &self,
+        _ctx: &TtrpcContext,
+        req: api::CreateTaskRequest,
+    ) -> ttrpc::Result<api::CreateTaskResponse> {
+        info!("Creating task {} in {}", req.id, req.bundle);
+        if req.terminal {
+            return Err(rpc_error(Code::UNIMPLEMENTED, "Terminals are not supported by ociplex"));
+        }
+        if !req.checkpoint.is_empty() {
+            return Err(rpc_error(Code::UNIMPLEMENTED, "Restoring checkpoints is not supported by ociplex"));
+        }
+        if self.shared.containers.lock().unwrap().contains_key(&req.id) {
+            return Err(rpc_error(Code::ALREADY_EXISTS, format!("Task {} already exists", req.id)));
+        }
+
+        let bundle = PathBuf::from(&req.bundle);
+        let rootfs = bundle.join("rootfs");
+        let mounted = !req.rootfs.is_empty();
+        mount_all(&req.rootfs, &rootfs).map_err(failed)?;
+        let mut container = Container {
+            bundle,
+            mounted,
+            init: Process::default(),
+            execs: HashMap::new(),
+        };
+        container.init.io = Io {
+            stdin: req.stdin.clone(),
+            stdout: req.stdout.clone(),
+            stderr: req.stderr.clone(),
+        };
+        let pid_file = container.pid_file("");
+        let args = [
+            OsStr::new("create"),
+            OsStr::new("--bundle"),
+            container.bundle.as_os_str(),
+            OsStr::new("--pid-file"),
+            pid_file.as_os_str(),
+            OsStr::new(&req.id),
+        ];
+        let created = command(&args)
+            .and_then(|cmd| self.worker.spawn(cmd, &container.init.io))
+            .and_then(|()| read_pid(&pid_file));
+        let pid = match created {
+            Ok(pid) => pid,
+            Err(err) => {
+                if mounted {
+                    unmount(&rootfs);
+                }
+                return Err(failed(err));
+            }
+        };
+        container.init.pid = pid;
+
+        let event = TaskCreate {
+            container_id: req.id.clone(),
+            bundle: req.bundle.clone(),
+            rootfs: req.rootfs.clone(),
+            io: MessageField::some(container.init.io.task_io()),
+            pid,
+            ..Default::default()
+        };
+        self.shared.containers.lock().unwrap().insert(req.id, container);
+        self.shared.events.publish(topics::TASK_CREATE_EVENT_TOPIC, &event);
+        Ok(api::CreateTaskResponse {
+            pid,
+            ..Default::default()
+        })
+    }
+
+    fn start(&self, _ctx: &TtrpcContext, req: api::StartRequest) -> ttrpc::Result<api::StartResponse> {
+        info!("Starting task {} {}", req.id, req.exec_id);
+        let pid = if req.exec_id.is_empty() {
+            let pid = self.container(&req.id, |container| container.init.pid)?;
+            command(&["start", &req.id])
+                .and_then(|cmd| self.worker.run(cmd))
+                .map_err(failed)?;
+            let event = TaskStart {
+                container_id: req.id.clone(),
+                pid,
+                ..Default::default()
+            };
+            self.shared.events.publish(topics::TASK_START_EVENT_TOPIC, &event);
+            pid
+        } else {
+            let pid = self.start_exec(&req.id, &req.exec_id)?;
+            let event = TaskExecStarted {
+                container_id: req.id.clone(),
+                exec_id: req.exec_id.clone(),
+                pid,
+                ..Default::default()
+            };
+            self.shared.events.publish(topics::TASK_EXEC_STARTED_EVENT_TOPIC, &event);
+            pid
+        };
+        Ok(api::StartResponse {
+            pid,
+            ..Default::default()
+        })
+    }
+
+    fn delete(&self, _ctx: &TtrpcContext, req: api::DeleteRequest) -> ttrpc::Result<api::DeleteResponse> {
+        info!("Deleting task {} {}", req.id, req.exec_id);
+        if !req.exec_id.is_empty() {
+            let mut containers = self.shared.containers.lock().unwrap();
+            let process = find(&containers, &req.id, &req.exec_id)?;
+            if process.pid != 0 && process.exit.is_none() {
+                return Err(rpc_error(Code::FAILED_PRECONDITION, "Process is still running"));
+            }
+            let container = containers.get_mut(&req.id).ok_or_else(|| not_found(&req.id, ""))?;
+            let _ = fs::remove_file(container.spec(&req.exec_id));
+            let _ = fs::remove_file(container.pid_file(&req.exec_id));
+            let process = container.execs.remove(&req.exec_id).unwrap_or_default();
+            let (exit_status, exited_at) = process.exited();
+            return Ok(api::DeleteResponse {
+                pid: process.pid,
+                exit_status,
+                exited_at,
+                ..Default::default()
+            });
+        }
+
+        self.container(&req.id, |_| ())?;
+        command(&["delete", &req.id])
+            .and_then(|cmd| self.worker.run(cmd))
+            .map_err(failed)?;
+        let container = self.shared.containers.lock().unwrap().remove(&req.id);
+        let container = container.ok_or_else(|| not_found(&req.id, ""))?;
+        if container.mounted {
+            unmount(&container.bundle.join("rootfs"));
+        }
+        let (exit_status, exited_at) = match &container.init.exit {
+            Some(_) => container.init.exited(),
+            None => (UNKNOWN_EXIT, MessageField::some(Timestamp::now())),
+        };
+        let event = TaskDelete {
+            container_id: req.id.clone(),
+            pid: container.init.pid,
+            exit_status,
+            exited_at: exited_at.clone(),
+            id: req.id.clone(),
+            ..Default::default()
+        };
+        self.shared.events.publish(topics::TASK_DELETE_EVENT_TOPIC, &event);
+        Ok(api::DeleteResponse {
+            pid: container.init.pid,
+            exit_status,
+            exited_at,
+            ..Default::default()
+        })
+    }
+
+    fn pids(&self, _ctx: &TtrpcContext, req: api::PidsRequest) -> ttrpc::Result<api::PidsResponse> {
+        self.container(&req.id, |_| ())?;
+        let output = command(&["ps", "--format", "json", &req.id])
+            .and_then(|cmd| self.worker.output(cmd))
+            .map_err(failed)?;
+        let pids: Vec<u32> = serde_json::from_slice(&output)
+            .map_err(|err| rpc_error(Code::UNKNOWN, format!("Parsing process list: {}", err)))?;
+        let processes = pids
+            .into_iter()
+            .map(|pid| api::ProcessInfo {
+                pid,
+                ..Default::default()
+            })
+            .collect();
+        Ok(api::PidsResponse {
+            processes,
+            ..Default::default()
+        })
+    }
+
+    fn pause(&self, _ctx: &TtrpcContext, req: api::PauseRequest) -> ttrpc::Result<api::Empty> {
+        self.container(&req.id, |_| ())?;
+        command(&["pause", &req.id])
+            .and_then(|cmd| self.worker.run(cmd))
+            .map_err(failed)?;
+        let event = TaskPaused {
+            container_id: req.id,
+            ..Default::default()
+        };
+        self.shared.events.publish(topics::TASK_PAUSED_EVENT_TOPIC, &event);
+        Ok(api::Empty::new())
+    }
+
+    fn resume(&self, _ctx: &TtrpcContext, req: api::ResumeRequest) -> ttrpc::Result<api::Empty> {
+        self.container(&req.id, |_| ())?;
+        command(&["resume", &req.id])
+            .and_then(|cmd| self.worker.run(cmd))
+            .map_err(failed)?;
+        let event = TaskResumed {
+            container_id: req.id,
+            ..Default::default()
+        };
+        self.shared.events.publish(topics::TASK_RESUMED_EVENT_TOPIC, &event);
+        Ok(api::Empty::new())
+    }
+
+    fn checkpoint(
+        &self,
+        _ctx: &TtrpcContext,
+        req: api::CheckpointTaskRequest,
+    ) -> ttrpc::Result<api::Empty> {
+        self.container(&req.id, |_| ())?;
+        command(&["checkpoint", "--image-path", &req.path, &req.id])
+            .and_then(|cmd| self.worker.run(cmd))
+            .map_err(failed)?;
+        Ok(api::Empty::new())
+    }
+
+    fn kill(&self, _ctx: &TtrpcContext, req: api::KillRequest) -> ttrpc::Result<api::Empty> {
+        info!("Killing task {} {} with {}", req.id, req.exec_id, req.signal);
+        let (pid, exited) = {
+            let containers = self.shared.containers.lock().unwrap();
+            let process = find(&containers, &req.id, &req.exec_id)?;
+            (process.pid, process.exit.is_some())
+        };
+        if exited {
+            return Err(rpc_error(Code::NOT_FOUND, "Process already finished"));
+        }
+        if req.exec_id.is_empty() {
+            let signal = req.signal.to_string();
+            let mut args = vec!["kill", &req.id, &signal];
+            if req.all {
+                args.push("--all");
+            }
+            command(&args)
+                .and_then(|cmd| self.worker.run(cmd))
+                .map_err(failed)?;
+        } else if pid != 0 {
+            // SAFETY: plain libc call
+            if unsafe { libc::kill(pid as libc::pid_t, req.signal as libc::c_int) } < 0 {
+                let err = io::Error::last_os_error();
+                return Err(rpc_error(Code::UNKNOWN, format!("Killing process {}: {}", pid, err)));
+            }
+        }
+        Ok(api::Empty::new())
+    }
+
+    fn exec(&self, _ctx: &TtrpcContext, req: api::ExecProcessRequest) -> ttrpc::Result<api::Empty> {
+        info!("Adding process {} to task {}", req.exec_id, req.id);
+        if req.terminal {
+            return Err(rpc_error(Code::UNIMPLEMENTED, "Terminals are not supported by ociplex"));
+        }
+        let mut containers = self.shared.containers.lock().unwrap();
+        let container = containers.get_mut(&req.id).ok_or_else(|| not_found(&req.id, ""))?;
+        if container.execs.contains_key(&req.exec_id) {
+            let message = format!("Process {} already exists", req.exec_id);
+            return Err(rpc_error(Code::ALREADY_EXISTS, message));
+        }
+
+        // The process spec is JSON, as the runtime expects it with --process
+        let spec = container.spec(&req.exec_id);
+        let value = req.spec.as_ref().map_or(&[][..], |spec| &spec.value[..]);
+        fs::write(&spec, value)
+            .map_err(|err| rpc_error(Code::UNKNOWN, format!("Writing {}: {}", spec.display(), err)))?;
+        let process = Process {
+            io: Io {
+                stdin: req.stdin,
+                stdout: req.stdout,
+                stderr: req.stderr,
+            },
+            ..Default::default()
+        };
+        container.execs.insert(req.exec_id.clone(), process);
+        drop(containers);
+
+        let event = TaskExecAdded {
+            container_id: req.id,
+            exec_id: req.exec_id,
+            ..Default::default()
+        };
+        self.shared.events.publish(topics::TASK_EXEC_ADDED_EVENT_TOPIC, &event);
+        Ok(api::Empty::new())
+    }
+
+    fn resize_pty(&self, _ctx: &TtrpcContext, _req: api::ResizePtyRequest) -> ttrpc::Result<api::Empty> {
+        Err(rpc_error(Code::UNIMPLEMENTED, "Terminals are not supported by ociplex"))
+    }
+
+    fn close_io(&self, _ctx: &TtrpcContext, req: api::CloseIORequest) -> ttrpc::Result<api::Empty> {
+        // Streams are only held by the processes, there is nothing to close
+        let containers = self.shared.containers.lock().unwrap();
+        find(&containers, &req.id, &req.exec_id)?;
+        Ok(api::Empty::new())
+    }
+
+    fn update(&self, _ctx: &TtrpcContext, req: api::UpdateTaskRequest) -> ttrpc::Result<api::Empty> {
+        let bundle = self.container(&req.id, |container| container.bundle.clone())?;
+        let resources = req.resources.as_ref().map_or(&[][..], |resources| &resources.value[..]);
+        let updated = tempfile::NamedTempFile::new_in(&bundle)
+            .and_then(|mut file| file.write_all(resources).map(|()| file))
+            .context("Writing resources")
+            .and_then(|file| {
+                let args = [
+                    OsStr::new("update"),
+                    OsStr::new("--resources"),
+                    file.path().as_os_str(),
+                    OsStr::new(&req.id),
+                ];
+                command(&args).and_then(|cmd| self.worker.run(cmd))
+            });
+        updated.map_err(failed)?;
+        Ok(api::Empty::new())
+    }
+
+    fn wait(&self, _ctx: &TtrpcContext, req: api::WaitRequest) -> ttrpc::Result<api::WaitResponse> {
+        let mut containers = self.shared.containers.lock().unwrap();
+        loop {
+            let process = find(&containers, &req.id, &req.exec_id)?;
+            if process.exit.is_some() {
+                let (exit_status, exited_at) = process.exited();
+                return Ok(api::WaitResponse {
+                    exit_status,
+                    exited_at,
+                    ..Default::default()
+                });
+            }
+            containers = self.shared.exited.wait(containers).unwrap();
+        }
+    }
+
+    fn state(&self, _ctx: &TtrpcContext, req: api::StateRequest) -> ttrpc::Result<api::StateResponse> {
+        let (bundle, pid, io, exit) = {
+            let containers = self.shared.containers.lock().unwrap();
+            let process = find(&containers, &req.id, &req.exec_id)?;
+            let bundle = containers[&req.id].bundle.clone();
+            (bundle, process.pid, process.io.clone(), process.exited())
+        };
+        let (exit_status, exited_at) = exit;
+        let status = if exited_at.is_some() {
+            api::Status::STOPPED
+        } else if !req.exec_id.is_empty() {
+            match pid {
+                0 => api::Status::CREATED,
+                _ => api::Status::RUNNING,
+            }
+        } else {
+            command(&["state", &req.id])
+                .and_then(|cmd| self.worker.output(cmd))
+                .and_then(|output| status(&output))
+                .map_err(failed)?
+        };
+        Ok(api::StateResponse {
+            id: req.id,
+            bundle: bundle.to_string_lossy().into_owned(),
+            pid,
+            status: status.into(),
+            stdin: io.stdin,
+            stdout: io.stdout,
+            stderr: io.stderr,
+            terminal: false,
+            exit_status,
+            exited_at,
+            exec_id: req.exec_id,
+            ..Default::default()
+        })
+    }
+
+    fn stats(&self, _ctx: &TtrpcContext, req: api::StatsRequest) -> ttrpc::Result<api::StatsResponse> {
+        self.container(&req.id, |_| ())?;
+        let output = command(&["events", "--stats", &req.id])
+            .and_then(|cmd| self.worker.output(cmd))
+            .map_err(failed)?;
+        let value = metrics(&output)
+            .and_then(|metrics| Ok(metrics.write_to_bytes()?))
+            .map_err(|err| rpc_error(Code::UNKNOWN, format!("{:#}", err)))?;
+        let stats = Any {
+            type_url: METRICS_TYPE.to_string(),
+            value,
+            ..Default::default()
+        };
+        Ok(api::StatsResponse {
+            stats: MessageField::some(stats),
+            ..Default::default()
+        })
+    }
+
+    fn connect(&self, _ctx: &TtrpcContext, req: api::ConnectRequest) -> ttrpc::Result<api::ConnectResponse> {
+        let task_pid = self.container(&req.id, |container| container.init.pid).unwrap_or(0);
+        Ok(api::ConnectResponse {
+            shim_pid: process::id(),
+            task_pid,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn shutdown(&self, _ctx: &TtrpcContext, req: api::ShutdownRequest) -> ttrpc::Result<api::Empty> {
+        // A shim serving a pod stays up as long as it has tasks
+        if req.now || self.shared.containers.lock().unwrap().is_empty() {
+            info!("Shutting down");
+            let _ = self.shutdown.lock().unwrap().send(());
+        }
+        Ok(api::Empty::new())
+    }
+}
@@ -0,0 +1,239 @@
+// ****************************************************************************
+//  syslog.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Send ociplex diagnostics to the local system logger
+//
+//     When the journald native socket is present, records are sent there
+//     with structured fields (container ID, subcommand, backend type).
+//     Otherwise, they are sent to the traditional `/dev/log` syslog socket
+//     in RFC 3164 format, with the structured fields appended to the message.
+//
+//     The socket can be overriden with the OCIPLEX_JOURNAL_SOCKET or
+//     OCIPLEX_SYSLOG_SOCKET environment variables, e.g. for testing.
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::env;
+use std::fmt::{self, Write as _};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use tracing::{field::Field, span, Event, Level, Subscriber};
+use tracing_subscriber::{
+    field::Visit, layer::Context as LayerContext, registry::LookupSpan, Layer,
+};
+
+use crate::fds;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+const IDENTIFIER: &str = "ociplex";
+
+// Syslog facility for system daemons, see RFC 3164
+const LOG_DAEMON: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol
+// ----------------------------------------------------------------------------
+//   The protocol spoken on the logging socket
+// ----------------------------------------------------------------------------
+{
+    Journal,
+    Syslog,
+}
+
+pub struct SyslogLayer
+// ----------------------------------------------------------------------------
+//   A tracing layer sending each event as a datagram to the system logger
+// ----------------------------------------------------------------------------
+{
+    socket: UnixDatagram,
+    path: PathBuf,
+    protocol: Protocol,
+}
+
+impl SyslogLayer {
+    pub fn new() -> Result<Self>
+    // ------------------------------------------------------------------------
+    //   Select the journal if available, syslog otherwise
+    // ------------------------------------------------------------------------
+    {
+        let (path, protocol) = if let Some(path) = env::var_os("OCIPLEX_JOURNAL_SOCKET") {
+            (PathBuf::from(path), Protocol::Journal)
+        } else if let Some(path) = env::var_os("OCIPLEX_SYSLOG_SOCKET") {
+            (PathBuf::from(path), Protocol::Syslog)
+        } else if Path::new(JOURNAL_SOCKET).exists() {
+            (PathBuf::from(JOURNAL_SOCKET), Protocol::Journal)
+        } else {
+            (PathBuf::from(SYSLOG_SOCKET), Protocol::Syslog)
+        };
+
+        let socket = UnixDatagram::unbound()
+            .and_then(fds::relocate)
+            .context("Creating syslog socket")?;
+        Ok(SyslogLayer {
+            socket,
+            path,
+            protocol,
+        })
+    }
+
+    fn journal_record(&self, level: &Level, fields: &[(String, String)]) -> Vec<u8>
+    // ------------------------------------------------------------------------
+    //   Format a record using the journald native protocol
+    // ------------------------------------------------------------------------
+    //   Values containing newlines use the binary length-prefixed encoding
+    {
+        let mut record = Vec::new();
+        let mut add = |key: &str, value: &str| {
+            record.extend_from_slice(key.as_bytes());
+            if value.contains('\n') {
+                record.push(b'\n');
+                record.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            } else {
+                record.push(b'=');
+            }
+            record.extend_from_slice(value.as_bytes());
+            record.push(b'\n');
+        };
+
+        add("PRIORITY", &severity(level).to_string());
+        add("SYSLOG_IDENTIFIER", IDENTIFIER);
+        add("SYSLOG_PID", &process::id().to_string());
+        for (key, value) in fields {
+            add(&journal_field_name(key), value);
+        }
+        record
+    }
+
+    fn syslog_record(&self, level: &Level, fields: &[(String, String)]) -> Vec<u8>
+    // ------------------------------------------------------------------------
+    //   Format a record following RFC 3164
+    // ------------------------------------------------------------------------
+    {
+        let priority = LOG_DAEMON * 8 + severity(level);
+        let time = Local::now().format("%b %e %H:%M:%S");
+        let mut line = format!("<{}>{} {}[{}]:", priority, time, IDENTIFIER, process::id());
+        for (_, value) in fields.iter().filter(|(key, _)| key == "message") {
+            let _ = write!(line, " {}", value);
+        }
+        for (key, value) in fields.iter().filter(|(key, _)| key != "message") {
+            let _ = write!(line, " {}={:?}", key, value);
+        }
+        line.into_bytes()
+    }
+}
+
+fn severity(level: &Level) -> u8
+// ----------------------------------------------------------------------------
+//   Syslog severity associated to a tracing level
+// ----------------------------------------------------------------------------
+{
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+fn journal_field_name(name: &str) -> String
+// ----------------------------------------------------------------------------
+//   Journal field names are upper-case, with only letters, digits and '_'
+// ----------------------------------------------------------------------------
+{
+    if name == "message" {
+        return "MESSAGE".into();
+    }
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' => c.to_ascii_uppercase(),
+            'A'..='Z' | '0'..='9' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+impl<S> Layer<S> for SyslogLayer
+// ----------------------------------------------------------------------------
+//   Send events with the fields of all enclosing spans
+// ----------------------------------------------------------------------------
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: LayerContext<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(visitor);
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: LayerContext<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            if let Some(visitor) = extensions.get_mut::<FieldVisitor>() {
+                values.record(visitor);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(fields) = span.extensions().get::<FieldVisitor>() {
+                    visitor.fields.extend(fields.fields.iter().cloned());
+                }
+            }
+        }
+        event.record(&mut visitor);
+
+        let level = event.metadata().level();
+        let record = match self.protocol {
+            Protocol::Journal => self.journal_record(level, &visitor.fields),
+            Protocol::Syslog => self.syslog_record(level, &visitor.fields),
+        };
+
+        // If the system logger is not there, there is nobody to tell
+        let _ = self.socket.send_to(&record, &self.path);
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor
+// ----------------------------------------------------------------------------
+//   Collect fields as strings, in the order they were recorded
+// ----------------------------------------------------------------------------
+{
+    fields: Vec<(String, String)>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.fields.push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.fields
+            .push((field.name().to_string(), format!("{:?}", value)));
+    }
+}
@@ -0,0 +1,156 @@
+// ****************************************************************************
+//  cli_args.rs                                                 ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check the arguments and environment passed to a CLI backend, including
+//     extra arguments and overrides from the backend configuration
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+
+mod common;
+use common::{ociplex, recorded_argv, recording_runtime, scratch_dir};
+
+const CONFIG: &str = r#"
+global_args = ["--systemd-cgroup", "--rootless=auto"]
+set_env = { OCIPLEX_TEST = "all" }
+unset_env = ["OCIPLEX_REMOVED"]
+
+[commands.create]
+global_args = ["--rootless=false"]
+extra_args = ["--pid-file", "/run/forced.pid"]
+remove_args = ["no-pivot"]
+set_env = { OCIPLEX_TEST = "create" }
+"#;
+
+#[test]
+fn extra_args_and_overrides() {
+    let dir = scratch_dir("cli-args-create");
+    let config = recording_runtime(&dir, CONFIG);
+    let status = ociplex(&config, &dir.join("log"))
+        .env("OCIPLEX_REMOVED", "1")
+        .args(["create", "--pid-file", "/tmp/pid", "--no-pivot", "my-container"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let argv = recorded_argv(&dir);
+    let log = dir.join("log");
+    let expected = [
+        "--log",
+        log.to_str().unwrap(),
+        "--systemd-cgroup",
+        "--rootless=false",
+        "create",
+        "--pid-file",
+        "/run/forced.pid",
+        "my-container",
+    ];
+    assert_eq!(argv, expected);
+
+    let env = fs::read_to_string(dir.join("env")).unwrap();
+    assert!(env.contains("OCIPLEX_TEST=create\n"));
+    assert!(!env.contains("OCIPLEX_REMOVED"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn defaults_apply_to_other_commands() {
+    let dir = scratch_dir("cli-args-start");
+    let config = recording_runtime(&dir, CONFIG);
+    let status = ociplex(&config, &dir.join("log"))
+        .args(["start", "my-container"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let argv = recorded_argv(&dir);
+    assert_eq!(
+        argv[2..],
+        ["--systemd-cgroup", "--rootless=auto", "start", "my-container"]
+    );
+    let env = fs::read_to_string(dir.join("env")).unwrap();
+    assert!(env.contains("OCIPLEX_TEST=all\n"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn options_are_translated() {
+    let dir = scratch_dir("cli-args-translate");
+    let config = recording_runtime(
+        &dir,
+        r#"
+[flags]
+"create.no-pivot" = "drop"
+"create.pid-file" = "-p"
+"*.console-socket" = "--console"
+"#,
+    );
+    let status = ociplex(&config, &dir.join("log"))
+        .args(["create", "--no-pivot", "--pid-file", "/tmp/pid"])
+        .args(["--console-socket", "/tmp/sock", "my-container"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let argv = recorded_argv(&dir);
+    assert_eq!(
+        argv[2..],
+        ["create", "--console", "/tmp/sock", "-p", "/tmp/pid", "my-container"]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn profile_renames_options() {
+    let dir = scratch_dir("cli-args-rename");
+    let config = recording_runtime(&dir, "profile = \"youki\"");
+    let log = dir.join("log");
+    let status = ociplex(&config, &log).args(["list", "--quiet"]).status().unwrap();
+    assert!(status.success());
+    assert_eq!(recorded_argv(&dir)[2..], ["list", "-q"]);
+
+    let status = ociplex(&config, &log)
+        .args(["exec", "--detach", "--additional-gids", "10", "my-container", "true"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let argv = recorded_argv(&dir);
+    assert!(argv.windows(2).any(|pair| pair == ["-g", "10"]), "{:?}", argv);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn profile_rejects_unsupported_options() {
+    let dir = scratch_dir("cli-args-reject");
+    let config = recording_runtime(&dir, "profile = \"runsc\"");
+    let log = dir.join("log");
+    let status = ociplex(&config, &log)
+        .args(["run", "--keep", "my-container"])
+        .status()
+        .unwrap();
+    assert!(!status.success());
+    assert!(!dir.join("argv").exists());
+
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(log.contains("Option --keep of run is not supported by backend"));
+    fs::remove_dir_all(&dir).unwrap();
+}
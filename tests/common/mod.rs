@@ -0,0 +1,108 @@
+// ****************************************************************************
+//  common/mod.rs                                               ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Helpers shared by the ociplex integration tests
+//
+//
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub fn scratch_dir(name: &str) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Create an empty scratch directory for a test
+// ----------------------------------------------------------------------------
+{
+    let dir = std::env::temp_dir().join(format!("ociplex-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn write_script(path: &Path, script: &str)
+// ----------------------------------------------------------------------------
+//   Write an executable shell script
+// ----------------------------------------------------------------------------
+{
+    fs::write(path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+pub fn ociplex(config: &Path, log: &Path) -> Command
+// ----------------------------------------------------------------------------
+//   Prepare an ociplex command with the given backend config and log file
+// ----------------------------------------------------------------------------
+{
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_ociplex"));
+    cmd.arg("--backend").arg(config).arg("--log").arg(log);
+    cmd
+}
+
+pub fn script_runtime(dir: &Path, script: &str, config: &str) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Create a CLI backend config for a runtime running the given script
+// ----------------------------------------------------------------------------
+//   The runtime is `runtime` in the directory, and the config is
+//   `backend.toml`, with the extra settings given in `config`.
+{
+    let runtime = dir.join("runtime");
+    write_script(&runtime, script);
+    let path = dir.join("backend.toml");
+    fs::write(
+        &path,
+        format!("backend-type = \"Cli\"\npath = {:?}\n{}\n", runtime, config),
+    )
+    .unwrap();
+    path
+}
+
+pub fn recording_runtime(dir: &Path, config: &str) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Create a CLI backend config for a runtime recording its arguments
+// ----------------------------------------------------------------------------
+//   The runtime writes one argument per line in `argv` in the directory,
+//   and the environment in `env`.
+{
+    let script = format!(
+        "printf '%s\\n' \"$@\" > {:?}\nenv > {:?}",
+        dir.join("argv"),
+        dir.join("env")
+    );
+    script_runtime(dir, &script, config)
+}
+
+pub fn recorded_argv(dir: &Path) -> Vec<String>
+// ----------------------------------------------------------------------------
+//   Return the arguments recorded by the recording runtime
+// ----------------------------------------------------------------------------
+{
+    fs::read_to_string(dir.join("argv"))
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect()
+}
@@ -0,0 +1,101 @@
+// ****************************************************************************
+//  dry_run.rs                                                  ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that a dry run prints what the CLI and shim v2 backends would
+//     do, without running the runtime or contacting the shim
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+
+use serde_json::Value;
+
+mod common;
+use common::{ociplex, recording_runtime, scratch_dir};
+
+fn lines(stdout: &[u8]) -> Vec<Value> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn dry_run_prints_cli_command()
+// ----------------------------------------------------------------------------
+//   With OCIPLEX_DRY_RUN, the runtime command is printed but not run
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("dry-run-cli");
+    let config = recording_runtime(&dir, "extra_args = [\"--debug\"]\nset_env = { MODE = \"test\" }");
+    let output = ociplex(&config, &dir.join("log"))
+        .env("OCIPLEX_DRY_RUN", "1")
+        .args(["kill", "ctr", "TERM"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(!dir.join("argv").exists());
+
+    let lines = lines(&output.stdout);
+    assert_eq!(lines.len(), 1);
+    let command = lines[0]["command"].as_array().unwrap();
+    assert_eq!(command[0], dir.join("runtime").to_str().unwrap());
+    assert!(command.ends_with(&["kill".into(), "--debug".into(), "ctr".into(), "TERM".into()]));
+    assert_eq!(lines[0]["env"]["MODE"], "test");
+    assert!(lines[0]["env"]["PATH"].is_string());
+}
+
+#[test]
+fn dry_run_prints_shim_requests()
+// ----------------------------------------------------------------------------
+//   A dry run of the shim v2 backend prints the launch command and requests
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("dry-run-shim");
+    let config = dir.join("backend.toml");
+    fs::write(
+        &config,
+        format!(
+            "backend-type = \"ShimV2\"\n\
+             shim = {:?}\nsocket = {:?}\nevents = \"/bin/true\"\n\
+             bundle_dir = \"/run/bundles/{{container-id}}\"\n\
+             debug_shim = false\ndry_run = true\n",
+            dir.join("shim"),
+            dir.join("socket")
+        ),
+    )
+    .unwrap();
+    let output = ociplex(&config, &dir.join("log"))
+        .args(["create", "--bundle", "/run/bundles/ctr", "ctr"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(!dir.join("socket").exists());
+
+    let lines = lines(&output.stdout);
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0]["command"][0], dir.join("shim").to_str().unwrap());
+    assert_eq!(lines[0]["command"].as_array().unwrap().last().unwrap(), "start");
+    assert_eq!(lines[0]["cwd"], "/run/bundles/ctr");
+    assert_eq!(lines[1]["request"], "Connect");
+    assert_eq!(lines[1]["message"], "id: \"ctr\"");
+    assert_eq!(lines[2]["request"], "Create");
+    assert_eq!(lines[2]["message"], "id: \"ctr\" bundle: \"/run/bundles/ctr\"");
+}
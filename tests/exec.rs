@@ -0,0 +1,64 @@
+// ****************************************************************************
+//  exec.rs                                                     ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check the exec fast path of the CLI backend, where the runtime replaces
+//     ociplex instead of running as a child process
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+
+mod common;
+use common::{ociplex, scratch_dir, script_runtime};
+
+fn runtime_pid(name: &str, exec: bool) -> (u32, u32)
+// ----------------------------------------------------------------------------
+//   Return the pid of ociplex and the pid seen by the runtime
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir(name);
+    let pid_file = dir.join("pid");
+    let script = format!("echo $$ > {:?}", pid_file);
+    let config = script_runtime(&dir, &script, &format!("exec = {}", exec));
+
+    let mut child = ociplex(&config, &dir.join("log.json"))
+        .args(["start", "my-container"])
+        .spawn()
+        .unwrap();
+    let ociplex_pid = child.id();
+    assert!(child.wait().unwrap().success());
+
+    let runtime_pid = fs::read_to_string(&pid_file).unwrap().trim().parse().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    (ociplex_pid, runtime_pid)
+}
+
+#[test]
+fn exec_mode_keeps_the_pid() {
+    let (ociplex, runtime) = runtime_pid("exec-mode", true);
+    assert_eq!(ociplex, runtime);
+}
+
+#[test]
+fn spawn_mode_uses_a_child() {
+    let (ociplex, runtime) = runtime_pid("spawn-mode", false);
+    assert_ne!(ociplex, runtime);
+}
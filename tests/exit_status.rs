@@ -0,0 +1,65 @@
+// ****************************************************************************
+//  exit_status.rs                                              ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that ociplex exits exactly like the CLI backend it invokes,
+//     whether the backend exits with a status code or dies from a signal
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+mod common;
+use common::{ociplex, scratch_dir, script_runtime};
+
+fn run_backend_script(name: &str, script: &str) -> ExitStatus
+// ----------------------------------------------------------------------------
+//   Run `ociplex start` with a CLI backend executing the given shell script
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir(name);
+    let config = script_runtime(&dir, script, "");
+    let status = ociplex(&config, &dir.join("log.json"))
+        .args(["start", "my-container"])
+        .status()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    status
+}
+
+#[test]
+fn exit_code_is_propagated() {
+    let status = run_backend_script("exit-code", "exit 42");
+    assert_eq!(status.code(), Some(42));
+}
+
+#[test]
+fn success_is_propagated() {
+    let status = run_backend_script("exit-success", "exit 0");
+    assert!(status.success());
+}
+
+#[test]
+fn signal_is_reraised() {
+    let status = run_backend_script("exit-signal", "kill -TERM $$");
+    assert_eq!(status.signal(), Some(libc::SIGTERM));
+}
@@ -0,0 +1,201 @@
+// ****************************************************************************
+//  fallback.rs                                                 ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that the fallback backend tries the next backend for configured
+//     classes of errors, and sticks to the backend that created a container
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+mod common;
+use common::{ociplex, scratch_dir, write_script};
+
+fn fallback(dir: &Path, first: &str, options: &str) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Create a fallback config trying a `first` backend, then `second`
+// ----------------------------------------------------------------------------
+//   The second runtime appends its subcommand to `second.log`. The first
+//   backend config is given as is, after its `backend-type` and `path`.
+{
+    let second = dir.join("second");
+    write_script(
+        &second,
+        &format!(
+            "for arg; do case $arg in create|start|delete) echo $arg >> {:?};; esac; done",
+            dir.join("second.log")
+        ),
+    );
+    let config = format!(
+        "backend-type = \"Fallback\"\nchain = [\"first\", \"second\"]\nstate_dir = {:?}\n{}\n\n\
+         [backends.first]\nbackend-type = \"Cli\"\npath = {:?}\n{}\n\n\
+         [backends.second]\nbackend-type = \"Cli\"\npath = {:?}\n",
+        dir.join("state"),
+        options,
+        dir.join("first"),
+        first,
+        second
+    );
+    let path = dir.join("backend.toml");
+    fs::write(&path, config).unwrap();
+    path
+}
+
+fn bundle(dir: &Path, version: &str) -> PathBuf {
+    let bundle = dir.join("bundle");
+    fs::create_dir_all(&bundle).unwrap();
+    fs::write(
+        bundle.join("config.json"),
+        format!("{{\"ociVersion\":\"{}\"}}", version),
+    )
+    .unwrap();
+    bundle
+}
+
+fn run(dir: &Path, config: &Path, args: &[&str]) -> ExitStatus {
+    ociplex(config, &dir.join("log")).args(args).status().unwrap()
+}
+
+fn second_log(dir: &Path) -> String {
+    fs::read_to_string(dir.join("second.log")).unwrap_or_default()
+}
+
+#[test]
+fn missing_runtime_falls_back_and_sticks()
+// ----------------------------------------------------------------------------
+//   A missing first runtime selects the second one for the whole lifecycle
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("fallback-missing");
+    let config = fallback(&dir, "", "");
+    let bundle = bundle(&dir, "1.0.2");
+
+    let bundle = bundle.to_str().unwrap();
+    assert!(run(&dir, &config, &["create", "--bundle", bundle, "ctr"]).success());
+    assert_eq!(
+        fs::read_to_string(dir.join("state/fallback/ctr")).unwrap(),
+        "second\n"
+    );
+
+    // Once the first runtime exists, the container still goes to the second
+    write_script(&dir.join("first"), &format!("echo $@ >> {:?}", dir.join("first.log")));
+    assert!(run(&dir, &config, &["start", "ctr"]).success());
+    assert!(run(&dir, &config, &["delete", "ctr"]).success());
+    assert_eq!(second_log(&dir), "create\nstart\ndelete\n");
+    assert!(!dir.join("first.log").exists());
+    assert!(!dir.join("state/fallback/ctr").exists());
+}
+
+#[test]
+fn exit_codes_select_fallback()
+// ----------------------------------------------------------------------------
+//   Only the configured exit codes of the first runtime fall back
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("fallback-exit");
+    let config = fallback(&dir, "", "exit_codes = [125]");
+    let bundle = bundle(&dir, "1.0.2");
+    let bundle = bundle.to_str().unwrap();
+
+    write_script(&dir.join("first"), "exit 125");
+    assert!(run(&dir, &config, &["create", "--bundle", bundle, "ctr"]).success());
+    assert_eq!(second_log(&dir), "create\n");
+
+    write_script(&dir.join("first"), "exit 3");
+    let status = run(&dir, &config, &["create", "--bundle", bundle, "other"]);
+    assert_eq!(status.code(), Some(3));
+    assert_eq!(second_log(&dir), "create\n");
+
+    // The failed container is not recorded, later commands try the chain
+    assert!(!dir.join("state/fallback/other").exists());
+}
+
+#[test]
+fn foreground_exit_codes_do_not_fall_back()
+// ----------------------------------------------------------------------------
+//   The exit code of a container run in the foreground is its own
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("fallback-foreground");
+    let config = fallback(&dir, "", "exit_codes = [125]");
+    let bundle = bundle(&dir, "1.0.2");
+    let bundle = bundle.to_str().unwrap();
+
+    write_script(&dir.join("first"), "exit 125");
+    let status = run(&dir, &config, &["run", "--bundle", bundle, "ctr"]);
+    assert_eq!(status.code(), Some(125));
+    let status = run(&dir, &config, &["exec", "ctr", "true"]);
+    assert_eq!(status.code(), Some(125));
+    assert_eq!(second_log(&dir), "");
+
+    // A detached run falls back, since the code is the runtime's
+    assert!(run(&dir, &config, &["run", "--detach", "--bundle", bundle, "ctr"]).success());
+    assert_eq!(
+        fs::read_to_string(dir.join("state/fallback/ctr")).unwrap(),
+        "second\n"
+    );
+}
+
+#[test]
+fn exec_backends_are_rejected()
+// ----------------------------------------------------------------------------
+//   A runtime replacing ociplex could not fall back, and is refused
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("fallback-exec");
+    let config = fallback(&dir, "exec = true", "exit_codes = [125]");
+    write_script(&dir.join("first"), &format!("echo $@ >> {:?}", dir.join("first.log")));
+    let status = run(&dir, &config, &["state", "ctr"]);
+    assert_eq!(status.code(), Some(1));
+    assert!(!dir.join("first.log").exists());
+    let log = fs::read_to_string(dir.join("log")).unwrap();
+    assert!(log.contains("Backends in a fallback chain cannot use exec"), "{}", log);
+}
+
+#[test]
+fn features_mismatch_falls_back()
+// ----------------------------------------------------------------------------
+//   A bundle newer than what the first runtime supports goes to the second
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("fallback-features");
+    let probe = format!("[backends.first.probe]\nbundle = true\ncache_dir = {:?}", dir);
+    let config = fallback(&dir, &probe, "");
+    write_script(
+        &dir.join("first"),
+        &format!(
+            "case $1 in features) echo '{{\"ociVersionMin\":\"1.0.0\",\"ociVersionMax\":\"1.0.2\"}}';; \
+             *) echo $@ >> {:?};; esac",
+            dir.join("first.log")
+        ),
+    );
+
+    let old = bundle(&dir, "1.0.2");
+    assert!(run(&dir, &config, &["create", "--bundle", old.to_str().unwrap(), "old"]).success());
+    assert!(dir.join("first.log").exists());
+    assert_eq!(second_log(&dir), "");
+
+    let new = bundle(&dir, "1.1.0");
+    assert!(run(&dir, &config, &["create", "--bundle", new.to_str().unwrap(), "new"]).success());
+    assert_eq!(second_log(&dir), "create\n");
+}
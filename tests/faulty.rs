@@ -0,0 +1,117 @@
+// ****************************************************************************
+//  faulty.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that the faulty backend injects the configured faults in the
+//     commands of a mock backend
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+mod common;
+use common::{ociplex, scratch_dir};
+
+fn faulty<'a>(dir: &'a Path, faults: &str) -> impl Fn(&[&str]) -> Command + 'a
+// ----------------------------------------------------------------------------
+//   Create a faulty backend over a mock one, return a command builder
+// ----------------------------------------------------------------------------
+{
+    let bundle = dir.join("bundle");
+    fs::create_dir_all(&bundle).unwrap();
+    let spec = json!({ "ociVersion": "1.0.2", "process": { "args": ["sleep", "30"] } });
+    fs::write(bundle.join("config.json"), spec.to_string()).unwrap();
+    fs::write(
+        dir.join("backend.toml"),
+        format!(
+            "backend-type = \"Faulty\"\n\n[backend]\nbackend-type = \"Mock\"\nroot = {:?}\n\n{}",
+            dir.join("root"),
+            faults
+        ),
+    )
+    .unwrap();
+    move |args| {
+        let mut cmd = ociplex(&dir.join("backend.toml"), &dir.join("log"));
+        cmd.args(args).current_dir(dir);
+        cmd
+    }
+}
+
+#[test]
+fn faulty_fails_before_command()
+// ----------------------------------------------------------------------------
+//   A failure instead of a command has its exit code and message
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("faulty-before");
+    let oci = faulty(
+        &dir,
+        "[[faults]]\nsubcommands = [\"start\"]\ncontainers = [\"web-*\"]\n\
+         exit_code = 125\nmessage = \"injected failure\"\n",
+    );
+    for id in ["web-1", "db-1"] {
+        assert!(oci(&["create", "--bundle", "bundle", id]).status().unwrap().success());
+    }
+
+    let status = oci(&["start", "web-1"]).status().unwrap();
+    assert_eq!(status.code(), Some(125));
+    assert!(fs::read_to_string(dir.join("log")).unwrap().contains("injected failure"));
+    let output = oci(&["state", "web-1"]).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).contains("\"created\""));
+
+    // Other containers are left alone
+    assert!(oci(&["start", "db-1"]).status().unwrap().success());
+    for id in ["web-1", "db-1"] {
+        assert!(oci(&["delete", "--force", id]).status().unwrap().success());
+    }
+}
+
+#[test]
+fn faulty_partial_failures()
+// ----------------------------------------------------------------------------
+//   Commands can run without pid file, or run and then crash
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("faulty-partial");
+    let oci = faulty(
+        &dir,
+        "[[faults]]\nsubcommands = [\"create\"]\nskip_pid_file = true\ndelay = 0.2\n\n\
+         [[faults]]\nsubcommands = [\"delete\"]\ncrash = \"SIGTERM\"\nwhen = \"after\"\n\n\
+         [[faults]]\nsubcommands = [\"state\"]\nexit_code = 2\nprobability = 0.0\n",
+    );
+
+    let start = Instant::now();
+    let status = oci(&["create", "--bundle", "bundle", "--pid-file", "pid", "ctr"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert!(!dir.join("pid").exists());
+    assert!(oci(&["state", "ctr"]).output().unwrap().status.success());
+
+    let status = oci(&["delete", "--force", "ctr"]).status().unwrap();
+    assert_eq!(status.signal(), Some(15));
+    assert!(!dir.join("root/ctr").exists());
+}
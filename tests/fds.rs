@@ -0,0 +1,99 @@
+// ****************************************************************************
+//  fds.rs                                                      ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that fds given with LISTEN_FDS and --preserve-fds reach the
+//     runtime with the same numbers, and that LISTEN_PID is rewritten
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+mod common;
+use common::{scratch_dir, script_runtime};
+
+fn run_with_fds(dir: &Path, shell: &str) -> String
+// ----------------------------------------------------------------------------
+//   Run ociplex from a shell setting up fds, return what the runtime saw
+// ----------------------------------------------------------------------------
+//   The runtime reports its pid, LISTEN_PID, which fds from 3 to 9 are open,
+//   and the contents of fds 3 and 4.
+{
+    let report = dir.join("report");
+    let script = format!(
+            "exec > {:?}\necho \"pid=$$ listen_pid=$LISTEN_PID\"\n\
+             printf 'fds='\n\
+             for fd in 3 4 5 6 7 8 9; do [ -e /proc/$$/fd/$fd ] && printf '%s ' $fd; done\n\
+             echo\n\
+             cat 2>/dev/null <&3\ncat <&4",
+        report
+    );
+    let config = script_runtime(dir, &script, "");
+    fs::write(dir.join("three"), "fd three\n").unwrap();
+    fs::write(dir.join("four"), "fd four\n").unwrap();
+
+    let status = Command::new("/bin/sh")
+        .current_dir(dir)
+        .env("OCIPLEX", env!("CARGO_BIN_EXE_ociplex"))
+        .env("CONFIG", &config)
+        .arg("-c")
+        .arg(shell)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    fs::read_to_string(report).unwrap()
+}
+
+#[test]
+fn listen_and_preserved_fds_arrive_intact() {
+    let dir = scratch_dir("fds-listen");
+    let report = run_with_fds(
+        &dir,
+        "LISTEN_FDS=1 LISTEN_PID=$$ exec $OCIPLEX --backend $CONFIG --log log \
+         create --preserve-fds 1 --bundle /tmp c1 3<three 4<four",
+    );
+    let mut lines = report.lines();
+    let pids = lines.next().unwrap();
+    let (pid, listen_pid) = pids.split_once(' ').unwrap();
+    assert_eq!(pid.strip_prefix("pid="), listen_pid.strip_prefix("listen_pid="));
+
+    assert_eq!(lines.next(), Some("fds=3 4 "));
+    assert_eq!(lines.next(), Some("fd three"));
+    assert_eq!(lines.next(), Some("fd four"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn holes_are_not_filled_by_ociplex() {
+    // Fd 3 is not open, so the log file must not land there
+    let dir = scratch_dir("fds-holes");
+    let report = run_with_fds(
+        &dir,
+        "exec $OCIPLEX --backend $CONFIG --log log --syslog \
+         create --preserve-fds 2 --bundle /tmp c1 4<four",
+    );
+    let mut lines = report.lines();
+    lines.next();
+    assert_eq!(lines.next(), Some("fds=4 "));
+    assert_eq!(lines.next(), Some("fd four"));
+    fs::remove_dir_all(&dir).unwrap();
+}
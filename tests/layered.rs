@@ -0,0 +1,190 @@
+// ****************************************************************************
+//  layered.rs                                                  ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that the layered backend sends commands through its layers,
+//     and that layers see the results of the inner ones
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+mod common;
+use common::{ociplex, recorded_argv, scratch_dir, write_script};
+
+fn layered(dir: &Path) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Create a layered config with log, metrics and policy layers
+// ----------------------------------------------------------------------------
+{
+    let runtime = dir.join("runtime");
+    write_script(&runtime, &format!("printf '%s\\n' \"$@\" > {:?}", dir.join("argv")));
+    let config = dir.join("backend.toml");
+    fs::write(
+        &config,
+        format!(
+            "backend-type = \"Layered\"\n\
+             [[layers]]\nlayer-type = \"Log\"\n\
+             [[layers]]\nlayer-type = \"Metrics\"\npath = {:?}\n\
+             [[layers]]\nlayer-type = \"Policy\"\nrequire_user_namespace = true\n\
+             [inner]\nbackend-type = \"Cli\"\npath = {:?}\n",
+            dir.join("metrics.json"),
+            runtime
+        ),
+    )
+    .unwrap();
+    config
+}
+
+fn bundle(dir: &Path, name: &str, namespaces: Value) -> String {
+    let bundle = dir.join(name);
+    fs::create_dir_all(&bundle).unwrap();
+    let spec = json!({ "ociVersion": "1.0.2", "linux": { "namespaces": namespaces } });
+    fs::write(bundle.join("config.json"), spec.to_string()).unwrap();
+    bundle.to_str().unwrap().to_string()
+}
+
+#[test]
+fn layered_runs_compliant_commands()
+// ----------------------------------------------------------------------------
+//   Commands allowed by all layers reach the inner backend and are counted
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("layered-pass");
+    let config = layered(&dir);
+    let bundle = bundle(&dir, "bundle", json!([{ "type": "user" }]));
+    let log = dir.join("log");
+    for args in [vec!["create", "--bundle", &bundle, "ctr"], vec!["start", "ctr"]] {
+        let status = ociplex(&config, &log).args(args).status().unwrap();
+        assert!(status.success());
+    }
+    assert!(recorded_argv(&dir).ends_with(&["start".into(), "ctr".into()]));
+
+    let metrics: Value =
+        serde_json::from_str(&fs::read_to_string(dir.join("metrics.json")).unwrap()).unwrap();
+    assert_eq!(metrics["create"]["count"], 1);
+    assert_eq!(metrics["create"]["outcomes"]["ok"], 1);
+    assert_eq!(metrics["start"]["outcomes"]["ok"], 1);
+
+    let log = fs::read_to_string(log).unwrap();
+    assert!(log.contains("Starting create ctr"), "{}", log);
+    assert!(log.contains("Finished start ctr: ok (0)"), "{}", log);
+}
+
+#[test]
+fn layered_outer_layers_see_rejections()
+// ----------------------------------------------------------------------------
+//   A rejection by the policy layer is counted and logged by outer layers
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("layered-reject");
+    let config = layered(&dir);
+    let bundle = bundle(&dir, "bundle", json!([{ "type": "pid" }]));
+    let log = dir.join("log");
+    let status = ociplex(&config, &log)
+        .args(["run", "--bundle", &bundle, "ctr"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert!(!dir.join("argv").exists());
+
+    let metrics: Value =
+        serde_json::from_str(&fs::read_to_string(dir.join("metrics.json")).unwrap()).unwrap();
+    assert_eq!(metrics["run"]["count"], 1);
+    assert_eq!(metrics["run"]["outcomes"]["policy"], 1);
+
+    let log = fs::read_to_string(log).unwrap();
+    assert!(log.contains("no user namespace"), "{}", log);
+    assert!(log.contains("Finished run ctr: policy (1)"), "{}", log);
+}
+
+#[test]
+fn layered_rejects_nested_exec_backend()
+// ----------------------------------------------------------------------------
+//   An inner backend using exec is found through the backends wrapping it
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("layered-exec");
+    let runtime = dir.join("runtime");
+    write_script(&runtime, &format!("printf '%s\\n' \"$@\" > {:?}", dir.join("argv")));
+    let config = dir.join("backend.toml");
+    fs::write(
+        &config,
+        format!(
+            "backend-type = \"Layered\"\n\
+             [[layers]]\nlayer-type = \"Log\"\n\
+             [inner]\nbackend-type = \"Faulty\"\n\
+             [inner.backend]\nbackend-type = \"Transform\"\n\
+             [inner.backend.backend]\nbackend-type = \"Cli\"\npath = {:?}\nexec = true\n",
+            runtime
+        ),
+    )
+    .unwrap();
+    let log = dir.join("log");
+    let status = ociplex(&config, &log).args(["state", "ctr"]).status().unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert!(!dir.join("argv").exists());
+    let log = fs::read_to_string(log).unwrap();
+    assert!(log.contains("Layers need an inner backend not using exec"), "{}", log);
+}
+
+#[test]
+fn layered_layers_rewrite_output()
+// ----------------------------------------------------------------------------
+//   The normalize layer rewrites the state printed by the inner backend
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("layered-output");
+    let runtime = dir.join("runtime");
+    write_script(
+        &runtime,
+        r#"echo '{"ociVersion":"1.0.2","id":"ctr","status":"running","pid":42,"bundle":"/b","created":"2023-05-04T12:00:00.5+02:00","extra":1}'"#,
+    );
+    let config = dir.join("backend.toml");
+    fs::write(
+        &config,
+        format!(
+            "backend-type = \"Layered\"\n\
+             [[layers]]\nlayer-type = \"Log\"\n\
+             [[layers]]\nlayer-type = \"Normalize\"\n\
+             [inner]\nbackend-type = \"Cli\"\npath = {:?}\n",
+            runtime
+        ),
+    )
+    .unwrap();
+    let log = dir.join("log");
+    let output = ociplex(&config, &log).args(["state", "ctr"]).output().unwrap();
+    assert!(output.status.success());
+    let state: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(state["created"], "2023-05-04T10:00:00.500000000Z");
+    assert!(state.get("owner").is_some());
+    assert!(state.get("extra").is_none());
+
+    // Invalid output is an error seen by the outer layers
+    write_script(&runtime, r#"echo '{"id":"ctr"}'"#);
+    let output = ociplex(&config, &log).args(["state", "ctr"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let log = fs::read_to_string(log).unwrap();
+    assert!(log.contains("Invalid state output"), "{}", log);
+    assert!(log.contains("Finished state ctr: error (1)"), "{}", log);
+}
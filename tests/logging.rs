@@ -0,0 +1,93 @@
+// ****************************************************************************
+//  logging.rs                                                  ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that diagnostics go to the --log file in the runc format, and
+//     never to stdout
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+mod common;
+use common::{ociplex, recording_runtime, scratch_dir};
+
+fn records(log: &Path) -> Vec<Value>
+// ----------------------------------------------------------------------------
+//   Parse a JSON log, checking that each record has the keys of runc
+// ----------------------------------------------------------------------------
+{
+    let text = fs::read_to_string(log).unwrap();
+    text.lines()
+        .map(|line| {
+            let record: Value = serde_json::from_str(line).unwrap();
+            for key in ["level", "msg", "time"] {
+                assert!(record[key].is_string(), "No {} in {}", key, line);
+            }
+            record
+        })
+        .collect()
+}
+
+#[test]
+fn json_log_records_debug_output()
+// ----------------------------------------------------------------------------
+//   With --debug, what ociplex runs is logged, and stdout stays empty
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("logging-debug");
+    let config = recording_runtime(&dir, "");
+    let log = dir.join("log.json");
+    let output = ociplex(&config, &log)
+        .args(["--log-format", "json", "--debug", "start", "ctr"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty(), "{}", String::from_utf8_lossy(&output.stdout));
+
+    let records = records(&log);
+    assert!(records.iter().any(|record| record["level"] == "debug"
+        && record["msg"].as_str().unwrap().starts_with("Running command")));
+}
+
+#[test]
+fn json_log_records_errors()
+// ----------------------------------------------------------------------------
+//   A failure is logged as an error record, not written to stdout
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("logging-error");
+    let config = dir.join("backend.toml");
+    fs::write(&config, "backend-type = \"Cli\"\npath = \"/nonexistent/runtime\"\n").unwrap();
+    let log = dir.join("log.json");
+    let output = ociplex(&config, &log)
+        .args(["--log-format", "json", "state", "ctr"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty(), "{}", String::from_utf8_lossy(&output.stdout));
+
+    let records = records(&log);
+    let last = records.last().unwrap();
+    assert_eq!(last["level"], "error");
+    assert!(!last["msg"].as_str().unwrap().is_empty());
+}
@@ -0,0 +1,249 @@
+// ****************************************************************************
+//  mirror.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that the mirror backend replays commands on a secondary backend
+//     without affecting the caller, and reports differences
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+mod common;
+use common::{ociplex, scratch_dir, write_script};
+
+fn mirror(dir: &Path, mode: &str, secondary_status: &str) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Create a mirror config with runtimes logging their arguments
+// ----------------------------------------------------------------------------
+//   Each runtime appends its arguments to a file named after it, and prints
+//   a state with the given status. The secondary also fails `kill`. The
+//   `--log` option given by the tests comes first, and is skipped. The
+//   secondary takes 10 seconds for `pause`, and the mirror gives it 1.
+{
+    let state = |status: &str| {
+        format!(
+            "{{\"ociVersion\":\"1.0.2\",\"id\":\"$2\",\"pid\":$$,\"status\":\"{}\",\
+             \"bundle\":\"/b\",\"created\":\"2023-01-01T00:00:00Z\",\"owner\":\"\"}}",
+            status
+        )
+    };
+    let primary = dir.join("primary");
+    write_script(
+        &primary,
+        &format!(
+            "shift 2\necho $@ >> {:?}\ncase $1 in state) echo '{}';; esac",
+            dir.join("primary.log"),
+            state("running").replace("$2", "'$2'").replace("$$", "'$$'")
+        ),
+    );
+    let secondary = dir.join("secondary");
+    write_script(
+        &secondary,
+        &format!(
+            "shift 2\necho $@ >> {:?}\necho noise >&2\n\
+             case $1 in state) echo '{}';; kill) exit 2;; pause) exec sleep 10;; esac",
+            dir.join("secondary.log"),
+            state(secondary_status).replace("$2", "'$2'").replace("$$", "'$$'")
+        ),
+    );
+    let config = format!(
+        "backend-type = \"Mirror\"\nreport = {:?}\nmode = {:?}\n\
+         state_dir = {:?}\ntimeout = 1\n\n\
+         [primary]\nbackend-type = \"Cli\"\npath = {:?}\n\n\
+         [secondary]\nbackend-type = \"Cli\"\npath = {:?}\n",
+        dir.join("report.jsonl"),
+        mode,
+        dir.join("state"),
+        primary,
+        secondary
+    );
+    let path = dir.join("backend.toml");
+    fs::write(&path, config).unwrap();
+    path
+}
+
+fn report(dir: &Path) -> Vec<Value> {
+    fs::read_to_string(dir.join("report.jsonl"))
+        .unwrap_or_default()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn isolated_mirror_reports_differences()
+// ----------------------------------------------------------------------------
+//   Commands are replayed on suffixed containers, only differences reported
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("mirror-isolated");
+    let config = mirror(&dir, "isolated", "stopped");
+    let log = dir.join("log");
+
+    let status = ociplex(&config, &log).args(["start", "ctr"]).status().unwrap();
+    assert!(status.success());
+    assert!(report(&dir).is_empty());
+
+    // The caller only sees the output and status of the primary
+    let output = ociplex(&config, &log).args(["state", "ctr"]).output().unwrap();
+    assert!(output.status.success());
+    let state: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(state["status"], "running");
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("noise"));
+
+    let status = ociplex(&config, &log).args(["kill", "ctr", "9"]).status().unwrap();
+    assert!(status.success());
+
+    assert_eq!(
+        fs::read_to_string(dir.join("secondary.log")).unwrap(),
+        "start ctr-mirror\nstate ctr-mirror\nkill ctr-mirror 9\n"
+    );
+    let report = report(&dir);
+    assert_eq!(report.len(), 2);
+    assert_eq!(report[0]["subcommand"], "state");
+    assert_eq!(report[0]["container"], "ctr");
+    assert_eq!(
+        report[0]["differences"],
+        serde_json::json!(["state status: \"running\" vs \"stopped\""])
+    );
+    assert_eq!(report[1]["subcommand"], "kill");
+    assert_eq!(report[1]["secondary"]["code"], 2);
+}
+
+#[test]
+fn read_only_mirror_skips_changes()
+// ----------------------------------------------------------------------------
+//   In read-only mode, only commands that do not change containers replay
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("mirror-read-only");
+    let config = mirror(&dir, "read-only", "running");
+    let log = dir.join("log");
+
+    for args in [&["start", "ctr"][..], &["state", "ctr"], &["kill", "ctr", "9"]] {
+        let status = ociplex(&config, &log).args(args).status().unwrap();
+        assert!(status.success());
+    }
+    assert_eq!(
+        fs::read_to_string(dir.join("secondary.log")).unwrap(),
+        "state ctr\n"
+    );
+    assert!(report(&dir).is_empty());
+}
+
+#[test]
+fn isolated_mirror_skips_foreground_processes()
+// ----------------------------------------------------------------------------
+//   Only detached `run` and `exec` are replayed, others would run twice
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("mirror-foreground");
+    let config = mirror(&dir, "isolated", "running");
+    let log = dir.join("log");
+
+    let bundle = dir.join("bundle");
+    fs::create_dir_all(&bundle).unwrap();
+    fs::write(bundle.join("config.json"), "{}").unwrap();
+    let bundle = bundle.to_str().unwrap();
+    for args in [
+        &["run", "--bundle", bundle, "ctr"][..],
+        &["run", "--detach", "--bundle", bundle, "ctr"],
+        &["exec", "ctr", "sh"],
+        &["exec", "--detach", "ctr", "sh"],
+    ] {
+        let status = ociplex(&config, &log).args(args).status().unwrap();
+        assert!(status.success());
+    }
+    assert_eq!(
+        fs::read_to_string(dir.join("secondary.log")).unwrap(),
+        format!(
+            "run --bundle {} --detach ctr-mirror\nexec --detach ctr-mirror sh\n",
+            dir.join("state/mirror/ctr-mirror/bundle").display()
+        )
+    );
+    let primary = fs::read_to_string(dir.join("primary.log")).unwrap();
+    assert_eq!(primary.lines().count(), 4);
+}
+
+#[test]
+fn isolated_mirror_copies_bundles()
+// ----------------------------------------------------------------------------
+//   Isolated containers get their own bundle and root filesystem
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("mirror-bundle");
+    let config = mirror(&dir, "isolated", "running");
+    let log = dir.join("log");
+    let rootfs = dir.join("rootfs");
+    fs::create_dir_all(rootfs.join("etc")).unwrap();
+    fs::write(rootfs.join("etc/hostname"), "ctr\n").unwrap();
+    let bundle = dir.join("bundle");
+    fs::create_dir_all(&bundle).unwrap();
+    fs::write(
+        bundle.join("config.json"),
+        format!("{{\"root\":{{\"path\":{:?}}}}}", rootfs),
+    )
+    .unwrap();
+
+    let status = ociplex(&config, &log)
+        .arg("create")
+        .arg("--bundle")
+        .arg(&bundle)
+        .arg("ctr")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let copy = dir.join("state/mirror/ctr-mirror");
+    let config_json = fs::read_to_string(copy.join("bundle/config.json")).unwrap();
+    let config_json: Value = serde_json::from_str(&config_json).unwrap();
+    assert_eq!(config_json["root"]["path"], copy.join("rootfs").to_str().unwrap());
+    assert_eq!(fs::read_to_string(copy.join("rootfs/etc/hostname")).unwrap(), "ctr\n");
+    assert_eq!(
+        fs::read_to_string(bundle.join("config.json")).unwrap(),
+        format!("{{\"root\":{{\"path\":{:?}}}}}", rootfs)
+    );
+
+    let status = ociplex(&config, &log).args(["delete", "ctr"]).status().unwrap();
+    assert!(status.success());
+    assert!(!copy.exists());
+}
+
+#[test]
+fn slow_secondary_times_out()
+// ----------------------------------------------------------------------------
+//   A secondary taking too long is killed and reported, the primary returns
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("mirror-timeout");
+    let config = mirror(&dir, "isolated", "running");
+    let start = std::time::Instant::now();
+    let status = ociplex(&config, &dir.join("log")).args(["pause", "ctr"]).status().unwrap();
+    assert!(status.success());
+    assert!(start.elapsed().as_secs() < 5);
+
+    let report = report(&dir);
+    assert_eq!(report.len(), 1);
+    assert_eq!(report[0]["subcommand"], "pause");
+    assert_eq!(report[0]["secondary"]["outcome"], "timeout");
+}
@@ -0,0 +1,181 @@
+// ****************************************************************************
+//  mock.rs                                                     ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that the mock backend runs containers through the whole OCI
+//     lifecycle as plain processes
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+mod common;
+use common::{ociplex, scratch_dir};
+
+fn mock(dir: &Path, args: &[&str]) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Create a mock backend config, and a bundle running the given process
+// ----------------------------------------------------------------------------
+{
+    let bundle = dir.join("bundle");
+    fs::create_dir_all(&bundle).unwrap();
+    let spec = json!({
+        "ociVersion": "1.0.2",
+        "process": {
+            "args": args,
+            "env": ["PATH=/usr/bin:/bin", "GREETING=hello"],
+            "cwd": bundle,
+        },
+        "annotations": { "test": "mock" },
+    });
+    fs::write(bundle.join("config.json"), spec.to_string()).unwrap();
+    let config = dir.join("backend.toml");
+    fs::write(
+        &config,
+        format!("backend-type = \"Mock\"\nroot = {:?}\n", dir.join("root")),
+    )
+    .unwrap();
+    config
+}
+
+fn runtime(dir: &Path) -> impl Fn(&[&str]) -> Command + '_ {
+    move |args| {
+        let mut cmd = ociplex(&dir.join("backend.toml"), &dir.join("log"));
+        cmd.args(args).current_dir(dir);
+        cmd
+    }
+}
+
+fn state(dir: &Path, id: &str) -> Value {
+    let output = runtime(dir)(&["state", id]).output().unwrap();
+    assert!(output.status.success());
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+fn wait_for(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "Timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn mock_lifecycle()
+// ----------------------------------------------------------------------------
+//   A container goes through created, running, paused and stopped
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("mock-lifecycle");
+    mock(&dir, &["sh", "-c", "echo $GREETING > started; exec sleep 30"]);
+    let oci = runtime(&dir);
+
+    let pid_file = dir.join("pid");
+    let status = oci(&["create", "--bundle", "bundle", "--pid-file", "pid", "ctr"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let created = state(&dir, "ctr");
+    assert_eq!(created["status"], "created");
+    assert_eq!(created["annotations"]["test"], "mock");
+    assert_eq!(created["bundle"], json!(dir.join("bundle").canonicalize().unwrap()));
+    let pid = created["pid"].as_i64().unwrap();
+    assert_eq!(fs::read_to_string(&pid_file).unwrap(), pid.to_string());
+
+    // The process only runs once started
+    thread::sleep(Duration::from_millis(100));
+    let started = dir.join("bundle/started");
+    assert!(!started.exists());
+    assert!(oci(&["start", "ctr"]).status().unwrap().success());
+    wait_for("the container process", || started.exists());
+    assert_eq!(fs::read_to_string(&started).unwrap(), "hello\n");
+    assert_eq!(state(&dir, "ctr")["status"], "running");
+
+    let output = oci(&["ps", "--format", "json", "ctr"]).output().unwrap();
+    let pids: Vec<i64> = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(pids, [pid]);
+    let output = oci(&["events", "--stats", "ctr"]).output().unwrap();
+    let event: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(event["type"], "stats");
+    assert_eq!(event["data"]["pids"]["current"], 1);
+
+    // Processes run by exec report their exit status
+    let status = oci(&["exec", "ctr", "--", "sh", "-c", "touch exec; exit 3"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(3));
+    assert!(dir.join("bundle/exec").exists());
+
+    assert!(oci(&["pause", "ctr"]).status().unwrap().success());
+    assert_eq!(state(&dir, "ctr")["status"], "paused");
+    assert!(oci(&["resume", "ctr"]).status().unwrap().success());
+    assert_eq!(state(&dir, "ctr")["status"], "running");
+
+    let output = oci(&["list", "--format", "json"]).output().unwrap();
+    let list: Vec<Value> = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0]["id"], "ctr");
+
+    // A running container cannot be deleted without force
+    assert!(!oci(&["delete", "ctr"]).status().unwrap().success());
+    assert!(oci(&["kill", "ctr", "SIGTERM"]).status().unwrap().success());
+    wait_for("the container to stop", || state(&dir, "ctr")["status"] == "stopped");
+    assert!(oci(&["delete", "ctr"]).status().unwrap().success());
+    assert!(!oci(&["state", "ctr"]).status().unwrap().success());
+}
+
+#[test]
+fn mock_run_reports_exit_status()
+// ----------------------------------------------------------------------------
+//   Run waits for the container, exits like it, and deletes it
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("mock-run");
+    mock(&dir, &["sh", "-c", "exit 7"]);
+    let oci = runtime(&dir);
+
+    let status = oci(&["run", "--bundle", "bundle", "ctr"]).status().unwrap();
+    assert_eq!(status.code(), Some(7));
+    let output = oci(&["list", "--quiet"]).output().unwrap();
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn mock_delete_force()
+// ----------------------------------------------------------------------------
+//   A created container can be deleted with force, killing its process
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("mock-force");
+    mock(&dir, &["sleep", "30"]);
+    let oci = runtime(&dir);
+
+    assert!(oci(&["create", "--bundle", "bundle", "ctr"]).status().unwrap().success());
+    let pid = state(&dir, "ctr")["pid"].as_i64().unwrap();
+    assert!(oci(&["delete", "--force", "ctr"]).status().unwrap().success());
+    assert!(!dir.join("root/ctr").exists());
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+    assert!(stat.is_empty() || stat.contains(") Z"));
+}
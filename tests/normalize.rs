@@ -0,0 +1,93 @@
+// ****************************************************************************
+//  normalize.rs                                                ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that the JSON output of `state`, `list` and `ps` is converted
+//     to a canonical form when the CLI backend has `normalize` set
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+mod common;
+use common::{ociplex, scratch_dir, script_runtime};
+
+// What youki prints: no owner, extra fields, a creation time with an offset
+const YOUKI_STATE: &str = r#"{"ociVersion":"1.0.2","id":"my-container","status":"running","pid":42,"bundle":"/bundle","annotations":{},"created":"2023-05-04T12:00:00.5+02:00","creator":0,"use_systemd":false}"#;
+
+fn printing_runtime(dir: &Path, output: &str) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Create a config for a runtime printing the given output
+// ----------------------------------------------------------------------------
+{
+    let script = format!("cat <<'EOF'\n{}\nEOF", output);
+    script_runtime(dir, &script, "normalize = true")
+}
+
+fn run(name: &str, output: &str, args: &[&str]) -> Output {
+    let dir = scratch_dir(name);
+    let config = printing_runtime(&dir, output);
+    let result = ociplex(&config, &dir.join("log")).args(args).output().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    result
+}
+
+#[test]
+fn state_is_normalized() {
+    let result = run("normalize-state", YOUKI_STATE, &["state", "my-container"]);
+    assert!(result.status.success());
+    let expected = r#"{
+  "ociVersion": "1.0.2",
+  "id": "my-container",
+  "pid": 42,
+  "status": "running",
+  "bundle": "/bundle",
+  "rootfs": "",
+  "created": "2023-05-04T10:00:00.500000000Z",
+  "owner": "root"
+}
+"#;
+    assert_eq!(String::from_utf8_lossy(&result.stdout), expected);
+}
+
+#[test]
+fn list_and_ps_are_normalized() {
+    let list = format!("[{}]", YOUKI_STATE);
+    let result = run("normalize-list", &list, &["list", "--format", "json"]);
+    assert!(result.status.success());
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    assert!(stdout.starts_with(r#"[{"ociVersion":"1.0.2","id":"my-container","pid":42,"#));
+    assert!(stdout.ends_with("\"owner\":\"root\"}]\n"));
+
+    let result = run("normalize-empty", "null", &["list", "--format", "json"]);
+    assert_eq!(String::from_utf8_lossy(&result.stdout), "[]\n");
+
+    let result = run("normalize-ps", "[ 1, 2,\n 3 ]", &["ps", "--format", "json", "my-container"]);
+    assert_eq!(String::from_utf8_lossy(&result.stdout), "[1,2,3]\n");
+}
+
+#[test]
+fn invalid_state_is_an_error() {
+    let result = run("normalize-invalid", r#"{"id":"my-container"}"#, &["state", "my-container"]);
+    assert!(!result.status.success());
+    assert!(result.stdout.is_empty());
+}
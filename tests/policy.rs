@@ -0,0 +1,281 @@
+// ****************************************************************************
+//  policy.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that the policy backend rejects bundles violating its rules
+//     without calling its backend, and passes compliant ones
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+mod common;
+use common::{ociplex, recorded_argv, scratch_dir, write_script};
+
+const POLICY: &str = "forbidden_capabilities = [\"SYS_ADMIN\"]\n\
+                      allowed_host_paths = [\"/data\"]\n\
+                      require_user_namespace = true\n\
+                      forbid_host_network = true\n\
+                      max_memory = 1073741824\n\
+                      max_cpus = 2.0\n";
+
+fn policy(dir: &Path, rules: &str, spec: Value) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Create a policy config over a recording runtime, and a bundle
+// ----------------------------------------------------------------------------
+{
+    let runtime = dir.join("runtime");
+    write_script(&runtime, &format!("printf '%s\\n' \"$@\" > {:?}", dir.join("argv")));
+    let config = dir.join("backend.toml");
+    fs::write(
+        &config,
+        format!(
+            "backend-type = \"Policy\"\n{}\n[backend]\nbackend-type = \"Cli\"\npath = {:?}\n",
+            rules, runtime
+        ),
+    )
+    .unwrap();
+    let bundle = dir.join("bundle");
+    fs::create_dir_all(&bundle).unwrap();
+    fs::write(bundle.join("config.json"), spec.to_string()).unwrap();
+    config
+}
+
+#[test]
+fn policy_passes_compliant_bundle()
+// ----------------------------------------------------------------------------
+//   A bundle respecting all rules is created by the backend
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("policy-pass");
+    let config = policy(
+        &dir,
+        POLICY,
+        json!({
+            "ociVersion": "1.0.2",
+            "process": { "capabilities": { "bounding": ["CAP_CHOWN"] } },
+            "mounts": [
+                { "destination": "/data", "type": "bind", "source": "/data/app" },
+                { "destination": "/proc", "type": "proc", "source": "proc" },
+            ],
+            "linux": {
+                "namespaces": [{ "type": "user" }, { "type": "network" }],
+                "resources": {
+                    "memory": { "limit": 536870912 },
+                    "cpu": { "quota": 100000, "period": 100000 },
+                },
+            },
+        }),
+    );
+    let bundle = dir.join("bundle");
+    let status = ociplex(&config, &dir.join("log"))
+        .args(["create", "--bundle", bundle.to_str().unwrap(), "ctr"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(recorded_argv(&dir).last().unwrap(), "ctr");
+}
+
+#[test]
+fn policy_rejects_violations()
+// ----------------------------------------------------------------------------
+//   All violations are logged, and the backend is not called
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("policy-reject");
+    let config = policy(
+        &dir,
+        POLICY,
+        json!({
+            "ociVersion": "1.0.2",
+            "process": { "capabilities": { "effective": ["CAP_SYS_ADMIN"] } },
+            "mounts": [
+                { "destination": "/host", "options": ["rbind"], "source": "/data/../etc" },
+            ],
+            "linux": {
+                "namespaces": [{ "type": "pid" }],
+                "resources": { "cpu": { "quota": 400000, "period": 100000 } },
+            },
+        }),
+    );
+    let bundle = dir.join("bundle");
+    let log = dir.join("log");
+    let status = ociplex(&config, &log)
+        .args(["run", "--bundle", bundle.to_str().unwrap(), "ctr"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert!(!dir.join("argv").exists());
+
+    let log = fs::read_to_string(log).unwrap();
+    for violation in [
+        "Container ctr violates policy",
+        "capability CAP_SYS_ADMIN in effective set",
+        "host path /etc mounted on /host is not allowed",
+        "no user namespace",
+        "host network, without a network namespace",
+        "no memory limit, the maximum is 1073741824",
+        "CPU limit 4 is above the maximum of 2",
+    ] {
+        assert!(log.contains(violation), "{} not in {}", violation, log);
+    }
+}
+
+#[test]
+fn policy_resolves_symlinks_in_host_paths()
+// ----------------------------------------------------------------------------
+//   A link in an allowed directory cannot be used to mount what it points to
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("policy-symlink");
+    let shared = dir.join("shared");
+    fs::create_dir_all(&shared).unwrap();
+    symlink("/etc", shared.join("escape")).unwrap();
+    let config = policy(
+        &dir,
+        &format!("allowed_host_paths = [{:?}]", shared),
+        json!({
+            "ociVersion": "1.0.2",
+            "mounts": [
+                { "destination": "/app", "type": "bind", "source": shared.join("app") },
+                { "destination": "/host", "type": "bind", "source": shared.join("escape") },
+            ],
+        }),
+    );
+    let bundle = dir.join("bundle");
+    let log = dir.join("log");
+    let status = ociplex(&config, &log)
+        .args(["create", "--bundle", bundle.to_str().unwrap(), "ctr"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert!(!dir.join("argv").exists());
+
+    let log = fs::read_to_string(log).unwrap();
+    assert!(log.contains("host path /etc mounted on /host is not allowed"), "{}", log);
+    assert!(!log.contains("mounted on /app"), "{}", log);
+}
+
+#[test]
+fn policy_checks_exec_capabilities()
+// ----------------------------------------------------------------------------
+//   An exec cannot add a forbidden capability that the bundle could not have
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("policy-exec");
+    let config = policy(&dir, POLICY, json!({ "ociVersion": "1.0.2" }));
+    let log = dir.join("log");
+    let status = ociplex(&config, &log)
+        .args(["exec", "--cap", "CAP_SYS_ADMIN", "ctr", "sh"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert!(!dir.join("argv").exists());
+    let text = fs::read_to_string(&log).unwrap();
+    assert!(text.contains("capability CAP_SYS_ADMIN added with --cap"), "{}", text);
+
+    let process = dir.join("process.json");
+    fs::write(&process, json!({ "capabilities": { "bounding": ["sys_admin"] } }).to_string())
+        .unwrap();
+    let status = ociplex(&config, &log)
+        .args(["exec", "--process", process.to_str().unwrap(), "ctr"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert!(!dir.join("argv").exists());
+    let text = fs::read_to_string(&log).unwrap();
+    assert!(text.contains("capability sys_admin in bounding set"), "{}", text);
+
+    let status = ociplex(&config, &log).args(["exec", "--cap", "CAP_CHOWN", "ctr", "sh"]).status();
+    assert!(status.unwrap().success());
+    assert_eq!(recorded_argv(&dir).last().unwrap(), "sh");
+}
+
+#[test]
+fn policy_rejects_joined_host_namespaces()
+// ----------------------------------------------------------------------------
+//   Joining a namespace by path only counts if the path is allowed
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("policy-joined");
+    let rules = "require_user_namespace = true\nforbid_host_network = true\n\
+                 allowed_namespace_paths = [\"/run/netns\"]";
+    let config = policy(
+        &dir,
+        rules,
+        json!({
+            "ociVersion": "1.0.2",
+            "linux": { "namespaces": [
+                { "type": "user", "path": "/proc/1/ns/user" },
+                { "type": "network", "path": "/run/netns/ctr" },
+            ] },
+        }),
+    );
+    let bundle = dir.join("bundle");
+    let log = dir.join("log");
+    let status = ociplex(&config, &log)
+        .args(["create", "--bundle", bundle.to_str().unwrap(), "ctr"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert!(!dir.join("argv").exists());
+    let log = fs::read_to_string(log).unwrap();
+    assert!(log.contains("user namespace joined from /proc/1/ns/user is not allowed"), "{}", log);
+    assert!(!log.contains("network"), "{}", log);
+}
+
+#[test]
+fn policy_checks_updates()
+// ----------------------------------------------------------------------------
+//   An update cannot raise the limits of a container above the ceilings
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("policy-update");
+    let config = policy(&dir, POLICY, json!({ "ociVersion": "1.0.2" }));
+    let log = dir.join("log");
+    let status = ociplex(&config, &log)
+        .args(["update", "--memory", "4294967296", "ctr"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert!(!dir.join("argv").exists());
+    let text = fs::read_to_string(&log).unwrap();
+    assert!(text.contains("memory limit 4294967296 is above the maximum"), "{}", text);
+
+    let resources = dir.join("resources.json");
+    fs::write(&resources, json!({ "cpu": { "quota": 800000, "period": 100000 } }).to_string())
+        .unwrap();
+    let status = ociplex(&config, &log)
+        .args(["update", "-r", resources.to_str().unwrap(), "ctr"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert!(!dir.join("argv").exists());
+    let text = fs::read_to_string(&log).unwrap();
+    assert!(text.contains("CPU limit 8 is above the maximum of 2"), "{}", text);
+
+    // Limits that are not changed keep their checked value
+    let status = ociplex(&config, &log).args(["update", "--pids-limit", "100", "ctr"]).status();
+    assert!(status.unwrap().success());
+    assert_eq!(recorded_argv(&dir).last().unwrap(), "ctr");
+}
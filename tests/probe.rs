@@ -0,0 +1,103 @@
+// ****************************************************************************
+//  probe.rs                                                    ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that options a CLI runtime does not support are dropped or
+//     rejected after probing the runtime, and that probing is cached
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+mod common;
+use common::{ociplex, recorded_argv, scratch_dir, write_script};
+
+fn probed_runtime(dir: &Path) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Create a config for a runtime that answers probes and records arguments
+// ----------------------------------------------------------------------------
+//   Each probe appends a line to `probes` in the directory.
+{
+    let runtime = dir.join("runtime");
+    let probes = dir.join("probes");
+    write_script(
+        &runtime,
+        &format!(
+            r#"case "$*" in
+features) echo '{{"ociVersionMin": "1.0.0"}}'; echo "$*" >> {probes:?}; exit 0;;
+--help) printf '   --log value\n   --root value\n'; echo "$*" >> {probes:?}; exit 0;;
+"create --help") printf '   --bundle value, -b value\n   --pid-file value\n'; echo "$*" >> {probes:?}; exit 0;;
+esac
+printf '%s\n' "$@" > {argv:?}"#,
+            probes = probes,
+            argv = dir.join("argv"),
+        ),
+    );
+    let path = dir.join("backend.toml");
+    fs::write(
+        &path,
+        format!(
+            "backend-type = \"Cli\"\npath = {:?}\n\n[probe]\nhelp = true\ncache_dir = {:?}\npolicy = {{ \"create.no-pivot\" = \"drop\" }}\n",
+            runtime,
+            dir.join("cache")
+        ),
+    )
+    .unwrap();
+    path
+}
+
+#[test]
+fn unsupported_options_are_dropped_and_probes_cached() {
+    let dir = scratch_dir("probe-drop");
+    let config = probed_runtime(&dir);
+    for _ in 0..2 {
+        let status = ociplex(&config, &dir.join("log"))
+            .args(["create", "--pid-file", "/tmp/pid", "--no-pivot", "my-container"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    let argv = recorded_argv(&dir);
+    assert!(argv.contains(&"--pid-file".to_string()));
+    assert!(!argv.contains(&"--no-pivot".to_string()));
+
+    let probes = fs::read_to_string(dir.join("probes")).unwrap();
+    assert_eq!(probes, "features\n--help\ncreate --help\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unsupported_options_are_rejected_by_default() {
+    let dir = scratch_dir("probe-reject");
+    let config = probed_runtime(&dir);
+    let log = dir.join("log");
+    let status = ociplex(&config, &log)
+        .args(["create", "--no-new-keyring", "my-container"])
+        .status()
+        .unwrap();
+    assert!(!status.success());
+    assert!(!dir.join("argv").exists());
+
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(log.contains("Option --no-new-keyring of create is not supported by backend"));
+    fs::remove_dir_all(&dir).unwrap();
+}
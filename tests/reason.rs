@@ -0,0 +1,79 @@
+// ****************************************************************************
+//  reason.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that the reason given by a failing CLI runtime, on stderr or in
+//     its log file, is included in the error reported by ociplex
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::process::Output;
+
+mod common;
+use common::{ociplex, scratch_dir, script_runtime};
+
+fn run_failing(name: &str, script: &str, args: &[&str]) -> (Output, String)
+// ----------------------------------------------------------------------------
+//   Run a failing runtime, return the output of ociplex and its log
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir(name);
+    let config = script_runtime(&dir, script, "");
+    let log = dir.join("log.json");
+    let output = ociplex(&config, &log)
+        .args(["--log-format", "json"])
+        .args(args)
+        .output()
+        .unwrap();
+    let log = fs::read_to_string(&log).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    (output, log)
+}
+
+#[test]
+fn reason_from_stderr() {
+    let (output, log) = run_failing(
+        "reason-stderr",
+        "echo 'some noise' >&2\necho 'level=error msg=\"container \\\"c1\\\" does not exist\"' >&2\nexit 1",
+        &["state", "c1"],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("some noise\n"));
+    assert!(log.contains(
+        r#""msg":"Backend CLI failed with status code 1: container \"c1\" does not exist""#
+    ));
+}
+
+#[test]
+fn reason_from_log_file() {
+    // The runtime receives `--log <file> --log-format json create ...`
+    let (output, log) = run_failing(
+        "reason-log",
+        "echo '{\"level\":\"info\",\"msg\":\"starting\"}' >> \"$2\"\n\
+         echo '{\"level\":\"error\",\"msg\":\"cannot allocate tty\"}' >> \"$2\"\n\
+         echo '{\"level\":\"debug\",\"msg\":\"cleaning up\"}' >> \"$2\"\n\
+         exit 3",
+        &["create", "--bundle", "/tmp", "c1"],
+    );
+    assert_eq!(output.status.code(), Some(3));
+    assert!(log.contains(r#""msg":"Backend CLI failed with status code 3: cannot allocate tty""#));
+}
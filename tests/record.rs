@@ -0,0 +1,173 @@
+// ****************************************************************************
+//  record.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that the record backend journals each invocation, and passes
+//     commands to its backend
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::Path;
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+mod common;
+use common::{ociplex, recorded_argv, scratch_dir, write_script};
+
+fn journal(dir: &Path) -> Vec<Value> {
+    fs::read_to_string(dir.join("journal.jsonl"))
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn record_journals_invocations()
+// ----------------------------------------------------------------------------
+//   Each command is journaled with its arguments, bundle and result
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("record");
+    let runtime = dir.join("runtime");
+    write_script(
+        &runtime,
+        &format!(
+            "printf '%s\\n' \"$@\" > {:?}\ncase $3 in kill) exit 1;; esac",
+            dir.join("argv")
+        ),
+    );
+    let config = dir.join("backend.toml");
+    fs::write(
+        &config,
+        format!(
+            "backend-type = \"Record\"\njournal = {:?}\nenv = [\"NOTIFY_SOCKET\", \"UNSET\"]\n\n\
+             [backend]\nbackend-type = \"Cli\"\npath = {:?}\n",
+            dir.join("journal.jsonl"),
+            runtime
+        ),
+    )
+    .unwrap();
+    let bundle = dir.join("bundle");
+    fs::create_dir_all(&bundle).unwrap();
+    let spec = "{\"ociVersion\":\"1.0.2\",\"annotations\":{\"a\":\"b\"}}";
+    fs::write(bundle.join("config.json"), spec).unwrap();
+
+    let log = dir.join("log");
+    let status = ociplex(&config, &log)
+        .env("NOTIFY_SOCKET", "/run/notify")
+        .args(["create", "--bundle", bundle.to_str().unwrap(), "--no-pivot", "ctr"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(
+        recorded_argv(&dir)[2..],
+        ["create", "--bundle", bundle.to_str().unwrap(), "--no-pivot", "ctr"]
+    );
+    let status = ociplex(&config, &log).args(["kill", "ctr", "KILL"]).status().unwrap();
+    assert_eq!(status.code(), Some(1));
+
+    let journal = journal(&dir);
+    assert_eq!(journal.len(), 2);
+    let create = &journal[0];
+    assert_eq!(create["subcommand"], "create");
+    assert_eq!(
+        create["args"],
+        json!({
+            "options": [["bundle", bundle], ["no-pivot", null]],
+            "operands": ["ctr"],
+            "trailing": [],
+        })
+    );
+    assert_eq!(create["global"]["log"], json!(log));
+    assert_eq!(create["env"], json!({ "NOTIFY_SOCKET": "/run/notify" }));
+    let digest: String = Sha256::digest(spec.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    assert_eq!(create["bundle"]["path"], json!(bundle));
+    assert_eq!(create["bundle"]["digest"], format!("sha256:{}", digest));
+    assert_eq!(create["bundle"]["config"]["annotations"]["a"], "b");
+    assert!(create["duration"].as_f64().unwrap() >= 0.0);
+    assert_eq!(create["result"], json!({ "outcome": "ok", "code": 0, "error": null }));
+
+    let kill = &journal[1];
+    assert_eq!(kill["subcommand"], "kill");
+    assert_eq!(kill["bundle"], Value::Null);
+    assert_eq!(kill["result"]["outcome"], "exit");
+    assert_eq!(kill["result"]["code"], 1);
+}
+
+#[test]
+fn trivial_records_with_mock()
+// ----------------------------------------------------------------------------
+//   The former trivial backend records invocations run by a mock backend
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("record-trivial");
+    let config = dir.join("backend.toml");
+    fs::write(
+        &config,
+        format!("backend-type = \"Trivial\"\njournal = {:?}\n", dir.join("journal.jsonl")),
+    )
+    .unwrap();
+    let status = ociplex(&config, &dir.join("log"))
+        .env("XDG_RUNTIME_DIR", &dir)
+        .args(["start", "ctr"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+
+    let journal = journal(&dir);
+    assert_eq!(journal.len(), 1);
+    assert_eq!(journal[0]["args"]["operands"], json!(["ctr"]));
+    assert_eq!(journal[0]["result"]["outcome"], "error");
+    assert_eq!(journal[0]["result"]["error"], "Container ctr does not exist");
+}
+
+#[test]
+fn record_rejects_exec_backend()
+// ----------------------------------------------------------------------------
+//   A backend replacing ociplex would leave the command out of the journal
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("record-exec");
+    let runtime = dir.join("runtime");
+    write_script(&runtime, &format!("printf '%s\\n' \"$@\" > {:?}", dir.join("argv")));
+    let config = dir.join("backend.toml");
+    fs::write(
+        &config,
+        format!(
+            "backend-type = \"Record\"\njournal = {:?}\n\n\
+             [backend]\nbackend-type = \"Cli\"\npath = {:?}\nexec = true\n",
+            dir.join("journal.jsonl"),
+            runtime
+        ),
+    )
+    .unwrap();
+    let log = dir.join("log");
+    let status = ociplex(&config, &log).args(["state", "c1"]).status().unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert!(!dir.join("argv").exists());
+    let log = fs::read_to_string(log).unwrap();
+    assert!(log.contains("Recorded backends cannot use exec"), "{}", log);
+}
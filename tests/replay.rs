@@ -0,0 +1,151 @@
+// ****************************************************************************
+//  replay.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that a journal written by the record backend can be replayed
+//     on another backend, with remapped IDs, and that divergences are found
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+mod common;
+use common::{ociplex, scratch_dir, write_script};
+
+fn runtime(dir: &Path, name: &str, status: &str) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Create a CLI backend config for a runtime logging its arguments
+// ----------------------------------------------------------------------------
+//   The runtime appends its arguments, without `--log`, to a file named
+//   after it, and prints a state with the given status.
+{
+    let runtime = dir.join(name);
+    write_script(
+        &runtime,
+        &format!(
+            "shift 2\necho $@ >> {:?}\ncase $1 in state) echo '{{\"ociVersion\":\"1.0.2\",\
+             \"id\":\"'$2'\",\"pid\":'$$',\"status\":\"{}\",\"bundle\":\"/b\",\
+             \"created\":\"2023-01-01T00:00:00Z\",\"owner\":\"\"}}';; esac",
+            dir.join(format!("{}.log", name)),
+            status
+        ),
+    );
+    let path = dir.join(format!("{}.toml", name));
+    fs::write(&path, format!("backend-type = \"Cli\"\npath = {:?}\n", runtime)).unwrap();
+    path
+}
+
+fn record(dir: &Path) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Record a short container lifecycle with a runtime reporting it running
+// ----------------------------------------------------------------------------
+{
+    runtime(dir, "recorded", "running");
+    let config = dir.join("record.toml");
+    fs::write(
+        &config,
+        format!(
+            "backend-type = \"Record\"\njournal = {:?}\n\n\
+             [backend]\nbackend-type = \"Cli\"\npath = {:?}\n",
+            dir.join("journal.jsonl"),
+            dir.join("recorded")
+        ),
+    )
+    .unwrap();
+    let bundle = dir.join("bundle");
+    fs::create_dir_all(&bundle).unwrap();
+    fs::write(bundle.join("config.json"), "{\"ociVersion\":\"1.0.2\"}").unwrap();
+
+    // The bundle is relative to where the engine ran, and the PID file its own
+    let log = dir.join("log");
+    let create = ["create", "--bundle", ".", "--pid-file", "ctr.pid", "ctr"];
+    let commands = [&create[..], &["state", "ctr"], &["kill", "ctr", "9"]];
+    for args in commands {
+        let output = ociplex(&config, &log).current_dir(&bundle).args(args).output().unwrap();
+        assert!(output.status.success());
+    }
+    dir.join("journal.jsonl")
+}
+
+#[test]
+fn replay_matches_recording()
+// ----------------------------------------------------------------------------
+//   Replaying on an equivalent backend remaps IDs and bundles, and passes
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("replay-match");
+    let journal = record(&dir);
+    let config = runtime(&dir, "replayed", "running");
+    let output = ociplex(&config, &dir.join("log"))
+        .arg("replay")
+        .arg(&journal)
+        .args(["--map-id", "ctr=other"])
+        .arg(format!("--map-bundle={}=/replayed", dir.display()))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        fs::read_to_string(dir.join("replayed.log")).unwrap(),
+        "create --bundle /replayed/bundle other\nstate other\nkill other 9\n"
+    );
+}
+
+#[test]
+fn replay_reports_divergences()
+// ----------------------------------------------------------------------------
+//   Differences in exit status or state output are reported, and fail
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("replay-diverge");
+    let journal = record(&dir);
+    let config = runtime(&dir, "replayed", "stopped");
+    let script = fs::read_to_string(dir.join("replayed")).unwrap();
+    write_script(&dir.join("replayed"), &format!("{}\ncase $1 in kill) exit 3;; esac", script));
+
+    let report = dir.join("report.jsonl");
+    let status = ociplex(&config, &dir.join("log"))
+        .arg("replay")
+        .arg(&journal)
+        .arg("--report")
+        .arg(&report)
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+
+    let report: Vec<Value> = fs::read_to_string(report)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(report.len(), 2);
+    assert_eq!(report[0]["line"], 2);
+    assert_eq!(report[0]["subcommand"], "state");
+    assert_eq!(report[0]["container"], "ctr");
+    assert_eq!(
+        report[0]["differences"],
+        json!(["state status: \"running\" vs \"stopped\""])
+    );
+    assert_eq!(report[1]["subcommand"], "kill");
+    assert_eq!(report[1]["recorded"], json!({ "outcome": "ok", "code": 0 }));
+    assert_eq!(report[1]["replayed"]["code"], 3);
+}
@@ -0,0 +1,234 @@
+// ****************************************************************************
+//  router.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that the router backend selects a backend from annotations at
+//     create, and sends later commands for the container to the same one
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+mod common;
+use common::{ociplex, scratch_dir, write_script};
+
+fn router(dir: &Path) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Create a router config for runtimes `runc` and `kata`
+// ----------------------------------------------------------------------------
+//   Each runtime appends its subcommand to a file named after it. Both
+//   list a `shared` container, and one named after them.
+{
+    let mut config = format!(
+        "backend-type = \"Router\"\ndefault = \"runc\"\nstate_dir = {:?}\n\n\
+         [[rules]]\nbackend = \"kata\"\n\
+         annotations = {{ \"io.kubernetes.cri.runtime-handler\" = \"kata*\" }}\n\n\
+         [[rules]]\nbackend = \"kata\"\nimage = \"*/untrusted/*\"\n",
+        dir.join("state")
+    );
+    for name in ["runc", "kata"] {
+        let runtime = dir.join(name);
+        write_script(
+            &runtime,
+            &format!(
+                "for arg; do case $arg in create|start|delete|list) echo $arg >> {:?};; esac; done\n\
+                 case \" $* \" in *' list '*) echo '[{{\"id\":\"shared\"}},{{\"id\":\"{}\"}}]';; esac",
+                dir.join(format!("{}.log", name)),
+                name
+            ),
+        );
+        config.push_str(&format!(
+            "\n[backends.{}]\nbackend-type = \"Cli\"\npath = {:?}\n",
+            name, runtime
+        ));
+    }
+    let path = dir.join("backend.toml");
+    fs::write(&path, config).unwrap();
+    path
+}
+
+fn bundle(dir: &Path, name: &str, annotations: &str) -> PathBuf {
+    let bundle = dir.join(name);
+    fs::create_dir_all(&bundle).unwrap();
+    fs::write(
+        bundle.join("config.json"),
+        format!("{{\"ociVersion\":\"1.0.2\",\"annotations\":{{{}}}}}", annotations),
+    )
+    .unwrap();
+    bundle
+}
+
+fn run(dir: &Path, config: &Path, args: &[&str]) {
+    let status = ociplex(config, &dir.join("log")).args(args).status().unwrap();
+    assert!(status.success());
+}
+
+fn commands(dir: &Path, name: &str) -> String {
+    fs::read_to_string(dir.join(format!("{}.log", name))).unwrap_or_default()
+}
+
+#[test]
+fn container_sticks_to_its_backend() {
+    let dir = scratch_dir("router-sticky");
+    let config = router(&dir);
+    let kata = bundle(&dir, "kata-bundle", r#""io.kubernetes.cri.runtime-handler":"kata-qemu""#);
+    let kata = kata.to_str().unwrap();
+    let plain = bundle(&dir, "plain-bundle", "");
+    let plain = plain.to_str().unwrap();
+
+    run(&dir, &config, &["create", "--bundle", kata, "c1"]);
+    run(&dir, &config, &["create", "--bundle", plain, "c2"]);
+    run(&dir, &config, &["start", "c1"]);
+    run(&dir, &config, &["start", "c2"]);
+    run(&dir, &config, &["delete", "c1"]);
+    run(&dir, &config, &["list"]);
+
+    assert_eq!(commands(&dir, "kata"), "create\nstart\ndelete\nlist\n");
+    assert_eq!(commands(&dir, "runc"), "create\nstart\nlist\n");
+    assert!(!dir.join("state/routes/c1").exists());
+    assert_eq!(fs::read_to_string(dir.join("state/routes/c2")).unwrap(), "runc\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn list_merges_all_backends() {
+    let dir = scratch_dir("router-list");
+    let config = router(&dir);
+    let output = ociplex(&config, &dir.join("log")).args(["list", "-q"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "shared\nrunc\nkata\n");
+
+    let output = ociplex(&config, &dir.join("log"))
+        .args(["list", "--format", "json"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "[{\"id\":\"shared\"},{\"id\":\"runc\"},{\"id\":\"kata\"}]\n"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unknown_containers_are_not_routed() {
+    // Without a record, the container was not created through the router
+    let dir = scratch_dir("router-unknown");
+    let config = router(&dir);
+    let log = dir.join("log");
+    let status = ociplex(&config, &log).args(["start", "c1"]).status().unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert_eq!(commands(&dir, "runc"), "");
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(log.contains("No backend recorded for container c1"), "{}", log);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn image_and_explicit_annotation() {
+    let dir = scratch_dir("router-image");
+    let config = router(&dir);
+    let image = bundle(
+        &dir,
+        "image-bundle",
+        r#""io.kubernetes.cri.image-name":"quay.io/untrusted/tool:latest""#,
+    );
+    let explicit = bundle(
+        &dir,
+        "explicit-bundle",
+        r#""io.kubernetes.cri.runtime-handler":"kata","io.ociplex.backend":"runc""#,
+    );
+
+    run(&dir, &config, &["create", "--bundle", image.to_str().unwrap(), "c1"]);
+    run(&dir, &config, &["create", "--bundle", explicit.to_str().unwrap(), "c2"]);
+    assert_eq!(commands(&dir, "kata"), "create\n");
+    assert_eq!(commands(&dir, "runc"), "create\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn subcommands_go_to_their_backend() {
+    let dir = scratch_dir("router-commands");
+    let config = router(&dir);
+    let text = fs::read_to_string(&config).unwrap();
+    let text = text.replacen("\n[[rules]]", "commands = { start = \"kata\", list = \"kata\" }\n\n[[rules]]", 1);
+    let root = format!("global_args = [\"--root\", {:?}]\n", dir.join("root"));
+    let text = text
+        .replace("[backends.runc]\n", &format!("[backends.runc]\n{}", root))
+        .replace("[backends.kata]\n", &format!("[backends.kata]\n{}", root));
+    fs::write(&config, text).unwrap();
+    let plain = bundle(&dir, "plain-bundle", "");
+
+    run(&dir, &config, &["create", "--bundle", plain.to_str().unwrap(), "c1"]);
+    run(&dir, &config, &["start", "c1"]);
+    run(&dir, &config, &["list"]);
+    run(&dir, &config, &["delete", "c1"]);
+    assert_eq!(commands(&dir, "runc"), "create\ndelete\n");
+    assert_eq!(commands(&dir, "kata"), "start\nlist\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn incompatible_state_roots_are_rejected() {
+    let dir = scratch_dir("router-roots");
+    let config = router(&dir);
+    let text = fs::read_to_string(&config).unwrap();
+    let text = text.replacen("\n[[rules]]", "commands = { checkpoint = \"kata\" }\n\n[[rules]]", 1);
+    let text = text.replace(
+        "[backends.kata]\n",
+        "[backends.kata]\nglobal_args = [\"--root\", \"/run/kata\"]\n",
+    );
+    fs::write(&config, text).unwrap();
+
+    let log = dir.join("log");
+    let status = ociplex(&config, &log).args(["list"]).status().unwrap();
+    assert!(!status.success());
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(log.contains("for checkpoint does not share the state root"), "{}", log);
+
+    // Default roots depend on the runtime, so they are never shared
+    let text = fs::read_to_string(&config).unwrap();
+    let text = text.replace("global_args = [\"--root\", \"/run/kata\"]\n", "");
+    fs::write(&config, text).unwrap();
+    let log = dir.join("log2");
+    let status = ociplex(&config, &log).args(["list"]).status().unwrap();
+    assert!(!status.success());
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(log.contains("for checkpoint does not share the state root"), "{}", log);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn exec_backends_are_rejected() {
+    let dir = scratch_dir("router-exec");
+    let config = router(&dir);
+    let text = fs::read_to_string(&config).unwrap();
+    let text = text.replace("[backends.kata]\n", "[backends.kata]\nexec = true\n");
+    fs::write(&config, text).unwrap();
+
+    let log = dir.join("log");
+    let status = ociplex(&config, &log).args(["list"]).status().unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert_eq!(commands(&dir, "runc"), "");
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(log.contains("Routed backends cannot use exec"), "{}", log);
+    fs::remove_dir_all(&dir).unwrap();
+}
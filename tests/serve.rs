@@ -0,0 +1,205 @@
+// ****************************************************************************
+//  serve.rs                                                    ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that ociplex forwards commands to a running daemon, with their
+//     output and failures, and runs them itself otherwise
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+
+mod common;
+use common::{ociplex, scratch_dir, script_runtime};
+
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn setup(dir: &Path) -> (PathBuf, PathBuf)
+// ----------------------------------------------------------------------------
+//   Create a config for a runtime telling who ran it, and a socket path
+// ----------------------------------------------------------------------------
+//   The runtime prints SERVED_BY, which only the daemon has, and its last
+//   argument. It fails `kill` with status 3.
+{
+    let config = script_runtime(
+        dir,
+        "for arg; do last=$arg; done\n\
+         echo \"${SERVED_BY:-client} $last\"\n\
+         case \" $* \" in *' kill '*) echo 'no such container' >&2; exit 3;; esac",
+        "",
+    );
+    (config, dir.join("ociplex.sock"))
+}
+
+fn start_daemon(config: &Path, socket: &Path, dir: &Path, vars: &[(&str, &str)]) -> Daemon {
+    let child = ociplex(config, &dir.join("daemon.log"))
+        .arg("--socket")
+        .arg(socket)
+        .arg("serve")
+        .env("SERVED_BY", "daemon")
+        .envs(vars.iter().copied())
+        .spawn()
+        .unwrap();
+    let daemon = Daemon(child);
+    for _ in 0..500 {
+        if socket.exists() {
+            return daemon;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("Daemon did not create {}", socket.display());
+}
+
+fn client(config: &Path, socket: &Path, dir: &Path) -> Command {
+    let mut cmd = ociplex(config, &dir.join("log"));
+    cmd.arg("--socket").arg(socket);
+    cmd
+}
+
+#[test]
+fn serve_runs_forwarded_commands()
+// ----------------------------------------------------------------------------
+//   Commands are run by the daemon, with output and failures sent back
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("serve-forward");
+    let (config, socket) = setup(&dir);
+    let _daemon = start_daemon(&config, &socket, &dir, &[]);
+
+    for id in ["one", "two"] {
+        let output = client(&config, &socket, &dir).args(["state", id]).output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), format!("daemon {}\n", id));
+    }
+
+    let output = client(&config, &socket, &dir).args(["kill", "ctr", "TERM"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    let log = fs::read_to_string(dir.join("log")).unwrap();
+    assert!(log.contains("Backend CLI failed with status code 3"), "{}", log);
+    assert!(log.contains("no such container"), "{}", log);
+}
+
+#[test]
+fn serve_leaves_other_commands_to_client()
+// ----------------------------------------------------------------------------
+//   Commands needing the client, or without a daemon, run in the client
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("serve-client");
+    let (config, socket) = setup(&dir);
+    let output = client(&config, &socket, &dir).args(["state", "ctr"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "client ctr\n");
+
+    let _daemon = start_daemon(&config, &socket, &dir, &[]);
+    let output = client(&config, &socket, &dir)
+        .args(["create", "--bundle", dir.to_str().unwrap(), "ctr"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "client ctr\n");
+
+    // A daemon serving another configuration refuses commands
+    let other = dir.join("other.toml");
+    fs::copy(&config, &other).unwrap();
+    let output = client(&other, &socket, &dir).args(["state", "ctr"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "client ctr\n");
+}
+
+#[test]
+fn serve_refuses_changed_config()
+// ----------------------------------------------------------------------------
+//   A config edited after the daemon started is not served with the old one
+// ----------------------------------------------------------------------------
+{
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = scratch_dir("serve-changed");
+    let (config, socket) = setup(&dir);
+    let _daemon = start_daemon(&config, &socket, &dir, &[]);
+    let mode = fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let text = fs::read_to_string(&config).unwrap();
+    fs::write(&config, text + "# edited\n").unwrap();
+    let output = client(&config, &socket, &dir).args(["state", "ctr"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "client ctr\n");
+}
+
+#[test]
+fn serve_uses_client_environment()
+// ----------------------------------------------------------------------------
+//   Commands see the runtime variables of the client, and return its stderr
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("serve-env");
+    let config = script_runtime(
+        &dir,
+        "echo \"${SERVED_BY:-client} ${XDG_RUNTIME_DIR:-none} ${RUNC_MARK:-unset}\"\n\
+         echo 'runtime warning' >&2",
+        "",
+    );
+    let socket = dir.join("ociplex.sock");
+    let vars = [("RUNC_MARK", "daemon"), ("XDG_RUNTIME_DIR", "/daemon")];
+    let _daemon = start_daemon(&config, &socket, &dir, &vars);
+
+    let runtime_dir = dir.join("client");
+    let output = client(&config, &socket, &dir)
+        .args(["state", "ctr"])
+        .env("RUNC_MARK", "client")
+        .env("XDG_RUNTIME_DIR", &runtime_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let expected = format!("daemon {} client\n", runtime_dir.display());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("runtime warning"));
+
+    // Variables the client does not have are not taken from the daemon
+    let output = client(&config, &socket, &dir)
+        .args(["state", "ctr"])
+        .env_remove("RUNC_MARK")
+        .env_remove("XDG_RUNTIME_DIR")
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "daemon none unset\n");
+
+    // A dry run in the client is a dry run in the daemon
+    let output = client(&config, &socket, &dir)
+        .args(["state", "ctr"])
+        .env("OCIPLEX_DRY_RUN", "1")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\"command\""), "{}", stdout);
+    assert!(stdout.contains("\"SERVED_BY\":\"daemon\""), "{}", stdout);
+}
@@ -0,0 +1,318 @@
+// ****************************************************************************
+//  shim.rs                                                     ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that ociplex invoked as a containerd shim serves the Task API,
+//     running tasks with the mock backend
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use containerd_shim_protos::api::{self, Status};
+use containerd_shim_protos::cgroups::metrics::Metrics;
+use containerd_shim_protos::protobuf::Message;
+use containerd_shim_protos::ttrpc::context::Context;
+use containerd_shim_protos::{Client, TaskClient};
+use serde_json::json;
+
+mod common;
+use common::{scratch_dir, write_script};
+
+fn setup(dir: &Path, args: &[&str]) -> (PathBuf, PathBuf)
+// ----------------------------------------------------------------------------
+//   Create a shim, a mock backend config, and a bundle running the process
+// ----------------------------------------------------------------------------
+//   The publish binary records the topic of each event in `events`.
+{
+    let shim = dir.join("containerd-shim-ociplex-v2");
+    symlink(env!("CARGO_BIN_EXE_ociplex"), &shim).unwrap();
+    fs::write(
+        dir.join("backend.toml"),
+        format!("backend-type = \"Mock\"\nroot = {:?}\n", dir.join("root")),
+    )
+    .unwrap();
+    write_script(
+        &dir.join("publish"),
+        &format!(
+            "cat > /dev/null\nwhile [ \"$1\" != --topic ]; do shift; done\necho \"$2\" >> {:?}",
+            dir.join("events")
+        ),
+    );
+
+    let bundle = dir.join("bundle");
+    fs::create_dir_all(&bundle).unwrap();
+    let spec = json!({
+        "ociVersion": "1.0.2",
+        "process": { "args": args, "env": ["PATH=/usr/bin:/bin"], "cwd": bundle },
+    });
+    fs::write(bundle.join("config.json"), spec.to_string()).unwrap();
+    (shim, bundle)
+}
+
+fn shim(dir: &Path, shim: &Path, bundle: &Path, action: &str) -> Command {
+    let mut cmd = Command::new(shim);
+    cmd.args(["-namespace", "test", "-address", "/run/containerd.sock"])
+        .arg("-publish-binary")
+        .arg(dir.join("publish"))
+        .args(["-id", "ctr", action])
+        .current_dir(bundle)
+        .env("OCIPLEX_BACKEND", dir.join("backend.toml"))
+        .env("XDG_RUNTIME_DIR", dir);
+    cmd
+}
+
+fn wait_for(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "Timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn shim_runs_task_with_backend()
+// ----------------------------------------------------------------------------
+//   The start action launches a shim, which runs a task through its life
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("shim-task");
+    let (shim_path, bundle) = setup(&dir, &["sh", "-c", "echo hello; exit 7"]);
+    let output = shim(&dir, &shim_path, &bundle, "start").output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let address = String::from_utf8(output.stdout).unwrap();
+    let address = address.trim();
+    assert!(address.starts_with("unix://"), "{}", address);
+
+    let client = TaskClient::new(Client::connect(address).unwrap());
+    let ctx = Context::default();
+    let stdout = dir.join("stdout");
+    fs::write(&stdout, "").unwrap();
+    let created = client
+        .create(
+            ctx.clone(),
+            &api::CreateTaskRequest {
+                id: "ctr".into(),
+                bundle: bundle.to_str().unwrap().into(),
+                stdout: stdout.to_str().unwrap().into(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert!(created.pid > 0);
+
+    let state = |client: &TaskClient| {
+        let request = api::StateRequest {
+            id: "ctr".into(),
+            ..Default::default()
+        };
+        client.state(Context::default(), &request).unwrap()
+    };
+    assert_eq!(state(&client).status.enum_value(), Ok(Status::CREATED));
+
+    let start = api::StartRequest {
+        id: "ctr".into(),
+        ..Default::default()
+    };
+    let started = client.start(ctx.clone(), &start).unwrap();
+    assert_eq!(started.pid, created.pid);
+
+    let wait = api::WaitRequest {
+        id: "ctr".into(),
+        ..Default::default()
+    };
+    let waited = client.wait(ctx.clone(), &wait).unwrap();
+    assert_eq!(waited.exit_status, 7);
+    assert_eq!(fs::read_to_string(&stdout).unwrap(), "hello\n");
+    let stopped = state(&client);
+    assert_eq!(stopped.status.enum_value(), Ok(Status::STOPPED));
+    assert_eq!(stopped.exit_status, 7);
+
+    let delete = api::DeleteRequest {
+        id: "ctr".into(),
+        ..Default::default()
+    };
+    let deleted = client.delete(ctx.clone(), &delete).unwrap();
+    assert_eq!(deleted.exit_status, 7);
+    assert!(client.state(ctx.clone(), &api::StateRequest {
+        id: "ctr".into(),
+        ..Default::default()
+    })
+    .is_err());
+
+    let events = dir.join("events");
+    let expected = "/tasks/create\n/tasks/start\n/tasks/exit\n/tasks/delete\n";
+    wait_for("events", || fs::read_to_string(&events).unwrap_or_default() == expected);
+
+    // Without tasks left, the shim exits and removes its socket
+    let _ = client.shutdown(ctx, &api::ShutdownRequest::default());
+    let socket = PathBuf::from(address.trim_start_matches("unix://"));
+    wait_for("shim exit", || !socket.exists());
+}
+
+#[test]
+fn shim_delete_action_cleans_up()
+// ----------------------------------------------------------------------------
+//   The delete action deletes the container and reports it as killed
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("shim-delete");
+    let (shim_path, bundle) = setup(&dir, &["sleep", "10"]);
+    let output = shim(&dir, &shim_path, &bundle, "delete").output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let response = api::DeleteResponse::parse_from_bytes(&output.stdout).unwrap();
+    assert_eq!(response.exit_status, 137);
+    assert!(response.exited_at.is_some());
+}
+
+#[test]
+fn shim_rejects_exec_backend()
+// ----------------------------------------------------------------------------
+//   A runtime replacing the shim would take down the Task service with it
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("shim-exec");
+    let (shim_path, bundle) = setup(&dir, &["true"]);
+    fs::write(
+        dir.join("backend.toml"),
+        "backend-type = \"Cli\"\npath = \"/bin/true\"\nexec = true\n",
+    )
+    .unwrap();
+    let output = shim(&dir, &shim_path, &bundle, "start").output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("The shim needs a backend not using exec"), "{}", stderr);
+    assert!(!dir.join("ociplex/s").exists());
+}
+
+#[test]
+fn shim_reports_statistics()
+// ----------------------------------------------------------------------------
+//   Statistics of the backend are returned as cgroup metrics
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("shim-stats");
+    let (shim_path, bundle) = setup(&dir, &["sleep", "10"]);
+    let output = shim(&dir, &shim_path, &bundle, "start").output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let address = String::from_utf8(output.stdout).unwrap();
+    let client = TaskClient::new(Client::connect(address.trim()).unwrap());
+    let ctx = Context::default();
+    let create = api::CreateTaskRequest {
+        id: "ctr".into(),
+        bundle: bundle.to_str().unwrap().into(),
+        ..Default::default()
+    };
+    client.create(ctx.clone(), &create).unwrap();
+    let start = api::StartRequest {
+        id: "ctr".into(),
+        ..Default::default()
+    };
+    client.start(ctx.clone(), &start).unwrap();
+
+    let request = api::StatsRequest {
+        id: "ctr".into(),
+        ..Default::default()
+    };
+    let stats = client.stats(ctx.clone(), &request).unwrap().stats.unwrap();
+    assert_eq!(stats.type_url, "io.containerd.cgroups.v1.Metrics");
+    let metrics = Metrics::parse_from_bytes(&stats.value).unwrap();
+    assert_eq!(metrics.pids.current, 1);
+
+    let kill = api::KillRequest {
+        id: "ctr".into(),
+        signal: 9,
+        ..Default::default()
+    };
+    client.kill(ctx.clone(), &kill).unwrap();
+    let wait = api::WaitRequest {
+        id: "ctr".into(),
+        ..Default::default()
+    };
+    assert_eq!(client.wait(ctx.clone(), &wait).unwrap().exit_status, 137);
+    let delete = api::DeleteRequest {
+        id: "ctr".into(),
+        ..Default::default()
+    };
+    client.delete(ctx.clone(), &delete).unwrap();
+    let _ = client.shutdown(ctx, &api::ShutdownRequest::default());
+}
+
+#[test]
+fn shim_reaps_adopted_processes()
+// ----------------------------------------------------------------------------
+//   Processes left behind by a task are reaped, not left as zombies
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("shim-reap");
+    let (shim_path, bundle) = setup(&dir, &["sh", "-c", "sleep 0.1 & exit 3"]);
+    let output = shim(&dir, &shim_path, &bundle, "start").output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let address = String::from_utf8(output.stdout).unwrap();
+    let client = TaskClient::new(Client::connect(address.trim()).unwrap());
+    let ctx = Context::default();
+    let create = api::CreateTaskRequest {
+        id: "ctr".into(),
+        bundle: bundle.to_str().unwrap().into(),
+        ..Default::default()
+    };
+    client.create(ctx.clone(), &create).unwrap();
+    let start = api::StartRequest {
+        id: "ctr".into(),
+        ..Default::default()
+    };
+    client.start(ctx.clone(), &start).unwrap();
+    let wait = api::WaitRequest {
+        id: "ctr".into(),
+        ..Default::default()
+    };
+    assert_eq!(client.wait(ctx.clone(), &wait).unwrap().exit_status, 3);
+
+    // The sleep outlives the task, and is adopted by the shim
+    let connect = api::ConnectRequest {
+        id: "ctr".into(),
+        ..Default::default()
+    };
+    let shim_pid = client.connect(ctx.clone(), &connect).unwrap().shim_pid.to_string();
+    let zombies = || {
+        fs::read_dir("/proc")
+            .unwrap()
+            .filter_map(|entry| fs::read_to_string(entry.ok()?.path().join("stat")).ok())
+            .filter(|stat| {
+                let fields: Vec<&str> = stat.rsplit(") ").next().unwrap().split(' ').collect();
+                fields[0] == "Z" && fields[1] == shim_pid
+            })
+            .count()
+    };
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(zombies(), 0);
+
+    let delete = api::DeleteRequest {
+        id: "ctr".into(),
+        ..Default::default()
+    };
+    client.delete(ctx.clone(), &delete).unwrap();
+    let _ = client.shutdown(ctx, &api::ShutdownRequest::default());
+}
@@ -0,0 +1,86 @@
+// ****************************************************************************
+//  syslog.rs                                                   ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that the --syslog option sends structured records to the
+//     system logger, using a local datagram socket in place of journald
+//     or /dev/log
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::os::unix::net::UnixDatagram;
+
+mod common;
+use common::{ociplex, scratch_dir, script_runtime};
+
+fn run_with_socket(name: &str, env_var: &str) -> Vec<String>
+// ----------------------------------------------------------------------------
+//   Run a failing `state` command with --syslog, return received records
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir(name);
+    let config = script_runtime(&dir, "exit 1", "");
+    let socket_path = dir.join("log.sock");
+    let socket = UnixDatagram::bind(&socket_path).unwrap();
+    socket.set_nonblocking(true).unwrap();
+
+    let status = ociplex(&config, &dir.join("log.json"))
+        .env(env_var, &socket_path)
+        .args(["--syslog", "state", "my-container"])
+        .status()
+        .unwrap();
+    assert!(!status.success());
+
+    let mut records = Vec::new();
+    let mut buffer = [0u8; 65536];
+    while let Ok(size) = socket.recv(&mut buffer) {
+        records.push(String::from_utf8_lossy(&buffer[..size]).into_owned());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+    records
+}
+
+#[test]
+fn journal_records_have_structured_fields() {
+    let records = run_with_socket("journal", "OCIPLEX_JOURNAL_SOCKET");
+    let error = records
+        .iter()
+        .find(|r| r.contains("PRIORITY=3\n"))
+        .expect("no error record sent to the journal");
+    assert!(error.contains("SYSLOG_IDENTIFIER=ociplex\n"));
+    assert!(error.contains("CONTAINER_ID=my-container\n"));
+    assert!(error.contains("SUBCOMMAND=state\n"));
+    assert!(error.contains("BACKEND=Cli\n"));
+    assert!(error.contains("MESSAGE=Backend CLI failed"));
+}
+
+#[test]
+fn syslog_records_follow_rfc3164() {
+    let records = run_with_socket("syslog", "OCIPLEX_SYSLOG_SOCKET");
+    let error = records
+        .iter()
+        .find(|r| r.starts_with("<27>"))
+        .expect("no error record sent to syslog");
+    assert!(error.contains(" ociplex["));
+    assert!(error.contains("Backend CLI failed"));
+    assert!(error.contains("container_id=\"my-container\""));
+    assert!(error.contains("backend=\"Cli\""));
+}
@@ -0,0 +1,118 @@
+// ****************************************************************************
+//  timeout.rs                                                  ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that CLI runtimes that take too long are terminated, then
+//     killed, and that unbounded subcommands are not timed out by default
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+use common::{ociplex, scratch_dir, script_runtime};
+
+fn run_slow(name: &str, script: &str, args: &[&str]) -> (bool, Duration, String)
+// ----------------------------------------------------------------------------
+//   Run a slow runtime with a 1s timeout, return success, duration and log
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir(name);
+    let config = script_runtime(&dir, script, "timeout = 1\nkill_grace = 1");
+    let log = dir.join("log");
+    let start = Instant::now();
+    let status = ociplex(&config, &log).args(args).status().unwrap();
+    let elapsed = start.elapsed();
+    let log = fs::read_to_string(&log).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    (status.success(), elapsed, log)
+}
+
+#[test]
+fn terminated_after_timeout() {
+    let (success, elapsed, log) = run_slow(
+        "timeout-term",
+        "trap 'kill $!; exit 0' TERM\nsleep 30 &\nwait",
+        &["delete", "c1"],
+    );
+    assert!(!success);
+    assert!(elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+    assert!(log.contains("Backend CLI timed out after 1 seconds"));
+}
+
+#[test]
+fn killed_after_grace_period() {
+    let (success, elapsed, log) =
+        run_slow("timeout-kill", "trap '' TERM\nexec sleep 30", &["delete", "c1"]);
+    assert!(!success);
+    assert!(elapsed >= Duration::from_secs(2), "took {:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(10), "took {:?}", elapsed);
+    assert!(log.contains("did not terminate"));
+    assert!(log.contains("Backend CLI timed out after 1 seconds"));
+}
+
+#[test]
+fn run_is_not_timed_out_by_default() {
+    let (success, elapsed, _) = run_slow(
+        "timeout-run",
+        "sleep 2",
+        &["run", "--bundle", "/tmp", "c1"],
+    );
+    assert!(success);
+    assert!(elapsed >= Duration::from_secs(2));
+}
+
+#[test]
+fn foreground_exec_is_not_timed_out_by_default() {
+    let (success, elapsed, _) = run_slow("timeout-exec", "sleep 2", &["exec", "c1", "sh"]);
+    assert!(success);
+    assert!(elapsed >= Duration::from_secs(2));
+
+    let (success, _, log) =
+        run_slow("timeout-exec-detach", "sleep 2", &["exec", "--detach", "c1", "sh"]);
+    assert!(!success);
+    assert!(log.contains("Backend CLI timed out after 1 seconds"));
+}
+
+#[test]
+fn children_terminated_after_timeout()
+// ----------------------------------------------------------------------------
+//   Processes started by the runtime are terminated along with it
+// ----------------------------------------------------------------------------
+{
+    let dir = scratch_dir("timeout-group");
+    let pid_file = dir.join("pid");
+    let script = format!("sleep 30 &\necho $! > {:?}\nwait", pid_file);
+    let config = script_runtime(&dir, &script, "timeout = 1\nkill_grace = 1");
+    let status = ociplex(&config, &dir.join("log")).args(["delete", "c1"]).status().unwrap();
+    assert!(!status.success());
+
+    // The orphan may linger as a zombie until it is reaped
+    let pid = fs::read_to_string(&pid_file).unwrap();
+    let stat = format!("/proc/{}/stat", pid.trim());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while fs::read_to_string(&stat).is_ok_and(|stat| !stat.contains(") Z ")) {
+        assert!(Instant::now() < deadline, "{} still running", pid.trim());
+        thread::sleep(Duration::from_millis(20));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
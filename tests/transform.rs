@@ -0,0 +1,219 @@
+// ****************************************************************************
+//  transform.rs                                                ociplex project
+// ****************************************************************************
+//
+//   File Description:
+//
+//     Check that the transform backend rewrites config.json in a shadow
+//     bundle, leaves the original bundle alone, and cleans up on delete
+//
+//
+//
+//
+// ****************************************************************************
+//   (C) 2023 Christophe de Dinechin <dinechin@redhat.com>
+//   This software is licensed under the terms outlined in LICENSE.txt
+// ****************************************************************************
+//   This file is part of ociplex.
+//
+//   ociplex is free software: you can redistribute it and/or modify
+//   it under the terms outlined in the LICENSE.txt file
+//
+//   ociplex is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// ****************************************************************************
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+mod common;
+use common::{ociplex, recorded_argv, scratch_dir, write_script};
+
+const ORIGINAL: &str = r#"{
+  "ociVersion": "1.0.2",
+  "process": { "terminal": false, "args": ["sh"], "env": ["PATH=/bin"] },
+  "root": { "path": "rootfs" }
+}"#;
+
+const TRANSFORM: &str = r#"
+[[transform]]
+op = "add"
+path = "/annotations/org.example.transformed"
+value = "yes"
+
+[[transform]]
+op = "add"
+path = "/hooks/prestart/-"
+value = { path = "/usr/bin/debug-hook", args = ["debug-hook"] }
+
+[[transform]]
+op = "add"
+path = "/process/env/-"
+value = "DEBUG=1"
+when = { "/process/terminal" = false }
+
+[[transform]]
+op = "replace"
+path = "/process/args/0"
+value = "bash"
+when = { "/process/terminal" = true }
+"#;
+
+fn transform_runtime(dir: &Path) -> PathBuf
+// ----------------------------------------------------------------------------
+//   Create a transform config for a runtime saving the config it receives
+// ----------------------------------------------------------------------------
+//   Its `state` reports the shadow bundle of container `c1`.
+{
+    let runtime = dir.join("runtime");
+    write_script(
+        &runtime,
+        &format!(
+            "printf '%s\\n' \"$@\" > {:?}\n\
+             case \" $* \" in *' state '*) echo '{{\"id\":\"c1\",\"bundle\":\"'{:?}'\"}}';; esac\n\
+             while [ $# -gt 0 ]; do\n\
+               [ \"$1\" = --bundle ] && cp \"$2/config.json\" {:?}\n\
+               shift\n\
+             done",
+            dir.join("argv"),
+            dir.join("state/bundles/c1"),
+            dir.join("seen.json")
+        ),
+    );
+    let path = dir.join("backend.toml");
+    fs::write(
+        &path,
+        format!(
+            "backend-type = \"Transform\"\nstate_dir = {:?}\n{}\n\
+             [backend]\nbackend-type = \"Cli\"\npath = {:?}\n",
+            dir.join("state"),
+            TRANSFORM,
+            runtime
+        ),
+    )
+    .unwrap();
+    let bundle = dir.join("bundle");
+    fs::create_dir_all(bundle.join("rootfs")).unwrap();
+    fs::write(bundle.join("config.json"), ORIGINAL).unwrap();
+    path
+}
+
+#[test]
+fn create_uses_shadow_bundle() {
+    let dir = scratch_dir("transform-create");
+    let config = transform_runtime(&dir);
+    let bundle = dir.join("bundle");
+    let status = ociplex(&config, &dir.join("log"))
+        .arg("create")
+        .arg("--bundle")
+        .arg(&bundle)
+        .arg("c1")
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let shadow = dir.join("state/bundles/c1");
+    let argv = recorded_argv(&dir);
+    assert!(argv.contains(&shadow.to_string_lossy().into_owned()));
+    assert_eq!(fs::read_to_string(bundle.join("config.json")).unwrap(), ORIGINAL);
+    assert!(shadow.join("rootfs").is_symlink());
+
+    let seen = fs::read_to_string(dir.join("seen.json")).unwrap();
+    let seen: Value = serde_json::from_str(&seen).unwrap();
+    assert_eq!(seen["annotations"]["org.example.transformed"], "yes");
+    assert_eq!(seen["hooks"]["prestart"][0]["path"], "/usr/bin/debug-hook");
+    assert_eq!(seen["process"]["env"], json!(["PATH=/bin", "DEBUG=1"]));
+    assert_eq!(seen["process"]["args"], json!(["sh"]));
+    assert_eq!(seen["root"]["path"], json!(bundle.join("rootfs").canonicalize().unwrap()));
+
+    // The state shows the bundle the container was created with
+    let output = ociplex(&config, &dir.join("log")).args(["state", "c1"]).output().unwrap();
+    assert!(output.status.success());
+    let state: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(state["bundle"], json!(bundle.canonicalize().unwrap()));
+
+    let status = ociplex(&config, &dir.join("log"))
+        .args(["delete", "c1"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(!shadow.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn run_keeps_shadow_bundle_with_keep() {
+    let dir = scratch_dir("transform-keep");
+    let config = transform_runtime(&dir);
+    let bundle = dir.join("bundle");
+    let run = |args: &[&str]| {
+        ociplex(&config, &dir.join("log"))
+            .arg("run")
+            .arg("--bundle")
+            .arg(&bundle)
+            .args(args)
+            .status()
+            .unwrap()
+    };
+    assert!(run(&["c1"]).success());
+    assert!(!dir.join("state/bundles/c1").exists());
+
+    // The container remains after run, and still uses the shadow bundle
+    assert!(run(&["--keep", "c1"]).success());
+    assert!(dir.join("state/bundles/c1/config.json").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn failed_operation_is_reported() {
+    let dir = scratch_dir("transform-fail");
+    let config = transform_runtime(&dir);
+    let text = fs::read_to_string(&config).unwrap();
+    let text = text.replacen(
+        "[[transform]]",
+        "[[transform]]\nop = \"test\"\npath = \"/ociVersion\"\nvalue = \"0.1\"\n\n[[transform]]",
+        1,
+    );
+    fs::write(&config, text).unwrap();
+
+    let log = dir.join("log");
+    let status = ociplex(&config, &log)
+        .arg("create")
+        .arg("--bundle")
+        .arg(dir.join("bundle"))
+        .arg("c1")
+        .status()
+        .unwrap();
+    assert!(!status.success());
+    assert!(!dir.join("argv").exists());
+    assert!(!dir.join("state/bundles/c1").exists());
+    assert!(fs::read_to_string(&log).unwrap().contains("Transform step 1"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn exec_backend_is_rejected() {
+    let dir = scratch_dir("transform-exec");
+    let config = transform_runtime(&dir);
+    let mut text = fs::read_to_string(&config).unwrap();
+    text.push_str("exec = true\n");
+    fs::write(&config, text).unwrap();
+
+    let log = dir.join("log");
+    let status = ociplex(&config, &log)
+        .arg("create")
+        .arg("--bundle")
+        .arg(dir.join("bundle"))
+        .arg("c1")
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+    assert!(!dir.join("argv").exists());
+    assert!(!dir.join("state/bundles/c1").exists());
+    let log = fs::read_to_string(&log).unwrap();
+    assert!(log.contains("Transformed backends cannot use exec"), "{}", log);
+    fs::remove_dir_all(&dir).unwrap();
+}